@@ -10,10 +10,19 @@ set -e
 
 mkdir -p $DIR
 
+LOCAL_ID=$(cat .dfx/local/canister_ids.json 2>/dev/null | jq -r ".beacon.local")
+# The number of pages covered by one checksum, see MAX_CHECKSUM_PAGES in the backend.
+CHECKSUM_PAGES=32
+
+checksum() {
+    $QU raw "$1" "backup_checksum" --args "($2:nat64, $CHECKSUM_PAGES:nat64)" --query |\
+        $QU send --yes --raw - > "$3"
+}
+
 restore() {
     FILE="$1"
     echo "Restoring $FILE..."
-    $QU raw $(cat .dfx/local/canister_ids.json | jq -r ".beacon.local") "stable_mem_write" --args-file "$FILE" | $QU send --yes --raw - > /dev/null
+    $QU raw $LOCAL_ID "stable_mem_write" --args-file "$FILE" | $QU send --yes --raw - > /dev/null
 }
 
 if [ "$CMD" == "restore" ]; then
@@ -26,7 +35,17 @@ if [ "$CMD" == "restore" ]; then
             PAGE=$((PAGE + 1))
         done
         wait
-        if [ "$(stat -f%z $FILE)" == "18" ]; then break; fi
+        if [ "$(stat -f%z $FILE)" == "20" ]; then break; fi
+    done
+    START=0
+    while [ -f "$DIR/checksum$START.bin" ]; do
+        echo "Verifying the checksum of pages from $START..."
+        checksum $LOCAL_ID $START "$DIR/checksum_restored.bin"
+        if ! cmp -s "$DIR/checksum$START.bin" "$DIR/checksum_restored.bin"; then
+            echo "Checksum mismatch: the restored pages from $START differ from the backup"
+            exit 1
+        fi
+        START=$((START + CHECKSUM_PAGES))
     done
     echo "Restoring heap..."
     dfx canister call beacon stable_to_heap
@@ -50,6 +69,13 @@ while true; do
         PAGE=$((PAGE + 1))
     done
     wait
-    if [ "$(stat -f%z $FILE)" == "18" ]; then break; fi
+    if [ "$(stat -f%z $FILE)" == "20" ]; then break; fi
 done
 
+# The checksums cover the whole stable memory, like the fetched pages, one range per query.
+START=0
+while [ $START -lt $PAGE ]; do
+    echo "Fetching the checksum of pages from $START..."
+    checksum "srn4v-3aaaa-aaaar-qaftq-cai" $START "$DIR/checksum$START.bin"
+    START=$((START + CHECKSUM_PAGES))
+done
@@ -7,17 +7,25 @@ use serde_bytes::ByteBuf;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 
+use crate::{queries::backend_data, read};
+
 type Headers = Vec<(String, String)>;
+// Bodies of an asset per content encoding in the order of preference; the uncompressed body is
+// always the last one.
+type Bodies = Vec<(&'static str, Vec<u8>)>;
 
 const LABEL: &[u8] = b"http_assets";
+const IDENTITY: &str = "identity";
 static mut ASSET_HASHES: Option<RbTree<Vec<u8>, Hash>> = None;
-static mut ASSETS: Option<HashMap<String, (Headers, Vec<u8>)>> = None;
+static mut ASSETS: Option<HashMap<String, (Headers, Bodies)>> = None;
+// Set whenever the state was mutated since the API responses were computed last time.
+static mut API_OUTDATED: bool = false;
 
 fn asset_hashes<'a>() -> &'a mut RbTree<Vec<u8>, Hash> {
     unsafe { ASSET_HASHES.as_mut().expect("uninitialized") }
 }
 
-fn assets<'a>() -> &'a mut HashMap<String, (Headers, Vec<u8>)> {
+fn assets<'a>() -> &'a mut HashMap<String, (Headers, Bodies)> {
     unsafe { ASSETS.as_mut().expect("uninitialized") }
 }
 
@@ -36,13 +44,14 @@ pub fn load() {
         include_bytes!("../../dist/frontend/index.html").to_vec(),
     );
 
-    add_asset(
+    add_encoded_asset(
         &["/index.js"],
-        vec![
-            ("Content-Type".to_string(), "text/javascript".to_string()),
-            ("Content-Encoding".to_string(), "gzip".to_string()),
-        ],
-        include_bytes!("../../dist/frontend/index.js.gz").to_vec(),
+        vec![("Content-Type".to_string(), "text/javascript".to_string())],
+        include_bytes!("../../dist/frontend/index.js").to_vec(),
+        vec![(
+            "gzip",
+            include_bytes!("../../dist/frontend/index.js.gz").to_vec(),
+        )],
     );
 
     add_asset(
@@ -66,13 +75,15 @@ pub fn load() {
         include_bytes!("../../dist/frontend/apple-touch-icon.png").to_vec(),
     );
 
-    let mut domains = vec![
-        "beacondex.link",
-        "srn4v-3aaaa-aaaar-qaftq-cai.icp0.io",
-        "cetrr-jaaaa-aaaak-afgxq-cai.icp0.io",
-    ];
-    let can_domain = format!("{}.icp0.io", ic_cdk::id());
-    domains.push(&can_domain);
+    load_domains();
+    refresh_api();
+}
+
+/// Rebuilds and re-certifies the domain assets from the domains stored in the state, so it must be
+/// called after every change of the domains.
+pub fn load_domains() {
+    let mut domains = read(|state| state.domains());
+    domains.push(format!("{}.icp0.io", ic_cdk::id()));
     add_asset(
         &["/.well-known/ii-alternative-origins"],
         vec![
@@ -96,23 +107,60 @@ pub fn load() {
         Default::default(),
         domains.join("\n").as_bytes().to_vec(),
     );
+    ic_cdk::api::set_certified_data(&labeled_hash(LABEL, &asset_hashes().root_hash()));
+}
 
+/// Marks the JSON API responses as outdated, so that they get recomputed by the next
+/// `refresh_outdated_api` call.
+pub fn invalidate_api() {
+    unsafe { API_OUTDATED = true }
+}
+
+/// Recomputes and re-certifies the JSON API responses if the state was mutated since they were
+/// computed last time. Queries can't update the certified data, so this is called from a timer.
+pub fn refresh_outdated_api() {
+    if unsafe { API_OUTDATED } {
+        refresh_api();
+    }
+}
+
+// The API responses are served and certified like static assets.
+fn refresh_api() {
+    unsafe { API_OUTDATED = false }
+    let (prices, tokens) = read(|state| {
+        (
+            serde_json::json!(state.prices()).to_string(),
+            serde_json::json!(state.tokens()).to_string(),
+        )
+    });
+    let headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+    add_asset(&["/api/prices"], headers.clone(), prices.into_bytes());
+    add_asset(&["/api/tokens"], headers, tokens.into_bytes());
     ic_cdk::api::set_certified_data(&labeled_hash(LABEL, &asset_hashes().root_hash()));
 }
 
 fn add_asset(paths: &[&str], headers: Headers, bytes: Vec<u8>) {
+    add_encoded_asset(paths, headers, bytes, Default::default())
+}
+
+// Adds an asset together with its pre-compressed variants. Only the hash of the uncompressed
+// body is certified, because the certificate verification decodes compressed bodies before
+// hashing them.
+fn add_encoded_asset(paths: &[&str], headers: Headers, bytes: Vec<u8>, mut encoded: Bodies) {
     let mut hasher = Sha256::new();
     hasher.update(&bytes);
     let hash = hasher.finalize().into();
+    encoded.push((IDENTITY, bytes));
     for path in paths {
         asset_hashes().insert(path.as_bytes().to_vec(), hash);
-        assets().insert(path.to_string(), (headers.clone(), bytes.clone()));
+        assets().insert(path.to_string(), (headers.clone(), encoded.clone()));
     }
 }
 
 #[derive(CandidType, Deserialize)]
 pub struct HttpRequest {
     url: String,
+    headers: Headers,
 }
 
 #[derive(CandidType, Serialize)]
@@ -125,12 +173,110 @@ pub struct HttpResponse {
 #[ic_cdk_macros::query]
 fn http_request(req: HttpRequest) -> HttpResponse {
     let path = req.url.split('?').next().expect("no path in url");
-    response(path)
+    match path {
+        "/metrics" => metrics_response(),
+        _ => response(path, &accepted_encodings(&req.headers)),
+    }
+}
+
+// Returns the content encodings accepted by the client according to the `Accept-Encoding`
+// header, skipping the ones explicitly rejected with `q=0`.
+fn accepted_encodings(headers: &Headers) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("accept-encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let encoding = parts.next()?.to_lowercase();
+            let rejected = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .map(|q| q == 0.0)
+                    .unwrap_or_default()
+            });
+            (!encoding.is_empty() && !rejected).then_some(encoding)
+        })
+        .collect()
 }
 
-fn response(path: &str) -> HttpResponse {
-    let (headers, bytes) = assets().get(path).expect("no asset");
+// Returns the metrics in the Prometheus text format. The metrics are computed on every request and
+// are not certified.
+fn metrics_response() -> HttpResponse {
+    let data = backend_data();
+    let metrics = [
+        (
+            "beacon_volume_day",
+            "Trading volume of the last 24 hours in payment token particles.",
+            data.volume_day.to_string(),
+        ),
+        (
+            "beacon_trades_day",
+            "Number of trades in the last 24 hours.",
+            data.trades_day.to_string(),
+        ),
+        (
+            "beacon_icp_locked",
+            "Payment tokens under management in payment token particles.",
+            data.payment_token_locked.to_string(),
+        ),
+        (
+            "beacon_tokens_listed",
+            "Number of listed tokens.",
+            data.tokens_listed.to_string(),
+        ),
+        (
+            "beacon_active_traders",
+            "Number of users with open orders.",
+            data.active_traders.to_string(),
+        ),
+        (
+            "beacon_cycle_balance",
+            "Cycle balance of the canister.",
+            data.cycle_balance.to_string(),
+        ),
+        (
+            "beacon_heap_size",
+            "Size of the serialized heap in bytes.",
+            data.heap_size.to_string(),
+        ),
+    ];
+    let mut body = String::new();
+    for (name, help, value) in metrics {
+        body.push_str(&format!(
+            "# HELP {0} {1}\n# TYPE {0} gauge\n{0} {2}\n",
+            name, help, value
+        ));
+    }
+    HttpResponse {
+        status_code: 200,
+        headers: vec![(
+            "Content-Type".to_string(),
+            "text/plain; version=0.0.4".to_string(),
+        )],
+        body: ByteBuf::from(body.into_bytes()),
+    }
+}
+
+fn response(path: &str, accepted_encodings: &[String]) -> HttpResponse {
+    let (headers, bodies) = assets().get(path).expect("no asset");
+    let (encoding, bytes) = bodies
+        .iter()
+        .find(|(encoding, _)| {
+            *encoding == IDENTITY
+                || accepted_encodings
+                    .iter()
+                    .any(|accepted| accepted == encoding || accepted == "*")
+        })
+        .expect("no uncompressed body");
     let mut headers = headers.clone();
+    if *encoding != IDENTITY {
+        headers.push(("Content-Encoding".to_string(), encoding.to_string()));
+    }
+    if bodies.len() > 1 {
+        headers.push(("Vary".to_string(), "Accept-Encoding".to_string()));
+    }
     headers.push(certificate_header(path));
     HttpResponse {
         status_code: 200,
@@ -155,3 +301,26 @@ fn certificate_header(path: &str) -> (String, String) {
         ),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepted_encodings() {
+        let headers = |value: &str| vec![("Accept-Encoding".to_string(), value.to_string())];
+        assert!(accepted_encodings(&vec![]).is_empty());
+        assert_eq!(
+            accepted_encodings(&headers("gzip, deflate, br")),
+            vec!["gzip", "deflate", "br"]
+        );
+        assert_eq!(
+            accepted_encodings(&headers("br;q=1.0, GZIP;q=0.5, deflate;q=0")),
+            vec!["br", "gzip"]
+        );
+        assert_eq!(
+            accepted_encodings(&vec![("accept-encoding".to_string(), "gzip".to_string())]),
+            vec!["gzip"]
+        );
+    }
+}
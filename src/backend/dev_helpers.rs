@@ -12,14 +12,22 @@ fn replace_user_id(old: Principal, new: Principal) {
 }
 
 #[update]
-fn stable_mem_write(input: Vec<(u64, Vec<u8>)>) {
-    if let Some((page, buffer)) = input.get(0) {
+fn stable_mem_write(input: Vec<(u64, Vec<u8>, Vec<u8>)>) {
+    if let Some((page, buffer, hash)) = input.first() {
         if buffer.is_empty() {
             return;
         }
+        assert_eq!(
+            &page_hash(buffer),
+            hash,
+            "checksum mismatch on page {}",
+            page
+        );
         let offset = page * BACKUP_PAGE_SIZE as u64;
         let current_size = api::stable::stable_size();
-        let needed_size = ((offset + buffer.len() as u64) >> 16) + 1;
+        // The restored memory must not be larger than the backed up one, so that their
+        // checksums match.
+        let needed_size = (offset + buffer.len() as u64).div_ceil(1 << 16);
         let delta = needed_size.saturating_sub(current_size);
         if delta > 0 {
             api::stable::stable_grow(delta).unwrap_or_else(|_| panic!("couldn't grow memory"));
@@ -1,8 +1,9 @@
 use ic_cdk::id;
-use std::collections::BTreeMap;
+use std::{cell::RefCell, collections::BTreeMap};
 
 use candid::{CandidType, Deserialize, Principal};
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 
 use crate::order_book::{TokenId, Tokens};
 
@@ -49,11 +50,16 @@ pub struct BadFee {
     expected_fee: u128,
 }
 
+#[derive(CandidType, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Duplicate {
+    duplicate_of: u128,
+}
+
 #[derive(CandidType, Debug, PartialEq, Deserialize, Serialize)]
 pub enum TransferError {
     BadFee(BadFee),
     // BadBurn(BadBurn),
-    // Duplicate(Duplicate),
+    Duplicate(Duplicate),
     TemporarilyUnavailable,
     InsufficientFunds(InsufficientFunds),
     TooOld,
@@ -69,6 +75,34 @@ pub enum Value {
     // Blob(Vec<u8>),
 }
 
+#[derive(CandidType, Debug, Deserialize)]
+pub struct StandardRecord {
+    pub name: String,
+    pub url: String,
+}
+
+pub async fn supported_standards(token: TokenId) -> Result<Vec<StandardRecord>, String> {
+    let (result,): (Vec<StandardRecord>,) = ic_cdk::call(token, "icrc1_supported_standards", ((),))
+        .await
+        .map_err(|err| format!("call failed: {:?}", err))?;
+    Ok(result)
+}
+
+/// Checks that the list of supported standards contains ICRC-1.
+pub fn verify_standards(standards: &[StandardRecord]) -> Result<(), String> {
+    if standards.iter().any(|standard| standard.name == "ICRC-1") {
+        Ok(())
+    } else {
+        Err(format!(
+            "token does not support ICRC-1; supported standards: {:?}",
+            standards
+                .iter()
+                .map(|standard| standard.name.as_str())
+                .collect::<Vec<_>>()
+        ))
+    }
+}
+
 pub async fn balance_of(token: TokenId, account: &Account) -> Result<Tokens, String> {
     let (result,): (Tokens,) = ic_cdk::call(token, "icrc1_balance_of", (&account,))
         .await
@@ -76,6 +110,13 @@ pub async fn balance_of(token: TokenId, account: &Account) -> Result<Tokens, Str
     Ok(result)
 }
 
+pub async fn fee(token: TokenId) -> Result<Tokens, String> {
+    let (result,): (Tokens,) = ic_cdk::call(token, "icrc1_fee", ((),))
+        .await
+        .map_err(|err| format!("call failed: {:?}", err))?;
+    Ok(result)
+}
+
 pub async fn metadata(token: TokenId) -> Result<BTreeMap<String, Value>, String> {
     let (result,): (Vec<(String, Value)>,) = ic_cdk::call(token, "icrc1_metadata", ((),))
         .await
@@ -106,25 +147,257 @@ pub async fn metadata(token: TokenId) -> Result<BTreeMap<String, Value>, String>
     Ok(data)
 }
 
+// The ledger of a token, abstracted so that the transfer logic can be tested without a canister.
+pub(crate) trait Ledger {
+    async fn transfer(&self, args: &TransferArgs) -> Result<Result<u128, TransferError>, String>;
+
+    async fn balance_of(&self, account: &Account) -> Result<Tokens, String>;
+}
+
+pub(crate) struct Icrc1Ledger(pub(crate) TokenId);
+
+impl Ledger for Icrc1Ledger {
+    async fn transfer(&self, args: &TransferArgs) -> Result<Result<u128, TransferError>, String> {
+        let (result,): (Result<u128, TransferError>,) =
+            ic_cdk::call(self.0, "icrc1_transfer", (args,))
+                .await
+                .map_err(|err| format!("call failed: {:?}", err))?;
+        Ok(result)
+    }
+
+    async fn balance_of(&self, account: &Account) -> Result<Tokens, String> {
+        balance_of(self.0, account).await
+    }
+}
+
+thread_local! {
+    // The number of started and of running transfers per token.
+    static TRANSFERS: RefCell<BTreeMap<TokenId, (u64, u64)>> = Default::default();
+}
+
+// Counts a transfer of the token as running for as long as it exists.
+struct RunningTransfer(TokenId);
+
+impl RunningTransfer {
+    fn new(token: TokenId) -> Self {
+        TRANSFERS.with(|transfers| {
+            let mut transfers = transfers.borrow_mut();
+            let (started, running) = transfers.entry(token).or_default();
+            *started += 1;
+            *running += 1;
+        });
+        Self(token)
+    }
+}
+
+impl Drop for RunningTransfer {
+    fn drop(&mut self) {
+        TRANSFERS.with(|transfers| {
+            if let Some((_, running)) = transfers.borrow_mut().get_mut(&self.0) {
+                *running = running.saturating_sub(1);
+            }
+        });
+    }
+}
+
+fn transfer_counters(token: TokenId) -> (u64, u64) {
+    TRANSFERS.with(|transfers| transfers.borrow().get(&token).copied().unwrap_or_default())
+}
+
+/// The outcome of a deposit into BEACON's account.
+#[derive(Debug, PartialEq)]
+pub struct Deposit {
+    // The amount sent by the ledger transfer.
+    pub sent: Tokens,
+    // The amount that arrived on BEACON's account.
+    pub received: Tokens,
+    pub fee: Tokens,
+}
+
+/// Transfers the amount from the subaccount to BEACON's account and verifies the amount arriving
+/// there, so that ledgers deducting more than the advertised fee are detected.
+pub async fn deposit(
+    token: TokenId,
+    from_subaccount: Option<Subaccount>,
+    amount: Tokens,
+    fee: Tokens,
+) -> Result<Deposit, String> {
+    let ledger = Icrc1Ledger(token);
+    let now = ic_cdk::api::time();
+    checked_deposit(
+        &ledger,
+        token,
+        from_subaccount,
+        main_account(),
+        amount,
+        fee,
+        now,
+    )
+    .await
+}
+
+// The received amount is the difference of the balances of the receiving account before and
+// after the transfer, capped by the sent amount, as third parties might send funds to the
+// account. If other transfers of the token ran in the meantime, the difference is meaningless
+// and the sent amount is assumed to be received.
+async fn checked_deposit(
+    ledger: &impl Ledger,
+    token: TokenId,
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    amount: Tokens,
+    fee: Tokens,
+    created_at_time: Timestamp,
+) -> Result<Deposit, String> {
+    let (started, running) = transfer_counters(token);
+    let balance_before = ledger.balance_of(&to).await?;
+    let (sent, fee) = transfer_with_fee_retry(
+        ledger,
+        token,
+        from_subaccount,
+        to.clone(),
+        amount,
+        fee,
+        created_at_time,
+    )
+    .await?;
+    let balance_after = ledger.balance_of(&to).await;
+    let received = match balance_after {
+        Ok(balance_after) if running == 0 && transfer_counters(token).0 == started + 1 => {
+            balance_after.saturating_sub(balance_before).min(sent)
+        }
+        _ => sent,
+    };
+    Ok(Deposit {
+        sent,
+        received,
+        fee,
+    })
+}
+
+/// Returns the balance of the account, if no transfer of the token ran while it was fetched.
+/// Such a balance is consistent with the internal accounting at the time it is returned, because
+/// deposits and withdrawals update the accounting in the same message their transfer completes.
+pub async fn settled_balance(token: TokenId, account: &Account) -> Result<Tokens, String> {
+    checked_balance(&Icrc1Ledger(token), token, account).await
+}
+
+async fn checked_balance(
+    ledger: &impl Ledger,
+    token: TokenId,
+    account: &Account,
+) -> Result<Tokens, String> {
+    let (started, running) = transfer_counters(token);
+    let balance = ledger.balance_of(account).await?;
+    if running > 0 || transfer_counters(token).0 != started {
+        return Err("transfers of the token are in progress".into());
+    }
+    Ok(balance)
+}
+
+/// Transfers the amount and returns the transferred amount together with the paid fee.
+///
+/// The transfer carries its creation time and a memo derived from its arguments, so that the
+/// ledger deduplicates it if it gets submitted again. If the ledger expects a different fee, the
+/// transfer is retried once with the expected fee and the amount adjusted so that the sum of the
+/// amount and the fee stays the same. Callers should update the cached fee in this case.
 pub async fn transfer(
     token: TokenId,
     from_subaccount: Option<Subaccount>,
     to: Account,
     amount: Tokens,
     fee: Tokens,
-) -> Result<u128, String> {
-    let args = TransferArgs {
+) -> Result<(Tokens, Tokens), String> {
+    let ledger = Icrc1Ledger(token);
+    let now = ic_cdk::api::time();
+    transfer_with_fee_retry(&ledger, token, from_subaccount, to, amount, fee, now).await
+}
+
+pub(crate) async fn transfer_with_fee_retry(
+    ledger: &impl Ledger,
+    token: TokenId,
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    amount: Tokens,
+    fee: Tokens,
+    created_at_time: Timestamp,
+) -> Result<(Tokens, Tokens), String> {
+    let _running = RunningTransfer::new(token);
+    let args = transfer_args(token, from_subaccount, to, amount, fee, created_at_time);
+    match submit_transfer(ledger, &args).await? {
+        Err(TransferError::BadFee(BadFee { expected_fee })) => {
+            let amount = (amount + fee)
+                .checked_sub(expected_fee)
+                .filter(|amount| *amount > 0)
+                .ok_or("amount smaller than the fee")?;
+            let args = transfer_args(
+                token,
+                args.from_subaccount,
+                args.to,
+                amount,
+                expected_fee,
+                created_at_time,
+            );
+            transfer_result(submit_transfer(ledger, &args).await?).map(|_| (amount, expected_fee))
+        }
+        result => transfer_result(result).map(|_| (amount, fee)),
+    }
+}
+
+fn transfer_args(
+    token: TokenId,
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    amount: Tokens,
+    fee: Tokens,
+    created_at_time: Timestamp,
+) -> TransferArgs {
+    TransferArgs {
+        memo: Some(memo(token, &from_subaccount, &to, amount, created_at_time)),
         from_subaccount,
         to,
         amount,
-        memo: None,
         fee: Some(fee),
-        created_at_time: None,
-    };
-    let (result,): (Result<u128, TransferError>,) = ic_cdk::call(token, "icrc1_transfer", (args,))
-        .await
-        .map_err(|err| format!("call failed: {:?}", err))?;
-    result.map_err(|err| format!("{:?}", err))
+        created_at_time: Some(created_at_time),
+    }
+}
+
+// If the call failed, we don't know whether the transfer was executed, so we retry it once with
+// the same arguments. If the first attempt succeeded, the ledger reports a duplicate.
+async fn submit_transfer(
+    ledger: &impl Ledger,
+    args: &TransferArgs,
+) -> Result<Result<u128, TransferError>, String> {
+    match ledger.transfer(args).await {
+        Err(_) => ledger.transfer(args).await,
+        result => result,
+    }
+}
+
+fn transfer_result(result: Result<u128, TransferError>) -> Result<u128, String> {
+    match result {
+        // The transfer was executed by a previous attempt.
+        Err(TransferError::Duplicate(Duplicate { duplicate_of })) => Ok(duplicate_of),
+        result => result.map_err(|err| format!("{:?}", err)),
+    }
+}
+
+// Returns a memo identifying the transfer by its sender, receiver, token, amount and creation time.
+fn memo(
+    token: TokenId,
+    from_subaccount: &Option<Subaccount>,
+    to: &Account,
+    amount: Tokens,
+    created_at_time: Timestamp,
+) -> Memo {
+    let mut hasher = Sha256::new();
+    hasher.update(token.as_slice());
+    hasher.update(from_subaccount.as_deref().unwrap_or_default());
+    hasher.update(to.owner.as_slice());
+    hasher.update(to.subaccount.as_deref().unwrap_or_default());
+    hasher.update(amount.to_be_bytes());
+    hasher.update(created_at_time.to_be_bytes());
+    hasher.finalize().into()
 }
 
 /// Returns BEACON's account.
@@ -144,3 +417,283 @@ pub fn user_account(user: Principal) -> Account {
         subaccount: Some(subaccount),
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    #[test]
+    fn test_verify_standards() {
+        assert_eq!(
+            verify_standards(&[]),
+            Err("token does not support ICRC-1; supported standards: []".into())
+        );
+        assert!(verify_standards(&[StandardRecord {
+            name: "ICRC-2".into(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".into(),
+        }])
+        .is_err());
+        assert!(verify_standards(&[
+            StandardRecord {
+                name: "ICRC-1".into(),
+                url: "https://github.com/dfinity/ICRC-1".into(),
+            },
+            StandardRecord {
+                name: "ICRC-2".into(),
+                url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".into(),
+            }
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn test_transfer_deduplication() {
+        let token = Principal::from_slice(&[1]);
+        let to = Account {
+            owner: Principal::from_slice(&[2]),
+            subaccount: None,
+        };
+        let memo_1 = memo(token, &None, &to, 100, 1);
+        // the memo is deterministic
+        assert_eq!(memo_1, memo(token, &None, &to, 100, 1));
+        // and differs for different transfers
+        assert_ne!(memo_1, memo(token, &None, &to, 101, 1));
+        assert_ne!(memo_1, memo(token, &None, &to, 100, 2));
+        assert_ne!(memo_1, memo(token, &Some(vec![0; 32]), &to, 100, 1));
+
+        // a duplicate means that the transfer was executed already
+        assert_eq!(
+            transfer_result(Err(TransferError::Duplicate(Duplicate { duplicate_of: 7 }))),
+            Ok(7)
+        );
+        assert_eq!(transfer_result(Ok(8)), Ok(8));
+        assert_eq!(
+            transfer_result(Err(TransferError::TooOld)),
+            Err("TooOld".into())
+        );
+    }
+
+    // A ledger returning the given responses and recording the amounts and fees of all calls.
+    pub(crate) struct MockLedger {
+        responses: RefCell<Vec<Result<Result<u128, TransferError>, String>>>,
+        calls: RefCell<Vec<(Tokens, Option<Tokens>)>>,
+        // The balance of the receiving account and the share of each transfer arriving there.
+        balance: RefCell<Tokens>,
+        delivered_percent: u128,
+        // Whether transfers suspend once like inter-canister calls, so that other futures can run
+        // while they are pending.
+        pub(crate) suspending: bool,
+    }
+
+    impl MockLedger {
+        pub(crate) fn new(mut responses: Vec<Result<Result<u128, TransferError>, String>>) -> Self {
+            responses.reverse();
+            Self {
+                responses: RefCell::new(responses),
+                calls: Default::default(),
+                balance: Default::default(),
+                delivered_percent: 100,
+                suspending: false,
+            }
+        }
+    }
+
+    // A future which is pending when it gets polled for the first time.
+    struct Suspend(bool);
+
+    impl Future for Suspend {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            context.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    impl Ledger for MockLedger {
+        async fn transfer(
+            &self,
+            args: &TransferArgs,
+        ) -> Result<Result<u128, TransferError>, String> {
+            if self.suspending {
+                Suspend(false).await;
+            }
+            self.calls.borrow_mut().push((args.amount, args.fee));
+            let response = self.responses.borrow_mut().pop().expect("no response");
+            if let Ok(Ok(_)) = response {
+                *self.balance.borrow_mut() += args.amount * self.delivered_percent / 100;
+            }
+            response
+        }
+
+        async fn balance_of(&self, _account: &Account) -> Result<Tokens, String> {
+            Ok(*self.balance.borrow())
+        }
+    }
+
+    // Polls the future once, which suffices, as the mock ledger never suspends.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        fn noop_raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                noop_raw_waker()
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        let mut future = Box::pin(future);
+        match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("the future is pending"),
+        }
+    }
+
+    fn transfer(ledger: &MockLedger, amount: Tokens, fee: Tokens) -> Result<(u128, u128), String> {
+        let to = Account {
+            owner: Principal::from_slice(&[2]),
+            subaccount: None,
+        };
+        block_on(transfer_with_fee_retry(
+            ledger,
+            Principal::from_slice(&[1]),
+            None,
+            to,
+            amount,
+            fee,
+            0,
+        ))
+    }
+
+    #[test]
+    fn test_transfer_bad_fee_retry() {
+        // the ledger rejects the fee first and accepts the retry with the expected fee
+        let ledger = MockLedger::new(vec![
+            Ok(Err(TransferError::BadFee(BadFee { expected_fee: 30 }))),
+            Ok(Ok(1)),
+        ]);
+        assert_eq!(transfer(&ledger, 1000, 10), Ok((980, 30)));
+        assert_eq!(
+            *ledger.calls.borrow(),
+            vec![(1000, Some(10)), (980, Some(30))]
+        );
+
+        // the fee is retried only once
+        let ledger = MockLedger::new(vec![
+            Ok(Err(TransferError::BadFee(BadFee { expected_fee: 30 }))),
+            Ok(Err(TransferError::BadFee(BadFee { expected_fee: 40 }))),
+        ]);
+        assert_eq!(
+            transfer(&ledger, 1000, 10),
+            Err("BadFee(BadFee { expected_fee: 40 })".into())
+        );
+        assert_eq!(ledger.calls.borrow().len(), 2);
+
+        // the amount can't cover the expected fee
+        let ledger = MockLedger::new(vec![Ok(Err(TransferError::BadFee(BadFee {
+            expected_fee: 1010,
+        })))]);
+        assert_eq!(
+            transfer(&ledger, 1000, 10),
+            Err("amount smaller than the fee".into())
+        );
+
+        // a failed call is retried with the same arguments and a duplicate counts as success
+        let ledger = MockLedger::new(vec![
+            Err("call failed".into()),
+            Ok(Err(TransferError::Duplicate(Duplicate { duplicate_of: 1 }))),
+        ]);
+        assert_eq!(transfer(&ledger, 1000, 10), Ok((1000, 10)));
+        assert_eq!(
+            *ledger.calls.borrow(),
+            vec![(1000, Some(10)), (1000, Some(10))]
+        );
+    }
+
+    fn deposit(ledger: &MockLedger, amount: Tokens) -> Result<Deposit, String> {
+        let token = Principal::from_slice(&[1]);
+        let to = Account {
+            owner: Principal::from_slice(&[2]),
+            subaccount: None,
+        };
+        block_on(checked_deposit(ledger, token, None, to, amount, 10, 0))
+    }
+
+    #[test]
+    fn test_checked_deposit() {
+        // a standard ledger delivers the full amount
+        let ledger = MockLedger::new(vec![Ok(Ok(1))]);
+        *ledger.balance.borrow_mut() = 5000;
+        assert_eq!(
+            deposit(&ledger, 1000),
+            Ok(Deposit {
+                sent: 1000,
+                received: 1000,
+                fee: 10
+            })
+        );
+
+        // a ledger deducting an additional fee from the transferred amount
+        let mut ledger = MockLedger::new(vec![Ok(Ok(1))]);
+        ledger.delivered_percent = 90;
+        assert_eq!(
+            deposit(&ledger, 1000),
+            Ok(Deposit {
+                sent: 1000,
+                received: 900,
+                fee: 10
+            })
+        );
+
+        // a failed transfer delivers nothing
+        let ledger = MockLedger::new(vec![Ok(Err(TransferError::TooOld))]);
+        assert_eq!(deposit(&ledger, 1000), Err("TooOld".into()));
+
+        // the received amount can't be verified while other transfers of the token are running
+        let token = Principal::from_slice(&[1]);
+        let running = RunningTransfer::new(token);
+        let mut ledger = MockLedger::new(vec![Ok(Ok(1))]);
+        ledger.delivered_percent = 90;
+        assert_eq!(
+            deposit(&ledger, 1000).map(|deposit| deposit.received),
+            Ok(1000)
+        );
+        drop(running);
+        assert_eq!(transfer_counters(token).1, 0);
+    }
+
+    #[test]
+    fn test_checked_balance() {
+        let token = Principal::from_slice(&[2]);
+        let ledger = MockLedger::new(vec![]);
+        *ledger.balance.borrow_mut() = 5000;
+        let account = Account {
+            owner: Principal::from_slice(&[2]),
+            subaccount: None,
+        };
+        assert_eq!(
+            block_on(checked_balance(&ledger, token, &account)),
+            Ok(5000)
+        );
+
+        // the balance is not settled while a transfer of the token is running
+        let running = RunningTransfer::new(token);
+        assert_eq!(
+            block_on(checked_balance(&ledger, token, &account)),
+            Err("transfers of the token are in progress".into())
+        );
+        drop(running);
+        assert_eq!(
+            block_on(checked_balance(&ledger, token, &account)),
+            Ok(5000)
+        );
+    }
+}
@@ -1,15 +1,15 @@
-use ic_cdk::api::stable::{stable_grow, stable_read, stable_size, stable_write};
+use ic_stable_structures::Memory;
 use icrc1::Account;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::cell::RefCell;
-use std::collections::BTreeMap;
 use std::time::Duration;
 
 use candid::Principal;
 use ic_cdk::{api::call::reply_raw, caller, spawn};
 use ic_cdk_macros::*;
-use ic_cdk_timers::{set_timer, set_timer_interval};
-use order_book::{Order, OrderType, State, Timestamp, TokenId, Tokens, TX_FEE};
+use ic_cdk_timers::set_timer_interval;
+use order_book::{Order, OrderType, Pair, State, Timestamp, TokenId, Tokens};
 
 mod assets;
 #[cfg(feature = "dev")]
@@ -17,6 +17,7 @@ mod dev_helpers;
 mod icrc1;
 mod order_book;
 mod queries;
+mod storage;
 mod updates;
 
 const BACKUP_PAGE_SIZE: u32 = 1024 * 1024;
@@ -41,6 +42,7 @@ fn unsafe_mutate<F, R>(f: F) -> R
 where
     F: FnOnce(&mut State) -> R,
 {
+    assets::invalidate_api();
     STATE.with(|cell| f(&mut cell.borrow_mut()))
 }
 
@@ -57,6 +59,7 @@ fn mutate_with_invarant_check<F, R>(f: F, liquidity_delta: Option<(TokenId, i128
 where
     F: FnOnce(&mut State) -> R,
 {
+    assets::invalidate_api();
     let balances_before = read(|state| state.funds_under_management());
     let result = STATE.with(|cell| f(&mut cell.borrow_mut()));
     let mut balances_after = read(|state| state.funds_under_management());
@@ -86,25 +89,82 @@ fn reply<T: serde::Serialize>(data: T) {
 // Starts all repeating tasks.
 fn kickstart() {
     assets::load();
+    // The JSON API responses are recomputed at most every 5 seconds and only if the state changed.
+    set_timer_interval(Duration::from_secs(5), assets::refresh_outdated_api);
     set_timer_interval(Duration::from_secs(24 * 60 * 60), || {
         unsafe_mutate(|state| state.clean_up(ic_cdk::api::time()));
     });
     set_timer_interval(Duration::from_secs(24 * 60 * 60), || {
         mutate(heap_to_stable);
     });
-    // weekly payment token metadata updates
-    set_timer(Duration::from_secs(24 * 60 * 60 * 7), move || {
-        spawn(async {
-            let payment_token_id = read(|state| state.payment_token_id());
-            register_token(payment_token_id)
-                .await
-                .expect("couldn't update payment token metadata");
-        })
+    set_timer_interval(
+        Duration::from_secs(order_book::KEEPALIVE_CHECK_INTERVAL_SECS),
+        || {
+            mutate(|state| {
+                state.close_stale_orders(ic_cdk::api::time());
+            });
+        },
+    );
+    set_timer_interval(Duration::from_secs(60 * 60), || {
+        mutate(|state| state.check_cycles(ic_cdk::api::canister_balance()));
     });
+    // weekly metadata updates of all listed tokens
+    set_timer_interval(Duration::from_secs(24 * 60 * 60 * 7), || {
+        spawn(refresh_all_token_metadata())
+    });
+}
+
+// Refreshes the metadata of all listed tokens one after another, so that the ledgers don't get
+// flooded with calls. Failures are logged and don't stop the refresh of the remaining tokens.
+async fn refresh_all_token_metadata() {
+    let tokens = read(|state| state.tokens().keys().copied().collect::<Vec<_>>());
+    for token in tokens {
+        if let Err(err) = refresh_token(token).await {
+            mutate(|state| {
+                state.log(format!(
+                    "couldn't refresh the metadata of token {}: {}",
+                    token, err
+                ))
+            });
+        }
+    }
 }
 
 fn stable_to_heap_core() {
-    STATE.with(|cell| cell.replace(stable_to_heap()));
+    // Previous versions stored the whole state, including the order archive and the logs, in one
+    // serialized heap at the beginning of the stable memory.
+    if storage::legacy_layout() {
+        let state = serde_cbor::from_slice(&storage::legacy_heap()).expect("couldn't deserialize");
+        STATE.with(|cell| cell.replace(state));
+        mutate(|state| {
+            state.migrate_to_stable_memory();
+            heap_to_stable(state);
+        });
+        return;
+    }
+    storage::reload();
+    let state = match stable_to_heap() {
+        Ok(state) => state,
+        Err(err) if storage::safe_mode() => {
+            // The serialized heap stays in stable memory until the operator finishes the
+            // recovery, because the recovering state is never written over it, so that it can
+            // still be downloaded and restored. The controller performing the upgrade becomes
+            // the revenue account, so that nobody else can claim it, and trading stays paused.
+            let len = heap_address().1;
+            let mut state = State::default();
+            state.revenue_account = Some(caller());
+            state.paused = true;
+            state.recovering = true;
+            state.log(format!(
+                "couldn't deserialize the heap of {} bytes, starting with an empty state: {}",
+                len, err
+            ));
+            storage::set_safe_mode(false);
+            state
+        }
+        Err(err) => panic!("couldn't deserialize: {}", err),
+    };
+    STATE.with(|cell| cell.replace(state));
 }
 
 fn parse<'a, T: serde::Deserialize<'a>>(bytes: &'a [u8]) -> T {
@@ -112,42 +172,74 @@ fn parse<'a, T: serde::Deserialize<'a>>(bytes: &'a [u8]) -> T {
 }
 
 pub fn heap_to_stable(state: &mut State) {
+    // The heap of a recovering state is the only copy of the user funds.
+    if state.recovering {
+        return;
+    }
+    let memory = storage::heap();
     let offset = 16; // start of the heap
     let bytes = serde_cbor::to_vec(&state).expect("couldn't serialize the state");
     let len = bytes.len() as u64;
-    let stable_mem_size_bytes = stable_size() << 16;
-    let new_pages = (offset + len).saturating_sub(stable_mem_size_bytes) >> 16;
+    let memory_size_bytes = memory.size() << 16;
+    let new_pages = (offset + len).saturating_sub(memory_size_bytes) >> 16;
     if new_pages > 0 {
-        stable_grow(new_pages + 1).expect("couldn't grow memory");
+        assert!(memory.grow(new_pages + 1) >= 0, "couldn't grow memory");
     }
-    stable_write(offset, &bytes);
-    stable_write(0, &offset.to_be_bytes());
-    stable_write(8, &len.to_be_bytes());
+    memory.write(offset, &bytes);
+    memory.write(0, &offset.to_be_bytes());
+    memory.write(8, &len.to_be_bytes());
 }
 
-fn stable_to_heap() -> State {
+fn stable_to_heap() -> Result<State, serde_cbor::Error> {
     let (offset, len) = heap_address();
     ic_cdk::println!("Reading heap from coordinates: {:?}", (offset, len));
-    let mut bytes = Vec::with_capacity(len as usize);
-    bytes.spare_capacity_mut();
-    unsafe {
-        bytes.set_len(len as usize);
-    }
-    stable_read(offset, &mut bytes);
-    serde_cbor::from_slice(&bytes).expect("couldn't deserialize")
+    let mut bytes = vec![0; len as usize];
+    storage::heap().read(offset, &mut bytes);
+    serde_cbor::from_slice(&bytes)
+}
+
+fn page_hash(bytes: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    hasher.finalize().to_vec()
 }
 
 fn heap_address() -> (u64, u64) {
+    let memory = storage::heap();
+    if memory.size() == 0 {
+        return (0, 0);
+    }
     let mut offset_bytes: [u8; 8] = Default::default();
-    stable_read(0, &mut offset_bytes);
+    memory.read(0, &mut offset_bytes);
     let offset = u64::from_be_bytes(offset_bytes);
     let mut len_bytes: [u8; 8] = Default::default();
-    stable_read(8, &mut len_bytes);
+    memory.read(8, &mut len_bytes);
     let len = u64::from_be_bytes(len_bytes);
     (offset, len)
 }
 
+/// Refetches the metadata of a listed token. Unlike a listing, the refresh doesn't count as an
+/// activity of the token, so that inactive tokens still get delisted. If the fee or the decimals
+/// have changed, all orders of the token get closed.
+pub async fn refresh_token(token: TokenId) -> Result<(), String> {
+    let metadata = icrc1::metadata(token)
+        .await
+        .map_err(|err| format!("couldn't fetch metadata: {}", err))?;
+    mutate_with_invarant_check(
+        |state| {
+            // The token might have been delisted in the meantime.
+            let timestamp = state.token(token)?.timestamp;
+            state.list_token(token, metadata, timestamp)
+        },
+        Some((token, 0)),
+    )
+}
+
 pub async fn register_token(token: TokenId) -> Result<(), String> {
+    let standards = icrc1::supported_standards(token)
+        .await
+        .map_err(|err| format!("couldn't fetch supported standards: {}", err))?;
+    icrc1::verify_standards(&standards)?;
     let metadata = icrc1::metadata(token)
         .await
         .map_err(|err| format!("couldn't fetch metadata: {}", err))?;
@@ -158,5 +250,11 @@ pub async fn register_token(token: TokenId) -> Result<(), String> {
 }
 
 use crate::assets::{HttpRequest, HttpResponse};
-use crate::order_book::OrderExecution;
+use crate::order_book::{
+    BookSnapshot, Candle, Event, FeeStats, LiquidityBreakdown, ListingPayment, Metadata,
+    OrderCounts, OrderFees, OrderRequest, ParticlesPerToken, Position, PriceImpact, RankedToken,
+    Reconciliation, SelfTradePrevention, SortKey, StopOrder, Ticker, TokenStatus, TradeBlocks,
+    TradeReceipt, TradeSimulation, TradingFees,
+};
+use crate::queries::MemoryStats;
 export_candid!();
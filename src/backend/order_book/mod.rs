@@ -1,39 +1,202 @@
 use std::{
     cmp::Ordering,
     collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    ops::Bound,
 };
 
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
 
-use crate::{icrc1::Value, DAY, HOUR};
+use crate::{
+    icrc1::Value,
+    storage::{Archive, Logs, TradeLog},
+    DAY, HOUR, LISTING_PRICE_USD, SECOND,
+};
 
 pub type Timestamp = u64;
 pub type Tokens = u128;
 pub type TokenId = Principal;
 pub type ParticlesPerToken = u128;
 
-pub const TX_FEE: u128 = 20; // 0.XX% per trade side
+// Default fees in basis points of the trade volume, i.e. 0.1% for the maker and 0.2% for the
+// taker.
+pub const MAKER_FEE: u128 = 10; // paid by the owner of the filled order
+pub const TAKER_FEE: u128 = 20; // paid by the trader filling the order
+
+// The fee paid by both sides before the fees were split. Buy orders created back then reserve it.
+const LEGACY_TRADING_FEE: u128 = 20;
+
+// One basis point is 1 / FEE_BASE.
+const FEE_BASE: u128 = 10_000;
+
+// The maximal trading fee in basis points, i.e. 1%.
+const MAX_TRADING_FEE: u128 = 100;
 
 const LOGS_SIZE: usize = 10_000;
 
+// The bounds of the number of logs the operator can configure to keep. The logs are kept in
+// stable memory, so that even the upper bound doesn't affect upgrades.
+const MIN_LOGS_SIZE: usize = 100;
+const MAX_LOGS_SIZE: usize = 1_000_000;
+
+// Tokens with more decimals are not supported, because the volume computations would lose their
+// precision or overflow.
+const MAX_TOKEN_DECIMALS: u32 = 18;
+
+// Trades with a client order id are remembered for this long, so that retries within this window
+// return the receipt of the first execution. At most `MAX_CLIENT_ORDER_IDS_PER_USER` ids are
+// remembered per user.
+const CLIENT_ORDER_ID_WINDOW: u64 = DAY;
+const MAX_CLIENT_ORDER_IDS_PER_USER: usize = 10_000;
+
+// The maximal number of events returned by one query.
+const MAX_EVENTS_PAGE: usize = 1_000;
+
+// The maximal number of logs returned by one query.
+const MAX_LOGS_PAGE: usize = 1_000;
+
+// The maximal number of listing payments returned by one query.
+const MAX_LISTING_PAYMENTS_PAGE: usize = 1_000;
+
+// The maximal number of token holders returned by one query.
+const MAX_HOLDERS_PAGE: usize = 1_000;
+
+// The maximal number of ranked tokens returned by one query.
+const MAX_TOKENS_PAGE: usize = 100;
+
+// The maximal number of trade blocks returned by one query.
+const MAX_TRADE_BLOCKS_PAGE: usize = 1_000;
+
+// The number of the latest trades of a user replayed to compute the user's positions.
+const MAX_POSITION_TRADES: usize = 1_000;
+
+// The number of the latest trades of all users searched for the trades of a user, which bounds the
+// instructions of the positions query.
+const MAX_POSITION_SCANNED_TRADES: usize = 100_000;
+
+// The maximal number of recent prices returned per token.
+const MAX_PRICES_DEPTH: usize = 20;
+
+// The leaderboard covers the maker volume of the last week, computed from at most
+// `MAX_LEADERBOARD_SCAN` archived orders and returning at most `MAX_LEADERBOARD_SIZE` traders.
+const LEADERBOARD_WINDOW_DAYS: u64 = 7;
+const MAX_LEADERBOARD_SCAN: usize = 100_000;
+const MAX_LEADERBOARD_SIZE: usize = 100;
+
+// The default lifetime of orders, which can be overridden per token up to
+// `MAX_ORDER_EXPIRATION_DAYS`.
 const ORDER_EXPIRATION_DAYS: u64 = 90;
+const MAX_ORDER_EXPIRATION_DAYS: u64 = 365;
+
+// Orders with a keepalive window are checked for missing heartbeats of their owners at this
+// interval, which is also the shortest possible window.
+pub const KEEPALIVE_CHECK_INTERVAL_SECS: u64 = 60;
+
+// The maximal number of beneficiaries the revenue can be split between.
+const MAX_REVENUE_BENEFICIARIES: usize = 10;
+
+// The number of orders each side of a book can hold by default, including stop-limit orders,
+// which bounds the memory used by the books and the matching time.
+const DEFAULT_MAX_ORDERS_PER_BOOK: usize = 10_000;
+
+// The frontend domains served by default besides the domain of the canister itself.
+const DEFAULT_DOMAINS: &[&str] = &[
+    "beacondex.link",
+    "srn4v-3aaaa-aaaar-qaftq-cai.icp0.io",
+    "cetrr-jaaaa-aaaak-afgxq-cai.icp0.io",
+];
+
+// The maximal number of days executed orders can be kept in the archive, which bounds the stable
+// memory used by it.
+const MAX_ARCHIVE_RETENTION_DAYS: u64 = 3 * 365;
+
+// By default, the volume of an order must be at least 10 times the minimal trading fee, so that
+// the fee never exceeds 10% of the volume.
+const DEFAULT_MIN_VOLUME: Tokens = 10;
 
 // This is a cycle drain protection.
 const MAX_ORDERS_PER_HOUR: usize = 15;
 
+// The maximal number of orders closed by one `close_all_orders` call, so that the call never
+// runs out of instructions.
+const CLOSE_ALL_ORDERS_CHUNK: usize = 10_000;
+
 #[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
 pub enum OrderType {
     Buy,
     Sell,
 }
 
-#[derive(CandidType, Serialize)]
+/// Defines what happens if a trade of a user meets an open order of the same user.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum SelfTradePrevention {
+    /// The user's own order stays open and the trade continues with the next order.
+    #[default]
+    Skip,
+    /// The user's own order gets closed and the trade continues with the next order.
+    CancelResting,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum OrderExecution {
     Filled(u128),
     FilledAndOrderCreated(u128),
 }
 
+/// The trading fees in basis points of the trade volume.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TradingFees {
+    // paid by the owner of the filled order
+    pub maker: u128,
+    // paid by the trader filling the order
+    pub taker: u128,
+}
+
+impl Default for TradingFees {
+    fn default() -> Self {
+        Self {
+            maker: MAKER_FEE,
+            taker: TAKER_FEE,
+        }
+    }
+}
+
+impl TradingFees {
+    // The fees of states serialized before the fees were configurable, so that the buy orders
+    // open at the upgrade keep the fee they reserved.
+    fn legacy() -> Self {
+        Self {
+            maker: LEGACY_TRADING_FEE,
+            taker: LEGACY_TRADING_FEE,
+        }
+    }
+
+    fn maker_fee(&self, volume: Tokens) -> Tokens {
+        trading_fee(volume, self.maker)
+    }
+
+    fn taker_fee(&self, volume: Tokens) -> Tokens {
+        trading_fee(volume, self.taker)
+    }
+}
+
+/// The fees of a new order in the quote token.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct OrderFees {
+    // The taker fee paid for the immediately filled amount.
+    pub taker_fee: Tokens,
+    // The maker fee charged at most for the amount added to the book, i.e. if it gets filled
+    // completely.
+    pub max_maker_fee: Tokens,
+}
+
+/// The outcome of a trade together with its fees.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TradeReceipt {
+    pub execution: OrderExecution,
+    pub fees: OrderFees,
+}
+
 impl OrderType {
     pub fn buy(&self) -> bool {
         self == &OrderType::Buy
@@ -43,6 +206,21 @@ impl OrderType {
     }
 }
 
+/// A market of a listed token against a quote token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pair {
+    // The traded token.
+    pub base: TokenId,
+    // The alternative quote token declared by the traded token; None means the payment token.
+    pub quote: Option<TokenId>,
+}
+
+impl From<TokenId> for Pair {
+    fn from(base: TokenId) -> Self {
+        Pair { base, quote: None }
+    }
+}
+
 #[derive(CandidType, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Order {
     // The direction of the order w.r.t to the underlying token.
@@ -59,26 +237,94 @@ pub struct Order {
     timestamp: Timestamp,
     // Implicit encoding of optional type: 0 means None - not executed yet.
     pub executed: Timestamp,
-    // The number of ICRC-1 decimals in the underlying token.
+    // The number of ICRC-1 decimals in the underlying token at the creation of the order. The
+    // amounts of archived orders must be interpreted with these decimals, because a relisting can
+    // change the decimals of the token.
     decimals: u32,
     // The fee in $payment_token.
     payment_token_fee: Tokens,
+    // The time when the order expires; None means the order lifetime of the token after its
+    // creation.
+    #[serde(default)]
+    expires_at: Option<Timestamp>,
+    // If set, the order gets closed once its owner didn't send a heartbeat for this many
+    // seconds.
+    #[serde(default)]
+    keepalive_secs: Option<u64>,
+    // Distinguishes otherwise identical orders; 0 for orders created before the ids were
+    // introduced.
+    #[serde(default)]
+    id: u64,
 }
 
 impl Order {
+    /// Creates an order usable for looking up an open order in a book, as only the fields
+    /// relevant for the order comparison are set.
+    pub fn key(
+        order_type: OrderType,
+        owner: Principal,
+        amount: Tokens,
+        price: ParticlesPerToken,
+        timestamp: Timestamp,
+    ) -> Self {
+        Order {
+            order_type,
+            owner,
+            price,
+            amount,
+            timestamp,
+            // This field is ignored in `Order` comparison
+            decimals: 0,
+            // This field is ignored in `Order` comparison
+            payment_token_fee: 0,
+            // This field is guaranteed to be 0 for all open orders.
+            executed: 0,
+            // This field is ignored in `Order` comparison
+            expires_at: None,
+            // This field is ignored in `Order` comparison
+            keepalive_secs: None,
+            // The smallest id, so that the key precedes all orders it matches.
+            id: 0,
+        }
+    }
+
+    /// Returns true if the order matches the key in all fields compared by `Ord` except the id.
+    fn matches(&self, key: &Order) -> bool {
+        (self.price, self.timestamp, self.amount, self.owner)
+            == (key.price, key.timestamp, key.amount, key.owner)
+    }
+
+    /// The time when the order expires and gets closed by the clean-up, given the order lifetime
+    /// of its token.
+    pub fn expiration(&self, expiration_days: u64) -> Timestamp {
+        self.expires_at
+            .unwrap_or(self.timestamp + expiration_days * DAY)
+    }
+
     /// The volume of this trade in payment particles.
     pub fn volume(&self) -> Tokens {
+        self.checked_volume().expect("overflow")
+    }
+
+    /// The volume of this trade in payment particles or an error, if the volume is too large
+    /// for the fee computations. Must be checked for every new order, so that the orders in
+    /// the books never overflow.
+    pub fn checked_volume(&self) -> Result<Tokens, String> {
         let token_base = 10_u128.pow(self.decimals);
-        (self.amount.checked_mul(self.price)).expect("overflow") / token_base
+        self.amount
+            .checked_mul(self.price)
+            .map(|product| product / token_base)
+            .filter(|volume| volume.checked_mul(FEE_BASE).is_some())
+            .ok_or("order too large".into())
     }
 
     /// The amount of user's tokens reserved for the trade.
-    /// - buy: $payment_token + fee.
+    /// - buy: $payment_token + maker fee.
     /// - sell: the underlying token.
-    fn reserved_liquidity(&self) -> Tokens {
+    fn reserved_liquidity(&self, fees: TradingFees) -> Tokens {
         if self.order_type.buy() {
             let volume = self.volume();
-            volume + trading_fee(volume)
+            volume + fees.maker_fee(volume)
         } else {
             self.amount
         }
@@ -108,9 +354,51 @@ impl Ord for Order {
             return self.amount.cmp(&other.amount);
         }
 
-        self.owner.cmp(&other.owner)
+        if self.owner != other.owner {
+            return self.owner.cmp(&other.owner);
+        }
+
+        self.id.cmp(&other.id)
+
+        // Note: self.decimals, self.payment_token_fee, self.expires_at and self.keepalive_secs
+        // are ignored.
+    }
+}
+
+/// A stop-limit order: a limit order at the price of `order`, which gets added to the book once
+/// the last trade price of the pair reaches the trigger price, i.e. drops to it for sell orders
+/// or rises to it for buy orders.
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StopOrder {
+    pub trigger_price: ParticlesPerToken,
+    pub order: Order,
+}
+
+impl StopOrder {
+    fn triggered(&self, last_price: ParticlesPerToken) -> bool {
+        if self.order.order_type.buy() {
+            last_price >= self.trigger_price
+        } else {
+            last_price <= self.trigger_price
+        }
+    }
+}
+
+impl PartialOrd for StopOrder {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-        // Note: self.decimals and self.payment_token_fee are ignored.
+impl Ord for StopOrder {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Orders of different types are never compared directly, as `Order` doesn't support it.
+        self.order
+            .order_type
+            .buy()
+            .cmp(&other.order.order_type.buy())
+            .then(self.trigger_price.cmp(&other.trigger_price))
+            .then_with(|| self.order.cmp(&other.order))
     }
 }
 
@@ -127,35 +415,517 @@ struct Book {
     // - x.executed == 0
     // - ordered from the lowest price to the highest price.
     sellers: BTreeSet<Order>,
+
+    // Stop-limit orders waiting for their trigger; their liquidity is reserved at placement.
+    #[serde(default)]
+    pending: BTreeSet<StopOrder>,
+
+    // The price of the latest trade in this book.
+    #[serde(default)]
+    last_price: Option<ParticlesPerToken>,
+}
+
+impl Book {
+    /// Returns all orders of the type reserving liquidity: the open orders followed by the
+    /// stop-limit orders.
+    fn reserving_orders(&self, order_type: OrderType) -> impl Iterator<Item = &Order> {
+        let orders = if order_type.buy() {
+            &self.buyers
+        } else {
+            &self.sellers
+        };
+        orders.iter().chain(
+            self.pending
+                .iter()
+                .map(|stop| &stop.order)
+                .filter(move |order| order.order_type == order_type),
+        )
+    }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(CandidType, Clone, Serialize, Deserialize)]
 pub struct Metadata {
     pub symbol: String,
     pub fee: Tokens,
     pub decimals: u32,
     pub logo: Option<String>,
     pub timestamp: Timestamp,
+    // An additional token, against which this token can be traded besides the payment token.
+    #[serde(default)]
+    pub quote_token: Option<TokenId>,
+    // The minimal volume of an order in particles of the quote token; 0 means no limit.
+    #[serde(default)]
+    pub min_notional: Tokens,
+    // If not 0, order prices must be a multiple of the tick size.
+    #[serde(default)]
+    pub tick_size: ParticlesPerToken,
+    // The volume in particles of the quote token below which orders are rejected and rests of
+    // partially filled orders are dropped; 0 means `DEFAULT_MIN_VOLUME`.
+    #[serde(default)]
+    pub min_volume: Tokens,
+    // The number of days after which orders without a custom expiration time expire; 0 means
+    // `ORDER_EXPIRATION_DAYS`.
+    #[serde(default)]
+    pub order_expiration_days: u64,
+}
+
+impl Metadata {
+    /// Returns the minimal volume of an order in particles of the quote token.
+    pub fn min_volume(&self) -> Tokens {
+        if self.min_volume == 0 {
+            DEFAULT_MIN_VOLUME
+        } else {
+            self.min_volume
+        }
+    }
+
+    /// Returns the number of days after which orders of the token expire by default.
+    pub fn order_expiration_days(&self) -> u64 {
+        if self.order_expiration_days == 0 {
+            ORDER_EXPIRATION_DAYS
+        } else {
+            self.order_expiration_days
+        }
+    }
+
+    /// Checks that the order volume is not below the minimal volume.
+    fn check_min_volume(&self, volume: Tokens) -> Result<(), String> {
+        if volume < self.min_volume() {
+            return Err(format!(
+                "the order is too small: the volume {} is below the minimum of {} particles \
+                (by default 10 times the minimal trading fee)",
+                volume,
+                self.min_volume()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the order against the min-notional and tick-size constraints of the token.
+    fn check_order(&self, order: &Order) -> Result<(), String> {
+        if order.volume() < self.min_notional {
+            return Err(format!(
+                "the order volume is below the minimum of {}",
+                self.min_notional
+            ));
+        }
+        if self.tick_size > 0 && order.price % self.tick_size != 0 {
+            return Err(format!(
+                "the price is not a multiple of the tick size {}",
+                self.tick_size
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A machine-readable record of a state change, stored alongside the human-readable logs.
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Event {
+    OrderCreated {
+        owner: Principal,
+        token: TokenId,
+        // The quote token, if it is not the payment token.
+        #[serde(default)]
+        quote: Option<TokenId>,
+        order_type: OrderType,
+        amount: Tokens,
+        price: ParticlesPerToken,
+        timestamp: Timestamp,
+    },
+    OrderClosed {
+        owner: Principal,
+        token: TokenId,
+        // The quote token, if it is not the payment token.
+        #[serde(default)]
+        quote: Option<TokenId>,
+        order_type: OrderType,
+        amount: Tokens,
+        price: ParticlesPerToken,
+        timestamp: Timestamp,
+    },
+    TradeExecuted {
+        // The owner of the filled order.
+        maker: Principal,
+        // The user who executed the trade.
+        taker: Principal,
+        token: TokenId,
+        // The quote token, if it is not the payment token.
+        #[serde(default)]
+        quote: Option<TokenId>,
+        // The type of the taker's trade.
+        order_type: OrderType,
+        amount: Tokens,
+        price: ParticlesPerToken,
+        timestamp: Timestamp,
+    },
+    TokenListed {
+        token: TokenId,
+    },
+    LiquidityDeposited {
+        user: Principal,
+        token: TokenId,
+        amount: Tokens,
+    },
+    LiquidityWithdrawn {
+        user: Principal,
+        token: TokenId,
+        amount: Tokens,
+    },
+}
+
+/// An executed trade in the append-only trade log.
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TradeBlock {
+    // The owner of the filled order.
+    pub maker: Principal,
+    // The user who executed the trade.
+    pub taker: Principal,
+    pub token: TokenId,
+    // The quote token, if it is not the payment token.
+    pub quote: Option<TokenId>,
+    // The type of the taker's trade.
+    pub order_type: OrderType,
+    pub amount: Tokens,
+    pub price: ParticlesPerToken,
+    // The sum of the maker and the taker fee in the quote token.
+    pub fee: Tokens,
+    pub timestamp: Timestamp,
+    // The decimals of the token at the time of the trade; missing in older blocks.
+    #[serde(default)]
+    pub decimals: Option<u32>,
+}
+
+/// A listing fee paid for a token.
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ListingPayment {
+    pub payer: Principal,
+    pub token: TokenId,
+    // The amount in payment particles.
+    pub amount: Tokens,
+    pub timestamp: Timestamp,
+}
+
+/// A page of the trade log.
+#[derive(CandidType, Serialize, Debug)]
+pub struct TradeBlocks {
+    // The total number of blocks in the log.
+    pub log_length: u64,
+    pub blocks: Vec<(u64, TradeBlock)>,
+}
+
+/// The position of a user in a pair, computed from the user's trades with the average cost
+/// method.
+#[derive(CandidType, Serialize, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub token: TokenId,
+    // The quote token, if it is not the payment token.
+    pub quote: Option<TokenId>,
+    // The bought minus the sold tokens; negative if the user sold more than they bought.
+    pub net_amount: i128,
+    // The average price of the open position in particles of the quote token per token.
+    pub avg_entry_price: ParticlesPerToken,
+    // The profit of the reduced positions in particles of the quote token, before fees.
+    pub realized_pnl: i128,
+}
+
+/// Whether orders of a token can be placed and the token data needed for them.
+#[derive(CandidType, Serialize, Debug, Default, PartialEq, Eq)]
+pub struct TokenStatus {
+    pub listed: bool,
+    // If true, no orders or trades of any token are accepted.
+    pub paused: bool,
+    // True if the book of the token against the payment token has any open orders.
+    pub has_liquidity: bool,
+    // True if orders of the token against the payment token can be placed.
+    pub tradable: bool,
+    // The ledger fee of the token.
+    pub fee: Tokens,
+    pub decimals: u32,
+}
+
+/// Aggregated price data of all orders executed within one time interval.
+#[derive(CandidType, Serialize, Debug, PartialEq, Eq)]
+pub struct Candle {
+    // The start of the interval.
+    pub timestamp: Timestamp,
+    pub open: ParticlesPerToken,
+    pub high: ParticlesPerToken,
+    pub low: ParticlesPerToken,
+    pub close: ParticlesPerToken,
+    // The total volume of the interval in payment particles.
+    pub volume: Tokens,
+}
+
+/// An order placed as a part of a batch.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OrderRequest {
+    pub token: TokenId,
+    // The quote token, if it is not the payment token.
+    pub quote: Option<TokenId>,
+    pub order_type: OrderType,
+    pub amount: Tokens,
+    pub price: ParticlesPerToken,
+    pub expires_at: Option<Timestamp>,
+    #[serde(default)]
+    pub keepalive_secs: Option<u64>,
+}
+
+/// Price statistics of a token over the last 24 hours.
+#[derive(CandidType, Serialize, Debug, Default, PartialEq, Eq)]
+pub struct Ticker {
+    // The price of the latest trade, even if it is older than 24 hours.
+    pub last: Option<ParticlesPerToken>,
+    pub high_24h: Option<ParticlesPerToken>,
+    pub low_24h: Option<ParticlesPerToken>,
+    // The volume in payment particles.
+    pub volume_24h: Tokens,
+    // The difference between the latest and the earliest price within 24 hours.
+    pub change_24h: Option<i128>,
+}
+
+/// The criterion by which listed tokens get ranked.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    /// The highest volume within 24 hours first.
+    Volume24h,
+    /// The highest price of the latest trade first.
+    LastPrice,
+    /// Alphabetically by the symbol.
+    Symbol,
+}
+
+/// A listed token with its price statistics.
+#[derive(CandidType, Serialize, Debug, PartialEq, Eq)]
+pub struct RankedToken {
+    pub token: TokenId,
+    pub symbol: String,
+    pub ticker: Ticker,
+}
+
+/// The internal accounting of a token compared to the balance of the main account on its ledger.
+#[derive(CandidType, Serialize, Debug, PartialEq, Eq)]
+pub struct Reconciliation {
+    pub internal: Tokens,
+    pub on_ledger: Tokens,
+    // A positive difference means the ledger holds more funds than accounted for internally.
+    pub diff: i128,
+}
+
+/// The metadata of a token together with a summary of its book against the payment token.
+#[derive(Serialize)]
+pub struct TokenOverview {
+    pub metadata: Metadata,
+    pub best_bid: Option<ParticlesPerToken>,
+    pub best_ask: Option<ParticlesPerToken>,
+    pub last_price: Option<ParticlesPerToken>,
+    // The volume in payment particles.
+    pub volume_24h: Tokens,
+    pub open_buy_orders: usize,
+    pub open_sell_orders: usize,
+}
+
+/// The number of open orders on each side of a book and the summed amounts of tokens.
+#[derive(CandidType, Serialize, Debug, Default, PartialEq, Eq)]
+pub struct OrderCounts {
+    pub buy_orders: u64,
+    pub sell_orders: u64,
+    pub buy_depth: Tokens,
+    pub sell_depth: Tokens,
+}
+
+/// The open orders of one side of a book together with the version of the books.
+#[derive(CandidType, Serialize)]
+pub struct BookSnapshot {
+    pub version: u64,
+    pub orders: Vec<Order>,
+}
+
+/// How much a trade of a given size moves the price.
+#[derive(CandidType, Serialize, Debug, PartialEq, Eq)]
+pub struct PriceImpact {
+    // The volume-weighted average price of the whole fill.
+    pub avg_fill_price: ParticlesPerToken,
+    // The price of the first order the trade would fill.
+    pub best_price: ParticlesPerToken,
+    // The difference between the average and the best price in basis points of the best price.
+    pub impact_bps: u64,
+}
+
+/// The expected outcome of a trade.
+#[derive(CandidType, Serialize, Debug, Default, PartialEq, Eq)]
+pub struct TradeSimulation {
+    // The amount of tokens which can be filled by the open orders.
+    pub fillable: Tokens,
+    // The average price of the filled amount; 0 if nothing can be filled.
+    pub avg_price: ParticlesPerToken,
+    // The taker fee in the quote token.
+    pub fee: Tokens,
+    // The amount which can't be filled and would be added to the book for limit orders.
+    pub rest_amount: Tokens,
+}
+
+/// Trading fees collected in the payment token.
+#[derive(CandidType, Serialize, Debug, PartialEq, Eq)]
+pub struct FeeStats {
+    // Fees of the trades executed within the last 24 hours.
+    pub fees_day: Tokens,
+    // Fees of the trades executed within the last 7 days.
+    pub fees_week: Tokens,
+    // All fees collected so far.
+    pub fees_total: Tokens,
+}
+
+/// The liquidity of a user in one token, split by what it is used for.
+#[derive(CandidType, Serialize, Debug, Default, PartialEq, Eq)]
+pub struct LiquidityBreakdown {
+    // Liquidity available for new orders and withdrawals.
+    pub free: Tokens,
+    // Liquidity locked in buy orders, including the maker fees.
+    pub reserved_in_buys: Tokens,
+    // Liquidity locked in sell orders.
+    pub reserved_in_sells: Tokens,
 }
 
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct State {
     #[serde(default)]
     pub payment_token_id: Option<Principal>,
-    // All open orders
+    // All open orders against the payment token
     orders: BTreeMap<TokenId, Book>,
-    // Executed or expired orders.
-    pub order_archive: BTreeMap<TokenId, VecDeque<Order>>,
+    // All open orders against alternative quote tokens, keyed by (token, quote token).
+    #[serde(default)]
+    alt_orders: BTreeMap<(TokenId, TokenId), Book>,
+    // Executed orders against the payment token, stored in stable memory.
+    #[serde(skip)]
+    pub order_archive: Archive,
+    // All executed trades, stored in stable memory.
+    #[serde(skip)]
+    pub trade_log: TradeLog,
+    // Executed orders kept on the heap by previous versions; only read for the migration.
+    #[serde(default, rename = "order_archive", skip_serializing)]
+    legacy_order_archive: BTreeMap<TokenId, VecDeque<Order>>,
     // How many tokens each user owns.
     pools: BTreeMap<TokenId, BTreeMap<Principal, Tokens>>,
+    // Liquidity reserved for withdrawals whose ledger transfers are not confirmed yet.
+    #[serde(default)]
+    pending_withdrawals: BTreeMap<TokenId, BTreeMap<Principal, Tokens>>,
     pub tokens: BTreeMap<TokenId, Metadata>,
     pub revenue_account: Option<Principal>,
-    pub logs: VecDeque<(u64, String)>,
+    // The beneficiaries of the fees with their shares in basis points; empty means that all fees
+    // go to the revenue account.
+    #[serde(default)]
+    revenue_split: Vec<(Principal, u16)>,
+    // Logs, stored in stable memory.
+    #[serde(skip)]
+    logs: Logs,
+    // Logs kept on the heap by previous versions; only read for the migration.
+    #[serde(default, rename = "logs", skip_serializing)]
+    legacy_logs: VecDeque<(u64, String)>,
+    #[serde(default)]
+    events: VecDeque<(u64, Event)>,
     event_id: u64,
     order_activity: HashMap<Principal, HashSet<Timestamp>>,
+    // The id of the latest created order.
+    #[serde(default)]
+    order_id: u64,
+    // If set, no new orders or trades are accepted; closing orders and withdrawals still work.
+    #[serde(default)]
+    pub paused: bool,
+    // Set if the canister started with an empty state because the serialized heap couldn't be
+    // deserialized. Until the operator clears it, the empty state isn't persisted, so that the
+    // original heap stays in stable memory, and no funds can be swept as unaccounted.
+    #[serde(default)]
+    pub recovering: bool,
+
+    // Changing the fees requires all orders to be closed, because buy orders reserve the maker
+    // fee. Upgraded states start with the legacy fees until the operator lowers them.
+    #[serde(default = "TradingFees::legacy")]
+    trading_fees: TradingFees,
+
+    // The total trading fees in the payment token collected since the counter was introduced.
+    #[serde(default)]
+    fees_collected: Tokens,
+
+    // The cycle balance below which a warning is logged and no new tokens can be listed; 0
+    // disables the check.
+    #[serde(default)]
+    pub cycle_warning_threshold: u64,
+    // True if the cycle balance was below the threshold at the last check.
+    #[serde(default)]
+    pub low_cycles: bool,
+
+    // The number of logs kept by the clean-up; None means `LOGS_SIZE`.
+    #[serde(default)]
+    max_logs: Option<usize>,
+
+    // The number of days executed orders are kept in the archive; None means
+    // `2 x ORDER_EXPIRATION_DAYS`.
+    #[serde(default)]
+    archive_retention_days: Option<u64>,
+
+    // The number of orders each side of a book can hold; None means
+    // `DEFAULT_MAX_ORDERS_PER_BOOK`.
+    #[serde(default)]
+    max_orders_per_book: Option<usize>,
+
+    // The frontend domains published in the certified domain assets; `None` means
+    // `DEFAULT_DOMAINS`.
+    #[serde(default)]
+    domains: Option<Vec<String>>,
+
+    // The canister and the method each user wants to be notified of fills of their orders with.
+    #[serde(default)]
+    fill_notifications: BTreeMap<Principal, (Principal, String)>,
+    // Notifications of filled orders not sent yet.
+    #[serde(skip)]
+    pending_fill_notifications: Vec<(Principal, String, Order)>,
+
+    // The listing price in whole payment tokens; None means `LISTING_PRICE_USD`.
+    #[serde(default)]
+    listing_price_usd: Option<u128>,
+    // Users who can list tokens for free.
+    #[serde(default)]
+    free_listing: BTreeSet<Principal>,
+    // Users exempt from the limit of orders per hour, e.g. vetted market makers.
+    #[serde(default)]
+    exempt_principals: BTreeSet<Principal>,
+    // Paid listing fees, the newest first.
+    #[serde(default)]
+    listing_payments: VecDeque<ListingPayment>,
+
+    // Incremented with every change of any order book.
+    #[serde(default)]
+    book_version: u64,
+
+    // The time of the last heartbeat of each user with orders having a keepalive window.
+    #[serde(default)]
+    heartbeats: BTreeMap<Principal, Timestamp>,
+
+    // The receipts of trades with a client order id and their execution times.
+    #[serde(default)]
+    client_orders: BTreeMap<(Principal, [u8; 32]), (Timestamp, TradeReceipt)>,
 }
 
 impl State {
+    /// Checks that the state survives the serialization used for upgrades: serializing it,
+    /// deserializing the result and serializing it again must yield the same structure.
+    pub fn verify_serialization(&self) -> Result<(), String> {
+        let bytes = serde_cbor::to_vec(self).map_err(|err| format!("serialization: {}", err))?;
+        let state: State =
+            serde_cbor::from_slice(&bytes).map_err(|err| format!("deserialization: {}", err))?;
+        let round_trip =
+            serde_cbor::to_vec(&state).map_err(|err| format!("re-serialization: {}", err))?;
+        // The comparison of CBOR values ignores the order of map entries, which is random for
+        // hash maps.
+        let parse = |bytes: &[u8]| serde_cbor::from_slice::<serde_cbor::Value>(bytes);
+        match (parse(&bytes), parse(&round_trip)) {
+            (Ok(before), Ok(after)) if before == after => Ok(()),
+            (Ok(_), Ok(_)) => Err("the deserialized state differs from the original".into()),
+            (Err(err), _) | (_, Err(err)) => Err(format!("parsing: {}", err)),
+        }
+    }
+
     /// Returns the payment token, which is ICP by default
     pub fn payment_token_id(&self) -> Principal {
         self.payment_token_id.unwrap_or_else(|| {
@@ -163,25 +933,194 @@ impl State {
         })
     }
 
-    // Count how many orders the user made within an hour and
-    // throw an error if the number is above `MAX_ORDERS_PER_HOUR`.
-    fn record_activity(
-        &mut self,
-        token: TokenId,
-        principal: Principal,
-        now: Timestamp,
-    ) -> Result<(), String> {
-        let metadata = self.tokens.get_mut(&token).ok_or("token not listed")?;
-        metadata.timestamp = now;
-        match self.order_activity.get_mut(&principal) {
-            Some(records) => {
-                records.retain(|timestamp| timestamp + HOUR >= now);
-                if records.len() >= MAX_ORDERS_PER_HOUR {
-                    return Err("too many orders within one hour; please try again later".into());
-                }
-                records.insert(now);
-                Ok(())
-            }
+    /// Returns the payment token together with its metadata, if it is listed.
+    pub fn payment_token(&self) -> Option<(TokenId, Metadata)> {
+        let id = self.payment_token_id();
+        self.tokens.get(&id).map(|metadata| (id, metadata.clone()))
+    }
+
+    /// Returns the token in which the orders of the pair are priced.
+    fn quote_token_id(&self, pair: Pair) -> TokenId {
+        pair.quote.unwrap_or_else(|| self.payment_token_id())
+    }
+
+    fn book(&self, pair: Pair) -> Option<&Book> {
+        match pair.quote {
+            None => self.orders.get(&pair.base),
+            Some(quote) => self.alt_orders.get(&(pair.base, quote)),
+        }
+    }
+
+    /// Returns all order books together with their pairs.
+    fn books(&self) -> impl Iterator<Item = (Pair, &'_ Book)> {
+        self.orders
+            .iter()
+            .map(|(token, book)| (Pair::from(*token), book))
+            .chain(self.alt_orders.iter().map(|((token, quote), book)| {
+                (
+                    Pair {
+                        base: *token,
+                        quote: Some(*quote),
+                    },
+                    book,
+                )
+            }))
+    }
+
+    /// Returns true if the token has open orders, either as the traded or as the quote token.
+    fn has_orders(&self, token: TokenId) -> bool {
+        self.books().any(|(pair, book)| {
+            (pair.base == token || pair.quote == Some(token))
+                && (!book.buyers.is_empty() || !book.sellers.is_empty() || !book.pending.is_empty())
+        })
+    }
+
+    /// Sets the alternative quote token of the token. All open orders against the previous
+    /// quote token get closed.
+    pub fn set_quote_token(
+        &mut self,
+        token: TokenId,
+        quote: Option<TokenId>,
+    ) -> Result<(), String> {
+        let payment_token_id = self.payment_token_id();
+        if token == payment_token_id {
+            return Err("no quote token can be set for the payment token".into());
+        }
+        let previous_quote = self.token(token)?.quote_token;
+        if let Some(quote) = quote {
+            if quote == token || quote == payment_token_id {
+                return Err("invalid quote token".into());
+            }
+            self.token(quote)?;
+        }
+        if let Some(previous_quote) = previous_quote {
+            let pair = Pair {
+                base: token,
+                quote: Some(previous_quote),
+            };
+            let orders = self
+                .book(pair)
+                .map(|book| {
+                    book.buyers
+                        .iter()
+                        .chain(book.sellers.iter())
+                        .cloned()
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            for order in orders {
+                self.close_order(
+                    order.owner,
+                    pair,
+                    order.amount,
+                    order.price,
+                    order.timestamp,
+                    order.order_type,
+                )?;
+            }
+            self.alt_orders.remove(&(token, previous_quote));
+        }
+        self.tokens
+            .get_mut(&token)
+            .ok_or("no token listed")?
+            .quote_token = quote;
+        self.log(format!(
+            "quote token of {} set to {:?}",
+            token,
+            quote.map(|id| id.to_string())
+        ));
+        Ok(())
+    }
+
+    /// Updates the cached ledger fee of the token, e.g. after the ledger rejected a transfer with
+    /// the previous fee.
+    pub fn set_token_fee(&mut self, token: TokenId, fee: Tokens) -> Result<(), String> {
+        let metadata = self.tokens.get_mut(&token).ok_or("no token listed")?;
+        let previous_fee = metadata.fee;
+        metadata.fee = fee;
+        self.log(format!(
+            "fee of token {} changed: {} -> {}",
+            token, previous_fee, fee
+        ));
+        Ok(())
+    }
+
+    /// Sets the minimal order volume and the price tick size of the token; 0 disables the
+    /// respective constraint. Existing orders are not affected.
+    pub fn set_order_limits(
+        &mut self,
+        token: TokenId,
+        min_notional: Tokens,
+        tick_size: ParticlesPerToken,
+    ) -> Result<(), String> {
+        if token == self.payment_token_id() {
+            return Err("no order limits can be set for the payment token".into());
+        }
+        let metadata = self.tokens.get_mut(&token).ok_or("no token listed")?;
+        metadata.min_notional = min_notional;
+        metadata.tick_size = tick_size;
+        self.log(format!(
+            "order limits of {} set to min_notional={}, tick_size={}",
+            token, min_notional, tick_size
+        ));
+        Ok(())
+    }
+
+    /// Returns the number of days after which orders of the token expire by default.
+    fn order_expiration_days(&self, token: TokenId) -> u64 {
+        self.tokens
+            .get(&token)
+            .map(|metadata| metadata.order_expiration_days())
+            .unwrap_or(ORDER_EXPIRATION_DAYS)
+    }
+
+    /// Sets the default lifetime of orders of the token; 0 restores the default.
+    pub fn set_order_expiration_days(&mut self, token: TokenId, days: u64) -> Result<(), String> {
+        if days > MAX_ORDER_EXPIRATION_DAYS {
+            return Err(format!(
+                "the order lifetime can't exceed {} days",
+                MAX_ORDER_EXPIRATION_DAYS
+            ));
+        }
+        let metadata = self.tokens.get_mut(&token).ok_or("no token listed")?;
+        metadata.order_expiration_days = days;
+        self.log(format!("order lifetime of {} set to {} days", token, days));
+        Ok(())
+    }
+
+    /// Sets the minimal volume of orders of the token; 0 restores the default.
+    pub fn set_min_volume(&mut self, token: TokenId, min_volume: Tokens) -> Result<(), String> {
+        let metadata = self.tokens.get_mut(&token).ok_or("no token listed")?;
+        metadata.min_volume = min_volume;
+        self.log(format!(
+            "minimal order volume of {} set to {}",
+            token, min_volume
+        ));
+        Ok(())
+    }
+
+    // Count how many orders the user made within an hour and
+    // throw an error if the number is above `MAX_ORDERS_PER_HOUR`, unless the user is exempt.
+    fn record_activity(
+        &mut self,
+        token: TokenId,
+        principal: Principal,
+        now: Timestamp,
+    ) -> Result<(), String> {
+        let metadata = self.tokens.get_mut(&token).ok_or("token not listed")?;
+        metadata.timestamp = now;
+        if self.exempt_principals.contains(&principal) {
+            return Ok(());
+        }
+        match self.order_activity.get_mut(&principal) {
+            Some(records) => {
+                records.retain(|timestamp| timestamp + HOUR >= now);
+                if records.len() >= MAX_ORDERS_PER_HOUR {
+                    return Err("too many orders within one hour; please try again later".into());
+                }
+                records.insert(now);
+                Ok(())
+            }
             None => {
                 self.order_activity.insert(principal, Default::default());
                 Ok(())
@@ -191,33 +1130,40 @@ impl State {
 
     /// Closes orders satisfying the given condition.
     ///
-    /// The token filter restricts the deletion to the list of tokens if it is not empty.
+    /// The token filter restricts the deletion to the list of tokens if it is not empty. An order
+    /// passes the filter if either its token or its quote token is in the list.
     ///
     /// To guarantee that this never runs out
     /// of instructions, we need an upper bound on the total number of orders here.
     pub fn close_orders_by_condition(
         &mut self,
-        predicate: &dyn Fn(&Order) -> bool,
+        predicate: &dyn Fn(Pair, &Order) -> bool,
         token_filter: HashSet<TokenId>,
         max_chunk: usize,
     ) -> usize {
         let mut closed_orders = 0;
-        self.orders
-            .iter()
-            .filter(|(token, _)| token_filter.is_empty() || token_filter.contains(token))
-            .flat_map(|(token, book)| {
+        let passes_filter = |pair: &Pair| {
+            token_filter.is_empty()
+                || token_filter.contains(&pair.base)
+                || pair
+                    .quote
+                    .is_some_and(|quote| token_filter.contains(&quote))
+        };
+        self.books()
+            .filter(|(pair, _)| passes_filter(pair))
+            .flat_map(|(pair, book)| {
                 book.buyers
                     .iter()
                     .chain(book.sellers.iter())
-                    .map(move |order| (*token, order.clone()))
+                    .map(move |order| (pair, order.clone()))
             })
-            .filter(|(_, order)| predicate(order))
+            .filter(|(pair, order)| predicate(*pair, order))
             .take(max_chunk)
             .collect::<Vec<_>>()
             .into_iter()
             .for_each(
                 |(
-                    token,
+                    pair,
                     Order {
                         order_type,
                         owner,
@@ -228,7 +1174,7 @@ impl State {
                     },
                 )| {
                     if let Err(err) =
-                        self.close_order(owner, token, amount, price, timestamp, order_type)
+                        self.close_order(owner, pair, amount, price, timestamp, order_type)
                     {
                         self.log(format!("failed to close an order: {}", err))
                     } else {
@@ -237,25 +1183,147 @@ impl State {
                 },
             );
 
+        let stop_orders = self
+            .books()
+            .filter(|(pair, _)| passes_filter(pair))
+            .flat_map(|(pair, book)| book.pending.iter().map(move |stop| (pair, stop.clone())))
+            .filter(|(pair, stop)| predicate(*pair, &stop.order))
+            .take(max_chunk.saturating_sub(closed_orders))
+            .collect::<Vec<_>>();
+        for (
+            pair,
+            StopOrder {
+                trigger_price,
+                order,
+            },
+        ) in stop_orders
+        {
+            if let Err(err) = self.close_stop_order(
+                order.owner,
+                pair,
+                order.amount,
+                trigger_price,
+                order.price,
+                order.timestamp,
+                order.order_type,
+            ) {
+                self.log(format!("failed to close a stop-limit order: {}", err))
+            } else {
+                closed_orders += 1
+            }
+        }
+
         closed_orders
     }
 
-    pub fn clean_up(&mut self, now: Timestamp) {
-        // Rotate logs
-        let deleted_logs = self.logs.len().saturating_sub(LOGS_SIZE);
-        self.logs.truncate(LOGS_SIZE);
+    /// Closes up to `CLOSE_ALL_ORDERS_CHUNK` orders of the user, including stop-limit orders,
+    /// optionally only those of the given token, and returns the number of closed orders.
+    pub fn close_user_orders(&mut self, user: Principal, token: Option<TokenId>) -> usize {
+        self.close_orders_by_condition(
+            &|_, order| order.owner == user,
+            token.into_iter().collect(),
+            CLOSE_ALL_ORDERS_CHUNK,
+        )
+    }
+
+    /// Closes up to `CLOSE_ALL_ORDERS_CHUNK` orders and returns the number of remaining open
+    /// orders, so that the caller knows whether it needs to repeat the call.
+    pub fn close_all_orders(&mut self) -> usize {
+        let closed_orders = self.close_orders_by_condition(
+            &|_, _| true,
+            Default::default(),
+            CLOSE_ALL_ORDERS_CHUNK,
+        );
+        let remaining_orders = self.open_orders();
+        self.log(format!(
+            "{} orders closed, {} orders remaining",
+            closed_orders, remaining_orders
+        ));
+        remaining_orders
+    }
+
+    /// Records a heartbeat of the user, which keeps the user's orders with a keepalive window
+    /// open. Users without such orders are ignored.
+    pub fn heartbeat(&mut self, user: Principal, now: Timestamp) {
+        if let Some(last_heartbeat) = self.heartbeats.get_mut(&user) {
+            *last_heartbeat = now;
+        }
+    }
+
+    /// Closes all orders whose owners didn't send a heartbeat within the keepalive window of the
+    /// order and returns the number of closed orders.
+    pub fn close_stale_orders(&mut self, now: Timestamp) -> usize {
+        if self.heartbeats.is_empty() {
+            return 0;
+        }
+        let heartbeats = self.heartbeats.clone();
+        let closed_orders = self.close_orders_by_condition(
+            &|_, order| {
+                order.keepalive_secs.is_some_and(|secs| {
+                    let last_heartbeat = heartbeats.get(&order.owner).copied().unwrap_or_default();
+                    last_heartbeat + secs * SECOND < now
+                })
+            },
+            Default::default(),
+            100000,
+        );
+        // Heartbeats are only tracked for users who still have orders with a keepalive window.
+        let owners = self
+            .books()
+            .flat_map(|(_, book)| book.buyers.iter().chain(book.sellers.iter()))
+            .filter(|order| order.keepalive_secs.is_some())
+            .map(|order| order.owner)
+            .collect::<BTreeSet<_>>();
+        self.heartbeats.retain(|user, _| owners.contains(user));
+        if closed_orders > 0 {
+            self.log(format!(
+                "{} orders closed due to missing heartbeats",
+                closed_orders
+            ));
+        }
+        closed_orders
+    }
+
+    /// Returns the number of all open orders, including stop-limit orders.
+    pub fn open_orders(&self) -> usize {
+        self.books()
+            .map(|(_, book)| book.buyers.len() + book.sellers.len() + book.pending.len())
+            .sum()
+    }
 
-        // Remove all archived orders older than 3 months
+    pub fn clean_up(&mut self, now: Timestamp) {
+        // Rotate logs and events
+        let max_logs = self.max_logs();
+        let deleted_logs = self.logs.len().saturating_sub(max_logs);
+        self.logs.truncate(max_logs);
+        self.events.truncate(LOGS_SIZE);
+        self.listing_payments.truncate(LOGS_SIZE);
+        self.client_orders
+            .retain(|_, (time, _)| *time + CLIENT_ORDER_ID_WINDOW > now);
+
+        // Remove all archived orders older than the retention period
         let mut deleted_archived_orders = 0;
-        for archive in self.order_archive.values_mut() {
-            let length_before = archive.len();
-            archive.retain(|order| order.timestamp + 2 * ORDER_EXPIRATION_DAYS * DAY > now);
-            deleted_archived_orders += length_before.saturating_sub(archive.len());
+        let retention = self.archive_retention_days() * DAY;
+        for token in self.order_archive.tokens() {
+            deleted_archived_orders += self
+                .order_archive
+                .retain(token, |order| order.timestamp + retention > now);
         }
 
-        // Close all orders older than `ORDER_EXPIRATION_DAYS`
+        // Close all expired orders
+        let expiration_days = self
+            .tokens
+            .iter()
+            .map(|(id, metadata)| (*id, metadata.order_expiration_days()))
+            .collect::<BTreeMap<_, _>>();
         let closed_orders = self.close_orders_by_condition(
-            &|order| order.timestamp + ORDER_EXPIRATION_DAYS * DAY < now,
+            &|pair, order| {
+                let days = expiration_days
+                    .get(&pair.base)
+                    .copied()
+                    .unwrap_or(ORDER_EXPIRATION_DAYS);
+                now >= order.expiration(days)
+            },
             Default::default(),
             100000,
         );
@@ -270,7 +1338,7 @@ impl State {
         // Delist all inactive tokens.
         //
         // Note that some users still might have funds in the frontend
-        // wallet. In this case, the token must be listed again to recover the funds.
+        // wallet. In this case, the operator can refund them with `refund_stranded`.
         for token_id in self.tokens.keys().copied().collect::<Vec<_>>() {
             if
             // the last order was created more than `2 x ORDER_EXPIRATION_DAYS` ago
@@ -279,16 +1347,11 @@ impl State {
                 .map(|data| data.timestamp + 2 * ORDER_EXPIRATION_DAYS * DAY < now)
                 .unwrap_or(true)
                 // there are no buy or sell orders
-                && self
-                    .orders
-                    .get(&token_id)
-                    .map(|book| book.sellers.is_empty() && book.buyers.is_empty())
-                    .unwrap_or(true)
+                && !self.has_orders(token_id)
                     // there is no liquidity locked
                     && self.pools.get(&token_id).map(|pool| pool.is_empty()).unwrap_or(true)
             {
-                self.tokens.remove(&token_id);
-                self.pools.remove(&token_id);
+                self.remove_token(token_id);
                 self.log(format!("token {} was delisted due to no demand", token_id));
             }
         }
@@ -296,1474 +1359,7875 @@ impl State {
         self.log(format!("clean-up routine ran at time {}", now));
     }
 
+    /// Closes all orders of the token and delists it, if no user has a balance in the token.
+    /// Otherwise, the token stays listed and the users with remaining balances are returned, so
+    /// that they can be refunded first. Hence, an empty list means that the token was delisted.
+    pub fn delist_token(&mut self, token_id: TokenId) -> Result<Vec<Principal>, String> {
+        if token_id == self.payment_token_id() {
+            return Err("the payment token can't be delisted".into());
+        }
+        self.token(token_id)?;
+        // A pending withdrawal gets released back to the pool if its transfer fails.
+        if self.pending_withdrawals.contains_key(&token_id) {
+            return Err("withdrawals of the token are pending".into());
+        }
+        let closed_orders = self.close_orders_by_condition(
+            &|_, _| true,
+            [token_id].iter().copied().collect(),
+            usize::MAX,
+        );
+        if self.has_orders(token_id) {
+            return Err("couldn't close all orders".into());
+        }
+        let users_with_balances = self
+            .pools
+            .get(&token_id)
+            .map(|pool| {
+                pool.iter()
+                    .filter_map(|(user, balance)| (*balance > 0).then_some(*user))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if users_with_balances.is_empty() {
+            self.remove_token(token_id);
+            self.log(format!(
+                "token {} was delisted by the operator, {} orders closed",
+                token_id, closed_orders
+            ));
+        } else {
+            self.log(format!(
+                "token {} can't be delisted yet, {} orders closed, {} users have balances",
+                token_id,
+                closed_orders,
+                users_with_balances.len()
+            ));
+        }
+        Ok(users_with_balances)
+    }
+
+    // Removes the token with its pool and all order books trading it.
+    fn remove_token(&mut self, token_id: TokenId) {
+        self.tokens.remove(&token_id);
+        self.pools.remove(&token_id);
+        self.orders.remove(&token_id);
+        // The archived orders are removed too, so that no statistics refer to an unlisted token.
+        self.order_archive.retain(token_id, |_| false);
+        self.alt_orders
+            .retain(|(token, quote), _| token != &token_id && quote != &token_id);
+        for metadata in self.tokens.values_mut() {
+            if metadata.quote_token == Some(token_id) {
+                metadata.quote_token = None;
+            }
+        }
+    }
+
+    /// Keeps only the `keep` newest archived orders of the token and returns the number of
+    /// removed orders.
+    pub fn compact_archive(&mut self, token: TokenId, keep: usize) -> usize {
+        let removed = self.order_archive.truncate(token, keep);
+        self.log(format!(
+            "compacted the archive of {} to {} orders, removed {} orders",
+            token, keep, removed
+        ));
+        removed
+    }
+
     /// Returns all users that have open orders.
     pub fn traders(&self) -> usize {
-        self.orders
-            .values()
-            .flat_map(|book| book.sellers.iter().chain(book.buyers.iter()))
+        self.books()
+            .flat_map(|(_, book)| book.sellers.iter().chain(book.buyers.iter()))
             .map(|order| order.owner)
             .collect::<BTreeSet<_>>()
             .len()
     }
 
-    pub fn list_token(
-        &mut self,
-        token: TokenId,
-        metadata: BTreeMap<String, Value>,
-        timestamp: Timestamp,
-    ) -> Result<(), String> {
-        match (
-            metadata.get("icrc1:symbol"),
-            metadata.get("icrc1:fee"),
-            metadata.get("icrc1:decimals"),
-            metadata.get("icrc1:logo"),
-        ) {
-            (
-                Some(Value::Text(symbol)),
-                Some(Value::Nat(fee)),
-                Some(Value::Nat(decimals)),
-                logo,
-            ) => self.add_token(
-                token,
-                symbol.clone(),
-                *fee,
-                *decimals as u32,
-                match logo {
-                    Some(Value::Text(hex)) => Some(hex.clone()),
-                    _ => None,
-                },
-                timestamp,
-            ),
-            (symbol, fee, decimals, _) => Err(format!(
-                "one of the required values missing: symbol={:?}, fee={:?}, decimals={:?}",
-                symbol, fee, decimals
-            )),
+    /// Closes all orders and switches to the new payment token, which must be listed already.
+    /// Since balances are not re-scaled, switching to a token with different decimals is only
+    /// possible while no payment token funds are managed.
+    pub fn set_payment_token(&mut self, token_id: TokenId) -> Result<(), String> {
+        let new_decimals = self.token(token_id)?.decimals;
+        if let Ok(current) = self.token(self.payment_token_id()) {
+            let payment_token_id = self.payment_token_id().to_string();
+            let funds = self
+                .funds_under_management()
+                .into_iter()
+                .find_map(|(id, funds)| (id == payment_token_id).then_some(funds))
+                .unwrap_or_default();
+            if current.decimals != new_decimals && funds > 0 {
+                return Err(format!(
+                    "the payment token can't be switched to a token with {} instead of {} \
+                    decimals while payment token balances exist",
+                    new_decimals, current.decimals
+                ));
+            }
         }
-    }
 
-    pub fn token_pool_balance(&self, token: TokenId, user: Principal) -> Tokens {
-        self.pools
-            .get(&token)
-            .and_then(|pool| pool.get(&user).copied())
-            .unwrap_or_default()
+        self.close_orders_by_condition(&|_, _| true, Default::default(), usize::MAX);
+        // we need to reset the order archive because the decimals of the new payment token might
+        // be different, which will lead to distorted prices
+        self.order_archive.clear();
+        self.payment_token_id = Some(token_id);
+        self.log(format!("payment token changed to {}", token_id));
+        Ok(())
     }
 
-    pub fn payment_token_pool(&self) -> &BTreeMap<Principal, Tokens> {
-        self.pools
-            .get(&self.payment_token_id())
-            .expect("no payment token pool")
+    /// Returns the listing price in whole payment tokens.
+    pub fn listing_price_usd(&self) -> u128 {
+        self.listing_price_usd.unwrap_or(LISTING_PRICE_USD)
     }
 
-    pub fn log(&mut self, message: String) {
-        ic_cdk::println!("{}", &message);
-        let event_id = self.event_id;
-        self.event_id += 1;
-        self.logs.push_front((event_id, message));
+    pub fn set_listing_price_usd(&mut self, price: u128) {
+        self.listing_price_usd = Some(price);
+        self.log(format!("listing price set to {}", price));
     }
 
-    pub fn close_order(
-        &mut self,
-        user: Principal,
-        token: TokenId,
-        amount: Tokens,
-        price: ParticlesPerToken,
-        timestamp: Timestamp,
-        order_type: OrderType,
-    ) -> Result<(), String> {
-        let orders = self
-            .orders
-            .get_mut(&token)
-            .map(|book| match order_type {
-                OrderType::Buy => &mut book.buyers,
-                OrderType::Sell => &mut book.sellers,
-            })
-            .ok_or("no token found")?;
-        let order = orders
-            .get(&Order {
-                order_type,
-                owner: user,
-                price,
-                amount,
-                timestamp,
-                // This field is ignored in `Order` comparison
-                decimals: 0,
-                // This field is ignored in `Order` comparison
-                payment_token_fee: 0,
-                // This field is guaranteed to be 0 for all open orders.
-                executed: 0,
-            })
-            .ok_or("no order found")?
-            .clone();
-        let reserved_liquidity = order.reserved_liquidity();
-        if !orders.remove(&order) {
-            return Err("order not found".into());
+    /// Adds the user to or removes them from the users who can list tokens for free.
+    pub fn set_free_listing(&mut self, user: Principal, free: bool) {
+        if free {
+            self.free_listing.insert(user);
+        } else {
+            self.free_listing.remove(&user);
         }
-        self.add_liquidity(
-            user,
-            if order_type.buy() {
-                self.payment_token_id()
-            } else {
-                token
-            },
-            reserved_liquidity,
-        );
-        Ok(())
+        self.log(format!("free listing for {}: {}", user, free));
     }
 
-    /// Returns open orders sorted by "the best price" for the order type.
-    /// - Buy: the highest price first
-    /// - Sell: the lowest price first
-    ///
-    /// Note: used in a query and tests only.
-    pub fn orders(
-        &self,
-        token: TokenId,
-        order_type: OrderType,
-    ) -> Box<dyn Iterator<Item = &'_ Order> + '_> {
-        if let Some(book) = self.orders.get(&token) {
-            match order_type {
-                OrderType::Buy => Box::new(book.buyers.iter().rev()),
-                OrderType::Sell => Box::new(book.sellers.iter()),
-            }
+    /// Exempts the user from the limit of orders per hour or revokes the exemption.
+    pub fn set_throttling_exemption(&mut self, user: Principal, exempt: bool) {
+        if exempt {
+            self.exempt_principals.insert(user);
         } else {
-            Box::new(std::iter::empty())
+            self.exempt_principals.remove(&user);
         }
+        self.log(format!("throttling exemption for {}: {}", user, exempt));
     }
 
-    /// Returns liquidity for each listed token together with the liquidity locked in orders.
-    /// Note: used in a query and tests only.
-    pub fn token_balances(&self, user: Principal) -> BTreeMap<TokenId, (Tokens, Tokens)> {
-        self.tokens
-            .keys()
-            .map(|token_id| {
-                (
-                    *token_id,
-                    (
-                        self.pools
-                            .get(token_id)
-                            .and_then(|pool| pool.get(&user).copied())
-                            .unwrap_or_default(),
-                        if token_id == &self.payment_token_id() {
-                            self.orders
-                                .values()
-                                .flat_map(|book| {
-                                    book.buyers.iter().filter_map(|order| {
-                                        (order.owner == user).then_some(order.reserved_liquidity())
-                                    })
-                                })
-                                .sum::<Tokens>()
-                        } else {
-                            self.orders
-                                .get(token_id)
-                                .map(|book| {
-                                    book.sellers
-                                        .iter()
-                                        .filter_map(|order| {
-                                            (order.owner == user)
-                                                .then_some(order.reserved_liquidity())
-                                        })
-                                        .sum::<Tokens>()
-                                })
-                                .unwrap_or_default()
-                        },
-                    ),
-                )
-            })
-            .collect()
+    /// Returns the amount of payment tokens the user gets charged for a token listing.
+    pub fn listing_charge(&self, user: Principal) -> Result<Tokens, String> {
+        if self.free_listing.contains(&user) {
+            return Ok(0);
+        }
+        let Metadata { fee, decimals, .. } = self
+            .token(self.payment_token_id())
+            .map_err(|_| "the payment token is not listed yet".to_string())?;
+        // we subtract the fee twice, because the user moved the funds to BEACON internal account
+        // first and now we need to move it to the payment pool again
+        Ok((self.listing_price_usd() * 10_u128.pow(decimals)).saturating_sub(fee + fee))
     }
 
-    pub fn logs(&self) -> &VecDeque<(u64, String)> {
-        &self.logs
+    /// Registers the canister method to be called with every filled order of the user.
+    pub fn set_fill_notification(&mut self, user: Principal, canister: Principal, method: String) {
+        self.log(format!(
+            "{} registered the fill notification {}.{}",
+            user, canister, method
+        ));
+        self.fill_notifications.insert(user, (canister, method));
     }
 
-    pub fn tokens(&self) -> &'_ BTreeMap<TokenId, Metadata> {
-        &self.tokens
+    /// Returns and removes all pending fill notifications as tuples of the canister, the method
+    /// and the filled order.
+    pub fn take_fill_notifications(&mut self) -> Vec<(Principal, String, Order)> {
+        std::mem::take(&mut self.pending_fill_notifications)
     }
 
-    pub fn token(&self, id: TokenId) -> Result<Metadata, String> {
-        self.tokens
-            .get(&id)
-            .cloned()
-            .ok_or("no token listed".into())
+    /// Compares the funds under management in the token with the given balance of the main
+    /// account on the token's ledger.
+    pub fn reconciliation(&self, token: TokenId, on_ledger: Tokens) -> Reconciliation {
+        let token_id = token.to_string();
+        let internal = self
+            .funds_under_management()
+            .into_iter()
+            .find_map(|(id, funds)| (id == token_id).then_some(funds))
+            .unwrap_or_default();
+        Reconciliation {
+            internal,
+            on_ledger,
+            diff: on_ledger as i128 - internal as i128,
+        }
     }
 
-    /// Transfers the given number of $payment_token from the user balance to the
-    /// revenue account balance.
-    pub fn charge(&mut self, user: Principal, amount: Tokens) -> Result<(), String> {
-        let payment_token_pool = self
-            .pools
-            .get_mut(&self.payment_token_id())
-            .ok_or("token not found")?;
-        let balance = payment_token_pool.entry(user).or_insert(0);
-        *balance = balance.checked_sub(amount).ok_or("not enough funds")?;
-        payment_token_pool
-            .entry(self.revenue_account.expect("no revenue account set"))
-            .and_modify(|balance| *balance += amount)
-            .or_insert(amount);
+    /// Returns the amount of the token that can be swept from the main account given its balance
+    /// on the ledger, i.e. the funds exceeding the funds under management minus the fee of the
+    /// transfer, so that the accounted liquidity is never touched.
+    pub fn sweepable_amount(
+        &self,
+        token: TokenId,
+        on_ledger: Tokens,
+        fee: Tokens,
+    ) -> Result<Tokens, String> {
+        if self.recovering {
+            return Err("the state is being recovered".into());
+        }
+        let excess = self.reconciliation(token, on_ledger).diff;
+        if excess <= fee as i128 {
+            return Err("no unaccounted funds to sweep".into());
+        }
+        Ok(excess as Tokens - fee)
+    }
 
-        self.log(format!("{} paid {} tokens", user, amount));
+    /// Returns the number of logs kept by the clean-up.
+    pub fn max_logs(&self) -> usize {
+        self.max_logs.unwrap_or(LOGS_SIZE)
+    }
+
+    pub fn set_max_logs(&mut self, max_logs: usize) -> Result<(), String> {
+        if !(MIN_LOGS_SIZE..=MAX_LOGS_SIZE).contains(&max_logs) {
+            return Err(format!(
+                "the number of logs must be between {} and {}",
+                MIN_LOGS_SIZE, MAX_LOGS_SIZE
+            ));
+        }
+        self.max_logs = Some(max_logs);
+        self.log(format!("log retention set to {} logs", max_logs));
         Ok(())
     }
 
-    /// Adds the given tokens to the user account balance.
-    pub fn add_liquidity(&mut self, user: Principal, id: TokenId, amount: Tokens) {
-        let pool = self.pools.entry(id).or_default();
-        let balance = pool.entry(user).or_default();
-        *balance += amount;
+    /// Returns the number of days executed orders are kept in the archive.
+    pub fn archive_retention_days(&self) -> u64 {
+        self.archive_retention_days
+            .unwrap_or(2 * ORDER_EXPIRATION_DAYS)
+    }
+
+    pub fn set_archive_retention_days(&mut self, days: u64) -> Result<(), String> {
+        if days == 0 || days > MAX_ARCHIVE_RETENTION_DAYS {
+            return Err(format!(
+                "the retention must be between 1 and {} days",
+                MAX_ARCHIVE_RETENTION_DAYS
+            ));
+        }
+        self.archive_retention_days = Some(days);
+        self.log(format!("archive retention set to {} days", days));
+        Ok(())
+    }
+
+    /// Returns the number of orders each side of a book can hold.
+    pub fn max_orders_per_book(&self) -> usize {
+        self.max_orders_per_book
+            .unwrap_or(DEFAULT_MAX_ORDERS_PER_BOOK)
+    }
+
+    /// Returns the trading fees in basis points of the trade volume.
+    pub fn trading_fees(&self) -> TradingFees {
+        self.trading_fees
+    }
+
+    /// Sets the trading fees. All orders, including stop-limit orders, must be closed before,
+    /// because buy orders reserve the maker fee.
+    pub fn set_trading_fees(&mut self, fees: TradingFees) -> Result<(), String> {
+        if fees.maker > MAX_TRADING_FEE || fees.taker > MAX_TRADING_FEE {
+            return Err(format!(
+                "the fees must not exceed {} basis points",
+                MAX_TRADING_FEE
+            ));
+        }
+        if self.books().any(|(_, book)| {
+            !book.buyers.is_empty() || !book.sellers.is_empty() || !book.pending.is_empty()
+        }) {
+            return Err("all orders must be closed before the fees can be changed".into());
+        }
+        self.trading_fees = fees;
         self.log(format!(
-            "added {} tokens to {} pool for {}",
-            amount, id, user,
+            "trading fees set to {} (maker) and {} (taker) basis points",
+            fees.maker, fees.taker
         ));
+        Ok(())
     }
 
-    pub fn withdraw_liquidity(&mut self, user: Principal, id: TokenId) -> Result<Tokens, String> {
-        let pool = self.pools.get_mut(&id).ok_or("no token found")?;
-        let amount = pool
-            .remove(&user)
-            .ok_or("nothing to withdraw".to_string())?;
+    /// Returns the frontend domains besides the domain of the canister itself.
+    pub fn domains(&self) -> Vec<String> {
+        self.domains.clone().unwrap_or_else(|| {
+            DEFAULT_DOMAINS
+                .iter()
+                .map(|domain| domain.to_string())
+                .collect()
+        })
+    }
+
+    pub fn add_domain(&mut self, domain: String) -> Result<(), String> {
+        if domain.is_empty()
+            || domain.len() > 253
+            || !domain
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        {
+            return Err("invalid domain".into());
+        }
+        let mut domains = self.domains();
+        if domains.contains(&domain) {
+            return Err("domain already added".into());
+        }
+        domains.push(domain.clone());
+        self.domains = Some(domains);
+        self.log(format!("domain {} added", domain));
+        Ok(())
+    }
+
+    pub fn remove_domain(&mut self, domain: String) -> Result<(), String> {
+        let mut domains = self.domains();
+        let len = domains.len();
+        domains.retain(|d| d != &domain);
+        if domains.len() == len {
+            return Err("domain not found".into());
+        }
+        self.domains = Some(domains);
+        self.log(format!("domain {} removed", domain));
+        Ok(())
+    }
+
+    pub fn set_max_orders_per_book(&mut self, limit: usize) -> Result<(), String> {
+        if limit == 0 {
+            return Err("the limit must be positive".into());
+        }
+        self.max_orders_per_book = Some(limit);
         self.log(format!(
-            "withdrew {} tokens from {} pool by {}",
-            amount, id, user,
+            "maximal number of orders per book set to {}",
+            limit
         ));
-        Ok(amount)
+        Ok(())
     }
 
-    fn add_token(
-        &mut self,
-        id: TokenId,
-        symbol: String,
-        fee: Tokens,
-        decimals: u32,
-        logo: Option<String>,
-        timestamp: Timestamp,
-    ) -> Result<(), String> {
-        if let Some(current_meta) = self.tokens.get(&id) {
-            // If this is a relisting and the fee or the decimals have changed, close all orders first.
-            if current_meta.fee != fee || current_meta.decimals != decimals {
-                // If the fee or decimals of a token has changed we close only orders or that token
-                // if it is not a payment token, otherwise, we close all orders.
-                let token_filter = if id == self.payment_token_id() {
-                    Default::default()
+    // Checks that the side of the book a new order of the type would be added to is not full.
+    // Existing orders stay open if the limit gets lowered.
+    fn check_book_capacity(&self, pair: Pair, order_type: OrderType) -> Result<(), String> {
+        let orders = self
+            .book(pair)
+            .map(|book| {
+                let open_orders = if order_type.buy() {
+                    book.buyers.len()
                 } else {
-                    [id].iter().copied().collect()
+                    book.sellers.len()
                 };
-                self.close_orders_by_condition(&|_| true, token_filter, usize::MAX);
-                if let Some(order_book) = self.orders.get(&id) {
-                    if !order_book.buyers.is_empty() || !order_book.sellers.is_empty() {
-                        return Err("couldn't close all orders".into());
-                    }
+                open_orders
+                    + book
+                        .pending
+                        .iter()
+                        .filter(|stop| stop.order.order_type == order_type)
+                        .count()
+            })
+            .unwrap_or_default();
+        if orders >= self.max_orders_per_book() {
+            return Err("the order book is full".into());
+        }
+        Ok(())
+    }
+
+    /// Compares the cycle balance with the warning threshold and logs a warning if the balance is
+    /// below it.
+    pub fn check_cycles(&mut self, balance: u64) {
+        self.low_cycles = balance < self.cycle_warning_threshold;
+        if self.low_cycles {
+            self.log(format!(
+                "WARNING: the cycle balance {} is below the threshold {}; token listings are \
+                suspended",
+                balance, self.cycle_warning_threshold
+            ));
+        }
+    }
+
+    /// Returns the trading fees collected in the payment token. The fees of the last day and week
+    /// are computed from the archived orders, the total is a running counter.
+    pub fn fee_stats(&self, now: Timestamp) -> FeeStats {
+        let mut stats = FeeStats {
+            fees_day: 0,
+            fees_week: 0,
+            fees_total: self.fees_collected,
+        };
+        for token in self.order_archive.tokens() {
+            for order in self
+                .order_archive
+                .iter(token)
+                .take_while(|order| order.executed + 7 * DAY >= now)
+            {
+                let volume = order.volume();
+                let fees =
+                    self.trading_fees.maker_fee(volume) + self.trading_fees.taker_fee(volume);
+                stats.fees_week += fees;
+                if order.executed + DAY >= now {
+                    stats.fees_day += fees;
                 }
             }
         }
-        self.tokens.insert(
-            id,
-            Metadata {
-                symbol,
-                logo,
-                fee,
-                decimals,
-                timestamp,
-            },
-        );
-        if let std::collections::btree_map::Entry::Vacant(e) = self.pools.entry(id) {
-            e.insert(Default::default());
-            self.log(format!("token {} was listed", id));
-        } else {
-            self.log(format!("token {} was re-listed", id));
+        stats
+    }
+
+    /// Returns the makers with the highest volume of filled orders in payment particles within
+    /// the leaderboard window, sorted by the volume.
+    pub fn top_traders(&self, limit: usize, now: Timestamp) -> Vec<(Principal, Tokens)> {
+        let mut volumes = BTreeMap::<Principal, Tokens>::new();
+        for order in self
+            .order_archive
+            .tokens()
+            .into_iter()
+            .flat_map(|token| {
+                self.order_archive
+                    .iter(token)
+                    .take_while(|order| order.executed + LEADERBOARD_WINDOW_DAYS * DAY >= now)
+            })
+            .take(MAX_LEADERBOARD_SCAN)
+        {
+            *volumes.entry(order.owner).or_default() += order.volume();
         }
-        Ok(())
+        let mut traders = volumes.into_iter().collect::<Vec<_>>();
+        traders.sort_by(|(_, a), (_, b)| b.cmp(a));
+        traders.truncate(limit.min(MAX_LEADERBOARD_SIZE));
+        traders
     }
 
-    pub fn create_order(
-        &mut self,
-        user: Principal,
-        token: TokenId,
-        amount: Tokens,
-        price: ParticlesPerToken,
-        timestamp: Timestamp,
-        order_type: OrderType,
-    ) -> Result<(), String> {
-        if price == 0 {
-            return Err("limit price is 0".into());
+    /// Returns whether orders of the token can be placed together with its fee and decimals.
+    pub fn token_status(&self, token: TokenId) -> TokenStatus {
+        let Some(metadata) = self.tokens.get(&token) else {
+            return TokenStatus {
+                paused: self.paused,
+                ..Default::default()
+            };
+        };
+        TokenStatus {
+            listed: true,
+            paused: self.paused,
+            has_liquidity: self
+                .book(token.into())
+                .is_some_and(|book| !book.buyers.is_empty() || !book.sellers.is_empty()),
+            tradable: !self.paused
+                && token != self.payment_token_id()
+                && self.tokens.contains_key(&self.payment_token_id()),
+            fee: metadata.fee,
+            decimals: metadata.decimals,
         }
+    }
 
-        self.record_activity(token, user, timestamp)?;
+    /// Returns the positions of the user in all pairs the user traded, computed by replaying the
+    /// user's latest `MAX_POSITION_TRADES` trades found among the latest
+    /// `MAX_POSITION_SCANNED_TRADES` trades of all users. Trades made before the decimals of the
+    /// token changed are skipped, because their amounts and prices are in other units. Trades
+    /// reducing a position realize the difference between their price and the average entry
+    /// price; trades beyond the position open a position in the opposite direction.
+    pub fn positions(&self, user: Principal) -> Vec<Position> {
+        // The net amount, the cost of the open position and the realized profit per pair.
+        let mut positions: BTreeMap<(TokenId, Option<TokenId>), (i128, Tokens, i128)> =
+            Default::default();
+        let mut trades = self
+            .trade_log
+            .iter_rev()
+            .take(MAX_POSITION_SCANNED_TRADES)
+            .filter_map(|(_, block)| {
+                let buy = if block.taker == user && block.maker != user {
+                    block.order_type.buy()
+                } else if block.maker == user && block.taker != user {
+                    !block.order_type.buy()
+                } else {
+                    return None;
+                };
+                Some((buy, block))
+            })
+            .take(MAX_POSITION_TRADES)
+            .collect::<Vec<_>>();
+        trades.reverse();
+        for (buy, block) in trades {
+            let Some(metadata) = self.tokens.get(&block.token) else {
+                continue;
+            };
+            if block
+                .decimals
+                .is_some_and(|decimals| decimals != metadata.decimals)
+            {
+                continue;
+            }
+            let volume = block.amount * block.price / 10_u128.pow(metadata.decimals);
+            let (net, cost, realized) = positions.entry((block.token, block.quote)).or_default();
+            let amount = block.amount as i128;
+            let delta = if buy { amount } else { -amount };
+            if *net == 0 || (*net > 0) == buy {
+                *net += delta;
+                *cost += volume;
+                continue;
+            }
+            // The trade reduces the position and may open one in the opposite direction.
+            let closed = block.amount.min(net.unsigned_abs());
+            let closed_cost = *cost * closed / net.unsigned_abs();
+            let proceeds = volume * closed / block.amount;
+            *realized += if buy {
+                closed_cost as i128 - proceeds as i128
+            } else {
+                proceeds as i128 - closed_cost as i128
+            };
+            *net += delta;
+            *cost = if closed < block.amount {
+                volume - proceeds
+            } else {
+                *cost - closed_cost
+            };
+        }
+        positions
+            .into_iter()
+            .map(|((token, quote), (net, cost, realized))| Position {
+                token,
+                quote,
+                net_amount: net,
+                avg_entry_price: if net == 0 {
+                    0
+                } else {
+                    let decimals = self
+                        .tokens
+                        .get(&token)
+                        .map(|m| m.decimals)
+                        .unwrap_or_default();
+                    cost * 10_u128.pow(decimals) / net.unsigned_abs()
+                },
+                realized_pnl: realized,
+            })
+            .collect()
+    }
 
-        assert_ne!(
-            token,
-            self.payment_token_id(),
-            "no orders for payment tokens are possible"
-        );
+    /// Returns a page of the users with a non-zero balance of the token, sorted by the balance.
+    /// Liquidity reserved in open orders is not included.
+    pub fn holders(&self, token: TokenId, offset: usize, limit: usize) -> Vec<(Principal, Tokens)> {
+        let mut holders = self
+            .pools
+            .get(&token)
+            .into_iter()
+            .flatten()
+            .filter(|(_, balance)| **balance > 0)
+            .map(|(user, balance)| (*user, *balance))
+            .collect::<Vec<_>>();
+        holders.sort_by(|(_, a), (_, b)| b.cmp(a));
+        holders
+            .into_iter()
+            .skip(offset)
+            .take(limit.min(MAX_HOLDERS_PAGE))
+            .collect()
+    }
 
-        let metadata = self.tokens.get(&token).ok_or("token not listed")?;
-        let payment_token_id = self.payment_token_id();
-        let payment_token_fee = self
-            .tokens
-            .get(&payment_token_id)
-            .ok_or("payment token not listed")?
-            .fee;
+    /// Returns the metadata of the token and a summary of its book against the payment token.
+    pub fn token_overview(&self, token: TokenId, now: Timestamp) -> Result<TokenOverview, String> {
+        let metadata = self.token(token)?;
+        let ticker = self.ticker(token, now);
+        Ok(TokenOverview {
+            metadata,
+            best_bid: self
+                .orders(token, OrderType::Buy)
+                .next()
+                .map(|order| order.price),
+            best_ask: self
+                .orders(token, OrderType::Sell)
+                .next()
+                .map(|order| order.price),
+            last_price: ticker.last,
+            volume_24h: ticker.volume_24h,
+            open_buy_orders: self.orders(token, OrderType::Buy).count(),
+            open_sell_orders: self.orders(token, OrderType::Sell).count(),
+        })
+    }
 
-        let order = Order {
-            order_type,
-            owner: user,
-            amount,
-            price,
-            decimals: metadata.decimals,
-            payment_token_fee,
-            timestamp,
-            executed: 0,
+    /// Returns the price statistics of the token over the 24 hours before `now`. The statistics
+    /// are None if there were no trades within the window.
+    pub fn ticker(&self, token: TokenId, now: Timestamp) -> Ticker {
+        let mut ticker = Ticker {
+            last: self.order_archive.front(token).map(|order| order.price),
+            ..Default::default()
         };
-        let order_book = self.orders.entry(token).or_default();
-        let token_balance = self
-            .pools
-            .get_mut(&if order_type.buy() {
-                payment_token_id
-            } else {
-                token
+        let mut earliest = None;
+        // The archive is sorted from the newest to the oldest order.
+        for order in self
+            .order_archive
+            .iter(token)
+            .take_while(|order| order.executed + DAY >= now)
+        {
+            ticker.high_24h = ticker.high_24h.max(Some(order.price));
+            ticker.low_24h = Some(ticker.low_24h.unwrap_or(order.price).min(order.price));
+            ticker.volume_24h += order.volume();
+            earliest = Some(order.price);
+        }
+        ticker.change_24h = earliest
+            .zip(ticker.last)
+            .map(|(earliest, last)| last as i128 - earliest as i128);
+        ticker
+    }
+
+    /// Returns a page of listed tokens, excluding the payment token, ranked by the sort key.
+    pub fn tokens_ranked(
+        &self,
+        sort_by: SortKey,
+        offset: usize,
+        limit: usize,
+        now: Timestamp,
+    ) -> Vec<RankedToken> {
+        let payment_token_id = self.payment_token_id();
+        let mut tokens = self
+            .tokens
+            .iter()
+            .filter(|(token, _)| **token != payment_token_id)
+            .map(|(token, metadata)| RankedToken {
+                token: *token,
+                symbol: metadata.symbol.clone(),
+                ticker: self.ticker(*token, now),
             })
-            .ok_or("no token found")?
-            .get_mut(&user)
-            .ok_or("no funds available")?;
-        let required_liquidity = order.reserved_liquidity();
-        if required_liquidity > *token_balance {
-            return Err("not enough funds available for this order size".into());
+            .collect::<Vec<_>>();
+        // The sort is stable, so ties stay ordered by the token id.
+        match sort_by {
+            SortKey::Volume24h => {
+                tokens.sort_by(|a, b| b.ticker.volume_24h.cmp(&a.ticker.volume_24h))
+            }
+            SortKey::LastPrice => tokens.sort_by(|a, b| b.ticker.last.cmp(&a.ticker.last)),
+            SortKey::Symbol => tokens.sort_by(|a, b| a.symbol.cmp(&b.symbol)),
         }
+        tokens
+            .into_iter()
+            .skip(offset)
+            .take(limit.min(MAX_TOKENS_PAGE))
+            .collect()
+    }
+
+    /// Returns the latest executed order of each token.
+    pub fn prices(&self) -> BTreeMap<TokenId, Order> {
+        self.tokens
+            .keys()
+            .filter_map(|token_id| {
+                self.order_archive
+                    .front(*token_id)
+                    .map(|order| (*token_id, order))
+            })
+            .collect()
+    }
 
-        let volume = order.volume();
-        let fee = trading_fee(volume);
-        if dust(fee, volume) {
-            return Err("the order is too small".into());
+    /// Returns up to `depth` most recent executed orders of each listed token, the newest first.
+    /// The depth is capped by `MAX_PRICES_DEPTH`.
+    pub fn recent_prices(&self, depth: usize) -> BTreeMap<TokenId, Vec<Order>> {
+        let depth = depth.min(MAX_PRICES_DEPTH);
+        self.tokens
+            .keys()
+            .filter_map(|token_id| {
+                let orders = self
+                    .order_archive
+                    .iter(*token_id)
+                    .take(depth)
+                    .collect::<Vec<_>>();
+                (!orders.is_empty()).then_some((*token_id, orders))
+            })
+            .collect()
+    }
+
+    /// Returns the time-weighted average price of the token over the window before `now`. The
+    /// price of each trade is weighted by the time until the next trade, or until `now` for the
+    /// latest one. Returns None if there were less than two trades within the window.
+    pub fn twap(
+        &self,
+        token: TokenId,
+        window_secs: u64,
+        now: Timestamp,
+    ) -> Option<ParticlesPerToken> {
+        let start = now.saturating_sub(window_secs.saturating_mul(SECOND));
+        let mut trades = 0;
+        let mut next_trade = now;
+        let (mut weighted_prices, mut total_weight) = (0_u128, 0_u128);
+        // The archive is sorted from the newest to the oldest order.
+        for order in self
+            .order_archive
+            .iter(token)
+            .skip_while(|order| order.executed > now)
+            .take_while(|order| order.executed >= start)
+        {
+            let weight = (next_trade - order.executed) as u128;
+            weighted_prices = weighted_prices.checked_add(order.price.checked_mul(weight)?)?;
+            total_weight += weight;
+            next_trade = order.executed;
+            trades += 1;
+        }
+        if trades < 2 {
+            return None;
         }
+        if total_weight == 0 {
+            // all trades happened at `now`
+            return self.order_archive.front(token).map(|order| order.price);
+        }
+        Some(weighted_prices / total_weight)
+    }
 
-        let inserted = if order_type.buy() {
-            order_book.buyers.insert(order)
-        } else {
-            order_book.sellers.insert(order)
+    /// Returns up to `count` candles of the given interval length for the token, starting from
+    /// the interval of the most recent trade and walking back in time. Intervals without trades
+    /// are skipped. The candles are sorted from the oldest to the newest.
+    pub fn candles(&self, token: TokenId, interval_secs: u64, count: usize) -> Vec<Candle> {
+        let interval = interval_secs.saturating_mul(SECOND);
+        if interval == 0 || count == 0 {
+            return Default::default();
+        }
+        let latest_bucket = match self.order_archive.front(token) {
+            Some(order) => order.executed / interval,
+            None => return Default::default(),
         };
-        if !inserted {
-            return Err("order exists already".into());
+        let first_bucket = latest_bucket.saturating_sub(count as u64 - 1);
+
+        let mut candles: Vec<Candle> = Vec::new();
+        // The archive is sorted from the newest to the oldest order, so the first order we see in
+        // a bucket closes it and the last one opens it.
+        for order in self
+            .order_archive
+            .iter(token)
+            .take_while(|order| order.executed / interval >= first_bucket)
+        {
+            let timestamp = (order.executed / interval) * interval;
+            match candles.last_mut() {
+                Some(candle) if candle.timestamp == timestamp => {
+                    candle.open = order.price;
+                    candle.high = candle.high.max(order.price);
+                    candle.low = candle.low.min(order.price);
+                    candle.volume += order.volume();
+                }
+                _ => candles.push(Candle {
+                    timestamp,
+                    open: order.price,
+                    high: order.price,
+                    low: order.price,
+                    close: order.price,
+                    volume: order.volume(),
+                }),
+            }
         }
-
-        *token_balance = token_balance.saturating_sub(required_liquidity);
-        self.log(format!(
-            "{} created {:?} order for {} {} at limit price {}",
-            user, order_type, amount, token, price
-        ));
-        Ok(())
+        candles.reverse();
+        candles
     }
 
-    pub fn trade(
+    pub fn list_token(
         &mut self,
-        trade_type: OrderType,
-        user: Principal,
         token: TokenId,
-        amount: u128,
-        price: ParticlesPerToken,
-        now: Timestamp,
-    ) -> Result<OrderExecution, String> {
-        // match existing orders
-        let filled = self.execute_trade(
-            trade_type,
-            user,
-            token,
-            amount,
-            (price > 0).then_some(price),
-            now,
-        )?;
-
-        // create a rest order if the original was not filled and this was a limit order
-        if filled < amount && price > 0 {
-            self.create_order(
-                user,
+        metadata: BTreeMap<String, Value>,
+        timestamp: Timestamp,
+    ) -> Result<(), String> {
+        match (
+            metadata.get("icrc1:symbol"),
+            metadata.get("icrc1:fee"),
+            metadata.get("icrc1:decimals"),
+            metadata.get("icrc1:logo"),
+        ) {
+            (
+                Some(Value::Text(symbol)),
+                Some(Value::Nat(fee)),
+                Some(Value::Nat(decimals)),
+                logo,
+            ) => self.add_token(
                 token,
-                amount.saturating_sub(filled),
-                price,
-                now,
-                trade_type,
-            )
-            .expect("order failed");
-            Ok(OrderExecution::FilledAndOrderCreated(filled))
-        } else {
-            Ok(OrderExecution::Filled(filled))
+                symbol.clone(),
+                *fee,
+                *decimals as u32,
+                match logo {
+                    Some(Value::Text(hex)) => Some(hex.clone()),
+                    _ => None,
+                },
+                timestamp,
+            ),
+            (symbol, fee, decimals, _) => Err(format!(
+                "one of the required values missing: symbol={:?}, fee={:?}, decimals={:?}",
+                symbol, fee, decimals
+            )),
         }
     }
 
-    fn execute_trade(
+    pub fn token_pool_balance(&self, token: TokenId, user: Principal) -> Tokens {
+        self.pools
+            .get(&token)
+            .and_then(|pool| pool.get(&user).copied())
+            .unwrap_or_default()
+    }
+
+    /// Returns the balances of the payment token, which are empty until the payment token is
+    /// listed.
+    pub fn payment_token_pool(&self) -> &BTreeMap<Principal, Tokens> {
+        static EMPTY_POOL: BTreeMap<Principal, Tokens> = BTreeMap::new();
+        self.pools
+            .get(&self.payment_token_id())
+            .unwrap_or(&EMPTY_POOL)
+    }
+
+    pub fn log(&mut self, message: String) {
+        ic_cdk::println!("{}", &message);
+        let event_id = self.event_id;
+        self.event_id += 1;
+        self.logs.push(event_id, message);
+    }
+
+    /// Records a structured event. Events share the id sequence with the logs.
+    pub fn emit(&mut self, event: Event) {
+        // Every change of a book is recorded by one of these events.
+        if matches!(
+            event,
+            Event::OrderCreated { .. } | Event::OrderClosed { .. } | Event::TradeExecuted { .. }
+        ) {
+            self.book_version += 1;
+        }
+        let event_id = self.event_id;
+        self.event_id += 1;
+        self.events.push_front((event_id, event));
+    }
+
+    /// Returns a page of events, the newest first.
+    pub fn events(&self, offset: usize, limit: usize) -> Vec<(u64, Event)> {
+        self.events
+            .iter()
+            .skip(offset)
+            .take(limit.min(MAX_EVENTS_PAGE))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns up to `length` trade blocks starting from the block index `start`.
+    pub fn trade_blocks(&self, start: u64, length: u64) -> TradeBlocks {
+        TradeBlocks {
+            log_length: self.trade_log.len(),
+            blocks: self
+                .trade_log
+                .range(start, (length as usize).min(MAX_TRADE_BLOCKS_PAGE)),
+        }
+    }
+
+    pub fn close_order(
         &mut self,
-        trade_type: OrderType,
-        trader: Principal,
-        token: TokenId,
-        mut amount: u128,
-        limit: Option<ParticlesPerToken>,
-        time: Timestamp,
-    ) -> Result<u128, String> {
-        let payment_token_id = self.payment_token_id();
+        user: Principal,
+        pair: impl Into<Pair>,
+        amount: Tokens,
+        price: ParticlesPerToken,
+        timestamp: Timestamp,
+        order_type: OrderType,
+    ) -> Result<(), String> {
+        let pair = pair.into();
+        let token = pair.base;
+        let quote_token_id = self.quote_token_id(pair);
+        let orders = book_mut(&mut self.orders, &mut self.alt_orders, pair)
+            .map(|book| match order_type {
+                OrderType::Buy => &mut book.buyers,
+                OrderType::Sell => &mut book.sellers,
+            })
+            .ok_or("no token found")?;
+        let order = find_order(
+            orders,
+            &Order::key(order_type, user, amount, price, timestamp),
+        )
+        .ok_or("no order found")?
+        .clone();
+        let reserved_liquidity = order.reserved_liquidity(self.trading_fees);
+        if !orders.remove(&order) {
+            return Err("order not found".into());
+        }
+        self.emit(Event::OrderClosed {
+            owner: user,
+            token,
+            quote: pair.quote,
+            order_type,
+            amount,
+            price,
+            timestamp,
+        });
+        self.add_liquidity(
+            user,
+            if order_type.buy() {
+                quote_token_id
+            } else {
+                token
+            },
+            reserved_liquidity,
+        );
+        Ok(())
+    }
 
-        let book = &mut match self.orders.get_mut(&token) {
-            Some(order_book) => order_book,
-            None => {
-                // There are no open orders, so nothing to execute against.
-                return Ok(0);
-            }
-        };
+    /// Changes the amount and the price of an open order in one step, without closing it and
+    /// creating a new one. The liquidity reserved by the order gets adjusted by the difference
+    /// and the order gets the new timestamp. If the user has not enough funds for the amended
+    /// order, the original order stays untouched. Like post-only orders, amended orders must
+    /// not cross the book, and every amendment counts against the rate limit.
+    pub fn amend_order(
+        &mut self,
+        pair: impl Into<Pair>,
+        key: Order,
+        new_amount: Tokens,
+        new_price: ParticlesPerToken,
+        now: Timestamp,
+    ) -> Result<(), String> {
+        if self.paused {
+            return Err("trading is paused".into());
+        }
 
-        let orders = if trade_type.buy() {
-            &mut book.sellers
-        } else {
-            &mut book.buyers
-        };
+        if new_price == 0 {
+            return Err("limit price is 0".into());
+        }
 
-        let archive = self.order_archive.entry(token).or_default();
+        let pair = pair.into();
+        let token = pair.base;
+        self.record_activity(token, key.owner, now)?;
 
-        let mut filled = 0;
-        while let Some(mut order) = if trade_type.buy() {
-            // Choose a sell order with the lowest price.
-            orders.pop_first()
+        let pool_id = if key.order_type.buy() {
+            self.quote_token_id(pair)
         } else {
-            // Choose a buy order with the highest price.
-            orders.pop_last()
-        } {
-            // if limit was set and we discover the first order with the price not matching the
-            // limit, stop filling orders
-            if let Some(limit) = limit {
-                if trade_type.buy() && limit < order.price
-                    || trade_type.sell() && limit > order.price
-                {
-                    orders.insert(order);
-                    break;
-                }
-            }
+            token
+        };
+        let metadata = self.token(token)?;
+        let book =
+            book_mut(&mut self.orders, &mut self.alt_orders, pair).ok_or("no token found")?;
+        // Like a post-only order, the amended order must not be matchable against the best order
+        // on the opposite side.
+        if if key.order_type.buy() {
+            book.sellers
+                .first()
+                .map(|best_ask| new_price >= best_ask.price)
+        } else {
+            book.buyers
+                .last()
+                .map(|best_bid| new_price <= best_bid.price)
+        }
+        .unwrap_or_default()
+        {
+            return Err("the amended order would cross".into());
+        }
+        let orders = match key.order_type {
+            OrderType::Buy => &mut book.buyers,
+            OrderType::Sell => &mut book.sellers,
+        };
+        let order = find_order(orders, &key).ok_or("no order found")?.clone();
 
-            amount = if order.amount > amount {
-                let prev_reserved_liquidity = order.reserved_liquidity();
-                // partial order fill - create a new one for leftovers
-                let mut remaining_order = order.clone();
-                remaining_order.amount = order.amount - amount;
-                remaining_order.timestamp = time;
-
-                let mut new_reserved_liquidity = 0;
-
-                let volume = remaining_order.volume();
-                let fee = trading_fee(volume);
-                // we do not create dust orders
-                if !dust(fee, volume) {
-                    new_reserved_liquidity = remaining_order.reserved_liquidity();
-                    assert!(orders.insert(remaining_order), "order overwritten");
-                }
+        let mut new_order = order.clone();
+        new_order.amount = new_amount;
+        new_order.price = new_price;
+        new_order.timestamp = now;
 
-                order.amount = amount;
-                let freed_liquidity = prev_reserved_liquidity
-                    .checked_sub(new_reserved_liquidity + order.reserved_liquidity())
-                    .expect("underflow");
-                if freed_liquidity > 0 {
-                    let id = if order.order_type.buy() {
-                        assert!(trade_type.sell());
-                        payment_token_id
-                    } else {
-                        assert!(trade_type.buy());
-                        token
-                    };
-                    if let Some(liquidity) = self
-                        .pools
-                        .get_mut(&id)
-                        .and_then(|pool| pool.get_mut(&order.owner))
-                    {
-                        *liquidity += freed_liquidity;
-                    }
-                }
-                0
-            } else {
-                amount - order.amount
-            };
+        metadata.check_min_volume(new_order.checked_volume()?)?;
+        metadata.check_order(&new_order)?;
 
-            adjust_pools(
-                &mut self.pools,
-                trader,
-                token,
-                &order,
-                self.revenue_account.unwrap(),
-                trade_type,
-                payment_token_id,
-            )?;
+        let balance = self
+            .pools
+            .get(&pool_id)
+            .and_then(|pool| pool.get(&order.owner))
+            .copied()
+            .unwrap_or_default();
+        let available_liquidity = balance + order.reserved_liquidity(self.trading_fees);
+        let required_liquidity = new_order.reserved_liquidity(self.trading_fees);
+        if required_liquidity > available_liquidity {
+            return Err("not enough funds available for this order size".into());
+        }
 
-            filled += order.amount;
-            order.executed = time;
-            archive.push_front(order);
+        orders.remove(&order);
+        if !orders.insert(new_order.clone()) {
+            orders.insert(order);
+            return Err("order exists already".into());
+        }
 
-            if amount == 0 {
-                break;
+        self.pools
+            .get_mut(&pool_id)
+            .ok_or("no token found")?
+            .insert(order.owner, available_liquidity - required_liquidity);
+        self.emit(Event::OrderClosed {
+            owner: order.owner,
+            token,
+            quote: pair.quote,
+            order_type: order.order_type,
+            amount: order.amount,
+            price: order.price,
+            timestamp: order.timestamp,
+        });
+        self.emit(Event::OrderCreated {
+            owner: new_order.owner,
+            token,
+            quote: pair.quote,
+            order_type: new_order.order_type,
+            amount: new_amount,
+            price: new_price,
+            timestamp: now,
+        });
+        self.log(format!(
+            "{} amended {:?} order for {} {} at limit price {} to {} at limit price {}",
+            order.owner, order.order_type, order.amount, token, order.price, new_amount, new_price
+        ));
+        Ok(())
+    }
+
+    /// Returns open orders sorted by "the best price" for the order type.
+    /// - Buy: the highest price first
+    /// - Sell: the lowest price first
+    ///
+    /// Note: used in a query and tests only.
+    pub fn orders(
+        &self,
+        pair: impl Into<Pair>,
+        order_type: OrderType,
+    ) -> Box<dyn Iterator<Item = &'_ Order> + '_> {
+        if let Some(book) = self.book(pair.into()) {
+            match order_type {
+                OrderType::Buy => Box::new(book.buyers.iter().rev()),
+                OrderType::Sell => Box::new(book.sellers.iter()),
             }
+        } else {
+            Box::new(std::iter::empty())
         }
+    }
 
-        if filled > 0 {
-            self.log(format!(
-                "{} {} {} {} with the limit price {:?}",
-                trader,
-                if trade_type.buy() { "bought" } else { "sold" },
-                filled,
-                token,
-                limit
-            ));
+    /// Returns the open orders with a price within `[min_price, max_price]`, sorted like
+    /// `orders`.
+    pub fn orders_in_range(
+        &self,
+        pair: impl Into<Pair>,
+        order_type: OrderType,
+        min_price: ParticlesPerToken,
+        max_price: ParticlesPerToken,
+    ) -> Vec<Order> {
+        let Some(book) = self.book(pair.into()) else {
+            return Default::default();
+        };
+        if min_price > max_price {
+            return Default::default();
+        }
+        // `Order` is ordered by the price first, so the bounds are the smallest orders with the
+        // minimal price and with the price above the maximal one.
+        let bound = |price| Order::key(order_type, Principal::from_slice(&[]), 0, price, 0);
+        let lower = Bound::Included(bound(min_price));
+        let upper = match max_price.checked_add(1) {
+            Some(price) => Bound::Excluded(bound(price)),
+            None => Bound::Unbounded,
+        };
+        let orders = if order_type.buy() {
+            &book.buyers
+        } else {
+            &book.sellers
+        };
+        let range = orders.range((lower, upper)).cloned();
+        if order_type.buy() {
+            range.rev().collect()
+        } else {
+            range.collect()
         }
+    }
 
-        Ok(filled)
+    /// Returns the version of the order books, which changes with every change of any book.
+    pub fn book_version(&self) -> u64 {
+        self.book_version
     }
 
-    /// This method is used for an invariance check, making sure that no funds get lost.
-    /// It returns a simple mapping from the token id, to the amount of managed funds.
-    ///
-    /// Note, that additionally to unlocked liquidity, we need to count all funds locked in
-    /// buying orders for the payment token, and all funds locked in sell orders of
-    /// a non-payment token
-    pub fn funds_under_management(&self) -> Vec<(String, Tokens)> {
-        self.pools
-            .iter()
-            .map(|(id, pool)| {
-                (
-                    id.to_string(),
-                    checked_sum(Box::new(pool.values().copied()))
-                        .checked_add(if id == &self.payment_token_id() {
-                            checked_sum(Box::new(self.orders.values().flat_map(|book| {
-                                book.buyers.iter().map(|order| order.reserved_liquidity())
-                            })))
-                        } else {
-                            self.orders
-                                .get(id)
-                                .map(|book| {
-                                    checked_sum(Box::new(
-                                        book.sellers.iter().map(|order| order.reserved_liquidity()),
-                                    ))
-                                })
-                                .unwrap_or_default()
-                        })
-                        .expect("overflow"),
-                )
-            })
-            .collect()
+    /// Returns the number of open orders and their summed amounts on both sides of the book.
+    pub fn order_counts(&self, pair: impl Into<Pair>) -> OrderCounts {
+        let Some(book) = self.book(pair.into()) else {
+            return Default::default();
+        };
+        OrderCounts {
+            buy_orders: book.buyers.len() as u64,
+            sell_orders: book.sellers.len() as u64,
+            buy_depth: book.buyers.iter().map(|order| order.amount).sum(),
+            sell_depth: book.sellers.iter().map(|order| order.amount).sum(),
+        }
     }
 
-    #[cfg(feature = "dev")]
-    // This method is used for local testing only.
-    pub fn replace_user_id(&mut self, old: Principal, new: Principal) {
-        self.orders.values_mut().for_each(|book| {
-            let mod_orders = book
-                .buyers
-                .clone()
-                .into_iter()
-                .map(|mut order| {
-                    if order.owner == old {
-                        order.owner = new;
-                    }
-                    order
-                })
-                .collect();
-            book.buyers = mod_orders;
-            let mod_orders = book
-                .sellers
-                .clone()
-                .into_iter()
-                .map(|mut order| {
-                    if order.owner == old {
-                        order.owner = new;
-                    }
-                    order
-                })
-                .collect();
-            book.sellers = mod_orders;
-        });
-        for pool in self.pools.values_mut() {
-            if let Some(balance) = pool.remove(&old) {
-                pool.insert(new, balance);
+    /// Returns the number of orders which get filled before the given order of the user, or
+    /// `None` if there is no such order. The position follows the order in which trades match the
+    /// book: sell orders by ascending and buy orders by descending `Order` ordering.
+    pub fn queue_position(
+        &self,
+        user: Principal,
+        pair: impl Into<Pair>,
+        order_type: OrderType,
+        amount: Tokens,
+        price: ParticlesPerToken,
+        timestamp: Timestamp,
+    ) -> Option<usize> {
+        let book = self.book(pair.into())?;
+        let key = Order::key(order_type, user, amount, price, timestamp);
+        Some(match order_type {
+            OrderType::Buy => {
+                let order = find_order(&book.buyers, &key)?;
+                book.buyers
+                    .range((Bound::Excluded(order), Bound::Unbounded))
+                    .count()
+            }
+            OrderType::Sell => {
+                let order = find_order(&book.sellers, &key)?;
+                book.sellers.range(..order).count()
+            }
+        })
+    }
+
+    /// Returns the pairs whose best bid is at or above the best ask. Such a book can only arise
+    /// from orders added without matching and should never exist.
+    pub fn crossed_books(&self) -> Vec<(TokenId, Option<TokenId>)> {
+        self.books()
+            .filter_map(|(pair, book)| crossing_orders(book).map(|_| (pair.base, pair.quote)))
+            .collect()
+    }
+
+    /// Resolves a crossed book by re-executing the newer of the best bid and the best ask as a
+    /// trade against the opposite side until the book isn't crossed anymore. The unfilled rest of
+    /// a re-executed order is added to the book again; own orders of its owner on the opposite
+    /// side get closed. Returns the number of re-executed orders, or an error if the owner of an
+    /// order can't pay for its re-execution, in which case the order stays in the book.
+    pub fn uncross(&mut self, pair: impl Into<Pair>, now: Timestamp) -> Result<usize, String> {
+        let pair = pair.into();
+        self.check_initialized()?;
+        let mut repaired = 0;
+        while repaired < CLOSE_ALL_ORDERS_CHUNK {
+            let Some((bid, ask)) = self.book(pair).and_then(crossing_orders) else {
+                break;
+            };
+            let order = if bid.timestamp >= ask.timestamp {
+                bid
+            } else {
+                ask
+            };
+            // The order gets closed only if its liquidity and the balance of the owner cover the
+            // re-execution, which charges the taker fee.
+            let pool_id = if order.order_type.buy() {
+                self.quote_token_id(pair)
+            } else {
+                pair.base
+            };
+            let available = self
+                .pools
+                .get(&pool_id)
+                .and_then(|pool| pool.get(&order.owner))
+                .copied()
+                .unwrap_or_default()
+                + order.reserved_liquidity(self.trading_fees);
+            let (_, spent) = self.simulated_spending(
+                order.order_type,
+                order.owner,
+                pair,
+                order.amount,
+                Some(order.price),
+            );
+            if spent > available {
+                return Err(format!(
+                    "the order of {} can't be re-executed: not enough funds",
+                    order.owner
+                ));
+            }
+            self.close_order(
+                order.owner,
+                pair,
+                order.amount,
+                order.price,
+                order.timestamp,
+                order.order_type,
+            )?;
+            let (filled, _) = self.execute_trade_with_fees(
+                order.order_type,
+                order.owner,
+                pair,
+                order.amount,
+                Some(order.price),
+                now,
+                SelfTradePrevention::CancelResting,
+            )?;
+            if filled < order.amount {
+                // If the rest can't be added to the book, e.g. because it is below the minimal
+                // volume, its liquidity stays available to the owner. The rest is not a new order
+                // of the owner, so it doesn't count against their rate limit.
+                if let Err(err) = self.add_order(
+                    order.owner,
+                    pair,
+                    order.amount - filled,
+                    order.price,
+                    now,
+                    order.order_type,
+                    order.expires_at,
+                    false,
+                    order.keepalive_secs,
+                ) {
+                    self.log(format!(
+                        "the rest of the uncrossed order of {} was closed: {}",
+                        order.owner, err
+                    ));
+                }
             }
+            repaired += 1;
         }
+        self.trigger_stop_orders(pair, now);
+        self.log(format!(
+            "uncrossed the book of {} by re-executing {} orders",
+            pair.base, repaired
+        ));
+        Ok(repaired)
     }
 
-    #[cfg(feature = "dev")]
-    // This method is used for local testing only.
-    pub fn replace_canister_id(&mut self, old: Principal, new: Principal) {
-        if let Some(orders) = self.orders.remove(&old) {
-            self.orders.insert(new, orders);
+    /// Returns all open orders of the user together with their token and the quote token, sorted
+    /// by the token, the quote token and the price.
+    ///
+    /// Note: used in a query and tests only.
+    pub fn user_orders(&self, user: Principal) -> Vec<(TokenId, Option<TokenId>, Order)> {
+        let mut orders = self
+            .books()
+            .flat_map(|(pair, book)| {
+                book.buyers
+                    .iter()
+                    .chain(book.sellers.iter())
+                    .filter(move |order| order.owner == user)
+                    .map(move |order| (pair.base, pair.quote, order.clone()))
+            })
+            .collect::<Vec<_>>();
+        orders.sort_by_key(|(token, quote, order)| (*token, *quote, order.price));
+        orders
+    }
+
+    /// Returns liquidity for each listed token together with the liquidity locked in orders.
+    /// Note: used in a query and tests only.
+    pub fn token_balances(&self, user: Principal) -> BTreeMap<TokenId, (Tokens, Tokens)> {
+        let mut balances = self
+            .tokens
+            .keys()
+            .map(|token_id| (*token_id, (self.token_pool_balance(*token_id, user), 0)))
+            .collect::<BTreeMap<_, _>>();
+        for (pair, book) in self.books() {
+            for order in book
+                .buyers
+                .iter()
+                .chain(book.sellers.iter())
+                .chain(book.pending.iter().map(|stop| &stop.order))
+                .filter(|order| order.owner == user)
+            {
+                let token_id = if order.order_type.buy() {
+                    self.quote_token_id(pair)
+                } else {
+                    pair.base
+                };
+                if let Some((_, locked)) = balances.get_mut(&token_id) {
+                    *locked += order.reserved_liquidity(self.trading_fees);
+                }
+            }
         }
-        if let Some(pool) = self.pools.remove(&old) {
-            self.pools.insert(new, pool);
+        balances
+    }
+
+    /// Returns the free liquidity of the user in the token and the liquidity reserved in buy and
+    /// sell orders. Buy orders reserve the quote token, i.e. the payment token or an alternative
+    /// quote token, sell orders reserve the traded token.
+    pub fn liquidity_breakdown(&self, user: Principal, token: TokenId) -> LiquidityBreakdown {
+        let mut breakdown = LiquidityBreakdown {
+            free: self.token_pool_balance(token, user),
+            ..Default::default()
+        };
+        for (pair, book) in self.books() {
+            if self.quote_token_id(pair) == token {
+                breakdown.reserved_in_buys += book
+                    .reserving_orders(OrderType::Buy)
+                    .filter(|order| order.owner == user)
+                    .map(|order| order.reserved_liquidity(self.trading_fees))
+                    .sum::<Tokens>();
+            }
+            if pair.base == token {
+                breakdown.reserved_in_sells += book
+                    .reserving_orders(OrderType::Sell)
+                    .filter(|order| order.owner == user)
+                    .map(|order| order.reserved_liquidity(self.trading_fees))
+                    .sum::<Tokens>();
+            }
         }
-        if let Some(metadata) = self.tokens.remove(&old) {
-            self.tokens.insert(new, metadata);
+        breakdown
+    }
+
+    /// Returns all logs, the newest first.
+    pub fn logs(&self) -> impl Iterator<Item = (u64, String)> {
+        self.logs.iter()
+    }
+
+    /// Returns a page of logs, the newest first, optionally only those containing the substring.
+    pub fn logs_paged(
+        &self,
+        offset: usize,
+        limit: usize,
+        substring: Option<String>,
+    ) -> Vec<(u64, String)> {
+        self.logs
+            .iter()
+            .filter(|(_, message)| {
+                substring
+                    .as_ref()
+                    .map(|substring| message.contains(substring.as_str()))
+                    .unwrap_or(true)
+            })
+            .skip(offset)
+            .take(limit.min(MAX_LOGS_PAGE))
+            .collect()
+    }
+
+    /// Moves the order archive and the logs deserialized from a heap of a previous version into
+    /// the stable memory.
+    pub fn migrate_to_stable_memory(&mut self) {
+        for (token, archive) in std::mem::take(&mut self.legacy_order_archive) {
+            // The archive is sorted from the newest to the oldest order.
+            for order in archive.into_iter().rev() {
+                self.order_archive.push(token, order);
+            }
         }
-        if let Some(archive) = self.order_archive.remove(&old) {
-            self.order_archive.insert(new, archive);
+        for (event_id, message) in std::mem::take(&mut self.legacy_logs) {
+            self.logs.push(event_id, message);
         }
     }
-}
 
-fn checked_sum(iter: Box<dyn Iterator<Item = Tokens> + '_>) -> Tokens {
-    let mut result: Tokens = 0;
-    for value in iter {
-        result = result.checked_add(value).expect("overflow");
+    pub fn tokens(&self) -> &'_ BTreeMap<TokenId, Metadata> {
+        &self.tokens
     }
-    result
-}
 
-/// Updates balances to execute the given order.
-/// The trader's balances are in the pool.
-/// The order owner's balances are partially in the pool and the order itself.
-/// 1) Buy case:
-/// - the trader buys N tokens for M + FEE of $payment_token.
-/// - the order contains N tokens.
-/// - the type of the order is sell.
-/// - pool[$payment_token][trader] -= M + FEE
-/// - pool[$payment_token][order.owner] += M - FEE
-/// - pool[token][trader] += order.amount
-/// - pool[$payment_token][revenue] += 2*FEE
-///
-/// 2) Sell case:
-/// - the trader sell N tokens for M + FEE of $payment_token.
-/// - the order contains M + FEE of $payment_token.
-/// - the type of the order is buy.
-/// - pool[$payment_token][trader] += M - FEE
-/// - pool[token][order.owner] += order.amount
-/// - pool[token][trader] -= order.amount
-/// - pool[$payment_token][revenue] += 2*FEE
-fn adjust_pools(
-    pools: &mut BTreeMap<TokenId, BTreeMap<Principal, Tokens>>,
-    trader: Principal,
-    token: TokenId,
-    order: &Order,
-    revenue_account: Principal,
-    trade_type: OrderType,
-    payment_token_id: Principal,
-) -> Result<(), String> {
-    // since the liquidity is locked inside the order,
-    // we need to know where we should avoid adjusting pools
-    assert_ne!(order.order_type, trade_type);
+    pub fn token(&self, id: TokenId) -> Result<Metadata, String> {
+        self.tokens
+            .get(&id)
+            .cloned()
+            .ok_or("no token listed".into())
+    }
 
-    let (payment_receiver, token_receiver) = if trade_type.buy() {
-        (order.owner, trader)
-    } else {
-        (trader, order.owner)
-    };
+    /// Returns an error if no fees could be collected, so that trades don't fail midway.
+    fn check_initialized(&self) -> Result<(), String> {
+        if self.revenue_account.is_none() {
+            return Err("exchange not initialized: revenue account unset".into());
+        }
+        Ok(())
+    }
+
+    /// Returns the beneficiaries of the fees with their shares in basis points. By default, the
+    /// revenue account gets all fees.
+    pub fn revenue_split(&self) -> Vec<(Principal, u16)> {
+        if !self.revenue_split.is_empty() {
+            return self.revenue_split.clone();
+        }
+        self.revenue_account
+            .map(|revenue_account| vec![(revenue_account, FEE_BASE as u16)])
+            .unwrap_or_default()
+    }
+
+    /// Sets the beneficiaries of the fees with their shares in basis points, which must sum up to
+    /// 10000. An empty table restores the default, where the revenue account gets all fees.
+    pub fn set_revenue_split(&mut self, split: Vec<(Principal, u16)>) -> Result<(), String> {
+        if split.len() > MAX_REVENUE_BENEFICIARIES {
+            return Err(format!(
+                "at most {} beneficiaries are supported",
+                MAX_REVENUE_BENEFICIARIES
+            ));
+        }
+        if split.iter().any(|(_, share)| *share == 0) {
+            return Err("each share must be positive".into());
+        }
+        let beneficiaries = split.iter().map(|(id, _)| id).collect::<BTreeSet<_>>();
+        if beneficiaries.len() < split.len() {
+            return Err("each beneficiary can be listed only once".into());
+        }
+        let total = split.iter().map(|(_, share)| *share as u128).sum::<u128>();
+        if !split.is_empty() && total != FEE_BASE {
+            return Err(format!(
+                "the shares sum up to {} instead of {} basis points",
+                total, FEE_BASE
+            ));
+        }
+        self.log(format!("revenue split set to {:?}", split));
+        self.revenue_split = split;
+        Ok(())
+    }
+
+    /// Transfers the given number of $payment_token from the user balance to the
+    /// balances of the revenue beneficiaries.
+    pub fn charge(&mut self, user: Principal, amount: Tokens) -> Result<(), String> {
+        let revenue_split = self.revenue_split();
+        if revenue_split.is_empty() {
+            return Err("no revenue account set".into());
+        }
+        let payment_token_pool = self
+            .pools
+            .get_mut(&self.payment_token_id())
+            .ok_or("the payment token is not listed yet")?;
+        let balance = payment_token_pool.entry(user).or_insert(0);
+        *balance = balance.checked_sub(amount).ok_or("not enough funds")?;
+        for (beneficiary, share) in split_revenue(&revenue_split, amount) {
+            *payment_token_pool.entry(beneficiary).or_default() += share;
+        }
+
+        self.log(format!("{} paid {} tokens", user, amount));
+        Ok(())
+    }
+
+    /// Adds the given tokens to the user account balance.
+    pub fn add_liquidity(&mut self, user: Principal, id: TokenId, amount: Tokens) {
+        let pool = self.pools.entry(id).or_default();
+        let balance = pool.entry(user).or_default();
+        *balance += amount;
+        self.log(format!(
+            "added {} tokens to {} pool for {}",
+            amount, id, user,
+        ));
+    }
+
+    pub fn withdraw_liquidity(&mut self, user: Principal, id: TokenId) -> Result<Tokens, String> {
+        let pool = self.pools.get_mut(&id).ok_or("no token found")?;
+        let amount = pool
+            .remove(&user)
+            .ok_or("nothing to withdraw".to_string())?;
+        self.emit(Event::LiquidityWithdrawn {
+            user,
+            token: id,
+            amount,
+        });
+        self.log(format!(
+            "withdrew {} tokens from {} pool by {}",
+            amount, id, user,
+        ));
+        Ok(amount)
+    }
+
+    /// Charges the user the listing fee for the token and records the payment.
+    pub fn charge_listing(
+        &mut self,
+        user: Principal,
+        token: TokenId,
+        amount: Tokens,
+        now: Timestamp,
+    ) -> Result<(), String> {
+        self.charge(user, amount)?;
+        self.listing_payments.push_front(ListingPayment {
+            payer: user,
+            token,
+            amount,
+            timestamp: now,
+        });
+        Ok(())
+    }
+
+    /// Returns a page of the paid listing fees, the newest first.
+    pub fn listing_payments(&self, offset: usize, limit: usize) -> Vec<ListingPayment> {
+        self.listing_payments
+            .iter()
+            .skip(offset)
+            .take(limit.min(MAX_LISTING_PAYMENTS_PAGE))
+            .cloned()
+            .collect()
+    }
+
+    /// Moves the whole balance of the user in the token into a reservation for a withdrawal and
+    /// returns the reserved amount. The reserved liquidity stays under management until the
+    /// withdrawal gets committed or released.
+    pub fn reserve_withdrawal(&mut self, user: Principal, id: TokenId) -> Result<Tokens, String> {
+        let amount = self
+            .pools
+            .get_mut(&id)
+            .ok_or("no token found")?
+            .remove(&user)
+            .ok_or("nothing to withdraw")?;
+        *self
+            .pending_withdrawals
+            .entry(id)
+            .or_default()
+            .entry(user)
+            .or_default() += amount;
+        Ok(amount)
+    }
+
+    /// Removes the reserved amount after the transfer to the user was confirmed.
+    pub fn commit_withdrawal(
+        &mut self,
+        user: Principal,
+        id: TokenId,
+        amount: Tokens,
+    ) -> Result<(), String> {
+        self.take_reservation(user, id, amount)?;
+        self.emit(Event::LiquidityWithdrawn {
+            user,
+            token: id,
+            amount,
+        });
+        self.log(format!(
+            "withdrew {} tokens from {} pool by {}",
+            amount, id, user,
+        ));
+        Ok(())
+    }
+
+    /// Returns the reserved amount to the user's balance after the transfer failed.
+    pub fn release_withdrawal(
+        &mut self,
+        user: Principal,
+        id: TokenId,
+        amount: Tokens,
+    ) -> Result<(), String> {
+        self.take_reservation(user, id, amount)?;
+        *self.pools.entry(id).or_default().entry(user).or_default() += amount;
+        Ok(())
+    }
+
+    fn take_reservation(
+        &mut self,
+        user: Principal,
+        id: TokenId,
+        amount: Tokens,
+    ) -> Result<(), String> {
+        let reservations = self
+            .pending_withdrawals
+            .get_mut(&id)
+            .ok_or("no withdrawal pending")?;
+        let reserved = reservations.get_mut(&user).ok_or("no withdrawal pending")?;
+        *reserved = reserved
+            .checked_sub(amount)
+            .ok_or("the amount exceeds the reservation")?;
+        if *reserved == 0 {
+            reservations.remove(&user);
+        }
+        if reservations.is_empty() {
+            self.pending_withdrawals.remove(&id);
+        }
+        Ok(())
+    }
+
+    /// Returns the fees collected on the revenue account per token.
+    pub fn revenue_balances(&self) -> BTreeMap<TokenId, Tokens> {
+        let Some(revenue_account) = self.revenue_account else {
+            return Default::default();
+        };
+        self.pools
+            .iter()
+            .filter_map(|(id, pool)| pool.get(&revenue_account).map(|balance| (*id, *balance)))
+            .collect()
+    }
+
+    /// Moves the given amount of the fees collected on the revenue account into a reservation for
+    /// a withdrawal and returns the revenue account. Like any other withdrawal, the reservation
+    /// gets committed or released once the transfer completes.
+    pub fn reserve_revenue_withdrawal(
+        &mut self,
+        token: TokenId,
+        amount: Tokens,
+    ) -> Result<Principal, String> {
+        let revenue_account = self.revenue_account.ok_or("no revenue account set")?;
+        let balance = self
+            .pools
+            .get_mut(&token)
+            .and_then(|pool| pool.get_mut(&revenue_account))
+            .ok_or("no revenue collected")?;
+        *balance = balance
+            .checked_sub(amount)
+            .ok_or("not enough revenue collected")?;
+        *self
+            .pending_withdrawals
+            .entry(token)
+            .or_default()
+            .entry(revenue_account)
+            .or_default() += amount;
+        Ok(revenue_account)
+    }
+
+    fn add_token(
+        &mut self,
+        id: TokenId,
+        symbol: String,
+        fee: Tokens,
+        decimals: u32,
+        logo: Option<String>,
+        timestamp: Timestamp,
+    ) -> Result<(), String> {
+        if decimals > MAX_TOKEN_DECIMALS {
+            return Err(format!(
+                "tokens with more than {} decimals are not supported",
+                MAX_TOKEN_DECIMALS
+            ));
+        }
+        if let Some(current_meta) = self.tokens.get(&id) {
+            // If this is a relisting and the fee or the decimals have changed, close all orders first.
+            if current_meta.fee != fee || current_meta.decimals != decimals {
+                self.log(format!(
+                    "metadata of token {} changed: fee {} -> {}, decimals {} -> {}",
+                    id, current_meta.fee, fee, current_meta.decimals, decimals
+                ));
+                // If the fee or decimals of a token has changed we close only orders or that token
+                // if it is not a payment token, otherwise, we close all orders.
+                let token_filter = if id == self.payment_token_id() {
+                    Default::default()
+                } else {
+                    [id].iter().copied().collect()
+                };
+                self.close_orders_by_condition(&|_, _| true, token_filter, usize::MAX);
+                if self.has_orders(id) {
+                    return Err("couldn't close all orders".into());
+                }
+            }
+        }
+        // Settings made by the operator survive relistings.
+        let (quote_token, min_notional, tick_size, min_volume, order_expiration_days) = self
+            .tokens
+            .get(&id)
+            .map(|metadata| {
+                (
+                    metadata.quote_token,
+                    metadata.min_notional,
+                    metadata.tick_size,
+                    metadata.min_volume,
+                    metadata.order_expiration_days,
+                )
+            })
+            .unwrap_or_default();
+        self.tokens.insert(
+            id,
+            Metadata {
+                symbol,
+                logo,
+                fee,
+                decimals,
+                timestamp,
+                quote_token,
+                min_notional,
+                tick_size,
+                min_volume,
+                order_expiration_days,
+            },
+        );
+        if let std::collections::btree_map::Entry::Vacant(e) = self.pools.entry(id) {
+            e.insert(Default::default());
+            self.emit(Event::TokenListed { token: id });
+            self.log(format!("token {} was listed", id));
+        } else {
+            self.log(format!("token {} was re-listed", id));
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_order(
+        &mut self,
+        user: Principal,
+        pair: impl Into<Pair>,
+        amount: Tokens,
+        price: ParticlesPerToken,
+        timestamp: Timestamp,
+        order_type: OrderType,
+        expires_at: Option<Timestamp>,
+        post_only: bool,
+        keepalive_secs: Option<u64>,
+    ) -> Result<OrderFees, String> {
+        let pair = pair.into();
+        let token = pair.base;
+        if self.paused {
+            return Err("trading is paused".into());
+        }
+        self.check_initialized()?;
+
+        if price == 0 {
+            return Err("limit price is 0".into());
+        }
+
+        check_expiration(expires_at, timestamp, self.order_expiration_days(token))?;
+        check_keepalive(keepalive_secs)?;
+        self.check_book_capacity(pair, order_type)?;
+
+        self.record_activity(token, user, timestamp)?;
+
+        self.add_order(
+            user,
+            pair,
+            amount,
+            price,
+            timestamp,
+            order_type,
+            expires_at,
+            post_only,
+            keepalive_secs,
+        )
+    }
+
+    /// Builds a new order and checks it against the listing of the token, its size constraints
+    /// and the balance of the owner, of which `spent` particles are already used up.
+    #[allow(clippy::too_many_arguments)]
+    fn new_order(
+        &self,
+        user: Principal,
+        pair: Pair,
+        amount: Tokens,
+        price: ParticlesPerToken,
+        timestamp: Timestamp,
+        order_type: OrderType,
+        expires_at: Option<Timestamp>,
+        keepalive_secs: Option<u64>,
+        spent: Tokens,
+    ) -> Result<Order, String> {
+        let token = pair.base;
+        assert_ne!(
+            token,
+            self.payment_token_id(),
+            "no orders for payment tokens are possible"
+        );
+
+        let metadata = self.tokens.get(&token).ok_or("token not listed")?;
+        if pair.quote.is_some() && pair.quote != metadata.quote_token {
+            return Err("quote token not supported".into());
+        }
+        let quote_token_id = self.quote_token_id(pair);
+        let payment_token_fee = self
+            .tokens
+            .get(&quote_token_id)
+            .ok_or(if pair.quote.is_some() {
+                "quote token not listed"
+            } else {
+                "payment token not listed"
+            })?
+            .fee;
+
+        let order = Order {
+            order_type,
+            owner: user,
+            amount,
+            price,
+            decimals: metadata.decimals,
+            payment_token_fee,
+            timestamp,
+            executed: 0,
+            expires_at,
+            keepalive_secs,
+            id: self.order_id + 1,
+        };
+        order.checked_volume()?;
+        let token_balance = self
+            .pools
+            .get(&if order_type.buy() {
+                quote_token_id
+            } else {
+                token
+            })
+            .ok_or("no token found")?
+            .get(&user)
+            .ok_or("no funds available")?;
+        if order.reserved_liquidity(self.trading_fees) + spent > *token_balance {
+            return Err("not enough funds available for this order size".into());
+        }
+
+        metadata.check_min_volume(order.volume())?;
+        metadata.check_order(&order)?;
+        Ok(order)
+    }
+
+    /// Adds a new order to the book without counting it against the rate limit of the owner.
+    #[allow(clippy::too_many_arguments)]
+    fn add_order(
+        &mut self,
+        user: Principal,
+        pair: Pair,
+        amount: Tokens,
+        price: ParticlesPerToken,
+        timestamp: Timestamp,
+        order_type: OrderType,
+        expires_at: Option<Timestamp>,
+        post_only: bool,
+        keepalive_secs: Option<u64>,
+    ) -> Result<OrderFees, String> {
+        let token = pair.base;
+        let order = self.new_order(
+            user,
+            pair,
+            amount,
+            price,
+            timestamp,
+            order_type,
+            expires_at,
+            keepalive_secs,
+            0,
+        )?;
+        let max_maker_fee = self.trading_fees.maker_fee(order.volume());
+        let required_liquidity = order.reserved_liquidity(self.trading_fees);
+        let quote_token_id = self.quote_token_id(pair);
+        let order_book = match pair.quote {
+            None => self.orders.entry(token).or_default(),
+            Some(quote) => self.alt_orders.entry((token, quote)).or_default(),
+        };
+
+        // A post-only order must not be matchable against the best order on the opposite side.
+        if post_only
+            && if order_type.buy() {
+                order_book
+                    .sellers
+                    .first()
+                    .map(|best_ask| price >= best_ask.price)
+            } else {
+                order_book
+                    .buyers
+                    .last()
+                    .map(|best_bid| price <= best_bid.price)
+            }
+            .unwrap_or_default()
+        {
+            return Err("post-only would cross".into());
+        }
+
+        let inserted = if order_type.buy() {
+            order_book.buyers.insert(order)
+        } else {
+            order_book.sellers.insert(order)
+        };
+        if !inserted {
+            return Err("order exists already".into());
+        }
+        self.order_id += 1;
+
+        if let Some(token_balance) = self
+            .pools
+            .get_mut(&if order_type.buy() {
+                quote_token_id
+            } else {
+                token
+            })
+            .and_then(|pool| pool.get_mut(&user))
+        {
+            *token_balance = token_balance.saturating_sub(required_liquidity);
+        }
+        // Creating an order with a keepalive window counts as a heartbeat.
+        if keepalive_secs.is_some() {
+            self.heartbeats.insert(user, timestamp);
+        }
+        self.emit(Event::OrderCreated {
+            owner: user,
+            token,
+            quote: pair.quote,
+            order_type,
+            amount,
+            price,
+            timestamp,
+        });
+        self.log(format!(
+            "{} created {:?} order for {} {} at limit price {}",
+            user, order_type, amount, token, price
+        ));
+        Ok(OrderFees {
+            taker_fee: 0,
+            max_maker_fee,
+        })
+    }
+
+    /// Adds all orders to the books without matching them and returns the result for each order.
+    /// Orders which would cross the book are rejected like post-only orders. As all orders share
+    /// the same creation time, the batch counts as a single order against the rate limit.
+    pub fn create_orders(
+        &mut self,
+        user: Principal,
+        requests: Vec<OrderRequest>,
+        now: Timestamp,
+    ) -> Vec<Result<OrderFees, String>> {
+        requests
+            .into_iter()
+            .enumerate()
+            .map(|(i, request)| {
+                if i >= MAX_ORDERS_PER_HOUR {
+                    return Err("too many orders in one batch".into());
+                }
+                self.create_order(
+                    user,
+                    Pair {
+                        base: request.token,
+                        quote: request.quote,
+                    },
+                    request.amount,
+                    request.price,
+                    now,
+                    request.order_type,
+                    request.expires_at,
+                    true,
+                    request.keepalive_secs,
+                )
+            })
+            .collect()
+    }
+
+    /// Executes the trade. If the client order id was used by the user for a trade within
+    /// `CLIENT_ORDER_ID_WINDOW`, the receipt of that trade is returned instead, so that clients
+    /// can safely retry trades.
+    #[allow(clippy::too_many_arguments)]
+    pub fn trade(
+        &mut self,
+        trade_type: OrderType,
+        user: Principal,
+        pair: impl Into<Pair>,
+        amount: u128,
+        price: ParticlesPerToken,
+        now: Timestamp,
+        self_trade_prevention: SelfTradePrevention,
+        expires_at: Option<Timestamp>,
+        post_only: bool,
+        keepalive_secs: Option<u64>,
+        client_order_id: Option<[u8; 32]>,
+    ) -> Result<TradeReceipt, String> {
+        let key = match client_order_id {
+            Some(id) => {
+                if let Some((_, receipt)) = self
+                    .client_orders
+                    .get(&(user, id))
+                    .filter(|(time, _)| time + CLIENT_ORDER_ID_WINDOW > now)
+                {
+                    return Ok(receipt.clone());
+                }
+                if self
+                    .client_orders
+                    .range((user, [0; 32])..=(user, [u8::MAX; 32]))
+                    .filter(|(_, (time, _))| time + CLIENT_ORDER_ID_WINDOW > now)
+                    .count()
+                    >= MAX_CLIENT_ORDER_IDS_PER_USER
+                {
+                    return Err("too many client order ids; please try again later".into());
+                }
+                Some((user, id))
+            }
+            None => None,
+        };
+        let receipt = self.place_trade(
+            trade_type,
+            user,
+            pair.into(),
+            amount,
+            price,
+            now,
+            self_trade_prevention,
+            expires_at,
+            post_only,
+            keepalive_secs,
+        )?;
+        // A trade is validated before any funds are moved, so a failed trade can be retried with
+        // the same id and only successful ones need to be remembered.
+        if let Some(key) = key {
+            self.client_orders.insert(key, (now, receipt.clone()));
+        }
+        Ok(receipt)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn place_trade(
+        &mut self,
+        trade_type: OrderType,
+        user: Principal,
+        pair: Pair,
+        amount: u128,
+        price: ParticlesPerToken,
+        now: Timestamp,
+        self_trade_prevention: SelfTradePrevention,
+        expires_at: Option<Timestamp>,
+        post_only: bool,
+        keepalive_secs: Option<u64>,
+    ) -> Result<TradeReceipt, String> {
+        if self.paused {
+            return Err("trading is paused".into());
+        }
+        self.check_initialized()?;
+
+        // A post-only order never takes liquidity, so it is only added to the book.
+        if post_only {
+            let fees = self.create_order(
+                user,
+                pair,
+                amount,
+                price,
+                now,
+                trade_type,
+                expires_at,
+                true,
+                keepalive_secs,
+            )?;
+            return Ok(TradeReceipt {
+                execution: OrderExecution::FilledAndOrderCreated(0),
+                fees,
+            });
+        }
+
+        // The rest order must not fail after the trade was executed, so it gets validated
+        // against the simulated fill before the matching.
+        check_expiration(expires_at, now, self.order_expiration_days(pair.base))?;
+        check_keepalive(keepalive_secs)?;
+        if price > 0 {
+            self.check_book_capacity(pair, trade_type)?;
+            let (fillable, spent) =
+                self.simulated_spending(trade_type, user, pair, amount, Some(price));
+            if fillable < amount {
+                self.new_order(
+                    user,
+                    pair,
+                    amount - fillable,
+                    price,
+                    now,
+                    trade_type,
+                    expires_at,
+                    keepalive_secs,
+                    spent,
+                )?;
+                self.record_activity(pair.base, user, now)?;
+            }
+        }
+
+        // match existing orders
+        let (filled, taker_fee) = self.execute_trade_with_fees(
+            trade_type,
+            user,
+            pair,
+            amount,
+            (price > 0).then_some(price),
+            now,
+            self_trade_prevention,
+        )?;
+
+        // create a rest order if the original was not filled and this was a limit order
+        let receipt = if filled < amount && price > 0 {
+            let fees = self.add_order(
+                user,
+                pair,
+                amount.saturating_sub(filled),
+                price,
+                now,
+                trade_type,
+                expires_at,
+                false,
+                keepalive_secs,
+            )?;
+            TradeReceipt {
+                execution: OrderExecution::FilledAndOrderCreated(filled),
+                fees: OrderFees { taker_fee, ..fees },
+            }
+        } else {
+            TradeReceipt {
+                execution: OrderExecution::Filled(filled),
+                fees: OrderFees {
+                    taker_fee,
+                    max_maker_fee: 0,
+                },
+            }
+        };
+        self.trigger_stop_orders(pair, now);
+        Ok(receipt)
+    }
+
+    /// Places a stop-limit order, which gets executed at the limit price once the last trade
+    /// price of the pair reaches the trigger price. The liquidity of the order gets reserved at
+    /// placement, so that the order can't fail for the lack of funds when it gets triggered.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_stop_order(
+        &mut self,
+        user: Principal,
+        pair: impl Into<Pair>,
+        amount: Tokens,
+        trigger_price: ParticlesPerToken,
+        limit_price: ParticlesPerToken,
+        timestamp: Timestamp,
+        order_type: OrderType,
+    ) -> Result<(), String> {
+        let pair = pair.into();
+        let token = pair.base;
+        if self.paused {
+            return Err("trading is paused".into());
+        }
+        self.check_initialized()?;
+        if trigger_price == 0 || limit_price == 0 {
+            return Err("the trigger price and the limit price must not be 0".into());
+        }
+        if token == self.payment_token_id() {
+            return Err("no orders for payment tokens are possible".into());
+        }
+        self.check_book_capacity(pair, order_type)?;
+
+        self.record_activity(token, user, timestamp)?;
+
+        let metadata = self.tokens.get(&token).ok_or("token not listed")?;
+        if pair.quote.is_some() && pair.quote != metadata.quote_token {
+            return Err("quote token not supported".into());
+        }
+        let quote_token_id = self.quote_token_id(pair);
+        let payment_token_fee = self
+            .tokens
+            .get(&quote_token_id)
+            .ok_or("quote token not listed")?
+            .fee;
+        let stop = StopOrder {
+            trigger_price,
+            order: Order {
+                order_type,
+                owner: user,
+                amount,
+                price: limit_price,
+                decimals: metadata.decimals,
+                payment_token_fee,
+                timestamp,
+                executed: 0,
+                expires_at: None,
+                keepalive_secs: None,
+                // The order gets its id once it's triggered and added to the book.
+                id: 0,
+            },
+        };
+        metadata.check_min_volume(stop.order.checked_volume()?)?;
+        metadata.check_order(&stop.order)?;
+
+        let book = self.book(pair);
+        if book
+            .and_then(|book| book.last_price)
+            .is_some_and(|last_price| stop.triggered(last_price))
+        {
+            return Err("the last price has reached the trigger price already".into());
+        }
+        if book.is_some_and(|book| book.pending.contains(&stop)) {
+            return Err("order exists already".into());
+        }
+
+        let reserved_token = if order_type.buy() {
+            quote_token_id
+        } else {
+            token
+        };
+        let required_liquidity = stop.order.reserved_liquidity(self.trading_fees);
+        let balance = self
+            .pools
+            .get_mut(&reserved_token)
+            .and_then(|pool| pool.get_mut(&user))
+            .ok_or("no funds available")?;
+        if required_liquidity > *balance {
+            return Err("not enough funds available for this order size".into());
+        }
+        *balance -= required_liquidity;
+
+        let book = match pair.quote {
+            None => self.orders.entry(token).or_default(),
+            Some(quote) => self.alt_orders.entry((token, quote)).or_default(),
+        };
+        book.pending.insert(stop);
+        self.log(format!(
+            "{} created {:?} stop-limit order for {} {} at trigger price {} and limit price {}",
+            user, order_type, amount, token, trigger_price, limit_price
+        ));
+        Ok(())
+    }
+
+    /// Closes a stop-limit order which wasn't triggered yet and releases its liquidity.
+    #[allow(clippy::too_many_arguments)]
+    pub fn close_stop_order(
+        &mut self,
+        user: Principal,
+        pair: impl Into<Pair>,
+        amount: Tokens,
+        trigger_price: ParticlesPerToken,
+        limit_price: ParticlesPerToken,
+        timestamp: Timestamp,
+        order_type: OrderType,
+    ) -> Result<(), String> {
+        let pair = pair.into();
+        let key = StopOrder {
+            trigger_price,
+            order: Order::key(order_type, user, amount, limit_price, timestamp),
+        };
+        let stop = book_mut(&mut self.orders, &mut self.alt_orders, pair)
+            .ok_or("no token found")?
+            .pending
+            .take(&key)
+            .ok_or("no order found")?;
+        let reserved_token = if order_type.buy() {
+            self.quote_token_id(pair)
+        } else {
+            pair.base
+        };
+        self.add_liquidity(
+            user,
+            reserved_token,
+            stop.order.reserved_liquidity(self.trading_fees),
+        );
+        Ok(())
+    }
+
+    /// Returns all stop-limit orders of the user together with their token and the quote token.
+    ///
+    /// Note: used in a query and tests only.
+    pub fn user_stop_orders(&self, user: Principal) -> Vec<(TokenId, Option<TokenId>, StopOrder)> {
+        self.books()
+            .flat_map(|(pair, book)| {
+                book.pending
+                    .iter()
+                    .filter(move |stop| stop.order.owner == user)
+                    .map(move |stop| (pair.base, pair.quote, stop.clone()))
+            })
+            .collect()
+    }
+
+    /// Executes all stop-limit orders of the pair triggered by the last trade price. Each
+    /// triggered order gets released and executed like a new limit order of its owner, which
+    /// moves the last price and can trigger further orders. If the rest of a triggered order
+    /// can't be added to the book, e.g. because of the rate limit, its liquidity stays free.
+    fn trigger_stop_orders(&mut self, pair: Pair, now: Timestamp) {
+        loop {
+            let book = match book_mut(&mut self.orders, &mut self.alt_orders, pair) {
+                Some(book) => book,
+                None => return,
+            };
+            let stop = match book.last_price.and_then(|last_price| {
+                book.pending
+                    .iter()
+                    .find(|stop| stop.triggered(last_price))
+                    .cloned()
+            }) {
+                Some(stop) => stop,
+                None => return,
+            };
+            book.pending.remove(&stop);
+
+            let StopOrder {
+                trigger_price,
+                order,
+            } = stop;
+            let reserved_token = if order.order_type.buy() {
+                self.quote_token_id(pair)
+            } else {
+                pair.base
+            };
+            self.add_liquidity(
+                order.owner,
+                reserved_token,
+                order.reserved_liquidity(self.trading_fees),
+            );
+            self.log(format!(
+                "{:?} stop-limit order of {} for {} {} triggered at {}",
+                order.order_type, order.owner, order.amount, pair.base, trigger_price
+            ));
+            let result = self
+                .execute_trade(
+                    order.order_type,
+                    order.owner,
+                    pair,
+                    order.amount,
+                    Some(order.price),
+                    now,
+                    SelfTradePrevention::Skip,
+                )
+                .and_then(|filled| {
+                    if filled < order.amount {
+                        self.create_order(
+                            order.owner,
+                            pair,
+                            order.amount - filled,
+                            order.price,
+                            now,
+                            order.order_type,
+                            None,
+                            false,
+                            None,
+                        )
+                        .map(|_| ())
+                    } else {
+                        Ok(())
+                    }
+                });
+            if let Err(err) = result {
+                self.log(format!(
+                    "the triggered stop-limit order of {} wasn't fully placed: {}",
+                    order.owner, err
+                ));
+            }
+        }
+    }
+
+    /// Buys the token against the payment token, filling the cheapest sell orders up to the price
+    /// `max_price` until the spent volume including the taker fees reaches `quote_amount`.
+    /// Returns the amount of bought tokens and the spent payment tokens.
+    pub fn trade_quote_amount(
+        &mut self,
+        user: Principal,
+        token: TokenId,
+        quote_amount: Tokens,
+        max_price: ParticlesPerToken,
+        now: Timestamp,
+    ) -> Result<(Tokens, Tokens), String> {
+        if self.paused {
+            return Err("trading is paused".into());
+        }
+        let payment_token_id = self.payment_token_id();
+        let balance_before = self.token_pool_balance(payment_token_id, user);
+        if balance_before < quote_amount {
+            return Err("not enough funds".into());
+        }
+
+        let pair = Pair::from(token);
+        let amount = self.amount_for_quote(user, pair, quote_amount, max_price);
+        let filled = self.execute_trade(
+            OrderType::Buy,
+            user,
+            pair,
+            amount,
+            Some(max_price),
+            now,
+            SelfTradePrevention::Skip,
+        )?;
+        let spent = balance_before - self.token_pool_balance(payment_token_id, user);
+        self.trigger_stop_orders(pair, now);
+        Ok((filled, spent))
+    }
+
+    /// Returns the amount of tokens that can be bought from the sell orders of other users up to
+    /// the price `max_price`, so that the volume including the taker fees doesn't exceed
+    /// `quote_amount`.
+    fn amount_for_quote(
+        &self,
+        user: Principal,
+        pair: Pair,
+        quote_amount: Tokens,
+        max_price: ParticlesPerToken,
+    ) -> Tokens {
+        let cost = |order: &Order, amount| {
+            let mut fill = order.clone();
+            fill.amount = amount;
+            let volume = fill.volume();
+            volume
+                + if amount > 0 {
+                    self.trading_fees.taker_fee(volume)
+                } else {
+                    0
+                }
+        };
+        let book = match self.book(pair) {
+            Some(book) => book,
+            None => return 0,
+        };
+        let (mut amount, mut budget) = (0, quote_amount);
+        for order in book.sellers.iter() {
+            if order.price > max_price {
+                break;
+            }
+            if order.owner == user {
+                continue;
+            }
+            // the largest fill of the order which the remaining budget can pay for
+            let (mut low, mut high) = (0, order.amount);
+            while low < high {
+                let mid = high - (high - low) / 2;
+                if cost(order, mid) <= budget {
+                    low = mid;
+                } else {
+                    high = mid - 1;
+                }
+            }
+            let mut fill = order.clone();
+            fill.amount = low;
+            if fill.volume() == 0 {
+                break;
+            }
+            amount += low;
+            budget -= cost(order, low);
+            if low < order.amount {
+                break;
+            }
+        }
+        amount
+    }
+
+    /// Checks that the user has enough funds to buy the amount of the token at the limit price,
+    /// including the taker fee, against the payment token.
+    pub fn check_buy_funds(
+        &self,
+        user: Principal,
+        token: TokenId,
+        amount: u128,
+        price: ParticlesPerToken,
+    ) -> Result<(), String> {
+        if price == 0 {
+            return Err("a limit price is required".into());
+        }
+        let order = Order {
+            decimals: self.token(token)?.decimals,
+            ..Order::key(OrderType::Buy, user, amount, price, 0)
+        };
+        let volume = order.checked_volume()?;
+        // the taker fee covers the maker fee of a rest order too
+        let required = volume + self.trading_fees.taker_fee(volume);
+        let balance = self.token_pool_balance(self.payment_token_id(), user);
+        if balance < required {
+            return Err(format!(
+                "the balance of {} doesn't cover the order of {} including fees",
+                balance, required
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the expected outcome of the trade by walking the open orders the same way
+    /// `execute_trade` does, without mutating anything. Own orders of the trader are skipped.
+    pub fn simulate_trade(
+        &self,
+        trade_type: OrderType,
+        trader: Principal,
+        pair: impl Into<Pair>,
+        amount: u128,
+        limit: Option<ParticlesPerToken>,
+    ) -> TradeSimulation {
+        let mut simulation = TradeSimulation::default();
+        let mut weighted_price = 0;
+        for fill in self.simulated_fills(trade_type, trader, pair.into(), amount, limit) {
+            simulation.fillable += fill.amount;
+            simulation.fee += self.trading_fees.taker_fee(fill.volume());
+            weighted_price += fill.amount * fill.price;
+        }
+        if simulation.fillable > 0 {
+            simulation.avg_price = weighted_price / simulation.fillable;
+        }
+        simulation.rest_amount = amount - simulation.fillable;
+        simulation
+    }
+
+    // Returns the amount a trade would fill and the funds the trader would spend on it: the quote
+    // tokens including the taker fee for buys and the sold tokens for sells.
+    fn simulated_spending(
+        &self,
+        trade_type: OrderType,
+        trader: Principal,
+        pair: Pair,
+        amount: u128,
+        limit: Option<ParticlesPerToken>,
+    ) -> (Tokens, Tokens) {
+        let fills = self.simulated_fills(trade_type, trader, pair, amount, limit);
+        let fillable = fills.iter().map(|order| order.amount).sum();
+        let spent = if trade_type.buy() {
+            fills
+                .iter()
+                .map(|order| order.volume() + self.trading_fees.taker_fee(order.volume()))
+                .sum()
+        } else {
+            fillable
+        };
+        (fillable, spent)
+    }
+
+    // Returns the parts of the open orders of other users a trade would be filled with. Like the
+    // execution, the matching stops at an order which can't be split.
+    fn simulated_fills(
+        &self,
+        trade_type: OrderType,
+        trader: Principal,
+        pair: Pair,
+        mut amount: u128,
+        limit: Option<ParticlesPerToken>,
+    ) -> Vec<Order> {
+        let min_volume = self
+            .tokens
+            .get(&pair.base)
+            .map(|metadata| metadata.min_volume())
+            .unwrap_or(DEFAULT_MIN_VOLUME);
+        let mut fills = Vec::new();
+        for order in self.matching_orders(trade_type, pair) {
+            if amount == 0 {
+                break;
+            }
+            if let Some(limit) = limit {
+                if trade_type.buy() && limit < order.price
+                    || trade_type.sell() && limit > order.price
+                {
+                    break;
+                }
+            }
+            if order.owner == trader {
+                continue;
+            }
+            let fill = if order.amount > amount {
+                // The time of the split doesn't affect its outcome.
+                match split_order(
+                    order,
+                    amount,
+                    order.timestamp,
+                    min_volume,
+                    self.trading_fees,
+                ) {
+                    Ok((fill, _, _)) => fill,
+                    Err(_) => break,
+                }
+            } else {
+                order.clone()
+            };
+            amount -= fill.amount;
+            fills.push(fill);
+        }
+        fills
+    }
+
+    /// Returns the price impact of a market trade of the given amount, or None if the open orders
+    /// of other users can't fill the whole amount.
+    pub fn price_impact(
+        &self,
+        trade_type: OrderType,
+        trader: Principal,
+        pair: impl Into<Pair>,
+        amount: u128,
+    ) -> Option<PriceImpact> {
+        let pair = pair.into();
+        let simulation = self.simulate_trade(trade_type, trader, pair, amount, None);
+        if simulation.fillable == 0 || simulation.rest_amount > 0 {
+            return None;
+        }
+        let best_price = self
+            .matching_orders(trade_type, pair)
+            .find(|order| order.owner != trader)?
+            .price;
+        Some(PriceImpact {
+            avg_fill_price: simulation.avg_price,
+            best_price,
+            impact_bps: (simulation.avg_price.abs_diff(best_price) * FEE_BASE / best_price) as u64,
+        })
+    }
+
+    // Returns the orders a trade of the given type gets filled with, the best price first.
+    fn matching_orders(
+        &self,
+        trade_type: OrderType,
+        pair: Pair,
+    ) -> Box<dyn Iterator<Item = &Order> + '_> {
+        match self.book(pair) {
+            Some(book) if trade_type.buy() => Box::new(book.sellers.iter()),
+            Some(book) => Box::new(book.buyers.iter().rev()),
+            None => Box::new(std::iter::empty()),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn execute_trade(
+        &mut self,
+        trade_type: OrderType,
+        trader: Principal,
+        pair: Pair,
+        amount: u128,
+        limit: Option<ParticlesPerToken>,
+        time: Timestamp,
+        self_trade_prevention: SelfTradePrevention,
+    ) -> Result<u128, String> {
+        self.execute_trade_with_fees(
+            trade_type,
+            trader,
+            pair,
+            amount,
+            limit,
+            time,
+            self_trade_prevention,
+        )
+        .map(|(filled, _)| filled)
+    }
+
+    // Executes the trade and returns the filled amount together with the taker fees paid for it.
+    #[allow(clippy::too_many_arguments)]
+    fn execute_trade_with_fees(
+        &mut self,
+        trade_type: OrderType,
+        trader: Principal,
+        pair: Pair,
+        mut amount: u128,
+        limit: Option<ParticlesPerToken>,
+        time: Timestamp,
+        self_trade_prevention: SelfTradePrevention,
+    ) -> Result<(u128, Tokens), String> {
+        let token = pair.base;
+        let quote_token_id = self.quote_token_id(pair);
+        let revenue_split = self.revenue_split();
+        let min_volume = self
+            .tokens
+            .get(&token)
+            .map(|metadata| metadata.min_volume())
+            .unwrap_or(DEFAULT_MIN_VOLUME);
+        let decimals = self.tokens.get(&token).map(|metadata| metadata.decimals);
+
+        let book = &mut match book_mut(&mut self.orders, &mut self.alt_orders, pair) {
+            Some(order_book) => order_book,
+            None => {
+                // There are no open orders, so nothing to execute against.
+                return Ok((0, 0));
+            }
+        };
+
+        let orders = if trade_type.buy() {
+            &mut book.sellers
+        } else {
+            &mut book.buyers
+        };
+
+        let mut events = Vec::new();
+        // Own orders of the trader, skipped during the execution.
+        let mut skipped_orders = Vec::new();
+        let mut split_error = None;
+        let mut filled = 0;
+        let mut taker_fees = 0;
+        let mut last_price = None;
+        while let Some(mut order) = if trade_type.buy() {
+            // Choose a sell order with the lowest price.
+            orders.pop_first()
+        } else {
+            // Choose a buy order with the highest price.
+            orders.pop_last()
+        } {
+            // if limit was set and we discover the first order with the price not matching the
+            // limit, stop filling orders
+            if let Some(limit) = limit {
+                if trade_type.buy() && limit < order.price
+                    || trade_type.sell() && limit > order.price
+                {
+                    orders.insert(order);
+                    break;
+                }
+            }
+
+            // users can't trade against their own orders
+            if order.owner == trader {
+                match self_trade_prevention {
+                    SelfTradePrevention::Skip => skipped_orders.push(order),
+                    SelfTradePrevention::CancelResting => {
+                        let id = if order.order_type.buy() {
+                            quote_token_id
+                        } else {
+                            token
+                        };
+                        *self
+                            .pools
+                            .get_mut(&id)
+                            .ok_or("no token found")?
+                            .entry(order.owner)
+                            .or_default() += order.reserved_liquidity(self.trading_fees);
+                        events.push(Event::OrderClosed {
+                            owner: order.owner,
+                            token,
+                            quote: pair.quote,
+                            order_type: order.order_type,
+                            amount: order.amount,
+                            price: order.price,
+                            timestamp: order.timestamp,
+                        });
+                    }
+                }
+                continue;
+            }
+
+            amount = if order.amount > amount {
+                // partial order fill - create a new one for leftovers
+                let split = split_order(&order, amount, time, min_volume, self.trading_fees)
+                    .and_then(|(filled_order, remaining_order, freed_liquidity)| {
+                        match remaining_order {
+                            Some(remaining_order) if orders.contains(&remaining_order) => {
+                                Err("the rest order exists already".to_string())
+                            }
+                            _ => Ok((filled_order, remaining_order, freed_liquidity)),
+                        }
+                    });
+                let (filled_order, remaining_order, freed_liquidity) = match split {
+                    Ok(split) => split,
+                    Err(err) => {
+                        // The order stays untouched and the matching stops.
+                        orders.insert(order);
+                        split_error = Some(err);
+                        break;
+                    }
+                };
+                if let Some(remaining_order) = remaining_order {
+                    orders.insert(remaining_order);
+                }
+
+                order = filled_order;
+                if freed_liquidity > 0 {
+                    let id = if order.order_type.buy() {
+                        quote_token_id
+                    } else {
+                        token
+                    };
+                    *self
+                        .pools
+                        .get_mut(&id)
+                        .ok_or("no token found")?
+                        .entry(order.owner)
+                        .or_default() += freed_liquidity;
+                }
+                0
+            } else {
+                amount - order.amount
+            };
+
+            let fees = adjust_pools(
+                &mut self.pools,
+                trader,
+                token,
+                &order,
+                &revenue_split,
+                trade_type,
+                quote_token_id,
+                self.trading_fees,
+            )?;
+            if pair.quote.is_none() {
+                self.fees_collected += fees;
+            }
+            self.trade_log.push(TradeBlock {
+                maker: order.owner,
+                taker: trader,
+                token,
+                quote: pair.quote,
+                order_type: trade_type,
+                amount: order.amount,
+                price: order.price,
+                fee: fees,
+                timestamp: time,
+                decimals,
+            });
+
+            filled += order.amount;
+            taker_fees += self.trading_fees.taker_fee(order.volume());
+            last_price = Some(order.price);
+            order.executed = time;
+            if let Some((canister, method)) = self.fill_notifications.get(&order.owner) {
+                self.pending_fill_notifications
+                    .push((*canister, method.clone(), order.clone()));
+            }
+            events.push(Event::TradeExecuted {
+                maker: order.owner,
+                taker: trader,
+                token,
+                quote: pair.quote,
+                order_type: trade_type,
+                amount: order.amount,
+                price: order.price,
+                timestamp: time,
+            });
+            // The archive is used for price and volume statistics in the payment token.
+            if pair.quote.is_none() {
+                self.order_archive.push(token, order);
+            }
+
+            if amount == 0 {
+                break;
+            }
+        }
+
+        for order in skipped_orders {
+            orders.insert(order);
+        }
+        if last_price.is_some() {
+            book.last_price = last_price;
+        }
+
+        for event in events {
+            self.emit(event);
+        }
+
+        if let Some(err) = split_error {
+            self.log(format!(
+                "the trade of {} in {} stopped, because an order couldn't be split: {}",
+                trader, token, err
+            ));
+        }
+
+        if filled > 0 {
+            self.log(format!(
+                "{} {} {} {} with the limit price {:?}",
+                trader,
+                if trade_type.buy() { "bought" } else { "sold" },
+                filled,
+                token,
+                limit
+            ));
+        }
+
+        Ok((filled, taker_fees))
+    }
+
+    /// This method is used for an invariance check, making sure that no funds get lost.
+    /// It returns a simple mapping from the token id, to the amount of managed funds.
+    ///
+    /// Note, that additionally to unlocked liquidity, we need to count all funds locked in
+    /// buying orders for their quote token, and all funds locked in sell orders for the traded
+    /// token, including the stop-limit orders, as well as the liquidity reserved for pending
+    /// withdrawals.
+    pub fn funds_under_management(&self) -> Vec<(String, Tokens)> {
+        let mut locked_funds = BTreeMap::<TokenId, Tokens>::new();
+        for (id, reservations) in &self.pending_withdrawals {
+            let locked = locked_funds.entry(*id).or_default();
+            *locked = locked
+                .checked_add(checked_sum(Box::new(reservations.values().copied())))
+                .expect("overflow");
+        }
+        for (pair, book) in self.books() {
+            let quote_token_id = self.quote_token_id(pair);
+            let locked = locked_funds.entry(quote_token_id).or_default();
+            *locked = locked
+                .checked_add(checked_sum(Box::new(
+                    book.reserving_orders(OrderType::Buy)
+                        .map(|order| order.reserved_liquidity(self.trading_fees)),
+                )))
+                .expect("overflow");
+            let locked = locked_funds.entry(pair.base).or_default();
+            *locked = locked
+                .checked_add(checked_sum(Box::new(
+                    book.reserving_orders(OrderType::Sell)
+                        .map(|order| order.reserved_liquidity(self.trading_fees)),
+                )))
+                .expect("overflow");
+        }
+        self.pools
+            .iter()
+            .map(|(id, pool)| {
+                (
+                    id.to_string(),
+                    checked_sum(Box::new(pool.values().copied()))
+                        .checked_add(locked_funds.get(id).copied().unwrap_or_default())
+                        .expect("overflow"),
+                )
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "dev")]
+    // This method is used for local testing only.
+    pub fn replace_user_id(&mut self, old: Principal, new: Principal) {
+        let books = self.orders.values_mut().chain(self.alt_orders.values_mut());
+        books.for_each(|book| {
+            let mod_orders = book
+                .buyers
+                .clone()
+                .into_iter()
+                .map(|mut order| {
+                    if order.owner == old {
+                        order.owner = new;
+                    }
+                    order
+                })
+                .collect();
+            book.buyers = mod_orders;
+            let mod_orders = book
+                .sellers
+                .clone()
+                .into_iter()
+                .map(|mut order| {
+                    if order.owner == old {
+                        order.owner = new;
+                    }
+                    order
+                })
+                .collect();
+            book.sellers = mod_orders;
+            book.pending = std::mem::take(&mut book.pending)
+                .into_iter()
+                .map(|mut stop| {
+                    if stop.order.owner == old {
+                        stop.order.owner = new;
+                    }
+                    stop
+                })
+                .collect();
+        });
+        for pool in self.pools.values_mut() {
+            if let Some(balance) = pool.remove(&old) {
+                pool.insert(new, balance);
+            }
+        }
+    }
+
+    #[cfg(feature = "dev")]
+    // This method is used for local testing only.
+    pub fn replace_canister_id(&mut self, old: Principal, new: Principal) {
+        if let Some(orders) = self.orders.remove(&old) {
+            self.orders.insert(new, orders);
+        }
+        self.alt_orders = std::mem::take(&mut self.alt_orders)
+            .into_iter()
+            .map(|((token, quote), book)| {
+                let replace = |id| if id == old { new } else { id };
+                ((replace(token), replace(quote)), book)
+            })
+            .collect();
+        for metadata in self.tokens.values_mut() {
+            if metadata.quote_token == Some(old) {
+                metadata.quote_token = Some(new);
+            }
+        }
+        if let Some(pool) = self.pools.remove(&old) {
+            self.pools.insert(new, pool);
+        }
+        if let Some(metadata) = self.tokens.remove(&old) {
+            self.tokens.insert(new, metadata);
+        }
+        for order in self.order_archive.take(old).into_iter().rev() {
+            self.order_archive.push(new, order);
+        }
+    }
+}
+
+fn checked_sum(iter: Box<dyn Iterator<Item = Tokens> + '_>) -> Tokens {
+    let mut result: Tokens = 0;
+    for value in iter {
+        result = result.checked_add(value).expect("overflow");
+    }
+    result
+}
+
+/// Splits the order into a filled part of the given amount and a rest order created at `time`.
+/// The rest order is dropped if its volume is below `min_volume`. Returns both parts and the
+/// liquidity reserved by the order but needed by neither part, e.g. due to the rounding of fees.
+fn split_order(
+    order: &Order,
+    amount: Tokens,
+    time: Timestamp,
+    min_volume: Tokens,
+    fees: TradingFees,
+) -> Result<(Order, Option<Order>, Tokens), String> {
+    let mut filled_order = order.clone();
+    filled_order.amount = amount;
+    let mut remaining_order = order.clone();
+    remaining_order.amount = order
+        .amount
+        .checked_sub(amount)
+        .ok_or("the fill exceeds the order")?;
+    remaining_order.timestamp = time;
+
+    // we do not create dust orders
+    let remaining_order = (remaining_order.volume() >= min_volume).then_some(remaining_order);
+    let required_liquidity = filled_order.reserved_liquidity(fees)
+        + remaining_order
+            .as_ref()
+            .map(|order| order.reserved_liquidity(fees))
+            .unwrap_or_default();
+    let freed_liquidity = order
+        .reserved_liquidity(fees)
+        .checked_sub(required_liquidity)
+        .ok_or_else(|| {
+            format!(
+                "the parts need {} while the order reserves only {}",
+                required_liquidity,
+                order.reserved_liquidity(fees)
+            )
+        })?;
+    Ok((filled_order, remaining_order, freed_liquidity))
+}
+
+/// Returns the order matching the key regardless of its id. Of identical orders, the one with
+/// the smallest id is returned.
+fn find_order<'a>(orders: &'a BTreeSet<Order>, key: &Order) -> Option<&'a Order> {
+    orders
+        .range(key..)
+        .next()
+        .filter(|order| order.matches(key))
+}
+
+/// Returns the best bid and the best ask of the book if the bid is at or above the ask.
+fn crossing_orders(book: &Book) -> Option<(Order, Order)> {
+    let bid = book.buyers.last()?;
+    let ask = book.sellers.first()?;
+    (bid.price >= ask.price).then(|| (bid.clone(), ask.clone()))
+}
+
+fn book_mut<'a>(
+    orders: &'a mut BTreeMap<TokenId, Book>,
+    alt_orders: &'a mut BTreeMap<(TokenId, TokenId), Book>,
+    pair: Pair,
+) -> Option<&'a mut Book> {
+    match pair.quote {
+        None => orders.get_mut(&pair.base),
+        Some(quote) => alt_orders.get_mut(&(pair.base, quote)),
+    }
+}
+
+/// Updates balances to execute the given order.
+/// The trader's balances are in the pool.
+/// The order owner's balances are partially in the pool and the order itself.
+/// 1) Buy case:
+/// - the trader buys N tokens for M + TAKER_FEE of $payment_token.
+/// - the order contains N tokens.
+/// - the type of the order is sell.
+/// - pool[$payment_token][trader] -= M + TAKER_FEE
+/// - pool[$payment_token][order.owner] += M - MAKER_FEE
+/// - pool[token][trader] += order.amount
+/// - pool[$payment_token][revenue] += MAKER_FEE + TAKER_FEE
+///
+/// 2) Sell case:
+/// - the trader sell N tokens for M - TAKER_FEE of $payment_token.
+/// - the order contains M + MAKER_FEE of $payment_token.
+/// - the type of the order is buy.
+/// - pool[$payment_token][trader] += M - TAKER_FEE
+/// - pool[token][order.owner] += order.amount
+/// - pool[token][trader] -= order.amount
+/// - pool[$payment_token][revenue] += MAKER_FEE + TAKER_FEE
+///
+/// For pairs with an alternative quote token, $payment_token is the quote token.
+///
+/// Returns the collected fees.
+#[allow(clippy::too_many_arguments)]
+fn adjust_pools(
+    pools: &mut BTreeMap<TokenId, BTreeMap<Principal, Tokens>>,
+    trader: Principal,
+    token: TokenId,
+    order: &Order,
+    revenue_split: &[(Principal, u16)],
+    trade_type: OrderType,
+    payment_token_id: Principal,
+    fees: TradingFees,
+) -> Result<Tokens, String> {
+    // since the liquidity is locked inside the order,
+    // we need to know where we should avoid adjusting pools
+    assert_ne!(order.order_type, trade_type);
+    if revenue_split.is_empty() {
+        return Err("no revenue account set".into());
+    }
+
+    let (payment_receiver, token_receiver) = if trade_type.buy() {
+        (order.owner, trader)
+    } else {
+        (trader, order.owner)
+    };
+
+    let token_pool = pools.get_mut(&token).ok_or("no token pool found")?;
+
+    // We only need to subtract token liquidity if we're executing a selling trade, because
+    // the liquidity for the buy order has already been reserved at order creation.
+    if trade_type.sell() {
+        let sellers_tokens = token_pool.entry(payment_receiver).or_insert(0);
+        *sellers_tokens = sellers_tokens
+            .checked_sub(order.amount)
+            .ok_or("not enough tokens")?;
+    }
+
+    let buyers_tokens = token_pool.entry(token_receiver).or_insert(0);
+    *buyers_tokens += order.amount;
+
+    let payment_token_pool = pools
+        .get_mut(&payment_token_id)
+        .ok_or("no payment pool found")?;
+
+    let volume = order.volume();
+    let maker_fee = fees.maker_fee(volume);
+    let taker_fee = fees.taker_fee(volume);
+    let (buyer_fee, seller_fee) = if trade_type.buy() {
+        (taker_fee, maker_fee)
+    } else {
+        (maker_fee, taker_fee)
+    };
+
+    // We only need to subtract payment liquidity if we're executing a buying trade, because
+    // the liquidity for the sell order has already been reserved at order creation.
+    if trade_type.buy() {
+        let buyers_payment_tokens = payment_token_pool
+            .get_mut(&token_receiver)
+            .ok_or("no payment tokens")?;
+        *buyers_payment_tokens = buyers_payment_tokens
+            .checked_sub(volume + buyer_fee)
+            .ok_or("not enough payment tokens")?;
+    }
+
+    let sellers_payment_tokens = payment_token_pool.entry(payment_receiver).or_default();
+    *sellers_payment_tokens += volume
+        .checked_sub(seller_fee)
+        .ok_or("amount smaller than fee")?;
+    for (beneficiary, share) in split_revenue(revenue_split, maker_fee + taker_fee) {
+        *payment_token_pool.entry(beneficiary).or_default() += share;
+    }
+    Ok(maker_fee + taker_fee)
+}
+
+// Splits the amount between the beneficiaries according to their shares in basis points. The
+// rounding remainder goes to the first beneficiary, so that the shares always sum up to the amount.
+fn split_revenue(revenue_split: &[(Principal, u16)], amount: Tokens) -> Vec<(Principal, Tokens)> {
+    let mut shares = revenue_split
+        .iter()
+        .map(|(beneficiary, share)| (*beneficiary, amount * *share as u128 / FEE_BASE))
+        .collect::<Vec<_>>();
+    let distributed = shares.iter().map(|(_, share)| share).sum::<Tokens>();
+    if let Some((_, share)) = shares.first_mut() {
+        *share += amount - distributed;
+    }
+    shares
+}
+
+// Checks that the keepalive window is not shorter than the interval at which it gets checked.
+fn check_keepalive(keepalive_secs: Option<u64>) -> Result<(), String> {
+    match keepalive_secs {
+        Some(secs) if secs < KEEPALIVE_CHECK_INTERVAL_SECS => Err(format!(
+            "the keepalive window must be at least {} seconds",
+            KEEPALIVE_CHECK_INTERVAL_SECS
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Checks that the custom expiration time of an order created at `now` lies in the future, but not
+// later than the default expiration of the token.
+fn check_expiration(
+    expires_at: Option<Timestamp>,
+    now: Timestamp,
+    expiration_days: u64,
+) -> Result<(), String> {
+    match expires_at {
+        Some(expires_at) if expires_at <= now => Err("the expiration time is in the past".into()),
+        Some(expires_at) if expires_at > now + expiration_days * DAY => {
+            Err("the expiration time exceeds the maximal order lifetime".into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// The trading fee is deducted from both buyer and seller and only from the payment token.
+// That is, if a trade is executed, the buyer pays the volumen amount + fee, while the seller
+// receives the volume amount - fee. The owner of the filled order pays the maker fee, the trader
+// filling it pays the taker fee.
+//
+// The `volume` represents the amount of order multiplied with the order price, i.e. it is given in
+// particles of the payment token, and `fee` is given in basis points. Hence the fee in particles is
+// `volume * fee / 10_000`, rounded down, but at least one particle unless the fee is 0. The fee
+// does not depend on the ledger fee of any token. E.g. a trade with a volume of 1_000_000 particles costs the maker
+// 1_000_000 * 10 / 10_000 = 1_000 and the taker 1_000_000 * 20 / 10_000 = 2_000 particles.
+fn trading_fee(volume: Tokens, fee: u128) -> Tokens {
+    if fee == 0 {
+        return 0;
+    }
+    (volume * fee / FEE_BASE).max(1)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+
+    use crate::{mutate, read, unsafe_mutate};
+
+    use super::*;
+
+    fn maker_fee(volume: Tokens) -> Tokens {
+        TradingFees::default().maker_fee(volume)
+    }
+
+    fn taker_fee(volume: Tokens) -> Tokens {
+        TradingFees::default().taker_fee(volume)
+    }
+
+    pub fn pr(n: u8) -> Principal {
+        let v = vec![0, n];
+        Principal::from_slice(&v)
+    }
+    fn user_orders(
+        state: &State,
+        token: TokenId,
+        user: Principal,
+        order_type: OrderType,
+    ) -> Box<dyn Iterator<Item = &'_ Order> + '_> {
+        Box::new(
+            state
+                .orders(token, order_type)
+                .filter(move |order| order.owner == user),
+        )
+    }
+
+    fn close_order(
+        state: &mut State,
+        user: Principal,
+        token: impl Into<Pair>,
+        amount: Tokens,
+        price: ParticlesPerToken,
+        timestamp: Timestamp,
+        order_type: OrderType,
+    ) -> Result<(), String> {
+        let funds = state.funds_under_management();
+        state.close_order(user, token, amount, price, timestamp, order_type)?;
+        assert_eq!(funds, state.funds_under_management());
+        Ok(())
+    }
+
+    fn create_order(
+        state: &mut State,
+        user: Principal,
+        token: impl Into<Pair>,
+        amount: Tokens,
+        price: ParticlesPerToken,
+        timestamp: Timestamp,
+        order_type: OrderType,
+    ) -> Result<(), String> {
+        let funds = state.funds_under_management();
+        state.create_order(
+            user, token, amount, price, timestamp, order_type, None, false, None,
+        )?;
+        assert_eq!(funds, state.funds_under_management());
+        Ok(())
+    }
+
+    fn trade(
+        state: &mut State,
+        trade_type: OrderType,
+        trader: Principal,
+        token: impl Into<Pair>,
+        amount: u128,
+        limit: Option<ParticlesPerToken>,
+        time: Timestamp,
+    ) -> Result<u128, String> {
+        let funds = state.funds_under_management();
+        let result = state.execute_trade(
+            trade_type,
+            trader,
+            token.into(),
+            amount,
+            limit,
+            time,
+            Default::default(),
+        )?;
+        assert_eq!(funds, state.funds_under_management());
+        Ok(result)
+    }
+
+    pub(crate) fn list_test_token(state: &mut State, token: TokenId, decimals: u32) {
+        state
+            .add_token(
+                token,
+                "TAGGR".into(),
+                25, // fee
+                decimals,
+                None,
+                0,
+            )
+            .unwrap();
+    }
+
+    pub(crate) fn list_payment_token(state: &mut State) {
+        state.revenue_account = Some(pr(255));
+        state
+            .pools
+            .insert(state.payment_token_id(), Default::default());
+        state.tokens.insert(
+            state.payment_token_id(),
+            Metadata {
+                symbol: "USD".into(),
+                fee: 10000,
+                decimals: 8,
+                logo: None,
+                timestamp: 0,
+                quote_token: None,
+                min_notional: 0,
+                tick_size: 0,
+                min_volume: 0,
+                order_expiration_days: 0,
+            },
+        );
+    }
+
+    #[test]
+    fn test_orderbook() {
+        let mut o1 = Order {
+            order_type: OrderType::Buy,
+            owner: pr(16),
+            amount: 12,
+            price: 0,
+            decimals: 6,
+            timestamp: 111,
+            executed: 0,
+            payment_token_fee: 10000,
+            expires_at: None,
+            keepalive_secs: None,
+            id: 0,
+        };
+        let mut o2 = Order {
+            order_type: OrderType::Buy,
+            owner: pr(16),
+            amount: 32,
+            price: 0,
+            decimals: 6,
+            timestamp: 111,
+            executed: 0,
+            payment_token_fee: 10000,
+            expires_at: None,
+            keepalive_secs: None,
+            id: 0,
+        };
+
+        assert_eq!(o1.cmp(&o1), Ordering::Equal);
+        assert_eq!(o1.cmp(&o2), Ordering::Less);
+        o1.amount = o2.amount;
+        assert_eq!(o1.cmp(&o2), Ordering::Equal);
+        o2.owner = pr(1);
+        o2.amount = 3000;
+        o2.price = 2;
+        o1.price = 3;
+        assert_eq!(o2.cmp(&o1), Ordering::Less);
+        let mut o3 = o1.clone();
+        assert_eq!(o3.cmp(&o1), Ordering::Equal);
+        o3.timestamp += 1;
+        assert_eq!(o3.cmp(&o1), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_simplest_trade() {
+        let state = &mut State::default();
+        let token = pr(100);
+        list_payment_token(state);
+        list_test_token(state, token, 2);
+
+        state.add_liquidity(pr(1), state.payment_token_id(), 210);
+        assert_eq!(taker_fee(20000), 40);
+        assert_eq!(maker_fee(20000), 20);
+        assert_eq!(
+            create_order(state, pr(1), token, 1, 0, 0, OrderType::Buy),
+            Err("limit price is 0".into())
+        );
+        assert_eq!(
+            create_order(state, pr(1), token, 1, 22000, 0, OrderType::Buy),
+            Err("not enough funds available for this order size".into())
+        );
+
+        assert_eq!(
+            create_order(state, pr(1), token, 1, 21000, 0, OrderType::Buy),
+            Err("not enough funds available for this order size".into())
+        );
+
+        assert_eq!(
+            create_order(state, pr(1), token, 1, 20000, 0, OrderType::Buy),
+            Ok(())
+        );
+
+        state.add_liquidity(pr(0), token, 1);
+        assert_eq!(
+            trade(state, OrderType::Sell, pr(0), token, 1, None, 123456),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn test_order_closing() {
+        let state = &mut State::default();
+        list_payment_token(state);
+
+        let token = pr(100);
+
+        list_test_token(state, token, 2);
+
+        state.add_liquidity(pr(0), token, 111);
+        state.add_liquidity(pr(0), token, 222);
+
+        assert_eq!(state.token_balances(pr(0)).get(&token).unwrap().0, 333);
+
+        assert_eq!(
+            create_order(state, pr(0), token, 250, 10, 0, OrderType::Sell),
+            Ok(())
+        );
+        assert_eq!(
+            create_order(state, pr(0), token, 50, 30, 0, OrderType::Sell),
+            Ok(())
+        );
+
+        assert_eq!(
+            state.token_balances(pr(0)).get(&token).unwrap().0,
+            333 - 250 - 50
+        );
+        assert_eq!(
+            close_order(state, pr(0), token, 50, 30, 0, OrderType::Sell),
+            Ok(())
+        );
+        assert_eq!(
+            close_order(state, pr(0), token, 250, 10, 0, OrderType::Sell),
+            Ok(())
+        );
+        assert_eq!(state.token_balances(pr(0)).get(&token).unwrap().0, 333);
+
+        state.add_liquidity(pr(0), state.payment_token_id(), 8 * 100000);
+        assert!(create_order(state, pr(0), token, 3, 10000000, 0, OrderType::Buy).is_ok());
+
+        let volume = 3 * 100000;
+
+        assert_eq!(
+            state
+                .token_balances(pr(0))
+                .get(&state.payment_token_id())
+                .copied()
+                .unwrap()
+                .0,
+            8 * 100000 - volume - maker_fee(volume)
+        );
+
+        // an identical order gets its own id
+        assert!(create_order(state, pr(0), token, 3, 10000000, 0, OrderType::Buy).is_ok());
+        assert_eq!(state.orders(token, OrderType::Buy).count(), 2);
+        assert_eq!(
+            close_order(state, pr(0), token, 3, 10000000, 0, OrderType::Buy),
+            Ok(())
+        );
+        assert_eq!(state.orders(token, OrderType::Buy).count(), 1);
+
+        assert!(create_order(state, pr(0), token, 4, 10000000, 0, OrderType::Buy).is_ok());
+
+        let volume2 = 4 * 100000;
+        assert_eq!(
+            state
+                .token_balances(pr(0))
+                .get(&state.payment_token_id())
+                .copied()
+                .unwrap()
+                .0,
+            8 * 100000 - volume - maker_fee(volume) - volume2 - maker_fee(volume2)
+        );
+        assert_eq!(
+            close_order(state, pr(0), token, 3, 10000000, 0, OrderType::Buy),
+            Ok(())
+        );
+        assert_eq!(
+            close_order(state, pr(0), token, 4, 10000000, 0, OrderType::Buy),
+            Ok(())
+        );
+        assert_eq!(
+            state
+                .token_balances(pr(0))
+                .get(&state.payment_token_id())
+                .copied()
+                .unwrap()
+                .0,
+            8 * 100000
+        );
+    }
+
+    #[test]
+    fn test_liquidity_adding_and_withdrawals() {
+        let state = &mut State::default();
+        list_payment_token(state);
+
+        let token = pr(100);
+
+        list_test_token(state, token, 2);
+
+        state.add_liquidity(pr(0), token, 111);
+        state.add_liquidity(pr(0), token, 222);
+
+        assert_eq!(state.token_balances(pr(0)).get(&token).unwrap().0, 333);
+
+        assert_eq!(
+            create_order(state, pr(0), token, 250, 10, 0, OrderType::Sell),
+            Ok(())
+        );
+
+        assert_eq!(
+            state.token_balances(pr(0)).get(&token).unwrap().0,
+            333 - 250
+        );
+
+        assert_eq!(user_orders(state, token, pr(0), OrderType::Buy).count(), 0);
+        let sell_orders = user_orders(state, token, pr(0), OrderType::Sell).collect::<Vec<_>>();
+        assert_eq!(sell_orders.len(), 1);
+        assert_eq!(sell_orders.first().unwrap().amount, 250);
+
+        assert_eq!(
+            state.withdraw_liquidity(pr(1), token),
+            Err("nothing to withdraw".into())
+        );
+        assert_eq!(state.withdraw_liquidity(pr(0), token), Ok(333 - 250));
+
+        let sell_orders = user_orders(state, token, pr(0), OrderType::Sell).collect::<Vec<_>>();
+        assert_eq!(sell_orders.len(), 1);
+
+        let one_icp = 100000000;
+        state.add_liquidity(pr(0), state.payment_token_id(), one_icp);
+        assert_eq!(
+            state.withdraw_liquidity(pr(1), state.payment_token_id()),
+            Err("nothing to withdraw".into())
+        );
+        assert_eq!(
+            state.withdraw_liquidity(pr(0), state.payment_token_id()),
+            Ok(100000000)
+        );
+    }
+
+    #[test]
+    fn test_selling() {
+        let state = &mut State::default();
+        list_payment_token(state);
+
+        state.revenue_account = Some(pr(255));
+
+        let token = pr(100);
+
+        assert_eq!(
+            create_order(state, pr(0), token, 7, 50000000, 0, OrderType::Buy),
+            Err("token not listed".into())
+        );
+
+        list_test_token(state, token, 2);
+
+        // buy order for 7 $TAGGR / 0.1 ICP each
+        assert_eq!(
+            create_order(state, pr(0), token, 7, 10000000, 0, OrderType::Buy),
+            Err("no funds available".into())
+        );
+
+        state.add_liquidity(pr(0), state.payment_token_id(), 8 * 100000);
+        assert!(create_order(state, pr(0), token, 7, 10000000, 0, OrderType::Buy).is_ok());
+
+        // buy order for 16 $TAGGR / 0.03 ICP each
+        state.add_liquidity(pr(1), state.payment_token_id(), 17 * 300000);
+        assert!(create_order(state, pr(1), token, 16, 3000000, 0, OrderType::Buy).is_ok());
+
+        // buy order for 25 $TAGGR / 0.01 ICP each
+        state.add_liquidity(pr(2), state.payment_token_id(), 24 * 10000);
+        assert_eq!(
+            create_order(state, pr(2), token, 25, 1000000, 0, OrderType::Buy),
+            Err("not enough funds available for this order size".into())
+        );
+        state.add_liquidity(pr(2), state.payment_token_id(), 2 * 10000);
+        assert!(create_order(state, pr(2), token, 25, 1000000, 0, OrderType::Buy).is_ok());
+
+        // buyer has 0.01 ICP left minus the maker fee
+        assert_eq!(state.payment_token_pool().get(&pr(2)).unwrap(), &9750);
+
+        let buyer_orders = &state.orders.get(&token).unwrap().buyers;
+        assert_eq!(
+            buyer_orders
+                .iter()
+                .map(|order| order.price)
+                .collect::<Vec<_>>(),
+            vec![1000000, 3000000, 10000000]
+        );
+        let best_order = buyer_orders.last().unwrap();
+        assert_eq!(best_order.amount, 7);
+        assert_eq!(best_order.price, 10000000);
+
+        // three buyers
+        assert_eq!(state.payment_token_pool().len(), 3);
+        let buyer_orders = &state.orders.get(&token).unwrap().buyers;
+        // we have 3 orders
+        assert_eq!(buyer_orders.len(), 3);
+
+        let seller = pr(5);
+
+        assert_eq!(
+            trade(
+                &mut state.clone(),
+                OrderType::Sell,
+                seller,
+                token,
+                5,
+                None,
+                123456
+            ),
+            Err("not enough tokens".into())
+        );
+        state.add_liquidity(seller, token, 250);
+        assert_eq!(
+            trade(state, OrderType::Sell, seller, token, 5, None, 123456),
+            Ok(5)
+        );
+
+        // verify the partial filling
+        let buyer_orders = &state.orders.get(&token).unwrap().buyers;
+        // we still have 3 orders
+        assert_eq!(buyer_orders.len(), 3);
+        let best_order = buyer_orders.last().unwrap();
+        // less tokens to buy at the given price as before
+        assert_eq!(best_order.amount, 2);
+        assert_eq!(best_order.price, 10000000);
+
+        assert_eq!(state.order_archive.len(token), 1);
+        let executed_order = state.order_archive.front(token).unwrap();
+        assert_eq!(executed_order.executed, 123456);
+        // only 5 tokens got traded
+        assert_eq!(executed_order.amount, 5);
+
+        // buyer got 5 tokens
+        assert_eq!(state.pools.get(&token).unwrap().get(&pr(0)).unwrap(), &5);
+
+        // now seller should get a balance too, plus the fee acount
+        assert_eq!(state.payment_token_pool().len(), 5);
+        // seller has expected amount of ICP: 5 * 0.1 ICP - fee
+        let volume = 500000;
+        assert_eq!(
+            state.payment_token_pool().get(&seller).unwrap(),
+            &(volume - taker_fee(volume))
+        );
+        // buyer should have previous amount - volume - fee;
+        assert_eq!(state.payment_token_pool().get(&pr(0)).unwrap(), &99300);
+        // fee account has the maker and the taker fee
+        assert_eq!(
+            state.payment_token_pool().get(&pr(255)).unwrap(),
+            &(maker_fee(volume) + taker_fee(volume))
+        );
+
+        // let's sell more
+        // at that point we have buy orders: 25 @ 0.01, 16 @ 0.03, 2 @ 0.1
+        let buyer_orders = &state.orders.get(&token).unwrap().buyers;
+        assert_eq!(buyer_orders.len(), 3);
+        let best_order = buyer_orders.last().unwrap();
+        assert_eq!(best_order.amount, 2);
+        assert_eq!(best_order.price, 10000000);
+
+        assert_eq!(
+            trade(state, OrderType::Sell, seller, token, 10, None, 123457),
+            Ok(10)
+        );
+
+        // we should have only two now
+        let buyer_orders = &state.orders.get(&token).unwrap().buyers;
+        assert_eq!(buyer_orders.len(), 2);
+        let best_order = buyer_orders.last().unwrap();
+        assert_eq!(best_order.amount, 8);
+        assert_eq!(best_order.price, 3000000);
+
+        // seller still has 250 - 15 tokens
+        assert_eq!(
+            state.pools.get(&token).unwrap().get(&seller).unwrap(),
+            &(250 - 15)
+        );
+
+        // at that point we have buy orders: 25 @ 0.01, 8 @ 0.03
+        assert_eq!(
+            trade(state, OrderType::Sell, seller, token, 150, None, 123457),
+            Ok(33)
+        );
+
+        // seller still has 250 - 30 - 18 tokens
+        assert_eq!(
+            state.pools.get(&token).unwrap().get(&seller).unwrap(),
+            &(250 - 30 - 18)
+        );
+        // all buyer got their tokens
+        assert_eq!(state.pools.get(&token).unwrap().get(&pr(0)).unwrap(), &7);
+        assert_eq!(state.pools.get(&token).unwrap().get(&pr(1)).unwrap(), &16);
+        assert_eq!(state.pools.get(&token).unwrap().get(&pr(2)).unwrap(), &25);
+
+        // executed orders: 25 @ 0.1, 16 @ 0.03, 7 @ 0.05
+        let (v1, v2, v3) = (25 * 10000, 16 * 30000, 7 * 100000);
+        let maker_fees = maker_fee(v1) + maker_fee(v2) + maker_fee(v3);
+        let taker_fees = taker_fee(v1) + taker_fee(v2) + taker_fee(v3);
+        assert_eq!(
+            state.payment_token_pool().get(&seller).unwrap(),
+            &(v1 + v2 + v3 - taker_fees)
+        );
+        assert_eq!(
+            state.payment_token_pool().get(&pr(255)).unwrap(),
+            &(maker_fees + taker_fees)
+        );
+    }
+
+    #[test]
+    fn test_buying() {
+        let state = &mut State::default();
+        list_payment_token(state);
+
+        state.revenue_account = Some(pr(255));
+
+        let token = pr(100);
+
+        assert_eq!(
+            create_order(
+                &mut state.clone(),
+                pr(0),
+                token,
+                7,
+                5000000,
+                0,
+                OrderType::Sell
+            ),
+            Err("token not listed".into())
+        );
+
+        list_test_token(state, token, 2);
+
+        // sell order for 7 $TAGGR / 0.05 ICP each
+        assert_eq!(
+            create_order(
+                &mut state.clone(),
+                pr(0),
+                token,
+                7,
+                5000000,
+                0,
+                OrderType::Sell
+            ),
+            Err("no funds available".into())
+        );
+
+        state.add_liquidity(pr(0), token, 7);
+        assert!(create_order(state, pr(0), token, 7, 5000000, 0, OrderType::Sell).is_ok());
+
+        // sell order for 16 $TAGGR / 0.03 ICP each
+        state.add_liquidity(pr(1), token, 16);
+        assert!(create_order(state, pr(1), token, 16, 3000000, 0, OrderType::Sell).is_ok());
+
+        // sell order for 25 $TAGGR / 1 ICP each
+        state.add_liquidity(pr(2), token, 24);
+        assert_eq!(
+            create_order(
+                &mut state.clone(),
+                pr(2),
+                token,
+                25,
+                100000000,
+                0,
+                OrderType::Sell
+            ),
+            Err("not enough funds available for this order size".into())
+        );
+        state.add_liquidity(pr(2), token, 1);
+        assert!(create_order(state, pr(2), token, 25, 100000000, 0, OrderType::Sell).is_ok());
+
+        // Order book: 16 @ 0.03, 7 @ 0.05, 25 @ 1
+        let sell_orders = &state.orders.get(&token).unwrap().sellers;
+        assert_eq!(sell_orders.len(), 3);
+        let best_order = sell_orders.first().unwrap();
+        assert_eq!(best_order.amount, 16);
+        assert_eq!(best_order.price, 3000000);
+
+        // three sellers
+        assert_eq!(state.pools.get(&token).unwrap().len(), 3);
+
+        let buyer = pr(5);
+
+        // since we had no ICP we didn't buy anything
+        assert_eq!(state.pools.get(&token).unwrap().get(&buyer), None);
+        state.add_liquidity(buyer, state.payment_token_id(), 12 * 30000);
+        assert_eq!(state.payment_token_pool().len(), 1);
+        assert_eq!(
+            trade(state, OrderType::Buy, buyer, token, 10, None, 123456),
+            Ok(10)
+        );
+
+        // verify the partial filling
+        let sell_orders = &state.orders.get(&token).unwrap().sellers;
+        // we still have 3 orders
+        assert_eq!(sell_orders.len(), 3);
+        let best_order = sell_orders.first().unwrap();
+        // less tokens to buy at the given price as before
+        assert_eq!(best_order.amount, 6);
+        assert_eq!(best_order.price, 3000000);
+
+        assert_eq!(state.order_archive.len(token), 1);
+        let executed_order = state.order_archive.front(token).unwrap();
+        assert_eq!(executed_order.executed, 123456);
+        // only 5 tokens got traded
+        assert_eq!(executed_order.amount, 10);
+
+        // buyer got 10 tokens
+        assert_eq!(state.pools.get(&token).unwrap().get(&buyer).unwrap(), &10);
+
+        // now seller should get a balance too, plus the fee acount
+        assert_eq!(state.payment_token_pool().len(), 3);
+
+        // let's buy more
+        // at that point we have buy orders: 6 @ 0.03, 7 @ 0.05, 25 @ 1
+        state.add_liquidity(buyer, state.payment_token_id(), 6 * 30000 + 2 * 5000);
+        assert_eq!(
+            trade(state, OrderType::Buy, buyer, token, 7, None, 123457),
+            Ok(7)
+        );
+        // buyer got 17 tokens
+        assert_eq!(state.pools.get(&token).unwrap().get(&buyer).unwrap(), &17);
+
+        // we should have only two now
+        let sell_orders = &state.orders.get(&token).unwrap().sellers;
+        assert_eq!(sell_orders.len(), 2);
+        let best_order = sell_orders.first().unwrap();
+        assert_eq!(best_order.amount, 6);
+        assert_eq!(best_order.price, 5000000);
+
+        state.add_liquidity(buyer, state.payment_token_id(), 6 * 50000 + 28 * 1000000);
+
+        assert_eq!(
+            trade(state, OrderType::Buy, buyer, token, 100, None, 123458),
+            Ok(31)
+        );
+
+        // all sellers got ICP
+        let (v2, v1, v3) = (16 * 30000, 7 * 50000, 25 * 1000000);
+        assert_eq!(
+            state.payment_token_pool().get(&pr(0)).unwrap(),
+            &(v1 - maker_fee(v1))
+        );
+        assert_eq!(
+            state.payment_token_pool().get(&pr(1)).unwrap(),
+            &(v2 - maker_fee(v2))
+        );
+        assert_eq!(
+            state.payment_token_pool().get(&pr(2)).unwrap(),
+            &(v3 - maker_fee(v3))
+        );
+
+        // executed orders: 16 @ 0.03, 7 @ 0.05, 25 @ 1
+        let maker_fees = maker_fee(v1) + maker_fee(v2) + maker_fee(v3);
+        let taker_fees = taker_fee(v1) + taker_fee(v2) + taker_fee(v3);
+        assert_eq!(
+            state.payment_token_pool().get(&pr(255)).unwrap(),
+            &(maker_fees + taker_fees)
+        );
+    }
+
+    #[test]
+    fn test_limit_selling() {
+        let state = &mut State::default();
+        list_payment_token(state);
+
+        state.revenue_account = Some(pr(255));
+
+        let token = pr(100);
+
+        list_test_token(state, token, 2);
+
+        // buy order for 7 $TAGGR / 0.1 ICP each
+        state.add_liquidity(pr(0), state.payment_token_id(), 8 * 10000000);
+        assert!(create_order(state, pr(0), token, 7, 10000000, 0, OrderType::Buy).is_ok());
+
+        // buy order for 16 $TAGGR / 0.03 ICP each
+        state.add_liquidity(pr(1), state.payment_token_id(), 17 * 30000000);
+        assert!(create_order(state, pr(1), token, 16, 3000000, 0, OrderType::Buy).is_ok());
+
+        // buy order for 25 $TAGGR / 0.01 ICP each
+        state.add_liquidity(pr(2), state.payment_token_id(), 26 * 1000000);
+        assert!(create_order(state, pr(2), token, 25, 1000000, 0, OrderType::Buy).is_ok());
+
+        // Orer book: 7 @ 0.1, 16 @ 0.03, 25 @ 0.01
+
+        let seller = pr(5);
+
+        state.add_liquidity(seller, token, 250);
+        assert_eq!(
+            trade(
+                state,
+                OrderType::Sell,
+                seller,
+                token,
+                50,
+                Some(2000000),
+                123456
+            ),
+            Ok(23)
+        );
+
+        // 2 orders were filled
+        let buyer_orders = &state.orders.get(&token).unwrap().buyers;
+        assert_eq!(buyer_orders.len(), 1);
+        let best_order = buyer_orders.last().unwrap();
+        // order below the limit wasn't touched
+        assert_eq!(best_order.amount, 25);
+
+        // only two buyer got their tokens
+        assert_eq!(state.pools.get(&token).unwrap().get(&pr(0)).unwrap(), &7);
+        assert_eq!(state.pools.get(&token).unwrap().get(&pr(1)).unwrap(), &16);
+        assert_eq!(state.pools.get(&token).unwrap().get(&pr(2)), None);
+    }
+
+    #[test]
+    fn test_limit_buying() {
+        let state = &mut State::default();
+        list_payment_token(state);
+
+        state.revenue_account = Some(pr(255));
+
+        let token = pr(100);
+
+        list_test_token(state, token, 2);
+
+        // sell order for 7 $TAGGR / 0.05 ICP each
+        state.add_liquidity(pr(0), token, 7);
+        assert!(create_order(state, pr(0), token, 7, 5000000, 0, OrderType::Sell).is_ok());
+
+        // sell order for 16 $TAGGR / 0.03 ICP each
+        state.add_liquidity(pr(1), token, 16);
+        assert!(create_order(state, pr(1), token, 16, 3000000, 0, OrderType::Sell).is_ok());
+
+        // sell order for 25 $TAGGR / 1 ICP each
+        state.add_liquidity(pr(2), token, 25);
+        assert!(create_order(state, pr(2), token, 25, 100000000, 0, OrderType::Sell).is_ok());
+
+        // Order book: 16 @ 0.03, 7 @ 0.05, 25 @ 1
+
+        let buyer = pr(5);
+
+        state.add_liquidity(buyer, state.payment_token_id(), 12 * 1000000);
+        assert_eq!(
+            trade(
+                state,
+                OrderType::Buy,
+                buyer,
+                token,
+                50,
+                Some(6000000),
+                123456
+            ),
+            Ok(23)
+        );
+
+        // verify the partial filling
+        let sell_orders = &state.orders.get(&token).unwrap().sellers;
+        // we still have 1 order
+        assert_eq!(sell_orders.len(), 1);
+        let best_order = sell_orders.first().unwrap();
+        // less tokens to buy at the given price as before
+        assert_eq!(best_order.amount, 25);
+
+        // buyer got 23 tokens
+        assert_eq!(state.pools.get(&token).unwrap().get(&buyer).unwrap(), &23);
+
+        // two sellers got ICP
+        let (v2, v1) = (16 * 30000, 7 * 50000);
+        assert_eq!(
+            state.payment_token_pool().get(&pr(0)).unwrap(),
+            &(v1 - maker_fee(v1))
+        );
+        assert_eq!(
+            state.payment_token_pool().get(&pr(1)).unwrap(),
+            &(v2 - maker_fee(v2))
+        );
+        assert_eq!(state.payment_token_pool().get(&pr(2)), None);
+    }
+
+    #[test]
+    fn test_liquitidy_lock() {
+        let state = &mut State::default();
+        list_payment_token(state);
+
+        state.revenue_account = Some(pr(255));
+
+        let token = pr(100);
+
+        list_test_token(state, token, 2);
+
+        // sell order for 7 $TAGGR / 0.05 ICP each
+        state.add_liquidity(pr(0), token, 7);
+        assert!(create_order(state, pr(0), token, 7, 5000000, 0, OrderType::Sell).is_ok());
+        assert_eq!(
+            create_order(state, pr(0), token, 7, 6000000, 0, OrderType::Sell),
+            Err("not enough funds available for this order size".into())
+        );
+    }
+
+    #[test]
+    fn test_candles() {
+        let state = &mut State::default();
+        let token = pr(100);
+        assert!(state.candles(token, 60, 10).is_empty());
+
+        let executed_order = |price, executed| Order {
+            order_type: OrderType::Buy,
+            owner: pr(0),
+            amount: 100,
+            price,
+            decimals: 2,
+            timestamp: 0,
+            executed,
+            payment_token_fee: 10000,
+            expires_at: None,
+            keepalive_secs: None,
+            id: 0,
+        };
+        let archive = &mut state.order_archive;
+        // first minute: 5, 7, 3, 4
+        archive.push(token, executed_order(5, 10 * SECOND));
+        archive.push(token, executed_order(7, 20 * SECOND));
+        archive.push(token, executed_order(3, 30 * SECOND));
+        archive.push(token, executed_order(4, 40 * SECOND));
+        // third minute: 6
+        archive.push(token, executed_order(6, 130 * SECOND));
+        // fourth minute: 8, 9
+        archive.push(token, executed_order(8, 190 * SECOND));
+        archive.push(token, executed_order(9, 200 * SECOND));
+
+        assert!(state.candles(token, 0, 10).is_empty());
+        assert!(state.candles(token, 60, 0).is_empty());
+
+        assert_eq!(
+            state.candles(token, 60, 10),
+            vec![
+                Candle {
+                    timestamp: 0,
+                    open: 5,
+                    high: 7,
+                    low: 3,
+                    close: 4,
+                    volume: 5 + 7 + 3 + 4,
+                },
+                Candle {
+                    timestamp: 120 * SECOND,
+                    open: 6,
+                    high: 6,
+                    low: 6,
+                    close: 6,
+                    volume: 6,
+                },
+                Candle {
+                    timestamp: 180 * SECOND,
+                    open: 8,
+                    high: 9,
+                    low: 8,
+                    close: 9,
+                    volume: 8 + 9,
+                },
+            ]
+        );
+
+        // only the last minute is covered
+        let candles = state.candles(token, 60, 1);
+        assert_eq!(candles.len(), 1);
+        assert_eq!(candles[0].timestamp, 180 * SECOND);
+
+        // the last three minutes contain only two candles
+        let candles = state.candles(token, 60, 3);
+        assert_eq!(candles.len(), 2);
+        assert_eq!(candles[0].timestamp, 120 * SECOND);
+    }
+
+    #[test]
+    fn test_events() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+
+        state.add_liquidity(pr(0), token, 10);
+        assert!(create_order(state, pr(0), token, 10, 5000000, 0, OrderType::Sell).is_ok());
+        state.add_liquidity(pr(1), state.payment_token_id(), 10 * 50000);
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 4, None, 123456),
+            Ok(4)
+        );
+
+        let events = state
+            .events(0, 100)
+            .into_iter()
+            .map(|(_, event)| event)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                Event::TradeExecuted {
+                    maker: pr(0),
+                    taker: pr(1),
+                    token,
+                    quote: None,
+                    order_type: OrderType::Buy,
+                    amount: 4,
+                    price: 5000000,
+                    timestamp: 123456,
+                },
+                Event::OrderCreated {
+                    owner: pr(0),
+                    token,
+                    quote: None,
+                    order_type: OrderType::Sell,
+                    amount: 10,
+                    price: 5000000,
+                    timestamp: 0,
+                },
+                Event::TokenListed { token },
+            ]
+        );
+        assert_eq!(state.events(1, 1).len(), 1);
+        assert_eq!(state.events(3, 10).len(), 0);
+    }
+
+    #[test]
+    fn test_relisting_with_changed_fee() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        let other_token = pr(101);
+        list_test_token(state, token, 2);
+        list_test_token(state, other_token, 2);
+
+        state.add_liquidity(pr(0), token, 20);
+        state.add_liquidity(pr(0), other_token, 20);
+        state.add_liquidity(pr(1), state.payment_token_id(), 10 * 50000);
+        assert!(create_order(state, pr(0), token, 10, 5000000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), other_token, 10, 5000000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(1), token, 5, 4000000, 0, OrderType::Buy).is_ok());
+
+        // relisting with the same metadata keeps the orders
+        let funds = state.funds_under_management();
+        list_test_token(state, token, 2);
+        assert_eq!(state.orders(token, OrderType::Sell).count(), 1);
+        assert_eq!(state.orders(token, OrderType::Buy).count(), 1);
+
+        // relisting with a new fee closes all orders of the token
+        state
+            .add_token(token, "TAGGR".into(), 50, 2, None, 0)
+            .unwrap();
+        assert_eq!(funds, state.funds_under_management());
+        assert_eq!(state.token(token).unwrap().fee, 50);
+        assert_eq!(state.orders(token, OrderType::Sell).count(), 0);
+        assert_eq!(state.orders(token, OrderType::Buy).count(), 0);
+        assert_eq!(state.token_pool_balance(token, pr(0)), 20);
+        assert_eq!(
+            state.token_pool_balance(state.payment_token_id(), pr(1)),
+            10 * 50000
+        );
+        // orders of other tokens are not affected
+        assert_eq!(state.orders(other_token, OrderType::Sell).count(), 1);
+    }
+
+    #[test]
+    fn test_pause() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+
+        state.add_liquidity(pr(0), token, 20);
+        assert!(create_order(state, pr(0), token, 10, 5000000, 0, OrderType::Sell).is_ok());
+
+        state.paused = true;
+        assert_eq!(
+            create_order(state, pr(0), token, 10, 6000000, 0, OrderType::Sell),
+            Err("trading is paused".into())
+        );
+        state.add_liquidity(pr(1), state.payment_token_id(), 10 * 50000);
+        assert!(matches!(
+            state.trade(
+                OrderType::Buy,
+                pr(1),
+                token,
+                1,
+                0,
+                123456,
+                Default::default(),
+                None,
+                false, None, None
+            ),
+            Err(err) if err == "trading is paused"
+        ));
+
+        // users can still exit
+        assert_eq!(
+            close_order(state, pr(0), token, 10, 5000000, 0, OrderType::Sell),
+            Ok(())
+        );
+        assert_eq!(state.withdraw_liquidity(pr(0), token), Ok(20));
+        assert_eq!(
+            state.withdraw_liquidity(pr(1), state.payment_token_id()),
+            Ok(10 * 50000)
+        );
+
+        state.paused = false;
+        state.add_liquidity(pr(0), token, 20);
+        assert!(create_order(state, pr(0), token, 10, 6000000, 0, OrderType::Sell).is_ok());
+    }
+
+    #[test]
+    fn test_migration_to_stable_memory() {
+        let state = &mut State::default();
+        let token = pr(100);
+        let executed_order = |price, executed| Order {
+            order_type: OrderType::Buy,
+            owner: pr(0),
+            amount: 100,
+            price,
+            decimals: 2,
+            timestamp: 0,
+            executed,
+            payment_token_fee: 10000,
+            expires_at: None,
+            keepalive_secs: None,
+            id: 0,
+        };
+        state.legacy_order_archive.insert(
+            token,
+            vec![
+                executed_order(3, 30),
+                executed_order(2, 20),
+                executed_order(1, 10),
+            ]
+            .into(),
+        );
+        state.legacy_logs = vec![(1, "second".to_string()), (0, "first".to_string())].into();
+        state.event_id = 2;
+
+        state.migrate_to_stable_memory();
+
+        assert!(state.legacy_order_archive.is_empty());
+        assert!(state.legacy_logs.is_empty());
+        assert_eq!(
+            state
+                .order_archive
+                .iter(token)
+                .map(|order| order.price)
+                .collect::<Vec<_>>(),
+            vec![3, 2, 1]
+        );
+        assert_eq!(state.order_archive.tokens(), vec![token]);
+        assert_eq!(
+            state.logs().collect::<Vec<_>>(),
+            vec![(1, "second".to_string()), (0, "first".to_string())]
+        );
+
+        // new entries are added on top
+        state.order_archive.push(token, executed_order(4, 40));
+        assert_eq!(state.order_archive.front(token).unwrap().price, 4);
+        assert_eq!(
+            state
+                .order_archive
+                .retain(token, |order| order.executed > 15),
+            1
+        );
+        assert_eq!(state.order_archive.len(token), 3);
+        state.log("third".into());
+        state.logs.truncate(2);
+        assert_eq!(
+            state.logs().map(|(_, message)| message).collect::<Vec<_>>(),
+            vec!["third".to_string(), "second".to_string()]
+        );
+
+        // the archive and the logs are not part of the serialized heap
+        let bytes = serde_cbor::to_vec(&state).unwrap();
+        let restored: State = serde_cbor::from_slice(&bytes).unwrap();
+        assert!(restored.legacy_order_archive.is_empty());
+        assert!(restored.legacy_logs.is_empty());
+    }
+
+    #[test]
+    fn test_close_all_orders() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+
+        let orders = CLOSE_ALL_ORDERS_CHUNK + 500;
+        state.set_max_orders_per_book(orders).unwrap();
+        state.add_liquidity(pr(0), token, orders as u128);
+        for i in 0..orders {
+            // every order is created an hour after the previous one to avoid the throttling
+            state
+                .create_order(
+                    pr(0),
+                    token,
+                    1,
+                    1000,
+                    i as u64 * HOUR,
+                    OrderType::Sell,
+                    None,
+                    false,
+                    None,
+                )
+                .unwrap();
+        }
+        assert_eq!(state.open_orders(), orders);
+        assert_eq!(state.token_pool_balance(token, pr(0)), 0);
+
+        let funds = state.funds_under_management();
+        assert_eq!(state.close_all_orders(), 500);
+        assert_eq!(state.close_all_orders(), 0);
+        assert_eq!(state.close_all_orders(), 0);
+        assert_eq!(funds, state.funds_under_management());
+        assert_eq!(state.orders(token, OrderType::Sell).count(), 0);
+        assert_eq!(state.token_pool_balance(token, pr(0)), orders as u128);
+    }
+
+    #[test]
+    fn test_amend_order() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+
+        // a sell order reserves the tokens
+        state.add_liquidity(pr(0), token, 100);
+        assert!(create_order(state, pr(0), token, 50, 100000, 0, OrderType::Sell).is_ok());
+        assert_eq!(state.token_pool_balance(token, pr(0)), 50);
+
+        let amend = |state: &mut State, key, amount, price, now| {
+            let funds = state.funds_under_management();
+            let result = state.amend_order(token, key, amount, price, now);
+            assert_eq!(funds, state.funds_under_management());
+            result
+        };
+
+        // increasing the amount reserves more tokens
+        let key = Order::key(OrderType::Sell, pr(0), 50, 100000, 0);
+        assert_eq!(amend(state, key, 80, 90000, 10), Ok(()));
+        assert_eq!(state.token_pool_balance(token, pr(0)), 20);
+        let order = state.orders(token, OrderType::Sell).next().unwrap();
+        assert_eq!(
+            (order.amount, order.price, order.timestamp),
+            (80, 90000, 10)
+        );
+
+        // the old order does not exist anymore
+        let key = Order::key(OrderType::Sell, pr(0), 50, 100000, 0);
+        assert_eq!(
+            amend(state, key, 60, 90000, 20),
+            Err("no order found".into())
+        );
+
+        // not enough funds for the increased amount, the order stays untouched
+        let key = Order::key(OrderType::Sell, pr(0), 80, 90000, 10);
+        assert_eq!(
+            amend(state, key.clone(), 101, 90000, 20),
+            Err("not enough funds available for this order size".into())
+        );
+        assert_eq!(state.token_pool_balance(token, pr(0)), 20);
+        let order = state.orders(token, OrderType::Sell).next().unwrap();
+        assert_eq!(
+            (order.amount, order.price, order.timestamp),
+            (80, 90000, 10)
+        );
+
+        // decreasing the amount releases the tokens
+        assert_eq!(amend(state, key, 30, 90000, 20), Ok(()));
+        assert_eq!(state.token_pool_balance(token, pr(0)), 70);
+        assert_eq!(state.orders(token, OrderType::Sell).count(), 1);
+        assert!(close_order(state, pr(0), token, 30, 90000, 20, OrderType::Sell).is_ok());
+        assert_eq!(state.token_pool_balance(token, pr(0)), 100);
+
+        // a buy order reserves the volume and the fee in payment tokens
+        state.add_liquidity(pr(1), payment_token_id, 1_000_000);
+        assert!(create_order(state, pr(1), token, 100, 500000, 0, OrderType::Buy).is_ok());
+        // 500000 + 500 maker fee
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(1)), 499500);
+
+        let key = Order::key(OrderType::Buy, pr(1), 100, 500000, 0);
+        assert_eq!(
+            amend(state, key.clone(), 100, 1000000, 30),
+            Err("not enough funds available for this order size".into())
+        );
+        assert_eq!(
+            amend(state, key.clone(), 1, 500, 30),
+            Err(
+                "the order is too small: the volume 5 is below the minimum of 10 particles \
+                (by default 10 times the minimal trading fee)"
+                    .into()
+            )
+        );
+        assert_eq!(amend(state, key, 100, 800000, 30), Ok(()));
+        // 800000 + 800 maker fee
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(1)), 199200);
+
+        // an amended order must not cross the book
+        assert!(create_order(state, pr(0), token, 10, 900000, 40, OrderType::Sell).is_ok());
+        let key = Order::key(OrderType::Buy, pr(1), 100, 800000, 30);
+        assert_eq!(
+            amend(state, key, 100, 900000, 50),
+            Err("the amended order would cross".into())
+        );
+        let key = Order::key(OrderType::Sell, pr(0), 10, 900000, 40);
+        assert_eq!(
+            amend(state, key, 10, 800000, 50),
+            Err("the amended order would cross".into())
+        );
+
+        // closing the amended orders returns everything
+        assert!(close_order(state, pr(0), token, 10, 900000, 40, OrderType::Sell).is_ok());
+        assert!(close_order(state, pr(1), token, 100, 800000, 30, OrderType::Buy).is_ok());
+        assert_eq!(state.token_pool_balance(token, pr(0)), 100);
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(1)), 1_000_000);
+    }
+
+    #[test]
+    fn test_amend_order_rate_limit() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 1000);
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+
+        // every amendment counts against the rate limit like a new order
+        for i in 1..=MAX_ORDERS_PER_HOUR as u128 {
+            let key = Order::key(OrderType::Sell, pr(0), 9 + i, 100000, i as u64 - 1);
+            assert_eq!(
+                state.amend_order(token, key, 10 + i, 100000, i as u64),
+                Ok(())
+            );
+        }
+        let amount = 10 + MAX_ORDERS_PER_HOUR as u128;
+        let key = Order::key(OrderType::Sell, pr(0), amount, 100000, 15);
+        assert_eq!(
+            state.amend_order(token, key, amount + 1, 100000, 16),
+            Err("too many orders within one hour; please try again later".into())
+        );
+        let order = state.orders(token, OrderType::Sell).next().unwrap();
+        assert_eq!((order.amount, order.timestamp), (amount, 15));
+    }
+
+    #[test]
+    fn test_self_trade_prevention() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(0), payment_token_id, 1_000_000);
+        state.add_liquidity(pr(1), token, 100);
+        assert!(create_order(state, pr(0), token, 50, 100000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(1), token, 50, 200000, 0, OrderType::Sell).is_ok());
+
+        let execute = |state: &mut State, mode| {
+            let funds = state.funds_under_management();
+            let result = state.execute_trade(
+                OrderType::Buy,
+                pr(0),
+                token.into(),
+                10,
+                Some(100000),
+                123456,
+                mode,
+            );
+            assert_eq!(funds, state.funds_under_management());
+            result
+        };
+
+        // the trader's own order is skipped and stays open, no fees are charged
+        assert_eq!(execute(state, SelfTradePrevention::Skip), Ok(0));
+        assert_eq!(state.orders(token, OrderType::Sell).count(), 2);
+        assert_eq!(state.token_pool_balance(token, pr(0)), 50);
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(0)), 1_000_000);
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(255)), 0);
+        assert_eq!(state.order_archive.len(token), 0);
+
+        // the trade continues with the orders of other users
+        assert_eq!(
+            state.execute_trade(
+                OrderType::Buy,
+                pr(0),
+                token.into(),
+                10,
+                None,
+                123456,
+                SelfTradePrevention::Skip
+            ),
+            Ok(10)
+        );
+        assert_eq!(user_orders(state, token, pr(0), OrderType::Sell).count(), 1);
+        assert_eq!(user_orders(state, token, pr(1), OrderType::Sell).count(), 1);
+        assert_eq!(state.token_pool_balance(token, pr(0)), 60);
+
+        // the trader's own order is closed and the liquidity is returned, no fees are charged
+        let revenue = state.token_pool_balance(payment_token_id, pr(255));
+        let payment_balance = state.token_pool_balance(payment_token_id, pr(0));
+        assert_eq!(execute(state, SelfTradePrevention::CancelResting), Ok(0));
+        assert_eq!(user_orders(state, token, pr(0), OrderType::Sell).count(), 0);
+        assert_eq!(user_orders(state, token, pr(1), OrderType::Sell).count(), 1);
+        assert_eq!(state.token_pool_balance(token, pr(0)), 110);
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(0)),
+            payment_balance
+        );
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(255)), revenue);
+    }
+
+    #[test]
+    fn test_alternative_quote_token() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        let quote = pr(101);
+        list_test_token(state, token, 2);
+        list_test_token(state, quote, 2);
+        let payment_token_id = state.payment_token_id();
+        let pair = Pair {
+            base: token,
+            quote: Some(quote),
+        };
+
+        // the quote token needs to be declared first
+        state.add_liquidity(pr(0), token, 100);
+        assert_eq!(
+            create_order(state, pr(0), pair, 10, 100000, 0, OrderType::Sell),
+            Err("quote token not supported".into())
+        );
+        assert_eq!(
+            state.set_quote_token(token, Some(token)),
+            Err("invalid quote token".into())
+        );
+        assert_eq!(
+            state.set_quote_token(token, Some(payment_token_id)),
+            Err("invalid quote token".into())
+        );
+        assert_eq!(state.set_quote_token(token, Some(quote)), Ok(()));
+
+        // the order against the quote token is kept in a separate book
+        assert!(create_order(state, pr(0), pair, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert_eq!(state.orders(pair, OrderType::Sell).count(), 1);
+        assert_eq!(state.orders(token, OrderType::Sell).count(), 0);
+        assert_eq!(state.token_balances(pr(0)).get(&token), Some(&(90, 10)));
+
+        // the buyer pays in the quote token: the volume is 10 * 100000 / 100 = 10000, the maker fee
+        // 10 and the taker fee 20
+        state.add_liquidity(pr(1), quote, 20000);
+        state.add_liquidity(pr(1), payment_token_id, 20000);
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 10, None, 123456),
+            Ok(0)
+        );
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), pair, 10, None, 123456),
+            Ok(10)
+        );
+        assert_eq!(state.token_pool_balance(token, pr(1)), 10);
+        assert_eq!(state.token_pool_balance(quote, pr(1)), 20000 - 10000 - 20);
+        assert_eq!(state.token_pool_balance(quote, pr(0)), 10000 - 10);
+        assert_eq!(state.token_pool_balance(quote, pr(255)), 30);
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(1)), 20000);
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(255)), 0);
+        // only trades against the payment token are archived
+        assert_eq!(state.order_archive.len(token), 0);
+
+        // a buy order reserves the quote token: 5000 + 5 maker fee
+        assert!(create_order(state, pr(1), pair, 10, 50000, 0, OrderType::Buy).is_ok());
+        assert_eq!(state.token_pool_balance(quote, pr(1)), 9980 - 5005);
+        assert_eq!(state.token_balances(pr(1)).get(&quote), Some(&(4975, 5005)));
+
+        // removing the quote token closes its orders
+        let funds = state.funds_under_management();
+        assert_eq!(state.set_quote_token(token, None), Ok(()));
+        assert_eq!(funds, state.funds_under_management());
+        assert_eq!(state.orders(pair, OrderType::Buy).count(), 0);
+        assert_eq!(state.token_pool_balance(quote, pr(1)), 9980);
+        assert_eq!(
+            create_order(state, pr(1), pair, 10, 50000, 0, OrderType::Buy),
+            Err("quote token not supported".into())
+        );
+    }
+
+    #[test]
+    fn test_user_orders() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        let other_token = pr(101);
+        list_test_token(state, token, 2);
+        list_test_token(state, other_token, 2);
+        assert_eq!(state.set_quote_token(token, Some(other_token)), Ok(()));
+        let pair = Pair {
+            base: token,
+            quote: Some(other_token),
+        };
+
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(0), other_token, 100);
+        state.add_liquidity(pr(1), token, 100);
+        state.add_liquidity(pr(0), state.payment_token_id(), 1_000_000);
+        assert!(create_order(state, pr(0), other_token, 10, 300000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), token, 10, 200000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Buy).is_ok());
+        assert!(create_order(state, pr(0), pair, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(1), token, 10, 150000, 0, OrderType::Sell).is_ok());
+
+        let orders = state
+            .user_orders(pr(0))
+            .into_iter()
+            .map(|(token, quote, order)| (token, quote, order.order_type, order.price))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            orders,
+            vec![
+                (token, None, OrderType::Buy, 100000),
+                (token, None, OrderType::Sell, 200000),
+                (token, Some(other_token), OrderType::Sell, 100000),
+                (other_token, None, OrderType::Sell, 300000),
+            ]
+        );
+        assert_eq!(state.user_orders(pr(1)).len(), 1);
+        assert!(state.user_orders(pr(2)).is_empty());
+    }
+
+    #[test]
+    fn test_revenue_withdrawal() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+
+        state.add_liquidity(pr(0), token, 10);
+        assert!(create_order(state, pr(0), token, 10, 5000000, 0, OrderType::Sell).is_ok());
+        state.add_liquidity(pr(1), payment_token_id, 1_000_000);
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 10, None, 123456),
+            Ok(10)
+        );
+
+        // 0.1% maker fee and 0.2% taker fee of 500000
+        let revenue = state.revenue_balances();
+        assert_eq!(revenue.get(&payment_token_id), Some(&1500));
+        assert_eq!(revenue.get(&token), None);
+
+        assert_eq!(
+            state.reserve_revenue_withdrawal(payment_token_id, 1501),
+            Err("not enough revenue collected".into())
+        );
+        assert_eq!(
+            state.reserve_revenue_withdrawal(token, 1),
+            Err("no revenue collected".into())
+        );
+        let funds = state.funds_under_management();
+        assert_eq!(
+            state.reserve_revenue_withdrawal(payment_token_id, 1000),
+            Ok(pr(255))
+        );
+        assert_eq!(state.funds_under_management(), funds);
+        assert_eq!(state.revenue_balances().get(&payment_token_id), Some(&500));
+        assert_eq!(
+            state.commit_withdrawal(pr(255), payment_token_id, 1000),
+            Ok(())
+        );
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(255)), 500);
+    }
+
+    #[test]
+    fn test_maker_and_taker_fees() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        let volume = 1_000_000;
+
+        // the maker sells, the taker buys
+        state.add_liquidity(pr(0), token, 10);
+        assert!(create_order(state, pr(0), token, 10, 10_000_000, 0, OrderType::Sell).is_ok());
+        state.add_liquidity(pr(1), payment_token_id, 2 * volume);
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 10, None, 1),
+            Ok(10)
+        );
+        assert_eq!(maker_fee(volume), 1000);
+        assert_eq!(taker_fee(volume), 2000);
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(0)),
+            volume - 1000
+        );
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(1)),
+            volume - 2000
+        );
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(255)), 3000);
+
+        // the maker buys, the taker sells
+        assert!(create_order(state, pr(0), token, 5, 10_000_000, 1, OrderType::Buy).is_ok());
+        // the maker fee is reserved with the order
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(0)),
+            volume - 1000 - volume / 2 - 500
+        );
+        assert_eq!(
+            trade(state, OrderType::Sell, pr(1), token, 5, None, 2),
+            Ok(5)
+        );
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(0)),
+            volume - 1000 - volume / 2 - 500
+        );
+        assert_eq!(state.token_pool_balance(token, pr(0)), 5);
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(1)),
+            volume - 2000 + volume / 2 - 1000
+        );
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(255)),
+            3000 + 500 + 1000
+        );
+    }
+
+    #[test]
+    fn test_order_limits() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 1000);
+
+        assert_eq!(
+            state.set_order_limits(state.payment_token_id(), 1, 1),
+            Err("no order limits can be set for the payment token".into())
+        );
+        assert_eq!(state.set_order_limits(token, 60000, 1000), Ok(()));
+
+        // the volume of 50 tokens at 100000 is 50000
+        assert_eq!(
+            create_order(state, pr(0), token, 50, 100000, 0, OrderType::Sell),
+            Err("the order volume is below the minimum of 60000".into())
+        );
+        assert_eq!(
+            create_order(state, pr(0), token, 100, 100500, 0, OrderType::Sell),
+            Err("the price is not a multiple of the tick size 1000".into())
+        );
+        assert_eq!(
+            create_order(state, pr(0), token, 100, 101000, 0, OrderType::Sell),
+            Ok(())
+        );
+
+        // amended orders are subject to the same limits
+        let key = Order::key(OrderType::Sell, pr(0), 100, 101000, 0);
+        assert_eq!(
+            state.amend_order(token, key.clone(), 50, 101000, 1),
+            Err("the order volume is below the minimum of 60000".into())
+        );
+        assert_eq!(
+            state.amend_order(token, key, 100, 101500, 1),
+            Err("the price is not a multiple of the tick size 1000".into())
+        );
+
+        // the rest order of a trade is checked before the matching
+        state.add_liquidity(pr(1), state.payment_token_id(), 1_000_000);
+        let mut buy = |amount, price| {
+            state.trade(
+                OrderType::Buy,
+                pr(1),
+                token,
+                amount,
+                price,
+                1,
+                Default::default(),
+                None,
+                false,
+                None,
+                None,
+            )
+        };
+        assert_eq!(
+            buy(150, 101000),
+            Err("the order volume is below the minimum of 60000".into())
+        );
+        assert_eq!(
+            buy(200, 101500),
+            Err("the price is not a multiple of the tick size 1000".into())
+        );
+        assert_eq!(state.token_pool_balance(token, pr(1)), 0);
+
+        // the limits survive a relisting
+        list_test_token(state, token, 2);
+        let metadata = state.token(token).unwrap();
+        assert_eq!((metadata.min_notional, metadata.tick_size), (60000, 1000));
+
+        // 0 disables the limits
+        assert_eq!(state.set_order_limits(token, 0, 0), Ok(()));
+        assert_eq!(
+            create_order(state, pr(0), token, 50, 100500, 0, OrderType::Sell),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_order_too_large() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 1000);
+
+        // amount * price overflows
+        assert_eq!(
+            create_order(
+                state,
+                pr(0),
+                token,
+                u128::MAX,
+                u128::MAX,
+                0,
+                OrderType::Sell
+            ),
+            Err("order too large".into())
+        );
+        // the volume doesn't overflow, but the fee computation would
+        assert_eq!(
+            create_order(state, pr(0), token, u128::MAX / 2, 2, 0, OrderType::Buy),
+            Err("order too large".into())
+        );
+
+        assert!(create_order(state, pr(0), token, 100, 100000, 0, OrderType::Sell).is_ok());
+        let key = Order::key(OrderType::Sell, pr(0), 100, 100000, 0);
+        assert_eq!(
+            state.amend_order(token, key, 100, u128::MAX, 1),
+            Err("order too large".into())
+        );
+    }
+
+    #[test]
+    fn test_trading_fee() {
+        assert_eq!(maker_fee(1_000_000), 1_000);
+        assert_eq!(taker_fee(1_000_000), 2_000);
+        // rounded down
+        assert_eq!(maker_fee(1_999), 1);
+        assert_eq!(taker_fee(1_999), 3);
+        // at least one particle
+        assert_eq!(maker_fee(0), 1);
+        assert_eq!(taker_fee(1), 1);
+    }
+
+    #[test]
+    fn test_liquidity_breakdown() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        let other_token = pr(101);
+        list_test_token(state, token, 2);
+        list_test_token(state, other_token, 2);
+        assert_eq!(state.set_quote_token(token, Some(other_token)), Ok(()));
+        let pair = Pair {
+            base: token,
+            quote: Some(other_token),
+        };
+
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(0), other_token, 100);
+        state.add_liquidity(pr(0), payment_token_id, 1_000_000);
+        state.add_liquidity(pr(1), token, 100);
+        assert!(create_order(state, pr(0), token, 10, 200000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Buy).is_ok());
+        assert!(create_order(state, pr(0), pair, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), pair, 10, 500, 0, OrderType::Buy).is_ok());
+        assert!(create_order(state, pr(1), token, 10, 150000, 0, OrderType::Sell).is_ok());
+
+        assert_eq!(
+            state.liquidity_breakdown(pr(0), token),
+            LiquidityBreakdown {
+                free: 80,
+                reserved_in_buys: 0,
+                reserved_in_sells: 20,
+            }
+        );
+        // the buy order has a volume of 10000 and a maker fee of 10
+        assert_eq!(
+            state.liquidity_breakdown(pr(0), payment_token_id),
+            LiquidityBreakdown {
+                free: 989_990,
+                reserved_in_buys: 10_010,
+                reserved_in_sells: 0,
+            }
+        );
+        // the buy order against the alternative quote token has a volume of 50 and a fee of 1
+        assert_eq!(
+            state.liquidity_breakdown(pr(0), other_token),
+            LiquidityBreakdown {
+                free: 49,
+                reserved_in_buys: 51,
+                reserved_in_sells: 0,
+            }
+        );
+        assert_eq!(
+            state.liquidity_breakdown(pr(1), token),
+            LiquidityBreakdown {
+                free: 90,
+                reserved_in_buys: 0,
+                reserved_in_sells: 10,
+            }
+        );
+        assert_eq!(
+            state.liquidity_breakdown(pr(2), token),
+            LiquidityBreakdown::default()
+        );
+
+        // the breakdown is consistent with the token balances
+        for (token, (free, reserved)) in state.token_balances(pr(0)) {
+            let breakdown = state.liquidity_breakdown(pr(0), token);
+            assert_eq!(breakdown.free, free);
+            assert_eq!(
+                breakdown.reserved_in_buys + breakdown.reserved_in_sells,
+                reserved
+            );
+        }
+    }
+
+    #[test]
+    fn test_delist_token() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        let other_token = pr(101);
+        list_test_token(state, token, 2);
+        list_test_token(state, other_token, 2);
+        assert_eq!(state.set_quote_token(other_token, Some(token)), Ok(()));
+        let pair = Pair {
+            base: other_token,
+            quote: Some(token),
+        };
+
+        assert_eq!(
+            state.delist_token(payment_token_id),
+            Err("the payment token can't be delisted".into())
+        );
+        assert_eq!(state.delist_token(pr(102)), Err("no token listed".into()));
+
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), payment_token_id, 1_000_000);
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(1), token, 10, 50000, 0, OrderType::Buy).is_ok());
+        assert!(create_order(state, pr(0), pair, 10, 500, 0, OrderType::Buy).is_ok());
+
+        // all orders get closed, but pr(0) still has a balance
+        let funds = state.funds_under_management();
+        assert_eq!(state.delist_token(token), Ok(vec![pr(0)]));
+        assert_eq!(funds, state.funds_under_management());
+        assert!(!state.has_orders(token));
+        assert!(state.tokens().contains_key(&token));
+        assert_eq!(state.token_pool_balance(token, pr(0)), 100);
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(1)), 1_000_000);
+
+        // the token can't be delisted while a withdrawal is pending
+        assert_eq!(state.reserve_withdrawal(pr(0), token), Ok(100));
+        assert_eq!(
+            state.delist_token(token),
+            Err("withdrawals of the token are pending".into())
+        );
+
+        // after the refund, the token gets delisted
+        assert_eq!(state.commit_withdrawal(pr(0), token, 100), Ok(()));
+        assert_eq!(state.delist_token(token), Ok(vec![]));
+        assert!(!state.tokens().contains_key(&token));
+        assert!(!state.pools.contains_key(&token));
+        assert_eq!(state.token(other_token).unwrap().quote_token, None);
+    }
+
+    #[test]
+    fn test_delisting_removes_archive() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), payment_token_id, 1_000_000);
+
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 10, None, 0),
+            Ok(10)
+        );
+        assert!(state.prices().contains_key(&token));
+
+        assert_eq!(state.withdraw_liquidity(pr(0), token), Ok(90));
+        assert_eq!(state.withdraw_liquidity(pr(1), token), Ok(10));
+        assert_eq!(state.delist_token(token), Ok(vec![]));
+
+        assert!(!state.tokens().contains_key(&token));
+        assert!(!state.order_archive.tokens().contains(&token));
+        assert!(state
+            .prices()
+            .keys()
+            .all(|token| state.tokens().contains_key(token)));
+    }
+
+    #[test]
+    fn test_order_expiration() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 1000);
+        let now = DAY;
+        let max_lifetime = ORDER_EXPIRATION_DAYS * DAY;
+
+        let create = |state: &mut State, price, expires_at| {
+            let funds = state.funds_under_management();
+            let result = state
+                .create_order(
+                    pr(0),
+                    token,
+                    10,
+                    price,
+                    now,
+                    OrderType::Sell,
+                    expires_at,
+                    false,
+                    None,
+                )
+                .map(|_| ());
+            assert_eq!(funds, state.funds_under_management());
+            result
+        };
+        assert_eq!(
+            create(state, 100000, Some(now)),
+            Err("the expiration time is in the past".into())
+        );
+        assert_eq!(
+            create(state, 100000, Some(now + max_lifetime + 1)),
+            Err("the expiration time exceeds the maximal order lifetime".into())
+        );
+        assert_eq!(
+            state
+                .trade(
+                    OrderType::Sell,
+                    pr(0),
+                    token,
+                    10,
+                    100000,
+                    now,
+                    Default::default(),
+                    Some(now - 1),
+                    false,
+                    None,
+                    None
+                )
+                .err(),
+            Some("the expiration time is in the past".into())
+        );
+        assert_eq!(create(state, 100000, Some(now + max_lifetime)), Ok(()));
+        assert_eq!(create(state, 200000, Some(now + HOUR)), Ok(()));
+        assert_eq!(create(state, 300000, None), Ok(()));
+        assert_eq!(state.token_pool_balance(token, pr(0)), 970);
+
+        let prices = |state: &State| {
+            state
+                .orders(token, OrderType::Sell)
+                .map(|order| order.price)
+                .collect::<Vec<_>>()
+        };
+
+        // the order with a custom expiration is closed exactly at the expiration time
+        state.clean_up(now + HOUR - 1);
+        assert_eq!(prices(state), vec![100000, 200000, 300000]);
+        state.clean_up(now + HOUR);
+        assert_eq!(prices(state), vec![100000, 300000]);
+        assert_eq!(state.token_pool_balance(token, pr(0)), 980);
+
+        // orders without a custom expiration expire after `ORDER_EXPIRATION_DAYS`
+        state.clean_up(now + max_lifetime - 1);
+        assert_eq!(prices(state), vec![100000, 300000]);
+        state.clean_up(now + max_lifetime);
+        assert!(prices(state).is_empty());
+        assert_eq!(state.token_pool_balance(token, pr(0)), 1000);
+    }
+
+    #[test]
+    fn test_post_only() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), payment_token_id, 1_000_000);
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(1), token, 10, 50000, 0, OrderType::Buy).is_ok());
+
+        let post = |state: &mut State, user, order_type, price| {
+            let funds = state.funds_under_management();
+            let result = state
+                .trade(
+                    order_type,
+                    user,
+                    token,
+                    10,
+                    price,
+                    1,
+                    Default::default(),
+                    None,
+                    true,
+                    None,
+                    None,
+                )
+                .map(|receipt| {
+                    matches!(receipt.execution, OrderExecution::FilledAndOrderCreated(0))
+                });
+            assert_eq!(funds, state.funds_under_management());
+            result
+        };
+
+        // crossing orders are rejected instead of being filled
+        assert_eq!(
+            post(state, pr(1), OrderType::Buy, 100000),
+            Err("post-only would cross".into())
+        );
+        assert_eq!(
+            post(state, pr(1), OrderType::Buy, 110000),
+            Err("post-only would cross".into())
+        );
+        assert_eq!(
+            post(state, pr(0), OrderType::Sell, 50000),
+            Err("post-only would cross".into())
+        );
+        assert_eq!(state.orders(token, OrderType::Sell).count(), 1);
+        assert_eq!(state.orders(token, OrderType::Buy).count(), 1);
+
+        // non-crossing orders are added to the book
+        assert_eq!(post(state, pr(1), OrderType::Buy, 99000), Ok(true));
+        assert_eq!(post(state, pr(0), OrderType::Sell, 99500), Ok(true));
+        assert_eq!(state.orders(token, OrderType::Sell).count(), 2);
+        assert_eq!(state.orders(token, OrderType::Buy).count(), 2);
+        assert_eq!(state.token_pool_balance(token, pr(0)), 80);
+    }
+
+    #[test]
+    fn test_create_orders() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(0), payment_token_id, 1_000_000);
+
+        let request = |order_type, amount, price| OrderRequest {
+            token,
+            quote: None,
+            order_type,
+            amount,
+            price,
+            expires_at: None,
+            keepalive_secs: None,
+        };
+        // the maximal maker fee of each order
+        let fees = |max_maker_fee| OrderFees {
+            taker_fee: 0,
+            max_maker_fee,
+        };
+        let funds = state.funds_under_management();
+        assert_eq!(
+            state.create_orders(
+                pr(0),
+                vec![
+                    request(OrderType::Sell, 10, 110000),
+                    request(OrderType::Sell, 10, 120000),
+                    // not enough tokens
+                    request(OrderType::Sell, 100, 130000),
+                    request(OrderType::Buy, 10, 90000),
+                    // would cross the sell orders of the batch
+                    request(OrderType::Buy, 10, 115000),
+                    request(OrderType::Buy, 10, 80000),
+                ],
+                1,
+            ),
+            vec![
+                Ok(fees(11)),
+                Ok(fees(12)),
+                Err("not enough funds available for this order size".into()),
+                Ok(fees(9)),
+                Err("post-only would cross".into()),
+                Ok(fees(8)),
+            ]
+        );
+        assert_eq!(funds, state.funds_under_management());
+        assert_eq!(state.orders(token, OrderType::Sell).count(), 2);
+        assert_eq!(state.orders(token, OrderType::Buy).count(), 2);
+        assert_eq!(state.token_pool_balance(token, pr(0)), 80);
+
+        // the whole batch counts as one order against the rate limit
+        assert_eq!(
+            state
+                .order_activity
+                .get(&pr(0))
+                .map(|records| records.len()),
+            Some(1)
+        );
+
+        // entries above the maximal batch size are rejected
+        let results = state.create_orders(
+            pr(0),
+            (0..MAX_ORDERS_PER_HOUR + 1)
+                .map(|i| request(OrderType::Buy, 1, 1000 + i as u128 * 1000))
+                .collect(),
+            2,
+        );
+        assert!(results[..MAX_ORDERS_PER_HOUR]
+            .iter()
+            .all(|result| result.is_ok()));
+        assert_eq!(
+            results[MAX_ORDERS_PER_HOUR],
+            Err("too many orders in one batch".into())
+        );
+    }
+
+    #[test]
+    fn test_fee_stats() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 10);
+        state.add_liquidity(pr(1), payment_token_id, 2_000_000);
+
+        // a volume of 1_000_000 yields fees of 1000 + 2000
+        assert!(create_order(state, pr(0), token, 5, 20_000_000, 0, OrderType::Sell).is_ok());
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 5, None, 1),
+            Ok(5)
+        );
+        // a volume of 500_000 yields fees of 500 + 1000
+        assert!(create_order(state, pr(0), token, 5, 10_000_000, 0, OrderType::Sell).is_ok());
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 5, None, 3 * DAY),
+            Ok(5)
+        );
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(255)), 4500);
+
+        assert_eq!(
+            state.fee_stats(3 * DAY),
+            FeeStats {
+                fees_day: 1500,
+                fees_week: 4500,
+                fees_total: 4500,
+            }
+        );
+        assert_eq!(
+            state.fee_stats(9 * DAY),
+            FeeStats {
+                fees_day: 0,
+                fees_week: 1500,
+                fees_total: 4500,
+            }
+        );
+    }
+
+    #[test]
+    fn test_check_cycles() {
+        let state = &mut State::default();
+        // the check is disabled by default
+        state.check_cycles(0);
+        assert!(!state.low_cycles);
+
+        state.cycle_warning_threshold = 1_000;
+        state.check_cycles(999);
+        assert!(state.low_cycles);
+        assert!(state
+            .logs()
+            .next()
+            .map(|(_, message)| message.starts_with("WARNING: the cycle balance 999"))
+            .unwrap_or_default());
+        state.check_cycles(1_000);
+        assert!(!state.low_cycles);
+    }
+
+    #[test]
+    fn test_simulate_trade() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), payment_token_id, 10_000_000);
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), token, 10, 200000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), token, 10, 300000, 0, OrderType::Sell).is_ok());
+
+        // nothing to fill
+        assert_eq!(
+            state.simulate_trade(OrderType::Sell, pr(1), token, 10, None),
+            TradeSimulation {
+                rest_amount: 10,
+                ..Default::default()
+            }
+        );
+        // own orders are skipped
+        assert_eq!(
+            state.simulate_trade(OrderType::Buy, pr(0), token, 10, None),
+            TradeSimulation {
+                rest_amount: 10,
+                ..Default::default()
+            }
+        );
+        // the limit stops the filling
+        assert_eq!(
+            state.simulate_trade(OrderType::Buy, pr(1), token, 25, Some(200000)),
+            TradeSimulation {
+                fillable: 20,
+                avg_price: 150000,
+                fee: 20 + 40,
+                rest_amount: 5,
+            }
+        );
+
+        // the simulation matches the execution
+        let simulation = state.simulate_trade(OrderType::Buy, pr(1), token, 25, None);
+        assert_eq!(
+            simulation,
+            TradeSimulation {
+                fillable: 25,
+                avg_price: 180000,
+                fee: 20 + 40 + 30,
+                rest_amount: 0,
+            }
+        );
+        let orders = state.orders(token, OrderType::Sell).count();
+        let revenue = state.token_pool_balance(payment_token_id, pr(255));
+        let balance = state.token_pool_balance(payment_token_id, pr(1));
+        assert_eq!(orders, 3);
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 25, None, 1),
+            Ok(25)
+        );
+        let volume = 10 * 1000 + 10 * 2000 + 5 * 3000;
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(1)),
+            balance - volume - simulation.fee
+        );
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(255)),
+            revenue + simulation.fee + 10 + 20 + 15
+        );
+    }
+
+    #[test]
+    fn test_ticker() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), payment_token_id, 10_000_000);
+
+        assert_eq!(state.ticker(token, DAY), Ticker::default());
+
+        for (price, time) in [
+            (300000, 0),
+            (100000, 2 * DAY),
+            (400000, 3 * DAY),
+            (200000, 3 * DAY + 1),
+        ] {
+            assert!(create_order(state, pr(0), token, 10, price, time, OrderType::Sell).is_ok());
+            assert_eq!(
+                trade(state, OrderType::Buy, pr(1), token, 10, None, time),
+                Ok(10)
+            );
+        }
+
+        // only the first trade is older than 24 hours
+        assert_eq!(
+            state.ticker(token, 3 * DAY),
+            Ticker {
+                last: Some(200000),
+                high_24h: Some(400000),
+                low_24h: Some(100000),
+                volume_24h: 10000 + 40000 + 20000,
+                change_24h: Some(200000 - 100000),
+            }
+        );
+        assert_eq!(
+            state.ticker(token, 4 * DAY),
+            Ticker {
+                last: Some(200000),
+                high_24h: Some(400000),
+                low_24h: Some(200000),
+                volume_24h: 40000 + 20000,
+                change_24h: Some(200000 - 400000),
+            }
+        );
+        // no trades within the window
+        assert_eq!(
+            state.ticker(token, 5 * DAY),
+            Ticker {
+                last: Some(200000),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_archive_retention() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), payment_token_id, 10_000_000);
+
+        assert_eq!(state.archive_retention_days(), 2 * ORDER_EXPIRATION_DAYS);
+        assert!(state.set_archive_retention_days(0).is_err());
+        assert!(state
+            .set_archive_retention_days(MAX_ARCHIVE_RETENTION_DAYS + 1)
+            .is_err());
+        assert_eq!(state.set_archive_retention_days(10), Ok(()));
+
+        for time in [0, 5 * DAY] {
+            assert!(create_order(state, pr(0), token, 10, 100000, time, OrderType::Sell).is_ok());
+            assert_eq!(
+                trade(state, OrderType::Buy, pr(1), token, 10, None, time),
+                Ok(10)
+            );
+        }
+        assert_eq!(state.order_archive.len(token), 2);
+
+        // the first order is older than 10 days
+        state.clean_up(12 * DAY);
+        assert_eq!(state.order_archive.len(token), 1);
+        assert_eq!(state.order_archive.front(token).unwrap().timestamp, 5 * DAY);
+
+        state.clean_up(16 * DAY);
+        assert_eq!(state.order_archive.len(token), 0);
+    }
+
+    #[test]
+    fn test_logs_paged() {
+        let state = &mut State::default();
+        for i in 0..10 {
+            state.log(format!(
+                "{} message {}",
+                if i % 2 == 0 { "even" } else { "odd" },
+                i
+            ));
+        }
+
+        let messages = |logs: Vec<(u64, String)>| {
+            logs.into_iter()
+                .map(|(_, message)| message)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            messages(state.logs_paged(0, 3, None)),
+            vec!["odd message 9", "even message 8", "odd message 7"]
+        );
+        assert_eq!(
+            messages(state.logs_paged(8, 5, None)),
+            vec!["odd message 1", "even message 0"]
+        );
+        assert_eq!(
+            messages(state.logs_paged(1, 2, Some("even".into()))),
+            vec!["even message 6", "even message 4"]
+        );
+        assert!(state.logs_paged(0, 10, Some("none".into())).is_empty());
+    }
+
+    #[test]
+    fn test_fill_notifications() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), token, 100);
+        state.add_liquidity(pr(2), payment_token_id, 10_000_000);
+
+        state.set_fill_notification(pr(0), pr(50), "on_fill".into());
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(1), token, 10, 110000, 0, OrderType::Sell).is_ok());
+
+        // only the order of the registered user triggers a notification
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(2), token, 15, None, 1),
+            Ok(15)
+        );
+        let notifications = state.take_fill_notifications();
+        assert_eq!(notifications.len(), 1);
+        let (canister, method, order) = &notifications[0];
+        assert_eq!(canister, &pr(50));
+        assert_eq!(method, "on_fill");
+        assert_eq!(order.owner, pr(0));
+        assert_eq!(order.amount, 10);
+        assert_eq!(order.executed, 1);
+        assert!(state.take_fill_notifications().is_empty());
+    }
+
+    #[test]
+    fn test_twap() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), payment_token_id, 10_000_000);
+
+        let execute = |state: &mut State, price, time| {
+            assert!(create_order(state, pr(0), token, 10, price, time, OrderType::Sell).is_ok());
+            assert_eq!(
+                trade(state, OrderType::Buy, pr(1), token, 10, None, time),
+                Ok(10)
+            );
+        };
+        execute(state, 500000, 0);
+        execute(state, 100000, 100 * SECOND);
+        assert_eq!(state.twap(token, 100, 110 * SECOND), None);
+
+        execute(state, 200000, 130 * SECOND);
+        // 100000 for 30 seconds and 200000 for 10 seconds
+        assert_eq!(state.twap(token, 60, 140 * SECOND), Some(125000));
+        // 500000 for 100 seconds, 100000 for 30 seconds and 200000 for 70 seconds
+        assert_eq!(
+            state.twap(token, 1000, 200 * SECOND),
+            Some((500000 * 100 + 100000 * 30 + 200000 * 70) / 200)
+        );
+        // trades after the end of the window are ignored
+        assert_eq!(
+            state.twap(token, 120, 120 * SECOND),
+            Some((500000 * 100 + 100000 * 20) / 120)
+        );
+    }
+
+    #[test]
+    fn test_trade_blocks() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), token, 100);
+        state.add_liquidity(pr(2), payment_token_id, 10_000_000);
+
+        assert_eq!(state.trade_blocks(0, 10).log_length, 0);
+
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(1), token, 10, 200000, 0, OrderType::Sell).is_ok());
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(2), token, 15, None, 5),
+            Ok(15)
+        );
+
+        let TradeBlocks { log_length, blocks } = state.trade_blocks(0, 10);
+        assert_eq!(log_length, 2);
+        assert_eq!(
+            blocks,
+            vec![
+                (
+                    0,
+                    TradeBlock {
+                        maker: pr(0),
+                        taker: pr(2),
+                        token,
+                        quote: None,
+                        order_type: OrderType::Buy,
+                        amount: 10,
+                        price: 100000,
+                        fee: maker_fee(10000) + taker_fee(10000),
+                        timestamp: 5,
+                        decimals: Some(2),
+                    }
+                ),
+                (
+                    1,
+                    TradeBlock {
+                        maker: pr(1),
+                        taker: pr(2),
+                        token,
+                        quote: None,
+                        order_type: OrderType::Buy,
+                        amount: 5,
+                        price: 200000,
+                        fee: maker_fee(10000) + taker_fee(10000),
+                        timestamp: 5,
+                        decimals: Some(2),
+                    }
+                )
+            ]
+        );
+
+        let TradeBlocks { log_length, blocks } = state.trade_blocks(1, 10);
+        assert_eq!(log_length, 2);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].0, 1);
+        assert!(state.trade_blocks(2, 10).blocks.is_empty());
+    }
+
+    #[test]
+    fn test_top_traders() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        for user in 0..3 {
+            state.add_liquidity(pr(user), token, 1000);
+        }
+        state.add_liquidity(pr(10), payment_token_id, 100_000_000);
+
+        let sell = |state: &mut State, user, amount, time| {
+            assert!(create_order(
+                state,
+                pr(user),
+                token,
+                amount,
+                100000,
+                time,
+                OrderType::Sell
+            )
+            .is_ok());
+            assert_eq!(
+                trade(state, OrderType::Buy, pr(10), token, amount, None, time),
+                Ok(amount)
+            );
+        };
+        // the first trade is outside of the window
+        sell(state, 0, 100, 0);
+        sell(state, 0, 10, 7 * DAY);
+        sell(state, 1, 30, 7 * DAY);
+        sell(state, 2, 20, 8 * DAY);
+
+        assert_eq!(
+            state.top_traders(10, 8 * DAY),
+            vec![(pr(1), 30000), (pr(2), 20000), (pr(0), 10000)]
+        );
+        assert_eq!(state.top_traders(1, 8 * DAY), vec![(pr(1), 30000)]);
+        assert!(state.top_traders(10, 20 * DAY).is_empty());
+    }
+
+    #[test]
+    fn test_trade_quote_amount() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), token, 100);
+        state.add_liquidity(pr(2), payment_token_id, 1_000_000);
+
+        // one token costs 1000 + 2 taker fee in the first and 2000 + 4 in the second order
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(1), token, 10, 200000, 0, OrderType::Sell).is_ok());
+
+        assert_eq!(
+            state.trade_quote_amount(pr(2), token, 2_000_000, 200000, 1),
+            Err("not enough funds".into())
+        );
+
+        // exact spend
+        assert_eq!(
+            state.trade_quote_amount(pr(2), token, 5010, 200000, 1),
+            Ok((5, 5010))
+        );
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(2)),
+            1_000_000 - 5010
+        );
+        assert_eq!(state.token_pool_balance(token, pr(2)), 5);
+
+        // the budget is used up across orders and the rest is not spent
+        assert_eq!(
+            state.trade_quote_amount(pr(2), token, 9000, 200000, 2),
+            Ok((6, 5010 + 2004))
+        );
+        assert_eq!(state.token_pool_balance(token, pr(2)), 11);
+
+        // the limit price stops the fill before the budget is spent
+        assert_eq!(
+            state.trade_quote_amount(pr(2), token, 100_000, 150000, 3),
+            Ok((0, 0))
+        );
+        assert_eq!(
+            state.trade_quote_amount(pr(2), token, 100_000, 200000, 3),
+            Ok((9, 9 * 2004))
+        );
+        assert!(!state.has_orders(token));
+    }
+
+    #[test]
+    fn test_reconciliation() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), token, 50);
+        // funds locked in orders are counted too
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+
+        assert_eq!(
+            state.reconciliation(token, 150),
+            Reconciliation {
+                internal: 150,
+                on_ledger: 150,
+                diff: 0
+            }
+        );
+        assert_eq!(state.reconciliation(token, 140).diff, -10);
+        assert_eq!(state.reconciliation(token, 170).diff, 20);
+        assert_eq!(
+            state.reconciliation(pr(101), 5),
+            Reconciliation {
+                internal: 0,
+                on_ledger: 5,
+                diff: 5
+            }
+        );
+    }
+
+    #[test]
+    fn test_listing_charge() {
+        let state = &mut State::default();
+        assert_eq!(
+            state.listing_charge(pr(0)),
+            Err("the payment token is not listed yet".into())
+        );
+        list_payment_token(state);
+
+        // the payment token has 8 decimals and a fee of 10000
+        assert_eq!(
+            state.listing_charge(pr(0)),
+            Ok(LISTING_PRICE_USD * 100_000_000 - 20000)
+        );
+
+        state.set_listing_price_usd(500);
+        assert_eq!(state.listing_charge(pr(0)), Ok(500 * 100_000_000 - 20000));
+
+        // waived listing
+        state.set_free_listing(pr(1), true);
+        assert_eq!(state.listing_charge(pr(1)), Ok(0));
+        assert_eq!(state.listing_charge(pr(0)), Ok(500 * 100_000_000 - 20000));
+        state.set_free_listing(pr(1), false);
+        assert_eq!(state.listing_charge(pr(1)), Ok(500 * 100_000_000 - 20000));
+
+        // a price below the transfer fees is free
+        state.set_listing_price_usd(0);
+        assert_eq!(state.listing_charge(pr(0)), Ok(0));
+    }
+
+    #[test]
+    fn test_token_overview() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), payment_token_id, 10_000_000);
+
+        assert!(state.token_overview(pr(101), 0).is_err());
+        let overview = state.token_overview(token, 0).unwrap();
+        assert_eq!(overview.best_bid, None);
+        assert_eq!(overview.best_ask, None);
+        assert_eq!(overview.last_price, None);
+
+        for price in [300000, 400000, 500000] {
+            assert!(create_order(state, pr(0), token, 10, price, 0, OrderType::Sell).is_ok());
+        }
+        for price in [100000, 200000] {
+            assert!(create_order(state, pr(1), token, 10, price, 0, OrderType::Buy).is_ok());
+        }
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 10, None, 1),
+            Ok(10)
+        );
+
+        let overview = state.token_overview(token, 2).unwrap();
+        assert_eq!(overview.metadata.decimals, 2);
+        assert_eq!(overview.best_bid, Some(200000));
+        assert_eq!(overview.best_ask, Some(400000));
+        assert_eq!(overview.last_price, Some(300000));
+        assert_eq!(overview.volume_24h, 30000);
+        assert_eq!(overview.open_buy_orders, 2);
+        assert_eq!(overview.open_sell_orders, 2);
+    }
+
+    #[test]
+    fn test_set_payment_token_decimals() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let same_decimals = pr(100);
+        let more_decimals = pr(101);
+        list_test_token(state, same_decimals, 8);
+        list_test_token(state, more_decimals, 12);
+        state.add_liquidity(pr(0), payment_token_id, 1_000_000);
+
+        assert_eq!(
+            state.set_payment_token(pr(102)),
+            Err("no token listed".into())
+        );
+        assert_eq!(
+            state.set_payment_token(more_decimals),
+            Err(
+                "the payment token can't be switched to a token with 12 instead of 8 decimals \
+                while payment token balances exist"
+                    .into()
+            )
+        );
+        assert_eq!(state.payment_token_id(), payment_token_id);
+
+        // funds locked in orders are balances too
+        state.add_liquidity(pr(1), same_decimals, 1000);
+        assert!(create_order(
+            state,
+            pr(0),
+            same_decimals,
+            100_000_000,
+            100000,
+            0,
+            OrderType::Buy
+        )
+        .is_ok());
+        assert_eq!(
+            state
+                .withdraw_liquidity(pr(0), payment_token_id)
+                .map(|_| ()),
+            Ok(())
+        );
+        assert!(state.set_payment_token(more_decimals).is_err());
+
+        // tokens with the same decimals are fine
+        assert_eq!(state.set_payment_token(same_decimals), Ok(()));
+        assert_eq!(state.payment_token_id(), same_decimals);
+        assert!(!state.has_orders(same_decimals));
+
+        // without any balances, the decimals can change
+        assert_eq!(state.withdraw_liquidity(pr(1), same_decimals), Ok(1000));
+        assert_eq!(state.set_payment_token(more_decimals), Ok(()));
+        assert_eq!(state.payment_token_id(), more_decimals);
+    }
+
+    #[test]
+    fn test_book_version() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), payment_token_id, 10_000_000);
+
+        let version = state.book_version();
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(state.book_version() > version);
+
+        // failed and empty operations don't change the book
+        let version = state.book_version();
+        assert!(create_order(state, pr(0), token, 1000, 100000, 1, OrderType::Sell).is_err());
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 10, Some(50000), 1),
+            Ok(0)
+        );
+        assert_eq!(state.book_version(), version);
+
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 5, None, 2),
+            Ok(5)
+        );
+        assert!(state.book_version() > version);
+
+        let version = state.book_version();
+        let order = state.orders(token, OrderType::Sell).next().unwrap().clone();
+        assert_eq!(
+            state.close_order(
+                pr(0),
+                token,
+                order.amount,
+                order.price,
+                order.timestamp,
+                OrderType::Sell
+            ),
+            Ok(())
+        );
+        assert!(state.book_version() > version);
+    }
+
+    #[test]
+    fn test_check_buy_funds() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), payment_token_id, 10020);
+
+        assert_eq!(
+            state.check_buy_funds(pr(0), pr(101), 10, 100000),
+            Err("no token listed".into())
+        );
+        assert_eq!(
+            state.check_buy_funds(pr(0), token, 10, 0),
+            Err("a limit price is required".into())
+        );
+        // 10 tokens for 10000 plus the taker fee of 20
+        assert_eq!(state.check_buy_funds(pr(0), token, 10, 100000), Ok(()));
+        assert_eq!(
+            state.check_buy_funds(pr(0), token, 11, 100000),
+            Err("the balance of 10020 doesn't cover the order of 11022 including fees".into())
+        );
+        assert_eq!(
+            state.check_buy_funds(pr(1), token, 10, 100000),
+            Err("the balance of 0 doesn't cover the order of 10020 including fees".into())
+        );
+    }
+
+    #[test]
+    fn test_min_volume() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 1000);
+        state.add_liquidity(pr(1), payment_token_id, 10_000_000);
+
+        assert_eq!(
+            state.set_min_volume(pr(101), 100),
+            Err("no token listed".into())
+        );
+
+        // by default, the volume must be at least 10 particles
+        assert!(create_order(state, pr(0), token, 1, 900, 0, OrderType::Sell).is_err());
+        assert!(create_order(state, pr(0), token, 1, 1000, 0, OrderType::Sell).is_ok());
+
+        assert_eq!(state.set_min_volume(token, 5000), Ok(()));
+        assert_eq!(state.token(token).unwrap().min_volume(), 5000);
+        assert_eq!(
+            create_order(state, pr(0), token, 10, 40000, 1, OrderType::Sell),
+            Err(
+                "the order is too small: the volume 4000 is below the minimum of 5000 particles \
+                (by default 10 times the minimal trading fee)"
+                    .into()
+            )
+        );
+        assert!(create_order(state, pr(0), token, 10, 50000, 1, OrderType::Sell).is_ok());
+
+        // the rest of 6 tokens of the partially filled order is below the minimum and gets dropped
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 5, Some(50000), 2),
+            Ok(5)
+        );
+        assert_eq!(state.orders(token, OrderType::Sell).count(), 0);
+        assert_eq!(state.token_pool_balance(token, pr(0)), 1000 - 1 - 4);
+
+        // the setting survives relistings and 0 restores the default
+        list_test_token(state, token, 2);
+        assert_eq!(state.token(token).unwrap().min_volume(), 5000);
+        assert_eq!(state.set_min_volume(token, 0), Ok(()));
+        assert_eq!(state.token(token).unwrap().min_volume(), DEFAULT_MIN_VOLUME);
+    }
+
+    #[test]
+    fn test_split_order() {
+        let order = Order {
+            decimals: 2,
+            ..Order::key(OrderType::Buy, pr(0), 100, 100000, 0)
+        };
+        // 100000 + 100 maker fee
+        assert_eq!(order.reserved_liquidity(Default::default()), 100100);
+
+        let (filled, rest, freed) = split_order(&order, 30, 5, 10, Default::default()).unwrap();
+        assert_eq!((filled.amount, filled.timestamp), (30, 0));
+        let rest = rest.unwrap();
+        assert_eq!((rest.amount, rest.timestamp), (70, 5));
+        assert_eq!(freed, 0);
+
+        // the dust rest is dropped and its liquidity freed
+        let (_, rest, freed) = split_order(&order, 99, 5, 10000, Default::default()).unwrap();
+        assert!(rest.is_none());
+        assert_eq!(freed, 100100 - 99099);
+
+        // the minimal fee of both parts exceeds the fee of the order
+        let order = Order {
+            decimals: 2,
+            ..Order::key(OrderType::Buy, pr(0), 2, 1000, 0)
+        };
+        assert_eq!(order.reserved_liquidity(Default::default()), 21);
+        assert_eq!(
+            split_order(&order, 1, 5, 10, Default::default()),
+            Err("the parts need 22 while the order reserves only 21".into())
+        );
+        assert!(split_order(&order, 3, 5, 10, Default::default()).is_err());
+    }
+
+    #[test]
+    fn test_split_failure_keeps_order() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), payment_token_id, 1000);
+        state.add_liquidity(pr(1), token, 1000);
+        assert!(create_order(state, pr(0), token, 2, 1000, 0, OrderType::Buy).is_ok());
+
+        // selling one token would need to split the order into parts needing more fees
+        assert_eq!(
+            trade(state, OrderType::Sell, pr(1), token, 1, None, 1),
+            Ok(0)
+        );
+        assert_eq!(state.orders(token, OrderType::Buy).count(), 1);
+        assert_eq!(state.token_pool_balance(token, pr(1)), 1000);
+
+        assert_eq!(
+            trade(state, OrderType::Sell, pr(1), token, 2, None, 2),
+            Ok(2)
+        );
+    }
+
+    // Executes random trades of random sizes against random orders and checks that no funds get
+    // lost or created.
+    #[test]
+    fn test_random_partial_fills_preserve_funds() {
+        // a deterministic linear congruential generator
+        let mut seed: u64 = 42;
+        let mut random = |max: u128| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as u128 % max
+        };
+
+        for round in 0..50 {
+            let state = &mut State::default();
+            list_payment_token(state);
+            let payment_token_id = state.payment_token_id();
+            let token = pr(100);
+            list_test_token(state, token, random(9) as u32);
+            for user in 0..4 {
+                state.add_liquidity(pr(user), token, 1_000_000_000_000);
+                state.add_liquidity(pr(user), payment_token_id, 1_000_000_000_000);
+            }
+
+            for i in 0..30 {
+                let user = pr(random(4) as u8);
+                let order_type = if random(2) == 0 {
+                    OrderType::Buy
+                } else {
+                    OrderType::Sell
+                };
+                let amount = 1 + random(1_000_000);
+                let price = 1 + random(10_000_000);
+                let time = round * 1000 + i;
+                if random(2) == 0 {
+                    let funds = state.funds_under_management();
+                    let _ =
+                        create_order(state, user, token, amount, price, time as u64, order_type);
+                    assert_eq!(funds, state.funds_under_management());
+                } else {
+                    let limit = (random(2) == 0).then_some(price);
+                    let _ = trade(state, order_type, user, token, amount, limit, time as u64);
+                }
+            }
+        }
+    }
 
-    let token_pool = pools.get_mut(&token).ok_or("no token pool found")?;
+    #[test]
+    fn test_verify_serialization() {
+        let state = &mut State::default();
+        assert_eq!(state.verify_serialization(), Ok(()));
 
-    // We only need to subtract token liquidity if we're executing a selling trade, because
-    // the liquidity for the buy order has already been reserved at order creation.
-    if trade_type.sell() {
-        let sellers_tokens = token_pool.entry(payment_receiver).or_insert(0);
-        *sellers_tokens = sellers_tokens
-            .checked_sub(order.amount)
-            .ok_or("not enough tokens")?;
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        for user in 0..5 {
+            state.add_liquidity(pr(user), token, 1000);
+            state.add_liquidity(pr(user), payment_token_id, 10_000_000);
+            assert!(create_order(
+                state,
+                pr(user),
+                token,
+                10,
+                100000 + user as u128,
+                0,
+                OrderType::Sell
+            )
+            .is_ok());
+            assert!(create_order(
+                state,
+                pr(user),
+                token,
+                10,
+                50000,
+                user as u64,
+                OrderType::Buy
+            )
+            .is_ok());
+        }
+        state.add_liquidity(pr(9), payment_token_id, 10_000_000);
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(9), token, 15, None, 1),
+            Ok(15)
+        );
+        state.set_fill_notification(pr(0), pr(50), "on_fill".into());
+        assert_eq!(state.set_order_limits(token, 10, 0), Ok(()));
+
+        assert_eq!(state.verify_serialization(), Ok(()));
     }
 
-    let buyers_tokens = token_pool.entry(token_receiver).or_insert(0);
-    *buyers_tokens += order.amount;
+    #[test]
+    fn test_recent_prices() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 10_000);
+        state.add_liquidity(pr(1), payment_token_id, 100_000_000);
 
-    let payment_token_pool = pools
-        .get_mut(&payment_token_id)
-        .ok_or("no payment pool found")?;
+        assert!(state.recent_prices(5).is_empty());
 
-    let volume = order.volume();
-    let fee = trading_fee(volume);
+        for i in 1..=25 {
+            let price = i * 100000;
+            assert_eq!(
+                create_order(
+                    state,
+                    pr(0),
+                    token,
+                    10,
+                    price,
+                    i as u64 * HOUR,
+                    OrderType::Sell
+                ),
+                Ok(())
+            );
+            assert_eq!(
+                trade(
+                    state,
+                    OrderType::Buy,
+                    pr(1),
+                    token,
+                    10,
+                    None,
+                    i as u64 * HOUR
+                ),
+                Ok(10)
+            );
+        }
 
-    // We only need to subtract payment liquidity if we're executing a buying trade, because
-    // the liquidity for the sell order has already been reserved at order creation.
-    if trade_type.buy() {
-        let buyers_payment_tokens = payment_token_pool
-            .get_mut(&token_receiver)
-            .ok_or("no payment tokens")?;
-        *buyers_payment_tokens = buyers_payment_tokens
-            .checked_sub(volume + fee)
-            .ok_or("not enough payment tokens")?;
+        let prices = |state: &State, depth| {
+            state
+                .recent_prices(depth)
+                .get(&token)
+                .map(|orders| orders.iter().map(|order| order.price).collect::<Vec<_>>())
+                .unwrap_or_default()
+        };
+        assert_eq!(prices(state, 0), Vec::<u128>::new());
+        assert_eq!(prices(state, 3), vec![2500000, 2400000, 2300000]);
+        // the latest price equals the single price returned by `prices`
+        assert_eq!(prices(state, 1), vec![state.prices()[&token].price]);
+        // the depth is capped
+        assert_eq!(prices(state, 100).len(), MAX_PRICES_DEPTH);
+        assert_eq!(prices(state, 100).last(), Some(&600000));
     }
 
-    let sellers_payment_tokens = payment_token_pool.entry(payment_receiver).or_default();
-    *sellers_payment_tokens += volume.checked_sub(fee).ok_or("amount smaller than fee")?;
-    let payment_fees = payment_token_pool.entry(revenue_account).or_default();
-    *payment_fees += 2 * fee;
-    Ok(())
-}
-
-// The trading fee is deducted from both buyer and seller and only from the payment token.
-// That is, if a trade is executed, the buyer pays the volumen amount + fee, while the seller
-// receives the volume amount - fee.
-//
-// The `volume` represents the amount of order multiplied with the order price.
-fn trading_fee(volume: Tokens) -> Tokens {
-    (volume * TX_FEE / 10_000).max(1)
-}
+    #[test]
+    fn test_stop_orders() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 1000);
+        state.add_liquidity(pr(1), payment_token_id, 100_000_000);
+        state.add_liquidity(pr(2), token, 1000);
+        state.add_liquidity(pr(3), payment_token_id, 100_000_000);
+
+        let create_stop = |state: &mut State, user, amount, trigger, limit, order_type| {
+            let funds = state.funds_under_management();
+            let result =
+                state.create_stop_order(user, token, amount, trigger, limit, 0, order_type);
+            assert_eq!(funds, state.funds_under_management());
+            result
+        };
 
-fn dust(fee: Tokens, volume: Tokens) -> bool {
-    volume < 10 * fee
-}
+        // the first trade sets the last price to 100000
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert_eq!(
+            state
+                .trade(
+                    OrderType::Buy,
+                    pr(1),
+                    token,
+                    10,
+                    0,
+                    0,
+                    Default::default(),
+                    None,
+                    false,
+                    None,
+                    None
+                )
+                .map(|receipt| receipt.execution),
+            Ok(OrderExecution::Filled(10))
+        );
 
-#[cfg(test)]
-mod tests {
+        // a stop-loss of pr(2) reserves the tokens at placement
+        assert_eq!(
+            create_stop(state, pr(2), 10, 100000, 80000, OrderType::Sell),
+            Err("the last price has reached the trigger price already".into())
+        );
+        assert_eq!(
+            create_stop(state, pr(2), 2000, 90000, 80000, OrderType::Sell),
+            Err("not enough funds available for this order size".into())
+        );
+        assert_eq!(
+            create_stop(state, pr(2), 10, 90000, 80000, OrderType::Sell),
+            Ok(())
+        );
+        assert_eq!(state.token_pool_balance(token, pr(2)), 990);
+        assert_eq!(
+            state.liquidity_breakdown(pr(2), token).reserved_in_sells,
+            10
+        );
+        assert_eq!(state.user_stop_orders(pr(2)).len(), 1);
+        // the stop-loss is not visible in the book
+        assert_eq!(state.orders(token, OrderType::Sell).count(), 0);
 
-    use crate::{mutate, read, unsafe_mutate};
+        // a buy stop of pr(3) gets closed before it triggers and releases its liquidity
+        let balance = state.token_pool_balance(payment_token_id, pr(3));
+        assert_eq!(
+            create_stop(state, pr(3), 10, 120000, 130000, OrderType::Buy),
+            Ok(())
+        );
+        assert!(state.token_pool_balance(payment_token_id, pr(3)) < balance);
+        assert_eq!(
+            state.close_stop_order(pr(3), token, 10, 120000, 130000, 0, OrderType::Buy),
+            Ok(())
+        );
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(3)), balance);
+        assert_eq!(
+            state.close_stop_order(pr(3), token, 10, 120000, 130000, 0, OrderType::Buy),
+            Err("no order found".into())
+        );
 
-    use super::*;
+        // pr(3) bids below the trigger price
+        assert!(create_order(state, pr(3), token, 5, 85000, 1, OrderType::Buy).is_ok());
 
-    pub fn pr(n: u8) -> Principal {
-        let v = vec![0, n];
-        Principal::from_slice(&v)
-    }
-    fn user_orders(
-        state: &State,
-        token: TokenId,
-        user: Principal,
-        order_type: OrderType,
-    ) -> Box<dyn Iterator<Item = &'_ Order> + '_> {
-        Box::new(
+        // a trade above the trigger price leaves the stop-loss pending
+        assert!(create_order(state, pr(1), token, 10, 95000, 2, OrderType::Buy).is_ok());
+        let funds = state.funds_under_management();
+        assert_eq!(
             state
-                .orders(token, order_type)
-                .filter(move |order| order.owner == user),
-        )
-    }
+                .trade(
+                    OrderType::Sell,
+                    pr(0),
+                    token,
+                    10,
+                    95000,
+                    3,
+                    Default::default(),
+                    None,
+                    false,
+                    None,
+                    None
+                )
+                .map(|receipt| receipt.execution),
+            Ok(OrderExecution::Filled(10))
+        );
+        assert_eq!(funds, state.funds_under_management());
+        assert_eq!(state.user_stop_orders(pr(2)).len(), 1);
 
-    fn close_order(
-        state: &mut State,
-        user: Principal,
-        token: TokenId,
-        amount: Tokens,
-        price: ParticlesPerToken,
-        timestamp: Timestamp,
-        order_type: OrderType,
-    ) -> Result<(), String> {
+        // a trade at the trigger price fills half of the stop-loss against the bid of pr(3) and
+        // adds the rest to the book at the limit price
+        assert!(create_order(state, pr(1), token, 10, 90000, 4, OrderType::Buy).is_ok());
         let funds = state.funds_under_management();
-        state.close_order(user, token, amount, price, timestamp, order_type)?;
+        assert_eq!(
+            state
+                .trade(
+                    OrderType::Sell,
+                    pr(0),
+                    token,
+                    10,
+                    90000,
+                    5,
+                    Default::default(),
+                    None,
+                    false,
+                    None,
+                    None
+                )
+                .map(|receipt| receipt.execution),
+            Ok(OrderExecution::Filled(10))
+        );
         assert_eq!(funds, state.funds_under_management());
-        Ok(())
+        assert!(state.user_stop_orders(pr(2)).is_empty());
+        assert_eq!(state.token_pool_balance(token, pr(3)), 5);
+        let rest = state
+            .orders(token, OrderType::Sell)
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(
+            (rest[0].owner, rest[0].amount, rest[0].price),
+            (pr(2), 5, 80000)
+        );
+        assert_eq!(state.liquidity_breakdown(pr(2), token).reserved_in_sells, 5);
+
+        // pending stop-limit orders are closed with all other orders
+        assert_eq!(
+            create_stop(state, pr(2), 10, 70000, 60000, OrderType::Sell),
+            Ok(())
+        );
+        assert_eq!(state.open_orders(), 2);
+        assert_eq!(state.close_all_orders(), 0);
+        assert_eq!(state.token_pool_balance(token, pr(2)), 995);
     }
 
-    fn create_order(
-        state: &mut State,
-        user: Principal,
-        token: TokenId,
-        amount: Tokens,
-        price: ParticlesPerToken,
-        timestamp: Timestamp,
-        order_type: OrderType,
-    ) -> Result<(), String> {
+    #[test]
+    fn test_revenue_split() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 1000);
+        state.add_liquidity(pr(1), payment_token_id, 100_000_000);
+
+        // the revenue account gets everything by default
+        assert_eq!(state.revenue_split(), vec![(pr(255), 10000)]);
+
+        assert_eq!(
+            state.set_revenue_split(vec![(pr(250), 5000), (pr(251), 4000)]),
+            Err("the shares sum up to 9000 instead of 10000 basis points".into())
+        );
+        assert_eq!(
+            state.set_revenue_split(vec![(pr(250), 5000), (pr(250), 5000)]),
+            Err("each beneficiary can be listed only once".into())
+        );
+        assert_eq!(
+            state.set_revenue_split(vec![(pr(250), 10000), (pr(251), 0)]),
+            Err("each share must be positive".into())
+        );
+        assert_eq!(
+            state.set_revenue_split((0..11).map(|i| (pr(i), 1000)).collect()),
+            Err("at most 10 beneficiaries are supported".into())
+        );
+        assert_eq!(
+            state.set_revenue_split(vec![(pr(250), 3333), (pr(251), 6667)]),
+            Ok(())
+        );
+
+        // the fees of 10 + 20 particles get split with the rounding remainder going to the first
+        // beneficiary
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 10, None, 0),
+            Ok(10)
+        );
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(250)), 10);
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(251)), 20);
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(255)), 0);
+
+        // charges are split the same way
         let funds = state.funds_under_management();
-        state.create_order(user, token, amount, price, timestamp, order_type)?;
+        assert_eq!(state.charge(pr(1), 1000), Ok(()));
         assert_eq!(funds, state.funds_under_management());
-        Ok(())
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(250)),
+            10 + 334
+        );
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(251)),
+            20 + 666
+        );
+
+        // an empty table restores the default
+        assert_eq!(state.set_revenue_split(vec![]), Ok(()));
+        assert_eq!(state.revenue_split(), vec![(pr(255), 10000)]);
     }
 
-    fn trade(
-        state: &mut State,
-        trade_type: OrderType,
-        trader: Principal,
-        token: TokenId,
-        amount: u128,
-        limit: Option<ParticlesPerToken>,
-        time: Timestamp,
-    ) -> Result<u128, String> {
-        let funds = state.funds_under_management();
-        let result = state.execute_trade(trade_type, trader, token, amount, limit, time)?;
-        assert_eq!(funds, state.funds_under_management());
-        Ok(result)
+    #[test]
+    fn test_order_counts() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 1000);
+        state.add_liquidity(pr(1), payment_token_id, 100_000_000);
+
+        assert_eq!(state.order_counts(token), OrderCounts::default());
+
+        assert!(create_order(state, pr(0), token, 10, 200000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), token, 15, 300000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(1), token, 20, 100000, 0, OrderType::Buy).is_ok());
+        assert_eq!(
+            state.order_counts(token),
+            OrderCounts {
+                buy_orders: 1,
+                sell_orders: 2,
+                buy_depth: 20,
+                sell_depth: 25,
+            }
+        );
+
+        // a partial fill reduces the depth
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 5, None, 1),
+            Ok(5)
+        );
+        assert_eq!(state.order_counts(token).sell_orders, 2);
+        assert_eq!(state.order_counts(token).sell_depth, 20);
     }
 
-    fn list_test_token(state: &mut State, token: TokenId, decimals: u32) {
-        state
-            .add_token(
-                token,
-                "TAGGR".into(),
-                25, // fee
-                decimals,
-                None,
+    #[test]
+    fn test_order_expiration_per_token() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let (short_lived, long_lived) = (pr(100), pr(101));
+        list_test_token(state, short_lived, 2);
+        list_test_token(state, long_lived, 2);
+        state.add_liquidity(pr(0), short_lived, 1000);
+        state.add_liquidity(pr(0), long_lived, 1000);
+
+        assert_eq!(
+            state.set_order_expiration_days(short_lived, MAX_ORDER_EXPIRATION_DAYS + 1),
+            Err("the order lifetime can't exceed 365 days".into())
+        );
+        assert_eq!(
+            state.set_order_expiration_days(pr(102), 10),
+            Err("no token listed".into())
+        );
+        assert_eq!(state.set_order_expiration_days(short_lived, 10), Ok(()));
+        assert_eq!(state.set_order_expiration_days(long_lived, 180), Ok(()));
+
+        // custom expiration times are bounded by the lifetime of the token
+        assert_eq!(
+            state.create_order(
+                pr(0),
+                short_lived,
+                10,
+                100000,
                 0,
-            )
-            .unwrap();
+                OrderType::Sell,
+                Some(11 * DAY),
+                false,
+                None
+            ),
+            Err("the expiration time exceeds the maximal order lifetime".into())
+        );
+        assert!(create_order(state, pr(0), short_lived, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), long_lived, 10, 100000, 0, OrderType::Sell).is_ok());
+
+        let open_orders = |state: &State, token| state.orders(token, OrderType::Sell).count();
+        state.clean_up(10 * DAY - 1);
+        assert_eq!(open_orders(state, short_lived), 1);
+        state.clean_up(10 * DAY);
+        assert_eq!(open_orders(state, short_lived), 0);
+        assert_eq!(open_orders(state, long_lived), 1);
+        assert_eq!(state.token_pool_balance(short_lived, pr(0)), 1000);
+
+        // the long-lived order outlives the default lifetime
+        state.clean_up(ORDER_EXPIRATION_DAYS * DAY);
+        assert_eq!(open_orders(state, long_lived), 1);
+        state.clean_up(180 * DAY);
+        assert_eq!(open_orders(state, long_lived), 0);
+
+        // the setting survives a relisting and 0 restores the default
+        list_test_token(state, short_lived, 2);
+        assert_eq!(
+            state.token(short_lived).unwrap().order_expiration_days(),
+            10
+        );
+        assert_eq!(state.set_order_expiration_days(short_lived, 0), Ok(()));
+        assert_eq!(
+            state.token(short_lived).unwrap().order_expiration_days(),
+            ORDER_EXPIRATION_DAYS
+        );
+    }
+
+    #[test]
+    fn test_withdrawal_reservation() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 1000);
+        let funds = state.funds_under_management();
+
+        assert_eq!(
+            state.reserve_withdrawal(pr(1), token),
+            Err("nothing to withdraw".into())
+        );
+
+        // the reserved liquidity can't be spent while the transfer is in flight
+        assert_eq!(state.reserve_withdrawal(pr(0), token), Ok(1000));
+        assert_eq!(funds, state.funds_under_management());
+        assert_eq!(state.token_pool_balance(token, pr(0)), 0);
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_err());
+
+        // a failed transfer restores the balance exactly
+        assert_eq!(
+            state.release_withdrawal(pr(0), token, 1001),
+            Err("the amount exceeds the reservation".into())
+        );
+        assert_eq!(state.release_withdrawal(pr(0), token, 1000), Ok(()));
+        assert_eq!(state.token_pool_balance(token, pr(0)), 1000);
+        assert_eq!(funds, state.funds_under_management());
+        assert!(state.pending_withdrawals.is_empty());
+        assert_eq!(
+            state.release_withdrawal(pr(0), token, 1000),
+            Err("no withdrawal pending".into())
+        );
+
+        // a confirmed transfer removes the liquidity
+        assert_eq!(state.reserve_withdrawal(pr(0), token), Ok(1000));
+        assert_eq!(state.commit_withdrawal(pr(0), token, 1000), Ok(()));
+        assert_eq!(state.token_pool_balance(token, pr(0)), 0);
+        assert_eq!(
+            state
+                .funds_under_management()
+                .into_iter()
+                .find(|(id, _)| id == &token.to_string()),
+            Some((token.to_string(), 0))
+        );
+        assert!(state.pending_withdrawals.is_empty());
     }
 
-    fn list_payment_token(state: &mut State) {
-        state.revenue_account = Some(pr(255));
-        state
-            .pools
-            .insert(state.payment_token_id(), Default::default());
-        state.tokens.insert(
-            state.payment_token_id(),
-            Metadata {
-                symbol: "USD".into(),
-                fee: 10000,
-                decimals: 8,
-                logo: None,
-                timestamp: 0,
-            },
+    #[test]
+    fn test_listing_payments() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), payment_token_id, 1000);
+
+        assert_eq!(
+            state.charge_listing(pr(0), pr(100), 2000, 1),
+            Err("not enough funds".into())
+        );
+        assert!(state.listing_payments(0, 10).is_empty());
+
+        assert_eq!(state.charge_listing(pr(0), pr(100), 300, 1), Ok(()));
+        assert_eq!(state.charge_listing(pr(0), pr(101), 200, 2), Ok(()));
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(0)), 500);
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(255)), 500);
+        assert_eq!(
+            state.listing_payments(0, 10),
+            vec![
+                ListingPayment {
+                    payer: pr(0),
+                    token: pr(101),
+                    amount: 200,
+                    timestamp: 2
+                },
+                ListingPayment {
+                    payer: pr(0),
+                    token: pr(100),
+                    amount: 300,
+                    timestamp: 1
+                },
+            ]
         );
+        assert_eq!(state.listing_payments(1, 10)[0].token, pr(100));
+        assert!(state.listing_payments(2, 10).is_empty());
     }
 
     #[test]
-    fn test_orderbook() {
-        let mut o1 = Order {
-            order_type: OrderType::Buy,
-            owner: pr(16),
-            amount: 12,
-            price: 0,
-            decimals: 6,
-            timestamp: 111,
-            executed: 0,
-            payment_token_fee: 10000,
+    fn test_keepalive_orders() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 1000);
+        state.add_liquidity(pr(1), token, 1000);
+
+        let sell = |state: &mut State, user, timestamp, keepalive_secs| {
+            state
+                .create_order(
+                    user,
+                    token,
+                    10,
+                    100000,
+                    timestamp,
+                    OrderType::Sell,
+                    None,
+                    false,
+                    keepalive_secs,
+                )
+                .map(|_| ())
         };
-        let mut o2 = Order {
-            order_type: OrderType::Buy,
-            owner: pr(16),
-            amount: 32,
-            price: 0,
-            decimals: 6,
-            timestamp: 111,
-            executed: 0,
-            payment_token_fee: 10000,
+        assert_eq!(
+            sell(state, pr(0), 0, Some(KEEPALIVE_CHECK_INTERVAL_SECS - 1)),
+            Err("the keepalive window must be at least 60 seconds".into())
+        );
+        assert_eq!(sell(state, pr(0), 0, Some(120)), Ok(()));
+        assert_eq!(sell(state, pr(1), 0, None), Ok(()));
+        let open_orders = |state: &State, user| {
+            state
+                .orders(token, OrderType::Sell)
+                .filter(|order| order.owner == user)
+                .count()
         };
 
-        assert_eq!(o1.cmp(&o1), Ordering::Equal);
-        assert_eq!(o1.cmp(&o2), Ordering::Less);
-        o1.amount = o2.amount;
-        assert_eq!(o1.cmp(&o2), Ordering::Equal);
-        o2.owner = pr(1);
-        o2.amount = 3000;
-        o2.price = 2;
-        o1.price = 3;
-        assert_eq!(o2.cmp(&o1), Ordering::Less);
-        let mut o3 = o1.clone();
-        assert_eq!(o3.cmp(&o1), Ordering::Equal);
-        o3.timestamp += 1;
-        assert_eq!(o3.cmp(&o1), Ordering::Greater);
+        // a heartbeat extends the keepalive window
+        assert_eq!(state.close_stale_orders(120 * SECOND), 0);
+        state.heartbeat(pr(0), 100 * SECOND);
+        assert_eq!(state.close_stale_orders(200 * SECOND), 0);
+        assert_eq!(open_orders(state, pr(0)), 1);
+
+        // users without keepalive orders are not tracked
+        state.heartbeat(pr(1), 100 * SECOND);
+        assert!(!state.heartbeats.contains_key(&pr(1)));
+
+        // once the window passes without a heartbeat, the order gets closed
+        assert_eq!(state.close_stale_orders(221 * SECOND), 1);
+        assert_eq!(open_orders(state, pr(0)), 0);
+        assert_eq!(state.token_pool_balance(token, pr(0)), 1000);
+        assert!(state.heartbeats.is_empty());
+
+        // orders without a keepalive window are not affected
+        assert_eq!(open_orders(state, pr(1)), 1);
     }
 
     #[test]
-    fn test_simplest_trade() {
+    fn test_tokens_ranked() {
         let state = &mut State::default();
-        let token = pr(100);
         list_payment_token(state);
-        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(1), payment_token_id, 10_000_000);
+        // token, symbol, price and the traded amount
+        for (token, symbol, price, amount) in [
+            (pr(100), "CCC", 300000, 10),
+            (pr(101), "AAA", 100000, 50),
+            (pr(102), "BBB", 200000, 20),
+        ] {
+            list_test_token(state, token, 2);
+            state.tokens.get_mut(&token).unwrap().symbol = symbol.into();
+            state.add_liquidity(pr(0), token, 100);
+            assert!(create_order(state, pr(0), token, amount, price, 0, OrderType::Sell).is_ok());
+            assert_eq!(
+                trade(state, OrderType::Buy, pr(1), token, amount, None, 0),
+                Ok(amount)
+            );
+        }
+        list_test_token(state, pr(103), 2);
+        state.tokens.get_mut(&pr(103)).unwrap().symbol = "DDD".into();
 
-        state.add_liquidity(pr(1), state.payment_token_id(), 210);
-        assert_eq!(trading_fee(20000), 40);
+        let ranked = |state: &State, sort_by, offset, limit, now| {
+            state
+                .tokens_ranked(sort_by, offset, limit, now)
+                .into_iter()
+                .map(|token| token.symbol)
+                .collect::<Vec<_>>()
+        };
         assert_eq!(
-            create_order(state, pr(1), token, 1, 0, 0, OrderType::Buy),
-            Err("limit price is 0".into())
+            ranked(state, SortKey::Volume24h, 0, 10, 0),
+            vec!["AAA", "BBB", "CCC", "DDD"]
         );
         assert_eq!(
-            create_order(state, pr(1), token, 1, 22000, 0, OrderType::Buy),
-            Err("not enough funds available for this order size".into())
+            ranked(state, SortKey::LastPrice, 0, 10, 0),
+            vec!["CCC", "BBB", "AAA", "DDD"]
         );
+        assert_eq!(ranked(state, SortKey::Symbol, 1, 2, 0), vec!["BBB", "CCC"]);
 
+        // the volume only covers the last 24 hours, so all tokens tie and keep their id order
         assert_eq!(
-            create_order(state, pr(1), token, 1, 21000, 0, OrderType::Buy),
-            Err("not enough funds available for this order size".into())
+            ranked(state, SortKey::Volume24h, 0, 10, 2 * DAY),
+            vec!["CCC", "AAA", "BBB", "DDD"]
         );
+        let top = state.tokens_ranked(SortKey::Volume24h, 0, 1, 0);
+        assert_eq!(top[0].token, pr(101));
+        assert_eq!(top[0].ticker.volume_24h, 50000);
+        assert!(state.tokens_ranked(SortKey::Symbol, 4, 10, 0).is_empty());
+    }
 
-        assert_eq!(
-            create_order(state, pr(1), token, 1, 20000, 0, OrderType::Buy),
-            Ok(())
-        );
+    #[test]
+    fn test_token_decimals_bounds() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(1), payment_token_id, 10_000_000);
 
-        state.add_liquidity(pr(0), token, 1);
         assert_eq!(
-            trade(state, OrderType::Sell, pr(0), token, 1, None, 123456),
-            Ok(1)
+            state.add_token(pr(100), "BIG".into(), 25, 19, None, 0),
+            Err("tokens with more than 18 decimals are not supported".into())
         );
+        assert!(state.token(pr(100)).is_err());
+
+        // the amounts of the sell order, the partial fill and the expected payment
+        for (token, decimals, amount, fill, payment) in [
+            (pr(101), 0, 10, 4, 400000),
+            (pr(102), 18, 10_u128.pow(18), 4 * 10_u128.pow(17), 40000),
+        ] {
+            list_test_token(state, token, decimals);
+            state.add_liquidity(pr(0), token, amount);
+            let payment_balance = state.token_pool_balance(payment_token_id, pr(0));
+            assert!(create_order(state, pr(0), token, amount, 100000, 0, OrderType::Sell).is_ok());
+            assert_eq!(
+                trade(state, OrderType::Buy, pr(1), token, fill, None, 0),
+                Ok(fill)
+            );
+            assert_eq!(state.token_pool_balance(token, pr(1)), fill);
+            let rest = state.orders(token, OrderType::Sell).next().unwrap();
+            assert_eq!(rest.amount, amount - fill);
+            assert_eq!(
+                state.token_pool_balance(payment_token_id, pr(0)),
+                payment_balance + payment - payment * MAKER_FEE / FEE_BASE
+            );
+        }
     }
 
     #[test]
-    fn test_order_closing() {
+    fn test_sweepable_amount() {
         let state = &mut State::default();
         list_payment_token(state);
-
         let token = pr(100);
-
         list_test_token(state, token, 2);
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), token, 50);
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
 
-        state.add_liquidity(pr(0), token, 111);
-        state.add_liquidity(pr(0), token, 222);
-
-        assert_eq!(state.token_balances(pr(0)).get(&token).unwrap().0, 333);
-
+        // 40 tokens were sent directly to the main account; the transfer fee is 25
+        assert_eq!(state.sweepable_amount(token, 190, 25), Ok(15));
+        // the excess must cover the fee
         assert_eq!(
-            create_order(state, pr(0), token, 250, 10, 0, OrderType::Sell),
-            Ok(())
+            state.sweepable_amount(token, 175, 25),
+            Err("no unaccounted funds to sweep".into())
         );
+        // missing funds can't be swept
         assert_eq!(
-            create_order(state, pr(0), token, 50, 30, 0, OrderType::Sell),
-            Ok(())
+            state.sweepable_amount(token, 100, 25),
+            Err("no unaccounted funds to sweep".into())
+        );
+        // pending withdrawals are still accounted
+        let reserved = state.reserve_withdrawal(pr(1), token).unwrap();
+        assert_eq!(reserved, 50);
+        assert_eq!(state.sweepable_amount(token, 190, 25), Ok(15));
+        // the funds of a recovering state are unknown
+        state.recovering = true;
+        assert_eq!(
+            state.sweepable_amount(token, 190, 25),
+            Err("the state is being recovered".into())
         );
+    }
 
+    #[test]
+    fn test_price_impact() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), payment_token_id, 10_000_000);
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), token, 10, 200000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(1), token, 10, 50000, 0, OrderType::Buy).is_ok());
+        assert!(create_order(state, pr(1), token, 10, 40000, 0, OrderType::Buy).is_ok());
+
+        // the best order alone causes no impact
         assert_eq!(
-            state.token_balances(pr(0)).get(&token).unwrap().0,
-            333 - 250 - 50
+            state.price_impact(OrderType::Buy, pr(1), token, 10),
+            Some(PriceImpact {
+                avg_fill_price: 100000,
+                best_price: 100000,
+                impact_bps: 0,
+            })
         );
         assert_eq!(
-            close_order(state, pr(0), token, 50, 30, 0, OrderType::Sell),
-            Ok(())
+            state.price_impact(OrderType::Buy, pr(1), token, 20),
+            Some(PriceImpact {
+                avg_fill_price: 150000,
+                best_price: 100000,
+                impact_bps: 5000,
+            })
         );
+        // sells move the price down
         assert_eq!(
-            close_order(state, pr(0), token, 250, 10, 0, OrderType::Sell),
-            Ok(())
+            state.price_impact(OrderType::Sell, pr(0), token, 15),
+            Some(PriceImpact {
+                avg_fill_price: 46666,
+                best_price: 50000,
+                impact_bps: 666,
+            })
         );
-        assert_eq!(state.token_balances(pr(0)).get(&token).unwrap().0, 333);
-
-        state.add_liquidity(pr(0), state.payment_token_id(), 8 * 100000);
-        assert!(create_order(state, pr(0), token, 3, 10000000, 0, OrderType::Buy).is_ok());
+        // the book can't fill the amount
+        assert_eq!(state.price_impact(OrderType::Buy, pr(1), token, 21), None);
+        assert_eq!(state.price_impact(OrderType::Buy, pr(1), token, 0), None);
+        // own orders are skipped
+        assert_eq!(state.price_impact(OrderType::Buy, pr(0), token, 10), None);
+    }
 
-        let volume = 3 * 100000;
+    #[test]
+    fn test_throttling_exemption() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        for user in [pr(0), pr(1)] {
+            state.add_liquidity(user, token, 1000);
+        }
+        state.set_throttling_exemption(pr(1), true);
 
+        // the first order is not counted
+        let orders = MAX_ORDERS_PER_HOUR as u64 + 1;
+        for i in 0..orders {
+            assert!(create_order(state, pr(0), token, 1, 100000, i, OrderType::Sell).is_ok());
+            assert!(create_order(state, pr(1), token, 1, 100000, i, OrderType::Sell).is_ok());
+        }
         assert_eq!(
-            state
-                .token_balances(pr(0))
-                .get(&state.payment_token_id())
-                .copied()
-                .unwrap()
-                .0,
-            8 * 100000 - volume - trading_fee(volume)
+            create_order(state, pr(0), token, 1, 100000, orders, OrderType::Sell),
+            Err("too many orders within one hour; please try again later".into())
         );
+        assert!(create_order(state, pr(1), token, 1, 100000, orders, OrderType::Sell).is_ok());
 
+        // once revoked, the limit applies again
+        state.set_throttling_exemption(pr(1), false);
+        for i in 0..orders {
+            assert!(create_order(
+                state,
+                pr(1),
+                token,
+                1,
+                100000,
+                orders + 1 + i,
+                OrderType::Sell
+            )
+            .is_ok());
+        }
         assert_eq!(
-            create_order(state, pr(0), token, 3, 10000000, 0, OrderType::Buy),
-            Err("order exists already".into())
+            create_order(
+                state,
+                pr(1),
+                token,
+                1,
+                100000,
+                2 * orders + 1,
+                OrderType::Sell
+            ),
+            Err("too many orders within one hour; please try again later".into())
         );
+    }
 
-        assert!(create_order(state, pr(0), token, 4, 10000000, 0, OrderType::Buy).is_ok());
+    #[test]
+    fn test_historical_prices_after_decimals_change() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(1), payment_token_id, 10_000_000);
+
+        // 0.1 tokens get traded at the same price before and after the decimals change
+        for (decimals, amount, time) in [(2, 10, 0), (4, 1000, HOUR)] {
+            list_test_token(state, token, decimals);
+            state.add_liquidity(pr(0), token, amount);
+            assert!(
+                create_order(state, pr(0), token, amount, 100000, time, OrderType::Sell).is_ok()
+            );
+            assert_eq!(
+                trade(state, OrderType::Buy, pr(1), token, amount, None, time),
+                Ok(amount)
+            );
+        }
 
-        let volume2 = 4 * 100000;
+        let prices = state.recent_prices(10).remove(&token).unwrap();
         assert_eq!(
-            state
-                .token_balances(pr(0))
-                .get(&state.payment_token_id())
-                .copied()
-                .unwrap()
-                .0,
-            8 * 100000 - volume - trading_fee(volume) - volume2 - trading_fee(volume2)
+            prices
+                .iter()
+                .map(|order| (order.amount, order.decimals, order.price, order.volume()))
+                .collect::<Vec<_>>(),
+            vec![(1000, 4, 100000, 10000), (10, 2, 100000, 10000)]
         );
+        assert_eq!(state.prices().get(&token).unwrap().decimals, 4);
+        let ticker = state.ticker(token, HOUR);
+        assert_eq!(ticker.volume_24h, 20000);
         assert_eq!(
-            close_order(state, pr(0), token, 3, 10000000, 0, OrderType::Buy),
-            Ok(())
+            (ticker.low_24h, ticker.high_24h),
+            (Some(100000), Some(100000))
         );
+    }
+
+    #[test]
+    fn test_close_user_orders() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let (token_a, token_b) = (pr(100), pr(101));
+        list_test_token(state, token_a, 2);
+        list_test_token(state, token_b, 2);
+        let payment_token_id = state.payment_token_id();
+        for user in [pr(0), pr(1)] {
+            state.add_liquidity(user, token_a, 100);
+            state.add_liquidity(user, token_b, 100);
+            state.add_liquidity(user, payment_token_id, 1_000_000);
+        }
+        for (time, user) in [(0, pr(0)), (1, pr(1))] {
+            assert!(create_order(state, user, token_a, 10, 200000, time, OrderType::Sell).is_ok());
+            assert!(create_order(state, user, token_a, 10, 50000, time, OrderType::Buy).is_ok());
+            assert!(create_order(state, user, token_b, 10, 200000, time, OrderType::Sell).is_ok());
+        }
         assert_eq!(
-            close_order(state, pr(0), token, 4, 10000000, 0, OrderType::Buy),
+            state.create_stop_order(pr(0), token_b, 10, 300000, 300000, 2, OrderType::Buy),
             Ok(())
         );
+
+        // only the orders of the token get closed
+        assert_eq!(state.close_user_orders(pr(0), Some(token_a)), 2);
+        assert_eq!(state.token_pool_balance(token_a, pr(0)), 100);
+        // the stop-limit order still reserves its volume and the maker fee
         assert_eq!(
-            state
-                .token_balances(pr(0))
-                .get(&state.payment_token_id())
-                .copied()
-                .unwrap()
-                .0,
-            8 * 100000
+            state.token_pool_balance(payment_token_id, pr(0)),
+            1_000_000 - 30000 - 30
         );
+        assert_eq!(state.user_orders(pr(0)).len(), 1);
+
+        // the remaining orders get closed, including stop-limit orders
+        assert_eq!(state.close_user_orders(pr(0), None), 2);
+        assert!(state.user_orders(pr(0)).is_empty());
+        assert!(state.user_stop_orders(pr(0)).is_empty());
+        assert_eq!(state.token_pool_balance(token_b, pr(0)), 100);
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(0)), 1_000_000);
+        assert_eq!(state.close_user_orders(pr(0), None), 0);
+
+        // orders of other users are not affected
+        assert_eq!(state.user_orders(pr(1)).len(), 3);
     }
 
     #[test]
-    fn test_liquidity_adding_and_withdrawals() {
+    fn test_trade_fees() {
         let state = &mut State::default();
         list_payment_token(state);
-
         let token = pr(100);
-
         list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), payment_token_id, 1_000_000);
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
 
-        state.add_liquidity(pr(0), token, 111);
-        state.add_liquidity(pr(0), token, 222);
-
-        assert_eq!(state.token_balances(pr(0)).get(&token).unwrap().0, 333);
-
+        let buy = |state: &mut State, amount, price, post_only| {
+            state.trade(
+                OrderType::Buy,
+                pr(1),
+                token,
+                amount,
+                price,
+                1,
+                Default::default(),
+                None,
+                post_only,
+                None,
+                None,
+            )
+        };
+        // the filled part pays the taker fee, the rest order at most the maker fee
+        let balance = state.token_pool_balance(payment_token_id, pr(1));
         assert_eq!(
-            create_order(state, pr(0), token, 250, 10, 0, OrderType::Sell),
-            Ok(())
+            buy(state, 15, 100000, false),
+            Ok(TradeReceipt {
+                execution: OrderExecution::FilledAndOrderCreated(10),
+                fees: OrderFees {
+                    taker_fee: 20,
+                    max_maker_fee: 5,
+                },
+            })
         );
-
         assert_eq!(
-            state.token_balances(pr(0)).get(&token).unwrap().0,
-            333 - 250
+            state.token_pool_balance(payment_token_id, pr(1)),
+            balance - 10000 - 20 - 5000 - 5
+        );
+        // a post-only order pays no taker fee
+        assert_eq!(
+            buy(state, 20, 90000, true).map(|receipt| receipt.fees),
+            Ok(OrderFees {
+                taker_fee: 0,
+                max_maker_fee: 18,
+            })
         );
+        // a filled market order creates no rest order
+        assert!(create_order(state, pr(0), token, 10, 100000, 2, OrderType::Sell).is_ok());
+        assert_eq!(
+            buy(state, 10, 0, false).map(|receipt| receipt.fees),
+            Ok(OrderFees {
+                taker_fee: 20,
+                max_maker_fee: 0,
+            })
+        );
+    }
 
-        assert_eq!(user_orders(state, token, pr(0), OrderType::Buy).count(), 0);
-        let sell_orders = user_orders(state, token, pr(0), OrderType::Sell).collect::<Vec<_>>();
-        assert_eq!(sell_orders.len(), 1);
-        assert_eq!(sell_orders.first().unwrap().amount, 250);
+    #[test]
+    fn test_holders() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        assert!(state.holders(token, 0, 10).is_empty());
+        assert!(state.holders(pr(101), 0, 10).is_empty());
 
+        state.add_liquidity(pr(0), token, 50);
+        state.add_liquidity(pr(1), token, 200);
+        state.add_liquidity(pr(2), token, 100);
+        state.add_liquidity(pr(3), token, 100);
         assert_eq!(
-            state.withdraw_liquidity(pr(1), token),
-            Err("nothing to withdraw".into())
+            state.holders(token, 0, 10),
+            vec![(pr(1), 200), (pr(2), 100), (pr(3), 100), (pr(0), 50)]
         );
-        assert_eq!(state.withdraw_liquidity(pr(0), token), Ok(333 - 250));
+        assert_eq!(state.holders(token, 1, 2), vec![(pr(2), 100), (pr(3), 100)]);
 
-        let sell_orders = user_orders(state, token, pr(0), OrderType::Sell).collect::<Vec<_>>();
-        assert_eq!(sell_orders.len(), 1);
+        // reserved liquidity is not counted and empty balances are skipped
+        assert!(create_order(state, pr(0), token, 50, 100000, 0, OrderType::Sell).is_ok());
+        assert_eq!(state.holders(token, 3, 10), vec![]);
+    }
 
-        let one_icp = 100000000;
-        state.add_liquidity(pr(0), state.payment_token_id(), one_icp);
+    #[test]
+    fn test_client_order_ids() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), payment_token_id, 1_000_000);
+        state.add_liquidity(pr(2), payment_token_id, 1_000_000);
+        assert!(create_order(state, pr(0), token, 50, 100000, 0, OrderType::Sell).is_ok());
+
+        let buy = |state: &mut State, user, now, client_order_id| {
+            state
+                .trade(
+                    OrderType::Buy,
+                    user,
+                    token,
+                    10,
+                    0,
+                    now,
+                    Default::default(),
+                    None,
+                    false,
+                    None,
+                    client_order_id,
+                )
+                .map(|receipt| receipt.execution)
+        };
+        let id = Some([7; 32]);
+        assert_eq!(buy(state, pr(1), 1, id), Ok(OrderExecution::Filled(10)));
+        // the retry returns the prior execution without trading again
+        assert_eq!(buy(state, pr(1), 2, id), Ok(OrderExecution::Filled(10)));
+        assert_eq!(state.token_pool_balance(token, pr(1)), 10);
+        // trades without an id or with another id get executed
+        assert_eq!(buy(state, pr(1), 3, None), Ok(OrderExecution::Filled(10)));
         assert_eq!(
-            state.withdraw_liquidity(pr(1), state.payment_token_id()),
-            Err("nothing to withdraw".into())
+            buy(state, pr(1), 4, Some([8; 32])),
+            Ok(OrderExecution::Filled(10))
+        );
+        assert_eq!(state.token_pool_balance(token, pr(1)), 30);
+        // ids are scoped to the user
+        assert_eq!(buy(state, pr(2), 5, id), Ok(OrderExecution::Filled(10)));
+        assert_eq!(state.token_pool_balance(token, pr(2)), 10);
+
+        // the id can be reused after the window and gets purged by the clean up
+        assert_eq!(
+            buy(state, pr(1), 1 + CLIENT_ORDER_ID_WINDOW, id),
+            Ok(OrderExecution::Filled(10))
+        );
+        assert_eq!(state.token_pool_balance(token, pr(1)), 40);
+        state.clean_up(5 + CLIENT_ORDER_ID_WINDOW);
+        assert_eq!(state.client_orders.len(), 1);
+
+        // the number of remembered ids is capped per user
+        let now = 5 + CLIENT_ORDER_ID_WINDOW;
+        for i in 0..MAX_CLIENT_ORDER_IDS_PER_USER {
+            let mut id = [0; 32];
+            id[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            state.client_orders.insert(
+                (pr(1), id),
+                (now, state.client_orders.values().next().unwrap().1.clone()),
+            );
+        }
+        assert_eq!(
+            buy(state, pr(1), now, Some([9; 32])),
+            Err("too many client order ids; please try again later".into())
         );
+        // other users are not affected; the book is empty by now
         assert_eq!(
-            state.withdraw_liquidity(pr(0), state.payment_token_id()),
-            Ok(100000000)
+            buy(state, pr(2), now, Some([9; 32])),
+            Ok(OrderExecution::Filled(0))
         );
     }
 
     #[test]
-    fn test_selling() {
+    fn test_max_orders_per_book() {
         let state = &mut State::default();
         list_payment_token(state);
-
-        state.revenue_account = Some(pr(255));
-
         let token = pr(100);
-
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 1000);
+        state.add_liquidity(pr(1), payment_token_id, 1_000_000);
         assert_eq!(
-            create_order(state, pr(0), token, 7, 50000000, 0, OrderType::Buy),
-            Err("token not listed".into())
+            state.set_max_orders_per_book(0),
+            Err("the limit must be positive".into())
         );
+        assert_eq!(state.set_max_orders_per_book(3), Ok(()));
 
-        list_test_token(state, token, 2);
-
-        // buy order for 7 $TAGGR / 0.1 ICP each
+        for i in 0..2 {
+            assert!(create_order(
+                state,
+                pr(0),
+                token,
+                10,
+                100000 + i as u128,
+                i * HOUR,
+                OrderType::Sell
+            )
+            .is_ok());
+        }
+        // stop-limit orders count against the capacity
         assert_eq!(
-            create_order(state, pr(0), token, 7, 10000000, 0, OrderType::Buy),
-            Err("no funds available".into())
+            state.create_stop_order(pr(0), token, 10, 90000, 90000, 2 * HOUR, OrderType::Sell),
+            Ok(())
         );
-
-        state.add_liquidity(pr(0), state.payment_token_id(), 8 * 100000);
-        assert!(create_order(state, pr(0), token, 7, 10000000, 0, OrderType::Buy).is_ok());
-
-        // buy order for 16 $TAGGR / 0.03 ICP each
-        state.add_liquidity(pr(1), state.payment_token_id(), 17 * 300000);
-        assert!(create_order(state, pr(1), token, 16, 3000000, 0, OrderType::Buy).is_ok());
-
-        // buy order for 25 $TAGGR / 0.01 ICP each
-        state.add_liquidity(pr(2), state.payment_token_id(), 24 * 10000);
         assert_eq!(
-            create_order(state, pr(2), token, 25, 1000000, 0, OrderType::Buy),
-            Err("not enough funds available for this order size".into())
+            create_order(state, pr(0), token, 10, 200000, 3 * HOUR, OrderType::Sell),
+            Err("the order book is full".into())
         );
-        state.add_liquidity(pr(2), state.payment_token_id(), 2 * 10000);
-        assert!(create_order(state, pr(2), token, 25, 1000000, 0, OrderType::Buy).is_ok());
+        assert_eq!(state.token_pool_balance(token, pr(0)), 970);
+        // the other side is not affected
+        assert!(create_order(state, pr(1), token, 10, 50000, 0, OrderType::Buy).is_ok());
 
-        // buyer has 0.01 ICP left minus fee
-        assert_eq!(state.payment_token_pool().get(&pr(2)).unwrap(), &9500);
-
-        let buyer_orders = &state.orders.get(&token).unwrap().buyers;
+        // a limit trade is rejected before the execution if its rest order doesn't fit
+        assert_eq!(state.set_max_orders_per_book(1), Ok(()));
         assert_eq!(
-            buyer_orders
-                .iter()
-                .map(|order| order.price)
-                .collect::<Vec<_>>(),
-            vec![1000000, 3000000, 10000000]
+            state
+                .trade(
+                    OrderType::Buy,
+                    pr(1),
+                    token,
+                    5,
+                    100000,
+                    HOUR,
+                    Default::default(),
+                    None,
+                    false,
+                    None,
+                    None,
+                )
+                .map(|receipt| receipt.execution),
+            Err("the order book is full".into())
         );
-        let best_order = buyer_orders.last().unwrap();
-        assert_eq!(best_order.amount, 7);
-        assert_eq!(best_order.price, 10000000);
+        assert_eq!(state.token_pool_balance(token, pr(1)), 0);
 
-        // three buyers
-        assert_eq!(state.payment_token_pool().len(), 3);
-        let buyer_orders = &state.orders.get(&token).unwrap().buyers;
-        // we have 3 orders
-        assert_eq!(buyer_orders.len(), 3);
+        // closing an order frees the capacity
+        assert_eq!(state.set_max_orders_per_book(3), Ok(()));
+        assert!(close_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), token, 10, 200000, 3 * HOUR, OrderType::Sell).is_ok());
+    }
 
-        let seller = pr(5);
+    #[test]
+    fn test_payment_token() {
+        let state = &mut State::default();
+        assert!(state.payment_token().is_none());
+        list_payment_token(state);
+        let (id, metadata) = state.payment_token().unwrap();
+        assert_eq!(id, state.payment_token_id());
+        assert_eq!((metadata.symbol.as_str(), metadata.decimals), ("USD", 8));
+    }
+
+    #[test]
+    fn test_queue_position() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        for user in [pr(0), pr(1), pr(2)] {
+            state.add_liquidity(user, token, 100);
+            state.add_liquidity(user, payment_token_id, 1_000_000);
+        }
+
+        assert!(create_order(state, pr(0), token, 10, 200000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(1), token, 10, 200000, 1, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(2), token, 10, 150000, 2, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), token, 10, 50000, 3, OrderType::Buy).is_ok());
+        assert!(create_order(state, pr(1), token, 10, 60000, 4, OrderType::Buy).is_ok());
 
+        // the cheapest sell order comes first, equal prices are ordered by time
         assert_eq!(
-            trade(
-                &mut state.clone(),
-                OrderType::Sell,
-                seller,
-                token,
-                5,
-                None,
-                123456
-            ),
-            Err("not enough tokens".into())
+            state.queue_position(pr(2), token, OrderType::Sell, 10, 150000, 2),
+            Some(0)
         );
-        state.add_liquidity(seller, token, 250);
         assert_eq!(
-            trade(state, OrderType::Sell, seller, token, 5, None, 123456),
-            Ok(5)
+            state.queue_position(pr(0), token, OrderType::Sell, 10, 200000, 0),
+            Some(1)
+        );
+        assert_eq!(
+            state.queue_position(pr(1), token, OrderType::Sell, 10, 200000, 1),
+            Some(2)
         );
 
-        // verify the partial filling
-        let buyer_orders = &state.orders.get(&token).unwrap().buyers;
-        // we still have 3 orders
-        assert_eq!(buyer_orders.len(), 3);
-        let best_order = buyer_orders.last().unwrap();
-        // less tokens to buy at the given price as before
-        assert_eq!(best_order.amount, 2);
-        assert_eq!(best_order.price, 10000000);
-
-        let archived_orders = state.order_archive.get(&token).unwrap();
-        assert_eq!(archived_orders.len(), 1);
-        let executed_order = archived_orders.front().unwrap();
-        assert_eq!(executed_order.executed, 123456);
-        // only 5 tokens got traded
-        assert_eq!(executed_order.amount, 5);
-
-        // buyer got 5 tokens
-        assert_eq!(state.pools.get(&token).unwrap().get(&pr(0)).unwrap(), &5);
-
-        // now seller should get a balance too, plus the fee acount
-        assert_eq!(state.payment_token_pool().len(), 5);
-        // seller has expected amount of ICP: 5 * 0.1 ICP - fee
-        let volume = 500000;
-        let fee_per_side = trading_fee(volume);
+        // the highest buy order comes first
         assert_eq!(
-            state.payment_token_pool().get(&seller).unwrap(),
-            &(volume - fee_per_side)
+            state.queue_position(pr(1), token, OrderType::Buy, 10, 60000, 4),
+            Some(0)
         );
-        // buyer should have previous amount - volume - fee;
-        assert_eq!(state.payment_token_pool().get(&pr(0)).unwrap(), &98600);
-        // fee account has 2 fee
         assert_eq!(
-            state.payment_token_pool().get(&pr(255)).unwrap(),
-            &(2 * fee_per_side)
+            state.queue_position(pr(0), token, OrderType::Buy, 10, 50000, 3),
+            Some(1)
         );
 
-        // let's sell more
-        // at that point we have buy orders: 25 @ 0.01, 16 @ 0.03, 2 @ 0.1
-        let buyer_orders = &state.orders.get(&token).unwrap().buyers;
-        assert_eq!(buyer_orders.len(), 3);
-        let best_order = buyer_orders.last().unwrap();
-        assert_eq!(best_order.amount, 2);
-        assert_eq!(best_order.price, 10000000);
-
+        // the position matches the matching order of trades
         assert_eq!(
-            trade(state, OrderType::Sell, seller, token, 10, None, 123457),
-            Ok(10)
+            state
+                .matching_orders(OrderType::Buy, token.into())
+                .map(|order| order.owner)
+                .collect::<Vec<_>>(),
+            vec![pr(2), pr(0), pr(1)]
         );
 
-        // we should have only two now
-        let buyer_orders = &state.orders.get(&token).unwrap().buyers;
-        assert_eq!(buyer_orders.len(), 2);
-        let best_order = buyer_orders.last().unwrap();
-        assert_eq!(best_order.amount, 8);
-        assert_eq!(best_order.price, 3000000);
-
-        // seller still has 250 - 15 tokens
+        // orders of other users or unknown orders have no position
         assert_eq!(
-            state.pools.get(&token).unwrap().get(&seller).unwrap(),
-            &(250 - 15)
+            state.queue_position(pr(1), token, OrderType::Sell, 10, 200000, 0),
+            None
+        );
+        assert_eq!(
+            state.queue_position(pr(0), pr(101), OrderType::Sell, 10, 200000, 0),
+            None
         );
 
-        // at that point we have buy orders: 25 @ 0.01, 8 @ 0.03
+        // the position moves up once orders ahead get filled
+        assert!(trade(state, OrderType::Buy, pr(1), token, 10, None, 5).is_ok());
         assert_eq!(
-            trade(state, OrderType::Sell, seller, token, 150, None, 123457),
-            Ok(33)
+            state.queue_position(pr(0), token, OrderType::Sell, 10, 200000, 0),
+            Some(0)
         );
+    }
+
+    #[test]
+    fn test_domains() {
+        let state = &mut State::default();
+        assert_eq!(state.domains().len(), DEFAULT_DOMAINS.len());
+        assert_eq!(state.domains()[0], "beacondex.link");
 
-        // seller still has 250 - 30 - 18 tokens
         assert_eq!(
-            state.pools.get(&token).unwrap().get(&seller).unwrap(),
-            &(250 - 30 - 18)
+            state.add_domain("https://example.com".into()),
+            Err("invalid domain".into())
         );
-        // all buyer got their tokens
-        assert_eq!(state.pools.get(&token).unwrap().get(&pr(0)).unwrap(), &7);
-        assert_eq!(state.pools.get(&token).unwrap().get(&pr(1)).unwrap(), &16);
-        assert_eq!(state.pools.get(&token).unwrap().get(&pr(2)).unwrap(), &25);
+        assert_eq!(state.add_domain("".into()), Err("invalid domain".into()));
+        assert_eq!(state.add_domain("example.com".into()), Ok(()));
+        assert_eq!(
+            state.add_domain("example.com".into()),
+            Err("domain already added".into())
+        );
+        assert_eq!(state.domains().len(), DEFAULT_DOMAINS.len() + 1);
 
-        // executed orders: 25 @ 0.1, 16 @ 0.03, 7 @ 0.05
-        let (v1, v2, v3) = (25 * 10000, 16 * 30000, 7 * 100000);
-        let fee = trading_fee(v1) + trading_fee(v2) + trading_fee(v3);
+        // default domains can be removed as well
+        assert_eq!(state.remove_domain("beacondex.link".into()), Ok(()));
         assert_eq!(
-            state.payment_token_pool().get(&seller).unwrap(),
-            &(v1 + v2 + v3 - fee)
+            state.remove_domain("beacondex.link".into()),
+            Err("domain not found".into())
         );
         assert_eq!(
-            state.payment_token_pool().get(&pr(255)).unwrap(),
-            &(2 * fee)
+            state.domains(),
+            vec![
+                "srn4v-3aaaa-aaaar-qaftq-cai.icp0.io".to_string(),
+                "cetrr-jaaaa-aaaak-afgxq-cai.icp0.io".to_string(),
+                "example.com".to_string()
+            ]
         );
     }
 
     #[test]
-    fn test_buying() {
+    fn test_concurrent_withdrawals() {
         let state = &mut State::default();
         list_payment_token(state);
+        let tokens = [pr(100), pr(101), pr(102)];
+        for token in tokens {
+            list_test_token(state, token, 2);
+            state.add_liquidity(pr(0), token, 1000);
+            state.add_liquidity(pr(1), token, 500);
+        }
+        let funds = state.funds_under_management();
 
-        state.revenue_account = Some(pr(255));
+        // all balances get reserved before any transfer completes
+        let reserved = tokens
+            .iter()
+            .map(|token| state.reserve_withdrawal(pr(0), *token).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(reserved, vec![1000, 1000, 1000]);
+        assert_eq!(state.funds_under_management(), funds);
+        for token in tokens {
+            assert_eq!(state.token_pool_balance(token, pr(0)), 0);
+        }
+
+        // the transfers complete in any order, some of them fail
+        assert_eq!(state.release_withdrawal(pr(0), tokens[1], 1000), Ok(()));
+        assert_eq!(state.commit_withdrawal(pr(0), tokens[2], 1000), Ok(()));
+        assert_eq!(state.commit_withdrawal(pr(0), tokens[0], 1000), Ok(()));
+
+        assert_eq!(state.token_pool_balance(tokens[0], pr(0)), 0);
+        assert_eq!(state.token_pool_balance(tokens[1], pr(0)), 1000);
+        assert_eq!(state.token_pool_balance(tokens[2], pr(0)), 0);
+        for token in tokens {
+            assert_eq!(state.token_pool_balance(token, pr(1)), 500);
+            assert_eq!(
+                state.commit_withdrawal(pr(0), token, 1),
+                Err("no withdrawal pending".into())
+            );
+        }
 
+        // a failed withdrawal can be retried
+        assert_eq!(state.reserve_withdrawal(pr(0), tokens[1]), Ok(1000));
+        assert_eq!(state.commit_withdrawal(pr(0), tokens[1], 1000), Ok(()));
+        assert_eq!(state.token_pool_balance(tokens[1], pr(0)), 0);
+    }
+
+    #[test]
+    fn test_uncross() {
+        let state = &mut State::default();
+        list_payment_token(state);
         let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        for user in [pr(0), pr(1)] {
+            state.add_liquidity(user, token, 100);
+            state.add_liquidity(user, payment_token_id, 1_000_000);
+        }
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), token, 10, 120000, 1, OrderType::Sell).is_ok());
+        assert!(state.crossed_books().is_empty());
+
+        // orders created without matching cross the book
+        assert!(create_order(state, pr(1), token, 15, 110000, 2, OrderType::Buy).is_ok());
+        assert_eq!(state.crossed_books(), vec![(token, None)]);
+        let funds = state.funds_under_management();
 
+        // the newer buy order takes the cheaper sell order and its rest stays in the book
+        assert_eq!(state.uncross(token, 3), Ok(1));
+        assert!(state.crossed_books().is_empty());
+        assert_eq!(state.funds_under_management(), funds);
+        assert_eq!(state.token_pool_balance(token, pr(1)), 110);
+        let buyers = state.orders(token, OrderType::Buy).collect::<Vec<_>>();
+        assert_eq!(buyers.len(), 1);
+        assert_eq!((buyers[0].amount, buyers[0].price), (5, 110000));
+        let sellers = state.orders(token, OrderType::Sell).collect::<Vec<_>>();
+        assert_eq!(sellers.len(), 1);
+        assert_eq!(sellers[0].price, 120000);
+        // the re-added rest doesn't count against the rate limit
         assert_eq!(
-            create_order(
-                &mut state.clone(),
-                pr(0),
-                token,
-                7,
-                5000000,
-                0,
-                OrderType::Sell
-            ),
-            Err("token not listed".into())
+            state
+                .order_activity
+                .get(&pr(1))
+                .map(|records| records.len()),
+            Some(0)
         );
 
-        list_test_token(state, token, 2);
+        // a book which isn't crossed stays untouched
+        assert_eq!(state.uncross(token, 4), Ok(0));
+        assert_eq!(state.orders(token, OrderType::Buy).count(), 1);
 
-        // sell order for 7 $TAGGR / 0.05 ICP each
+        // an order whose owner can't pay the taker fee stays in the book
+        state.add_liquidity(pr(2), payment_token_id, 12000 + maker_fee(12000));
+        assert!(create_order(state, pr(2), token, 10, 120000, 5, OrderType::Buy).is_ok());
+        let funds = state.funds_under_management();
         assert_eq!(
-            create_order(
-                &mut state.clone(),
-                pr(0),
-                token,
-                7,
-                5000000,
-                0,
-                OrderType::Sell
-            ),
-            Err("no funds available".into())
+            state.uncross(token, 6),
+            Err(format!(
+                "the order of {} can't be re-executed: not enough funds",
+                pr(2)
+            ))
         );
+        assert_eq!(state.funds_under_management(), funds);
+        assert_eq!(user_orders(state, token, pr(2), OrderType::Buy).count(), 1);
+        assert_eq!(state.crossed_books(), vec![(token, None)]);
+    }
 
-        state.add_liquidity(pr(0), token, 7);
-        assert!(create_order(state, pr(0), token, 7, 5000000, 0, OrderType::Sell).is_ok());
+    #[test]
+    fn test_trading_fees() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        assert_eq!(state.trading_fees(), TradingFees::default());
+        for user in [pr(0), pr(1)] {
+            state.add_liquidity(user, token, 100);
+            state.add_liquidity(user, payment_token_id, 1_000_000);
+        }
 
-        // sell order for 16 $TAGGR / 0.03 ICP each
-        state.add_liquidity(pr(1), token, 16);
-        assert!(create_order(state, pr(1), token, 16, 3000000, 0, OrderType::Sell).is_ok());
+        let fees = TradingFees {
+            maker: 50,
+            taker: 100,
+        };
+        assert_eq!(
+            state.set_trading_fees(TradingFees {
+                maker: 50,
+                taker: 101
+            }),
+            Err("the fees must not exceed 100 basis points".into())
+        );
 
-        // sell order for 25 $TAGGR / 1 ICP each
-        state.add_liquidity(pr(2), token, 24);
+        // the fees can't change while orders reserve the maker fee
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Buy).is_ok());
         assert_eq!(
-            create_order(
-                &mut state.clone(),
-                pr(2),
+            state.set_trading_fees(fees),
+            Err("all orders must be closed before the fees can be changed".into())
+        );
+        assert_eq!(
+            state.close_order(pr(0), token, 10, 100000, 0, OrderType::Buy),
+            Ok(())
+        );
+        assert_eq!(state.set_trading_fees(fees), Ok(()));
+        assert_eq!(state.trading_fees(), fees);
+
+        // a buy order over a volume of 10000 reserves the new maker fee of 0.5%
+        assert_eq!(
+            state.create_order(
+                pr(0),
                 token,
-                25,
-                100000000,
-                0,
-                OrderType::Sell
+                10,
+                100000,
+                1,
+                OrderType::Buy,
+                None,
+                false,
+                None
             ),
-            Err("not enough funds available for this order size".into())
+            Ok(OrderFees {
+                taker_fee: 0,
+                max_maker_fee: 50
+            })
+        );
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(0)),
+            1_000_000 - 10000 - 50
         );
-        state.add_liquidity(pr(2), token, 1);
-        assert!(create_order(state, pr(2), token, 25, 100000000, 0, OrderType::Sell).is_ok());
-
-        // Order book: 16 @ 0.03, 7 @ 0.05, 25 @ 1
-        let sell_orders = &state.orders.get(&token).unwrap().sellers;
-        assert_eq!(sell_orders.len(), 3);
-        let best_order = sell_orders.first().unwrap();
-        assert_eq!(best_order.amount, 16);
-        assert_eq!(best_order.price, 3000000);
 
-        // three sellers
-        assert_eq!(state.pools.get(&token).unwrap().len(), 3);
+        // the taker pays the new taker fee of 1%
+        let revenue = state.token_pool_balance(payment_token_id, pr(255));
+        assert_eq!(
+            trade(state, OrderType::Sell, pr(1), token, 10, None, 2),
+            Ok(10)
+        );
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(1)),
+            1_000_000 + 10000 - 100
+        );
+        assert_eq!(state.token_pool_balance(token, pr(0)), 110);
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(255)),
+            revenue + 50 + 100
+        );
+    }
 
-        let buyer = pr(5);
+    #[test]
+    fn test_orders_in_range() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        for user in [pr(0), pr(1)] {
+            state.add_liquidity(user, token, 100);
+            state.add_liquidity(user, payment_token_id, 1_000_000);
+        }
+        for (time, user, price) in [
+            (0, pr(0), 100000),
+            (1, pr(1), 110000),
+            (2, pr(0), 120000),
+            (3, pr(1), 120000),
+            (4, pr(0), 130000),
+        ] {
+            assert!(create_order(state, user, token, 10, price, time, OrderType::Sell).is_ok());
+            assert!(
+                create_order(state, user, token, 10, price - 50000, time, OrderType::Buy).is_ok()
+            );
+        }
+        let prices = |orders: Vec<Order>| {
+            orders
+                .iter()
+                .map(|order| (order.price, order.timestamp))
+                .collect::<Vec<_>>()
+        };
 
-        // since we had no ICP we didn't buy anything
-        assert_eq!(state.pools.get(&token).unwrap().get(&buyer), None);
-        state.add_liquidity(buyer, state.payment_token_id(), 12 * 30000);
-        assert_eq!(state.payment_token_pool().len(), 1);
+        // the bounds are inclusive
         assert_eq!(
-            trade(state, OrderType::Buy, buyer, token, 10, None, 123456),
-            Ok(10)
+            prices(state.orders_in_range(token, OrderType::Sell, 110000, 120000)),
+            vec![(110000, 1), (120000, 2), (120000, 3)]
         );
+        assert_eq!(
+            prices(state.orders_in_range(token, OrderType::Buy, 60000, 70000)),
+            vec![(70000, 3), (70000, 2), (60000, 1)]
+        );
+        assert_eq!(
+            prices(state.orders_in_range(token, OrderType::Sell, 0, u128::MAX)),
+            prices(state.orders(token, OrderType::Sell).cloned().collect())
+        );
+        assert_eq!(
+            prices(state.orders_in_range(token, OrderType::Buy, 0, u128::MAX)),
+            prices(state.orders(token, OrderType::Buy).cloned().collect())
+        );
+        assert!(state
+            .orders_in_range(token, OrderType::Sell, 111000, 119000)
+            .is_empty());
+        assert!(state
+            .orders_in_range(token, OrderType::Sell, 120000, 110000)
+            .is_empty());
+        assert!(state
+            .orders_in_range(pr(101), OrderType::Sell, 0, u128::MAX)
+            .is_empty());
+    }
 
-        // verify the partial filling
-        let sell_orders = &state.orders.get(&token).unwrap().sellers;
-        // we still have 3 orders
-        assert_eq!(sell_orders.len(), 3);
-        let best_order = sell_orders.first().unwrap();
-        // less tokens to buy at the given price as before
-        assert_eq!(best_order.amount, 6);
-        assert_eq!(best_order.price, 3000000);
-
-        let archived_orders = state.order_archive.get(&token).unwrap();
-        assert_eq!(archived_orders.len(), 1);
-        let executed_order = archived_orders.front().unwrap();
-        assert_eq!(executed_order.executed, 123456);
-        // only 5 tokens got traded
-        assert_eq!(executed_order.amount, 10);
+    #[test]
+    fn test_identical_orders() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 100);
+        state.add_liquidity(pr(1), payment_token_id, 1_000_000);
+
+        // a batch of identical orders in one timestamp
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        let sellers = state.orders(token, OrderType::Sell).collect::<Vec<_>>();
+        assert_eq!(sellers.len(), 2);
+        assert_eq!((sellers[0].id, sellers[1].id), (1, 2));
+        assert_eq!(state.token_pool_balance(token, pr(0)), 80);
+        assert_eq!(
+            state.queue_position(pr(0), token, OrderType::Sell, 10, 100000, 0),
+            Some(0)
+        );
 
-        // buyer got 10 tokens
-        assert_eq!(state.pools.get(&token).unwrap().get(&buyer).unwrap(), &10);
+        // a partial fill keeps the id of the order
+        assert_eq!(
+            trade(state, OrderType::Buy, pr(1), token, 15, None, 1),
+            Ok(15)
+        );
+        let sellers = state.orders(token, OrderType::Sell).collect::<Vec<_>>();
+        assert_eq!(sellers.len(), 1);
+        assert_eq!((sellers[0].amount, sellers[0].id), (5, 2));
+
+        // identical orders are closed one by one
+        assert!(create_order(state, pr(0), token, 10, 100000, 2, OrderType::Sell).is_ok());
+        assert!(create_order(state, pr(0), token, 10, 100000, 2, OrderType::Sell).is_ok());
+        for _ in 0..2 {
+            assert_eq!(
+                close_order(state, pr(0), token, 10, 100000, 2, OrderType::Sell),
+                Ok(())
+            );
+        }
+        assert_eq!(
+            close_order(state, pr(0), token, 10, 100000, 2, OrderType::Sell),
+            Err("no order found".into())
+        );
+        // 15 tokens were sold and 5 are still reserved by the partially filled order
+        assert_eq!(state.token_pool_balance(token, pr(0)), 80);
+    }
 
-        // now seller should get a balance too, plus the fee acount
-        assert_eq!(state.payment_token_pool().len(), 3);
+    #[test]
+    fn test_compact_archive() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let (token_a, token_b) = (pr(100), pr(101));
+        list_test_token(state, token_a, 2);
+        list_test_token(state, token_b, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token_a, 100);
+        state.add_liquidity(pr(0), token_b, 100);
+        state.add_liquidity(pr(1), payment_token_id, 1_000_000);
+        for time in 0..5 {
+            for token in [token_a, token_b] {
+                assert!(create_order(
+                    state,
+                    pr(0),
+                    token,
+                    10,
+                    100000 + time as u128,
+                    time,
+                    OrderType::Sell
+                )
+                .is_ok());
+                assert_eq!(
+                    trade(state, OrderType::Buy, pr(1), token, 10, None, time),
+                    Ok(10)
+                );
+            }
+        }
+        assert_eq!(state.order_archive.len(token_a), 5);
 
-        // let's buy more
-        // at that point we have buy orders: 6 @ 0.03, 7 @ 0.05, 25 @ 1
-        state.add_liquidity(buyer, state.payment_token_id(), 6 * 30000 + 2 * 5000);
+        // the newest orders are kept
+        assert_eq!(state.compact_archive(token_a, 2), 3);
         assert_eq!(
-            trade(state, OrderType::Buy, buyer, token, 7, None, 123457),
-            Ok(7)
+            state
+                .order_archive
+                .iter(token_a)
+                .map(|order| order.price)
+                .collect::<Vec<_>>(),
+            vec![100004, 100003]
         );
-        // buyer got 17 tokens
-        assert_eq!(state.pools.get(&token).unwrap().get(&buyer).unwrap(), &17);
+        assert_eq!(state.compact_archive(token_a, 2), 0);
+        assert_eq!(state.order_archive.len(token_b), 5);
 
-        // we should have only two now
-        let sell_orders = &state.orders.get(&token).unwrap().sellers;
-        assert_eq!(sell_orders.len(), 2);
-        let best_order = sell_orders.first().unwrap();
-        assert_eq!(best_order.amount, 6);
-        assert_eq!(best_order.price, 5000000);
+        assert_eq!(state.compact_archive(token_b, 0), 5);
+        assert_eq!(state.order_archive.len(token_b), 0);
+    }
 
-        state.add_liquidity(buyer, state.payment_token_id(), 6 * 50000 + 28 * 1000000);
+    #[test]
+    fn test_positions() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        for user in [pr(0), pr(1)] {
+            state.add_liquidity(user, token, 100);
+            state.add_liquidity(user, payment_token_id, 1_000_000);
+        }
+        assert!(state.positions(pr(1)).is_empty());
+
+        // pr(1) buys 10 tokens at 1000 and 10 tokens at 1200 from pr(0)
+        for (time, price) in [(0, 100000), (1, 120000)] {
+            assert!(create_order(state, pr(0), token, 10, price, time, OrderType::Sell).is_ok());
+            assert_eq!(
+                trade(state, OrderType::Buy, pr(1), token, 10, None, time),
+                Ok(10)
+            );
+        }
+        let position = |state: &State, user| state.positions(user).pop().unwrap();
+        assert_eq!(
+            position(state, pr(1)),
+            Position {
+                token,
+                quote: None,
+                net_amount: 20,
+                avg_entry_price: 110000,
+                realized_pnl: 0,
+            }
+        );
 
+        // pr(1) sells 5 tokens at 1300 as a maker and realizes 5 * (1300 - 1100)
+        assert!(create_order(state, pr(1), token, 5, 130000, 2, OrderType::Sell).is_ok());
         assert_eq!(
-            trade(state, OrderType::Buy, buyer, token, 100, None, 123458),
-            Ok(31)
+            trade(state, OrderType::Buy, pr(0), token, 5, None, 2),
+            Ok(5)
+        );
+        assert_eq!(
+            position(state, pr(1)),
+            Position {
+                token,
+                quote: None,
+                net_amount: 15,
+                avg_entry_price: 110000,
+                realized_pnl: 1000,
+            }
         );
 
-        // all sellers got ICP
-        let (v2, v1, v3) = (16 * 30000, 7 * 50000, 25 * 1000000);
+        // pr(0) sold more than it bought, so its position is short with the opposite profit
         assert_eq!(
-            state.payment_token_pool().get(&pr(0)).unwrap(),
-            &(v1 - trading_fee(v1))
+            position(state, pr(0)),
+            Position {
+                token,
+                quote: None,
+                net_amount: -15,
+                avg_entry_price: 110000,
+                realized_pnl: -1000,
+            }
         );
+
+        // a trade beyond the position opens a position in the opposite direction
+        assert!(create_order(state, pr(0), token, 20, 100000, 3, OrderType::Buy).is_ok());
         assert_eq!(
-            state.payment_token_pool().get(&pr(1)).unwrap(),
-            &(v2 - trading_fee(v2))
+            trade(state, OrderType::Sell, pr(1), token, 20, None, 3),
+            Ok(20)
         );
         assert_eq!(
-            state.payment_token_pool().get(&pr(2)).unwrap(),
-            &(v3 - trading_fee(v3))
+            position(state, pr(1)),
+            Position {
+                token,
+                quote: None,
+                net_amount: -5,
+                avg_entry_price: 100000,
+                realized_pnl: 1000 - 1500,
+            }
         );
 
-        // executed orders: 16 @ 0.03, 7 @ 0.05, 25 @ 1
-        let fee = trading_fee(v1) + trading_fee(v2) + trading_fee(v3);
+        // trades made with other decimals of the token are skipped
+        state.tokens.get_mut(&token).unwrap().decimals = 3;
+        assert!(state.positions(pr(1)).is_empty());
+        assert!(create_order(state, pr(0), token, 10, 1000000, 4, OrderType::Sell).is_ok());
         assert_eq!(
-            state.payment_token_pool().get(&pr(255)).unwrap(),
-            &(2 * fee)
+            trade(state, OrderType::Buy, pr(1), token, 10, None, 4),
+            Ok(10)
+        );
+        assert_eq!(
+            position(state, pr(1)),
+            Position {
+                token,
+                quote: None,
+                net_amount: 10,
+                avg_entry_price: 1000000,
+                realized_pnl: 0,
+            }
         );
     }
 
     #[test]
-    fn test_limit_selling() {
+    fn test_max_logs() {
         let state = &mut State::default();
-        list_payment_token(state);
-
-        state.revenue_account = Some(pr(255));
+        assert_eq!(state.max_logs(), LOGS_SIZE);
+        assert!(state.set_max_logs(MIN_LOGS_SIZE - 1).is_err());
+        assert!(state.set_max_logs(MAX_LOGS_SIZE + 1).is_err());
+        assert_eq!(state.set_max_logs(150), Ok(()));
+        assert_eq!(state.max_logs(), 150);
+
+        for i in 0..200 {
+            state.log(format!("log {}", i));
+        }
+        state.clean_up(0);
+        // the newest logs are kept and the clean-up logs its results afterwards
+        let logs = state.logs().map(|(_, message)| message).collect::<Vec<_>>();
+        assert_eq!(logs.len(), 150 + 2);
+        assert_eq!(logs.last().unwrap(), "log 50");
+    }
 
+    #[test]
+    fn test_token_status() {
+        let state = &mut State::default();
         let token = pr(100);
+        assert_eq!(state.token_status(token), TokenStatus::default());
 
+        list_payment_token(state);
         list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        assert_eq!(
+            state.token_status(token),
+            TokenStatus {
+                listed: true,
+                paused: false,
+                has_liquidity: false,
+                tradable: true,
+                fee: 25,
+                decimals: 2,
+            }
+        );
+        // the payment token can't be traded against itself
+        assert!(state.token_status(payment_token_id).listed);
+        assert!(!state.token_status(payment_token_id).tradable);
+
+        state.add_liquidity(pr(0), token, 100);
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
+        assert!(state.token_status(token).has_liquidity);
+
+        state.paused = true;
+        let status = state.token_status(token);
+        assert!(status.paused && !status.tradable);
+        assert!(state.token_status(pr(101)).paused);
+    }
 
-        // buy order for 7 $TAGGR / 0.1 ICP each
-        state.add_liquidity(pr(0), state.payment_token_id(), 8 * 10000000);
-        assert!(create_order(state, pr(0), token, 7, 10000000, 0, OrderType::Buy).is_ok());
-
-        // buy order for 16 $TAGGR / 0.03 ICP each
-        state.add_liquidity(pr(1), state.payment_token_id(), 17 * 30000000);
-        assert!(create_order(state, pr(1), token, 16, 3000000, 0, OrderType::Buy).is_ok());
-
-        // buy order for 25 $TAGGR / 0.01 ICP each
-        state.add_liquidity(pr(2), state.payment_token_id(), 26 * 1000000);
-        assert!(create_order(state, pr(2), token, 25, 1000000, 0, OrderType::Buy).is_ok());
-
-        // Orer book: 7 @ 0.1, 16 @ 0.03, 25 @ 0.01
-
-        let seller = pr(5);
+    #[test]
+    fn test_trading_without_revenue_account() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        state.revenue_account = None;
+        state.add_liquidity(pr(0), token, 100);
+        let error = "exchange not initialized: revenue account unset".to_string();
 
-        state.add_liquidity(seller, token, 250);
         assert_eq!(
-            trade(
-                state,
+            create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell),
+            Err(error.clone())
+        );
+        assert_eq!(
+            state.trade(
                 OrderType::Sell,
-                seller,
+                pr(0),
                 token,
-                50,
-                Some(2000000),
-                123456
+                10,
+                0,
+                0,
+                Default::default(),
+                None,
+                false,
+                None,
+                None
             ),
-            Ok(23)
+            Err(error.clone())
         );
+        assert_eq!(
+            state.create_stop_order(pr(0), token, 10, 90000, 90000, 0, OrderType::Sell),
+            Err(error)
+        );
+        assert_eq!(state.token_pool_balance(token, pr(0)), 100);
 
-        // 2 orders were filled
-        let buyer_orders = &state.orders.get(&token).unwrap().buyers;
-        assert_eq!(buyer_orders.len(), 1);
-        let best_order = buyer_orders.last().unwrap();
-        // order below the limit wasn't touched
-        assert_eq!(best_order.amount, 25);
-
-        // only two buyer got their tokens
-        assert_eq!(state.pools.get(&token).unwrap().get(&pr(0)).unwrap(), &7);
-        assert_eq!(state.pools.get(&token).unwrap().get(&pr(1)).unwrap(), &16);
-        assert_eq!(state.pools.get(&token).unwrap().get(&pr(2)), None);
+        state.revenue_account = Some(pr(255));
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
     }
 
     #[test]
-    fn test_limit_buying() {
+    fn test_trade_rejects_invalid_rest_order() {
         let state = &mut State::default();
         list_payment_token(state);
-
-        state.revenue_account = Some(pr(255));
-
         let token = pr(100);
-
         list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), token, 10);
+        // enough for the fill of 10 tokens, but not for a rest order of 10 more tokens
+        state.add_liquidity(pr(1), payment_token_id, 10000 + taker_fee(10000) + 6000);
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Sell).is_ok());
 
-        // sell order for 7 $TAGGR / 0.05 ICP each
-        state.add_liquidity(pr(0), token, 7);
-        assert!(create_order(state, pr(0), token, 7, 5000000, 0, OrderType::Sell).is_ok());
+        let buy = |state: &mut State, amount, price| {
+            state
+                .trade(
+                    OrderType::Buy,
+                    pr(1),
+                    token,
+                    amount,
+                    price,
+                    1,
+                    Default::default(),
+                    None,
+                    false,
+                    None,
+                    None,
+                )
+                .map(|receipt| receipt.execution)
+        };
+        let funds = state.funds_under_management();
 
-        // sell order for 16 $TAGGR / 0.03 ICP each
-        state.add_liquidity(pr(1), token, 16);
-        assert!(create_order(state, pr(1), token, 16, 3000000, 0, OrderType::Sell).is_ok());
+        assert_eq!(
+            buy(state, 20, 100000),
+            Err("not enough funds available for this order size".into())
+        );
 
-        // sell order for 25 $TAGGR / 1 ICP each
-        state.add_liquidity(pr(2), token, 25);
-        assert!(create_order(state, pr(2), token, 25, 100000000, 0, OrderType::Sell).is_ok());
+        state.set_min_volume(token, 2000).unwrap();
+        assert_eq!(
+            buy(state, 11, 100000),
+            Err(
+                "the order is too small: the volume 1000 is below the minimum of 2000 particles \
+                (by default 10 times the minimal trading fee)"
+                    .into()
+            )
+        );
+        state.set_min_volume(token, 0).unwrap();
 
-        // Order book: 16 @ 0.03, 7 @ 0.05, 25 @ 1
+        state.set_order_limits(token, 0, 1000).unwrap();
+        assert_eq!(
+            buy(state, 15, 100500),
+            Err("the price is not a multiple of the tick size 1000".into())
+        );
+        state.set_order_limits(token, 0, 0).unwrap();
 
-        let buyer = pr(5);
+        // none of the rejected trades were executed
+        assert_eq!(state.funds_under_management(), funds);
+        assert_eq!(state.token_pool_balance(token, pr(1)), 0);
+        assert_eq!(user_orders(state, token, pr(0), OrderType::Sell).count(), 1);
 
-        state.add_liquidity(buyer, state.payment_token_id(), 12 * 1000000);
         assert_eq!(
-            trade(
-                state,
-                OrderType::Buy,
-                buyer,
-                token,
-                50,
-                Some(6000000),
-                123456
-            ),
-            Ok(23)
+            buy(state, 15, 100000),
+            Ok(OrderExecution::FilledAndOrderCreated(10))
         );
+        assert_eq!(state.token_pool_balance(token, pr(1)), 10);
+        assert_eq!(user_orders(state, token, pr(1), OrderType::Buy).count(), 1);
+    }
 
-        // verify the partial filling
-        let sell_orders = &state.orders.get(&token).unwrap().sellers;
-        // we still have 1 order
-        assert_eq!(sell_orders.len(), 1);
-        let best_order = sell_orders.first().unwrap();
-        // less tokens to buy at the given price as before
-        assert_eq!(best_order.amount, 25);
+    #[test]
+    fn test_upgrade_keeps_legacy_fee_reservations() {
+        let state = &mut State::default();
+        list_payment_token(state);
+        let token = pr(100);
+        list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        state.add_liquidity(pr(0), payment_token_id, 1_000_000);
+        // before the fees were split, a buy order reserved a fee of 20 basis points
+        state.trading_fees = TradingFees::legacy();
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Buy).is_ok());
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(0)),
+            1_000_000 - 10000 - 20
+        );
+        let funds = state.funds_under_management();
 
-        // buyer got 23 tokens
-        assert_eq!(state.pools.get(&token).unwrap().get(&buyer).unwrap(), &23);
+        // a state serialized before the fees were introduced
+        let bytes = serde_cbor::to_vec(&*state).unwrap();
+        let mut value: serde_cbor::Value = serde_cbor::from_slice(&bytes).unwrap();
+        if let serde_cbor::Value::Map(fields) = &mut value {
+            assert!(fields
+                .remove(&serde_cbor::Value::Text("trading_fees".into()))
+                .is_some());
+        }
+        let bytes = serde_cbor::to_vec(&value).unwrap();
+        let upgraded = &mut serde_cbor::from_slice::<State>(&bytes).unwrap();
+        assert_eq!(upgraded.trading_fees(), TradingFees::legacy());
+        assert_eq!(upgraded.funds_under_management(), funds);
 
-        // two sellers got ICP
-        let (v2, v1) = (16 * 30000, 7 * 50000);
+        // the fees can't be lowered while the order is open
         assert_eq!(
-            state.payment_token_pool().get(&pr(0)).unwrap(),
-            &(v1 - trading_fee(v1))
+            upgraded.set_trading_fees(TradingFees::default()),
+            Err("all orders must be closed before the fees can be changed".into())
         );
+
+        // closing the order refunds the full reservation
+        assert!(close_order(upgraded, pr(0), token, 10, 100000, 0, OrderType::Buy).is_ok());
         assert_eq!(
-            state.payment_token_pool().get(&pr(1)).unwrap(),
-            &(v2 - trading_fee(v2))
+            upgraded.token_pool_balance(payment_token_id, pr(0)),
+            1_000_000
         );
-        assert_eq!(state.payment_token_pool().get(&pr(2)), None);
+        assert_eq!(upgraded.funds_under_management(), funds);
+
+        // afterwards the operator can lower the fees
+        assert_eq!(upgraded.set_trading_fees(TradingFees::default()), Ok(()));
+        assert_eq!(upgraded.trading_fees(), TradingFees::default());
     }
 
     #[test]
-    fn test_liquitidy_lock() {
+    fn test_zero_maker_fee() {
         let state = &mut State::default();
         list_payment_token(state);
-
-        state.revenue_account = Some(pr(255));
-
         let token = pr(100);
-
         list_test_token(state, token, 2);
+        let payment_token_id = state.payment_token_id();
+        let fees = TradingFees {
+            maker: 0,
+            taker: 20,
+        };
+        assert_eq!(state.set_trading_fees(fees), Ok(()));
+        state.add_liquidity(pr(0), payment_token_id, 1_000_000);
+        state.add_liquidity(pr(1), token, 100);
 
-        // sell order for 7 $TAGGR / 0.05 ICP each
-        state.add_liquidity(pr(0), token, 7);
-        assert!(create_order(state, pr(0), token, 7, 5000000, 0, OrderType::Sell).is_ok());
+        // a buy order reserves only its volume
+        assert!(create_order(state, pr(0), token, 10, 100000, 0, OrderType::Buy).is_ok());
         assert_eq!(
-            create_order(state, pr(0), token, 7, 6000000, 0, OrderType::Sell),
-            Err("not enough funds available for this order size".into())
+            state.token_pool_balance(payment_token_id, pr(0)),
+            1_000_000 - 10000
+        );
+
+        // the maker pays nothing, not even a particle, and the taker pays 20 basis points
+        assert_eq!(
+            trade(state, OrderType::Sell, pr(1), token, 10, None, 1),
+            Ok(10)
+        );
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(0)),
+            1_000_000 - 10000
+        );
+        assert_eq!(state.token_pool_balance(token, pr(0)), 10);
+        assert_eq!(
+            state.token_pool_balance(payment_token_id, pr(1)),
+            10000 - 20
         );
+        assert_eq!(state.token_pool_balance(payment_token_id, pr(255)), 20);
+        assert_eq!(trading_fee(10000, 0), 0);
+        assert_eq!(trading_fee(1, 10), 1);
     }
 
     #[test]
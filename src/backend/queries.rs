@@ -1,10 +1,111 @@
+use candid::CandidType;
 use ic_cdk::api::{call::arg_data_raw, canister_balance};
 
 use super::*;
 
 #[query]
-fn orders(token: TokenId, order_type: OrderType) -> Vec<Order> {
-    read(|state| state.orders(token, order_type).cloned().collect())
+fn orders(token: TokenId, order_type: OrderType, quote: Option<TokenId>) -> Vec<Order> {
+    read(|state| {
+        state
+            .orders(Pair { base: token, quote }, order_type)
+            .cloned()
+            .collect()
+    })
+}
+
+// Returns the open orders with a price within the inclusive bounds.
+#[query]
+fn orders_in_range(
+    token: TokenId,
+    order_type: OrderType,
+    min_price: ParticlesPerToken,
+    max_price: ParticlesPerToken,
+    quote: Option<TokenId>,
+) -> Vec<Order> {
+    read(|state| {
+        state.orders_in_range(
+            Pair { base: token, quote },
+            order_type,
+            min_price,
+            max_price,
+        )
+    })
+}
+
+// Returns the open orders together with the version of the order books.
+#[query]
+fn orders_snapshot(token: TokenId, order_type: OrderType, quote: Option<TokenId>) -> BookSnapshot {
+    read(|state| BookSnapshot {
+        version: state.book_version(),
+        orders: state
+            .orders(Pair { base: token, quote }, order_type)
+            .cloned()
+            .collect(),
+    })
+}
+
+// Returns the number of open orders on both sides of the book and their summed amounts.
+#[query]
+fn order_counts(token: TokenId, quote: Option<TokenId>) -> OrderCounts {
+    read(|state| state.order_counts(Pair { base: token, quote }))
+}
+
+// Returns the version of the order books, which changes with every change of any book, so that
+// clients can skip fetching unchanged orders.
+#[query]
+fn book_version() -> u64 {
+    read(|state| state.book_version())
+}
+
+// Returns all open orders of the caller as tuples of the token, the quote token (None for the
+// payment token) and the order.
+#[query]
+fn my_open_orders() -> Vec<(TokenId, Option<TokenId>, Order)> {
+    read(|state| state.user_orders(caller()))
+}
+
+// Returns all stop-limit orders of the caller as tuples of the token, the quote token (None for
+// the payment token) and the order.
+#[query]
+fn my_stop_orders() -> Vec<(TokenId, Option<TokenId>, StopOrder)> {
+    read(|state| state.user_stop_orders(caller()))
+}
+
+// Returns the number of orders which get filled before the given order of the caller, or nothing
+// if the caller has no such order.
+#[query]
+fn queue_position(
+    token: TokenId,
+    order_type: OrderType,
+    amount: Tokens,
+    price: ParticlesPerToken,
+    timestamp: Timestamp,
+    quote: Option<TokenId>,
+) -> Option<usize> {
+    read(|state| {
+        state.queue_position(
+            caller(),
+            Pair { base: token, quote },
+            order_type,
+            amount,
+            price,
+            timestamp,
+        )
+    })
+}
+
+// Returns the pairs whose best bid is at or above the best ask as tuples of the token and the quote
+// token (None for the payment token).
+#[query]
+fn crossed_books() -> Vec<(TokenId, Option<TokenId>)> {
+    read(|state| state.crossed_books())
+}
+
+// Returns the account of the caller to which tokens must be sent before they can be deposited with
+// `deposit_liquidity`.
+#[query]
+fn deposit_account() -> Account {
+    icrc1::user_account(caller())
 }
 
 #[export_name = "canister_query tokens"]
@@ -12,26 +113,87 @@ fn tokens() {
     read(|state| reply(state.tokens()));
 }
 
+// Returns a page of listed tokens with their price statistics, ranked by the sort key.
+#[query]
+fn tokens_ranked(sort_by: SortKey, offset: usize, limit: usize) -> Vec<RankedToken> {
+    read(|state| state.tokens_ranked(sort_by, offset, limit, ic_cdk::api::time()))
+}
+
+// Returns whether orders of the token can be placed together with its fee and decimals.
+#[query]
+fn token_status(token: TokenId) -> TokenStatus {
+    read(|state| state.token_status(token))
+}
+
+// Returns the token all other tokens are traded against together with its metadata, or nothing if
+// it is not listed yet.
+#[query]
+fn payment_token() -> Option<(TokenId, Metadata)> {
+    read(|state| state.payment_token())
+}
+
 #[export_name = "canister_query token_balances"]
 fn token_balances() {
     reply(read(|state| state.token_balances(caller())));
 }
 
-#[export_name = "canister_query prices"]
-fn prices() {
+// Returns the expected outcome of a trade of the caller without executing it. A price of 0 means
+// a market order.
+#[query]
+fn simulate_trade(
+    token: TokenId,
+    amount: u128,
+    price: Tokens,
+    order_type: OrderType,
+    quote: Option<TokenId>,
+) -> TradeSimulation {
     read(|state| {
-        reply(
-            state
-                .tokens()
-                .keys()
-                .filter_map(|token_id| {
-                    state
-                        .order_archive
-                        .get(token_id)
-                        .and_then(|archive| archive.front().map(|order| (token_id, order)))
-                })
-                .collect::<BTreeMap<_, _>>(),
+        state.simulate_trade(
+            order_type,
+            caller(),
+            Pair { base: token, quote },
+            amount,
+            (price > 0).then_some(price),
         )
+    })
+}
+
+// Returns how much a market trade of the caller over the given amount would move the price, or
+// nothing if the book can't fill the amount.
+#[query]
+fn price_impact(
+    token: TokenId,
+    amount: u128,
+    order_type: OrderType,
+    quote: Option<TokenId>,
+) -> Option<PriceImpact> {
+    read(|state| state.price_impact(order_type, caller(), Pair { base: token, quote }, amount))
+}
+
+// Returns the positions of the caller in all pairs the caller traded recently. Only the latest
+// trades of the caller among the latest trades of all users are replayed, so older trades are
+// missing from the positions.
+#[query]
+fn positions() -> Vec<Position> {
+    read(|state| state.positions(caller()))
+}
+
+// Returns the free liquidity of the caller in the token and the liquidity reserved in buy and sell
+// orders.
+#[query]
+fn liquidity_breakdown(token: TokenId) -> LiquidityBreakdown {
+    read(|state| state.liquidity_breakdown(caller(), token))
+}
+
+// Without arguments, returns the latest executed order of each token. With a depth, returns up to
+// that many latest executed orders of each token.
+#[export_name = "canister_query prices"]
+fn prices() {
+    let arg = arg_data_raw();
+    let depth: Option<usize> = if arg.is_empty() { None } else { parse(&arg) };
+    read(|state| match depth {
+        Some(depth) => reply(state.recent_prices(depth)),
+        None => reply(state.prices()),
     });
 }
 
@@ -42,72 +204,244 @@ fn executed_orders() {
         reply(
             state
                 .order_archive
-                .get(&Principal::from_text(token).expect("couldn't parse principal"))
-                .map(|list| list.iter().take(75).cloned().collect::<Vec<_>>())
-                .unwrap_or_default(),
+                .iter(Principal::from_text(token).expect("couldn't parse principal"))
+                .take(75)
+                .collect::<Vec<_>>(),
         )
     })
 }
 
+#[export_name = "canister_query token_overview"]
+fn token_overview() {
+    let token: String = parse(&arg_data_raw());
+    read(|state| {
+        reply(state.token_overview(
+            Principal::from_text(token).expect("couldn't parse principal"),
+            ic_cdk::api::time(),
+        ))
+    })
+}
+
+// Returns the price statistics of the token over the last 24 hours.
+#[query]
+fn ticker(token: TokenId) -> Ticker {
+    read(|state| state.ticker(token, ic_cdk::api::time()))
+}
+
+// Returns the time-weighted average price of the token over the last `window_secs` seconds.
+#[query]
+fn twap(token: TokenId, window_secs: u64) -> Option<ParticlesPerToken> {
+    read(|state| state.twap(token, window_secs, ic_cdk::api::time()))
+}
+
+#[query]
+fn candles(token: TokenId, interval_secs: u64, count: usize) -> Vec<Candle> {
+    read(|state| state.candles(token, interval_secs, count))
+}
+
+// Returns a page of the append-only log of executed trades.
+#[query]
+fn get_trade_blocks(start: u64, length: u64) -> TradeBlocks {
+    read(|state| state.trade_blocks(start, length))
+}
+
+// Returns a page of the typed event stream, the newest first, skipping `offset` events and
+// returning at most `limit` of them.
+#[query]
+fn events(offset: usize, limit: usize) -> Vec<(u64, Event)> {
+    read(|state| state.events(offset, limit))
+}
+
+// Returns the makers with the highest volume in the payment token over the last week.
+#[query]
+fn top_traders(limit: usize) -> Vec<(Principal, Tokens)> {
+    read(|state| state.top_traders(limit, ic_cdk::api::time()))
+}
+
+// Returns the trading fees collected in the payment token.
+#[query]
+fn fee_stats() -> FeeStats {
+    read(|state| state.fee_stats(ic_cdk::api::time()))
+}
+
+// Returns a page of the paid listing fees, the newest first.
+#[query]
+fn listing_payments(offset: usize, limit: usize) -> Vec<ListingPayment> {
+    read(|state| state.listing_payments(offset, limit))
+}
+
+// Returns a page of the users holding the token, sorted by their balance.
+#[query]
+fn holders(token: TokenId, offset: usize, limit: usize) -> Vec<(Principal, Tokens)> {
+    read(|state| state.holders(token, offset, limit))
+}
+
+// Returns the fees collected on the revenue account per token.
+#[query]
+fn revenue_balances() -> Vec<(TokenId, Tokens)> {
+    read(|state| state.revenue_balances().into_iter().collect())
+}
+
+// Returns the funds under management per token, i.e. the balances of all users including the
+// liquidity locked in orders, so that monitoring can detect unexpected drifts over time.
+#[query]
+fn invariant_report() -> Vec<(String, u128)> {
+    read(|state| state.funds_under_management())
+}
+
 #[export_name = "canister_query logs"]
 fn logs() {
-    read(|state| reply(state.logs()));
+    read(|state| reply(state.logs().collect::<Vec<_>>()));
+}
+
+// Returns a page of logs, the newest first, optionally only those containing the substring.
+#[query]
+fn logs_paged(offset: usize, limit: usize, substring: Option<String>) -> Vec<(u64, String)> {
+    read(|state| state.logs_paged(offset, limit, substring))
 }
 
 #[derive(Serialize)]
-struct BackendData {
-    volume_day: u128,
-    trades_day: u64,
-    payment_token_locked: u128,
-    fee: u128,
-    cycle_balance: u64,
-    heap_size: u64,
-    tokens_listed: usize,
-    active_traders: usize,
-    listing_price_usd: u128,
-    payment_token_id: Principal,
+pub struct BackendData {
+    pub volume_day: u128,
+    pub trades_day: u64,
+    pub payment_token_locked: u128,
+    pub fee: u128,
+    pub maker_fee: u128,
+    pub cycle_balance: u64,
+    pub heap_size: u64,
+    pub tokens_listed: usize,
+    pub active_traders: usize,
+    pub listing_price_usd: u128,
+    pub payment_token_id: Principal,
 }
 
 #[export_name = "canister_query data"]
 fn data() {
-    let now = ic_cdk::api::time();
-    reply(read(|state| {
-        let day_orders = state
-            .order_archive
-            .values()
-            .flatten()
-            .filter(|order| order.executed + DAY >= now);
-
-        BackendData {
-            volume_day: day_orders.clone().map(|order| order.volume()).sum(),
-            trades_day: day_orders.count() as u64,
-            payment_token_locked: state
-                .funds_under_management()
-                .iter()
-                .find_map(|(id, balance)| {
-                    (&state.payment_token_id().to_string() == id).then_some(balance)
-                })
-                .copied()
-                .unwrap_or_default(),
-            fee: TX_FEE,
-            cycle_balance: canister_balance(),
-            heap_size: heap_address().1,
-            // We subtract one, because the list of tokens always contains the payment token
-            tokens_listed: state.tokens.len() - 1,
-            active_traders: state.traders(),
-            listing_price_usd: LISTING_PRICE_USD,
-            payment_token_id: state.payment_token_id(),
-        }
-    }))
+    reply(backend_data());
 }
 
+pub fn backend_data() -> BackendData {
+    read(|state| {
+        collect_backend_data(
+            state,
+            ic_cdk::api::time(),
+            canister_balance(),
+            heap_address().1,
+        )
+    })
+}
+
+fn collect_backend_data(
+    state: &State,
+    now: Timestamp,
+    cycle_balance: u64,
+    heap_size: u64,
+) -> BackendData {
+    let day_orders = state
+        .order_archive
+        .tokens()
+        .into_iter()
+        .flat_map(|token| {
+            state
+                .order_archive
+                .iter(token)
+                .take_while(|order| order.executed + DAY >= now)
+        })
+        .collect::<Vec<_>>();
+
+    BackendData {
+        volume_day: day_orders.iter().map(|order| order.volume()).sum(),
+        trades_day: day_orders.len() as u64,
+        payment_token_locked: state
+            .funds_under_management()
+            .iter()
+            .find_map(|(id, balance)| {
+                (&state.payment_token_id().to_string() == id).then_some(balance)
+            })
+            .copied()
+            .unwrap_or_default(),
+        fee: state.trading_fees().taker,
+        maker_fee: state.trading_fees().maker,
+        cycle_balance,
+        heap_size,
+        // We subtract one, because the list of tokens contains the payment token once it's
+        // listed
+        tokens_listed: state.tokens.len().saturating_sub(1),
+        active_traders: state.traders(),
+        listing_price_usd: state.listing_price_usd(),
+        payment_token_id: state.payment_token_id(),
+    }
+}
+
+#[derive(CandidType)]
+pub struct MemoryStats {
+    // The size of the wasm heap in 64KiB pages.
+    pub heap_pages: u64,
+    // The size of the stable memory in 64KiB pages.
+    pub stable_pages: u64,
+    // The length of the state serialized to stable memory by the last backup or upgrade.
+    pub serialized_state_bytes: u64,
+}
+
+// Returns the memory usage of the canister, so that operators can plan upgrades before reaching
+// the memory limits.
+#[query]
+fn memory_stats() -> MemoryStats {
+    #[cfg(target_arch = "wasm32")]
+    let heap_pages = core::arch::wasm32::memory_size(0) as u64;
+    #[cfg(not(target_arch = "wasm32"))]
+    let heap_pages = 0;
+    MemoryStats {
+        heap_pages,
+        stable_pages: ic_cdk::api::stable::stable_size(),
+        serialized_state_bytes: heap_address().1,
+    }
+}
+
+// Checks that the state survives the serialization used for upgrades. Should be called before
+// every upgrade.
+#[query]
+fn verify_serialization() -> Result<(), String> {
+    read(|state| state.verify_serialization())
+}
+
+// Query replies are limited to 3MiB; the margin leaves room for the hashes and the encoding.
+const MAX_STABLE_READ_BYTES: usize = 3 * 1024 * 1024 - 64 * 1024;
+
+// Returns the page of stable memory together with the SHA-256 hash of its bytes.
+#[query]
+fn stable_mem_read(page: u64) -> Vec<(u64, Vec<u8>, Vec<u8>)> {
+    read_stable_page(page).into_iter().collect()
+}
+
+// Returns up to `count` consecutive pages of stable memory starting with `start_page`, each
+// together with the SHA-256 hash of its bytes. Fewer pages are returned if they would exceed the
+// reply size limit or the end of the stable memory, so the caller continues with the page after
+// the last returned one.
 #[query]
-fn stable_mem_read(page: u64) -> Vec<(u64, Vec<u8>)> {
+fn stable_mem_read_range(start_page: u64, count: u64) -> Vec<(u64, Vec<u8>, Vec<u8>)> {
+    let mut pages = Vec::new();
+    let mut bytes = 0;
+    for page in start_page..start_page.saturating_add(count) {
+        if bytes + BACKUP_PAGE_SIZE as usize > MAX_STABLE_READ_BYTES {
+            break;
+        }
+        let Some(entry) = read_stable_page(page) else {
+            break;
+        };
+        bytes += entry.1.len();
+        pages.push(entry);
+    }
+    pages
+}
+
+fn read_stable_page(page: u64) -> Option<(u64, Vec<u8>, Vec<u8>)> {
     let offset = page * BACKUP_PAGE_SIZE as u64;
-    let (heap_off, heap_size) = heap_address();
-    let memory_end = heap_off + heap_size;
+    // The backup covers the whole stable memory, because besides the heap it contains the
+    // stable structures.
+    let memory_end = ic_cdk::api::stable::stable_size() << 16;
     if offset > memory_end {
-        return Default::default();
+        return None;
     }
     let chunk_size = (BACKUP_PAGE_SIZE as u64).min(memory_end - offset) as usize;
     let mut buf = Vec::with_capacity(chunk_size);
@@ -116,5 +450,48 @@ fn stable_mem_read(page: u64) -> Vec<(u64, Vec<u8>)> {
         buf.set_len(chunk_size);
     }
     ic_cdk::api::stable::stable_read(offset, &mut buf);
-    vec![(page, buf)]
+    let hash = page_hash(&buf);
+    Some((page, buf, hash))
+}
+
+// The number of pages hashed by one checksum query, so that it stays within the instruction limit.
+const MAX_CHECKSUM_PAGES: u64 = 32;
+
+// Returns the SHA-256 hash of up to `count` consecutive pages of stable memory starting with
+// `start_page`, i.e. of the bytes `stable_mem_read` returns for them, so that a restored backup
+// can be verified range by range. At most `MAX_CHECKSUM_PAGES` pages are hashed.
+#[query]
+fn backup_checksum(start_page: u64, count: u64) -> Vec<u8> {
+    let memory_end = (ic_cdk::api::stable::stable_size() << 16).min(
+        start_page
+            .saturating_add(count.min(MAX_CHECKSUM_PAGES))
+            .saturating_mul(BACKUP_PAGE_SIZE as u64),
+    );
+    let mut hasher = Sha256::new();
+    let mut offset = start_page.saturating_mul(BACKUP_PAGE_SIZE as u64);
+    while offset < memory_end {
+        let mut buf = vec![0; (BACKUP_PAGE_SIZE as u64).min(memory_end - offset) as usize];
+        ic_cdk::api::stable::stable_read(offset, &mut buf);
+        hasher.update(&buf);
+        offset += buf.len() as u64;
+    }
+    hasher.finalize().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_without_payment_token() {
+        let state = &State::default();
+        let data = collect_backend_data(state, DAY, 1_000, 0);
+        assert_eq!(data.volume_day, 0);
+        assert_eq!(data.trades_day, 0);
+        assert_eq!(data.payment_token_locked, 0);
+        assert_eq!(data.tokens_listed, 0);
+        assert_eq!(data.active_traders, 0);
+        assert_eq!(data.cycle_balance, 1_000);
+        assert!(state.payment_token_pool().is_empty());
+    }
 }
@@ -0,0 +1,326 @@
+use std::{borrow::Cow, cell::RefCell, ops::Bound};
+
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    storable::Bound as StorableBound,
+    DefaultMemoryImpl, Memory as _, StableBTreeMap, Storable,
+};
+
+use crate::order_book::{Order, TokenId, TradeBlock};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// The serialized heap.
+const HEAP: MemoryId = MemoryId::new(0);
+// Executed orders.
+const ARCHIVE: MemoryId = MemoryId::new(1);
+// Human-readable logs.
+const LOGS: MemoryId = MemoryId::new(2);
+// Executed trades as an append-only log.
+const TRADES: MemoryId = MemoryId::new(3);
+// Settings which must be readable before the heap is deserialized.
+const SETTINGS: MemoryId = MemoryId::new(4);
+
+// The first bytes of the stable memory written by the memory manager.
+const MEMORY_MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static ARCHIVE_MAP: RefCell<StableBTreeMap<(TokenId, u64), Order, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(ARCHIVE)));
+
+    static LOGS_MAP: RefCell<StableBTreeMap<u64, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(LOGS)));
+
+    static TRADES_MAP: RefCell<StableBTreeMap<u64, TradeBlock, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory(TRADES)));
+}
+
+fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+/// Returns the memory containing the serialized heap.
+pub fn heap() -> Memory {
+    memory(HEAP)
+}
+
+/// Returns true if the stable memory contains the heap in the layout used before the memory
+/// manager was introduced. Must be called before any stable structure is accessed, because the
+/// memory manager overwrites such a layout on initialization.
+pub fn legacy_layout() -> bool {
+    let memory = DefaultMemoryImpl::default();
+    if memory.size() == 0 {
+        return false;
+    }
+    let mut magic = [0; 3];
+    memory.read(0, &mut magic);
+    &magic != MEMORY_MANAGER_MAGIC
+}
+
+/// Reads the serialized heap from the stable memory in the legacy layout.
+pub fn legacy_heap() -> Vec<u8> {
+    let memory = DefaultMemoryImpl::default();
+    let mut offset_bytes: [u8; 8] = Default::default();
+    memory.read(0, &mut offset_bytes);
+    let mut len_bytes: [u8; 8] = Default::default();
+    memory.read(8, &mut len_bytes);
+    let (offset, len) = (
+        u64::from_be_bytes(offset_bytes),
+        u64::from_be_bytes(len_bytes),
+    );
+    ic_cdk::println!("Reading legacy heap from coordinates: {:?}", (offset, len));
+    let mut bytes = vec![0; len as usize];
+    memory.read(offset, &mut bytes);
+    bytes
+}
+
+/// Returns true if the canister may start with an empty state when the heap can't be
+/// deserialized after an upgrade.
+pub fn safe_mode() -> bool {
+    let memory = memory(SETTINGS);
+    if memory.size() == 0 {
+        return false;
+    }
+    let mut flag = [0; 1];
+    memory.read(0, &mut flag);
+    flag[0] == 1
+}
+
+pub fn set_safe_mode(enabled: bool) {
+    let memory = memory(SETTINGS);
+    if memory.size() == 0 {
+        assert!(memory.grow(1) >= 0, "couldn't grow memory");
+    }
+    memory.write(0, &[enabled as u8]);
+}
+
+/// Re-initializes the memory manager and all stable structures from the stable memory, e.g.
+/// after the stable memory was restored from a backup.
+pub fn reload() {
+    MEMORY_MANAGER.with(|manager| {
+        manager.replace(MemoryManager::init(DefaultMemoryImpl::default()));
+    });
+    ARCHIVE_MAP.with(|map| map.replace(StableBTreeMap::init(memory(ARCHIVE))));
+    LOGS_MAP.with(|map| map.replace(StableBTreeMap::init(memory(LOGS))));
+    TRADES_MAP.with(|map| map.replace(StableBTreeMap::init(memory(TRADES))));
+}
+
+impl Storable for Order {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("couldn't serialize the order"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("couldn't deserialize the order")
+    }
+
+    const BOUND: StorableBound = StorableBound::Unbounded;
+}
+
+impl Storable for TradeBlock {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(self).expect("couldn't serialize the trade"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(&bytes).expect("couldn't deserialize the trade")
+    }
+
+    const BOUND: StorableBound = StorableBound::Unbounded;
+}
+
+/// Executed orders of all tokens, kept in stable memory so that they don't get re-serialized with
+/// the heap. Orders of each token are indexed by a sequence number growing with every new entry.
+#[derive(Clone, Default)]
+pub struct Archive;
+
+impl Archive {
+    /// Adds the order as the newest entry of the token's archive.
+    pub fn push(&mut self, token: TokenId, order: Order) {
+        let seq = self.last_seq(token).map(|seq| seq + 1).unwrap_or_default();
+        ARCHIVE_MAP.with(|map| map.borrow_mut().insert((token, seq), order));
+    }
+
+    /// Returns archived orders of the token, the newest first.
+    pub fn iter(&self, token: TokenId) -> ArchiveIter {
+        ArchiveIter {
+            token,
+            upper: self.last_seq(token),
+        }
+    }
+
+    /// Returns the latest archived order of the token.
+    pub fn front(&self, token: TokenId) -> Option<Order> {
+        self.iter(token).next()
+    }
+
+    pub fn len(&self, token: TokenId) -> usize {
+        ARCHIVE_MAP.with(|map| map.borrow().range((token, 0)..=(token, u64::MAX)).count())
+    }
+
+    /// Returns all tokens with a non-empty archive.
+    pub fn tokens(&self) -> Vec<TokenId> {
+        ARCHIVE_MAP.with(|map| {
+            let map = map.borrow();
+            let mut tokens = Vec::new();
+            let mut next = map.first_key_value().map(|((token, _), _)| token);
+            while let Some(token) = next {
+                tokens.push(token);
+                next = map
+                    .range((Bound::Excluded((token, u64::MAX)), Bound::Unbounded))
+                    .next()
+                    .map(|((token, _), _)| token);
+            }
+            tokens
+        })
+    }
+
+    /// Keeps only the archived orders of the token satisfying the predicate and returns the
+    /// number of removed orders.
+    pub fn retain(&mut self, token: TokenId, predicate: impl Fn(&Order) -> bool) -> usize {
+        ARCHIVE_MAP.with(|map| {
+            let mut map = map.borrow_mut();
+            let keys = map
+                .range((token, 0)..=(token, u64::MAX))
+                .filter_map(|(key, order)| (!predicate(&order)).then_some(key))
+                .collect::<Vec<_>>();
+            for key in &keys {
+                map.remove(key);
+            }
+            keys.len()
+        })
+    }
+
+    /// Keeps only the `keep` newest archived orders of the token and returns the number of
+    /// removed orders.
+    pub fn truncate(&mut self, token: TokenId, keep: usize) -> usize {
+        ARCHIVE_MAP.with(|map| {
+            let mut map = map.borrow_mut();
+            let keys = map
+                .range((token, 0)..=(token, u64::MAX))
+                .rev()
+                .skip(keep)
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>();
+            for key in &keys {
+                map.remove(key);
+            }
+            keys.len()
+        })
+    }
+
+    /// Removes and returns all archived orders of the token, the newest first.
+    pub fn take(&mut self, token: TokenId) -> Vec<Order> {
+        let orders = self.iter(token).collect();
+        self.retain(token, |_| false);
+        orders
+    }
+
+    pub fn clear(&mut self) {
+        ARCHIVE_MAP.with(|map| map.borrow_mut().clear_new());
+    }
+
+    fn last_seq(&self, token: TokenId) -> Option<u64> {
+        ARCHIVE_MAP.with(|map| {
+            map.borrow()
+                .range((token, 0)..=(token, u64::MAX))
+                .next_back()
+                .map(|((_, seq), _)| seq)
+        })
+    }
+}
+
+/// Iterates over the archived orders of one token from the newest to the oldest one.
+pub struct ArchiveIter {
+    token: TokenId,
+    // The sequence number of the next order to return.
+    upper: Option<u64>,
+}
+
+impl Iterator for ArchiveIter {
+    type Item = Order;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let upper = self.upper?;
+        let ((_, seq), order) = ARCHIVE_MAP.with(|map| {
+            map.borrow()
+                .range((self.token, 0)..=(self.token, upper))
+                .next_back()
+        })?;
+        self.upper = seq.checked_sub(1);
+        Some(order)
+    }
+}
+
+/// Human-readable logs indexed by the event id, kept in stable memory.
+#[derive(Clone, Default)]
+pub struct Logs;
+
+impl Logs {
+    pub fn push(&mut self, id: u64, message: String) {
+        LOGS_MAP.with(|map| map.borrow_mut().insert(id, message));
+    }
+
+    /// Returns all logs, the newest first.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, String)> {
+        let mut upper = LOGS_MAP.with(|map| map.borrow().last_key_value().map(|(id, _)| id));
+        std::iter::from_fn(move || {
+            let (id, message) = LOGS_MAP.with(|map| map.borrow().range(..=upper?).next_back())?;
+            upper = id.checked_sub(1);
+            Some((id, message))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        LOGS_MAP.with(|map| map.borrow().len() as usize)
+    }
+
+    /// Removes the oldest logs so that at most `len` logs remain.
+    pub fn truncate(&mut self, len: usize) {
+        LOGS_MAP.with(|map| {
+            let mut map = map.borrow_mut();
+            while map.len() as usize > len {
+                map.pop_first();
+            }
+        })
+    }
+}
+
+/// Executed trades of all pairs, kept in stable memory. Blocks are never removed, so the index of
+/// each block is stable.
+#[derive(Clone, Default)]
+pub struct TradeLog;
+
+impl TradeLog {
+    /// Appends the block and returns its index.
+    pub fn push(&mut self, block: TradeBlock) -> u64 {
+        TRADES_MAP.with(|map| {
+            let mut map = map.borrow_mut();
+            let id = map.len();
+            map.insert(id, block);
+            id
+        })
+    }
+
+    /// Returns all blocks, the newest first.
+    pub fn iter_rev(&self) -> impl Iterator<Item = (u64, TradeBlock)> {
+        let mut upper = self.len().checked_sub(1);
+        std::iter::from_fn(move || {
+            let (id, block) = TRADES_MAP.with(|map| map.borrow().range(..=upper?).next_back())?;
+            upper = id.checked_sub(1);
+            Some((id, block))
+        })
+    }
+
+    /// Returns up to `length` blocks starting from the index `start`, the oldest first.
+    pub fn range(&self, start: u64, length: usize) -> Vec<(u64, TradeBlock)> {
+        TRADES_MAP.with(|map| map.borrow().range(start..).take(length).collect())
+    }
+
+    pub fn len(&self) -> u64 {
+        TRADES_MAP.with(|map| map.borrow().len())
+    }
+}
@@ -1,11 +1,18 @@
-use crate::order_book::{Metadata, OrderExecution};
+use crate::order_book::{
+    Event, OrderFees, OrderRequest, Pair, Reconciliation, SelfTradePrevention, TradeReceipt,
+    TradingFees,
+};
 use ic_cdk::api::time;
+use std::convert::TryInto;
 
 use super::*;
 
 #[init]
 fn init() {
-    stable_grow(1).expect("stable memory intialization failed");
+    assert!(
+        storage::heap().grow(1) >= 0,
+        "stable memory intialization failed"
+    );
     kickstart();
 }
 
@@ -41,36 +48,316 @@ fn set_revenue_account(new_address: Principal) {
     })
 }
 
+// Splits the fees between the given beneficiaries by their shares in basis points, which must sum
+// up to 10000. An empty table sends all fees to the revenue account.
+#[update]
+fn set_revenue_split(split: Vec<(Principal, u16)>) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.set_revenue_split(split)
+    })
+}
+
+// Stops the acceptance of new orders and trades, e.g. during an incident. Closing of orders and
+// withdrawals keep working, so that users can always exit.
+#[update]
+fn set_paused(paused: bool) {
+    mutate(|state| {
+        if state.revenue_account == Some(caller()) {
+            state.paused = paused;
+            state.log(format!("trading paused: {}", paused));
+        }
+    })
+}
+
+// Allows the next upgrade to start with an empty state if the serialized state can't be
+// deserialized, instead of trapping. The flag gets reset once it was used. This is a last resort:
+// `verify_serialization` should be called before every upgrade.
+#[update]
+fn set_safe_mode(enabled: bool) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        storage::set_safe_mode(enabled);
+        state.log(format!("safe mode for upgrades: {}", enabled));
+        Ok(())
+    })
+}
+
+// Ends the recovery of a state that couldn't be deserialized after an upgrade: from now on the
+// current state overwrites the original heap in stable memory, so that one should be backed up
+// first.
+#[update]
+fn finish_recovery() -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.recovering = false;
+        state.log("recovery finished".into());
+        Ok(())
+    })
+}
+
+// Sets the cycle balance below which a warning is logged and token listings are suspended; 0
+// disables the check.
+#[update]
+fn set_cycle_warning_threshold(threshold: u64) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.cycle_warning_threshold = threshold;
+        state.log(format!("cycle warning threshold set to {}", threshold));
+        Ok(())
+    })
+}
+
+// Sets the minimal volume of orders of the token in particles of the quote token; 0 restores the
+// default.
+#[update]
+fn set_min_volume(token: TokenId, min_volume: Tokens) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.set_min_volume(token, min_volume)
+    })
+}
+
+// Sets the number of days after which orders of the token expire by default; 0 restores the
+// default.
+#[update]
+fn set_order_expiration_days(token: TokenId, days: u64) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.set_order_expiration_days(token, days)
+    })
+}
+
+// Sets the listing price in whole payment tokens.
+#[update]
+fn set_listing_price_usd(price: u128) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.set_listing_price_usd(price);
+        Ok(())
+    })
+}
+
+// Lets the user list tokens for free or revokes it.
+#[update]
+fn set_free_listing(user: Principal, free: bool) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.set_free_listing(user, free);
+        Ok(())
+    })
+}
+
+// Exempts the user from the limit of orders per hour or revokes the exemption.
+#[update]
+fn set_throttling_exemption(user: Principal, exempt: bool) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.set_throttling_exemption(user, exempt);
+        Ok(())
+    })
+}
+
+// Sets the number of days executed orders are kept in the archive.
+#[update]
+fn set_archive_retention_days(days: u64) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.set_archive_retention_days(days)
+    })
+}
+
+// Sets the number of logs kept by the daily clean-up.
+#[update]
+fn set_max_logs(max_logs: u64) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.set_max_logs(max_logs as usize)
+    })
+}
+
+// Adds a frontend domain to the certified domain assets.
+#[update]
+fn add_domain(domain: String) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.add_domain(domain)
+    })?;
+    assets::load_domains();
+    Ok(())
+}
+
+// Removes a frontend domain from the certified domain assets.
+#[update]
+fn remove_domain(domain: String) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.remove_domain(domain)
+    })?;
+    assets::load_domains();
+    Ok(())
+}
+
+// Resolves a crossed book of the pair and returns the number of re-executed orders.
+#[update]
+fn uncross(token: TokenId, quote: Option<TokenId>) -> Result<u64, String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state
+            .uncross(Pair { base: token, quote }, time())
+            .map(|repaired| repaired as u64)
+    })
+}
+
+// Sets the maker and the taker fee in basis points of the trade volume. All orders must be closed
+// before, e.g. with `close_all_orders`.
+#[update]
+fn set_trading_fees(fees: TradingFees) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.set_trading_fees(fees)
+    })
+}
+
+// Keeps only the `keep` newest archived orders of the token and returns the number of removed
+// orders.
+#[update]
+fn compact_archive(token: TokenId, keep: u64) -> Result<u64, String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        Ok(state.compact_archive(token, keep as usize) as u64)
+    })
+}
+
+// Sets the number of orders each side of a book can hold.
+#[update]
+fn set_max_orders_per_book(limit: usize) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.set_max_orders_per_book(limit)
+    })
+}
+
 // Closing of all orders is needed in order to upgrade the fees or the payment token.
 // Additionally, it could help in an emergency situation.
+//
+// The number of orders closed per call is bounded, so the method returns the number of remaining
+// open orders and needs to be called until it returns 0.
 #[update]
-fn close_all_orders() {
+fn close_all_orders() -> Result<usize, String> {
     mutate(|state| {
-        if state.revenue_account == Some(caller()) {
-            state.close_orders_by_condition(&|_| true, Default::default(), 10000);
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
         }
+        Ok(state.close_all_orders())
     })
 }
 
+// Closes the orders of the caller, optionally only those of the given token, and returns the
+// number of closed orders. The number of orders closed per call is bounded, so the method needs to
+// be called until it returns 0.
+#[update]
+fn cancel_my_orders(token: Option<TokenId>) -> usize {
+    mutate(|state| state.close_user_orders(caller(), token))
+}
+
 // In case something happens to the payment token, we can always switch to a new one.
 #[update]
-async fn set_payment_token(token_id: Principal) {
+async fn set_payment_token(token_id: Principal) -> Result<(), String> {
     if read(|state| state.revenue_account) != Some(caller()) {
-        return;
+        return Err("not authorized".into());
     }
 
     register_token(token_id)
         .await
-        .expect("couldn't register payment token");
+        .map_err(|err| format!("couldn't register payment token: {}", err))?;
 
+    mutate(|state| state.set_payment_token(token_id))
+}
+
+// Lets the token be traded against the given quote token in addition to the payment token.
+// Setting the quote token to None or changing it closes all orders against the previous one.
+#[update]
+fn set_quote_token(token: TokenId, quote: Option<TokenId>) -> Result<(), String> {
     mutate(|state| {
-        state.close_orders_by_condition(&|_| true, Default::default(), usize::MAX);
-        // we need to reset the order archive because the decimals of the new payment token might
-        // be different, which will lead to distorted prices
-        state.order_archive.clear();
-        state.payment_token_id = Some(token_id);
-        state.log(format!("payment token changed to {}", token_id));
-    });
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.set_quote_token(token, quote)
+    })
+}
+
+// Sets the minimal order volume in particles of the quote token and the price tick size of the
+// token; 0 disables the respective limit.
+#[update]
+fn set_order_limits(
+    token: TokenId,
+    min_notional: Tokens,
+    tick_size: ParticlesPerToken,
+) -> Result<(), String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.set_order_limits(token, min_notional, tick_size)
+    })
+}
+
+// Closes all orders of the token and delists it, if no user has a balance in the token. Otherwise,
+// the users with remaining balances are returned and the token stays listed, so that they can be
+// refunded first.
+#[update]
+fn delist_token(token: TokenId) -> Result<Vec<Principal>, String> {
+    mutate(|state| {
+        if state.revenue_account != Some(caller()) {
+            return Err("not authorized".into());
+        }
+        state.delist_token(token)
+    })
+}
+
+// Refetches the metadata of a listed token, e.g. after its ledger announced a fee change.
+// If the fee or the decimals have changed, all orders of the token get closed.
+#[update]
+async fn refresh_token_metadata(token: TokenId) -> Result<(), String> {
+    if read(|state| state.revenue_account) != Some(caller()) {
+        return Err("not authorized".into());
+    }
+    refresh_token(token).await
 }
 
 #[update]
@@ -80,9 +367,96 @@ async fn close_order(
     amount: u128,
     price: Tokens,
     timestamp: Timestamp,
-) {
-    mutate(|state| state.close_order(caller(), token, amount, price, timestamp, order_type))
-        .expect("couldn't close order")
+    quote: Option<TokenId>,
+) -> Result<(), String> {
+    mutate(|state| {
+        state.close_order(
+            caller(),
+            Pair { base: token, quote },
+            amount,
+            price,
+            timestamp,
+            order_type,
+        )
+    })
+}
+
+// Places a stop-limit order, which gets executed at the limit price once the last trade price
+// reaches the trigger price.
+#[update]
+fn create_stop_order(
+    token: TokenId,
+    order_type: OrderType,
+    amount: u128,
+    trigger_price: ParticlesPerToken,
+    limit_price: ParticlesPerToken,
+    quote: Option<TokenId>,
+) -> Result<(), String> {
+    mutate(|state| {
+        state.create_stop_order(
+            caller(),
+            Pair { base: token, quote },
+            amount,
+            trigger_price,
+            limit_price,
+            time(),
+            order_type,
+        )
+    })
+}
+
+// Keeps the caller's orders with a keepalive window open for another window.
+#[update]
+fn heartbeat() {
+    mutate(|state| state.heartbeat(caller(), time()))
+}
+
+#[update]
+#[allow(clippy::too_many_arguments)]
+fn close_stop_order(
+    token: TokenId,
+    order_type: OrderType,
+    amount: u128,
+    trigger_price: ParticlesPerToken,
+    limit_price: ParticlesPerToken,
+    timestamp: Timestamp,
+    quote: Option<TokenId>,
+) -> Result<(), String> {
+    mutate(|state| {
+        state.close_stop_order(
+            caller(),
+            Pair { base: token, quote },
+            amount,
+            trigger_price,
+            limit_price,
+            timestamp,
+            order_type,
+        )
+    })
+}
+
+#[update]
+#[allow(clippy::too_many_arguments)]
+async fn amend_order(
+    token: TokenId,
+    order_type: OrderType,
+    amount: u128,
+    price: Tokens,
+    timestamp: Timestamp,
+    new_amount: u128,
+    new_price: Tokens,
+    quote: Option<TokenId>,
+) -> Result<(), String> {
+    let key = Order::key(order_type, caller(), amount, price, timestamp);
+    mutate(|state| {
+        state.amend_order(
+            Pair { base: token, quote },
+            key,
+            new_amount,
+            new_price,
+            time(),
+        )
+    })
 }
 
 // This method deposits liquidity from user's subaccount into the token pools.
@@ -94,7 +468,44 @@ async fn close_order(
 // If the balance is smaller than the fee, the function does nothing.
 #[update]
 async fn deposit_liquidity(token: TokenId) -> Result<(), String> {
+    deposit_wallet(caller(), token).await
+}
+
+// Deposits the payment tokens from caller's subaccount and places a buy order with them. If the
+// balance doesn't cover the order, no order is placed and the deposit stays in the pool.
+#[update]
+async fn deposit_and_buy(
+    token: TokenId,
+    amount: u128,
+    price: Tokens,
+) -> Result<TradeReceipt, String> {
     let user = caller();
+    let payment_token_id = read(|state| state.payment_token_id());
+    deposit_wallet(user, payment_token_id).await?;
+    let result = mutate(|state| {
+        // The trade must not fail after it started, since it runs in the same message as the
+        // credit of the deposit.
+        state.check_buy_funds(user, token, amount, price)?;
+        state.trade(
+            OrderType::Buy,
+            user,
+            token,
+            amount,
+            price,
+            time(),
+            Default::default(),
+            None,
+            false,
+            None,
+            None,
+        )
+    });
+    notify_fills();
+    result
+}
+
+// Moves all funds from user's subaccount, except for the fee, into the token pool.
+async fn deposit_wallet(user: Principal, token: TokenId) -> Result<(), String> {
     let user_account = icrc1::user_account(user);
     let fee = read(|state| state.token(token))?.fee;
     let wallet_balance = icrc1::balance_of(token, &user_account)
@@ -107,92 +518,498 @@ async fn deposit_liquidity(token: TokenId) -> Result<(), String> {
 
     // if the balance is above 0, move everything from the wallet to BEACON
     if wallet_balance > 0 {
-        icrc1::transfer(
-            token,
-            user_account.subaccount,
-            icrc1::main_account(),
+        deposit(user, token, wallet_balance, fee).await?;
+    }
+    Ok(())
+}
+
+// Deposits exactly the given amount from user's subaccount into the token pool. The subaccount
+// must hold the amount plus the fee.
+#[update]
+async fn deposit_liquidity_amount(token: TokenId, amount: u128) -> Result<(), String> {
+    let user = caller();
+    let fee = read(|state| state.token(token))?.fee;
+    if amount == 0 {
+        return Err("amount is 0".into());
+    }
+    assert!(amount < i128::MAX as u128, "overflow");
+    let wallet_balance = icrc1::balance_of(token, &icrc1::user_account(user)).await?;
+    if wallet_balance < amount.saturating_add(fee) {
+        return Err(format!(
+            "not enough funds in the wallet: {} available, {} required including the fee",
             wallet_balance,
-            fee,
-        )
+            amount.saturating_add(fee)
+        ));
+    }
+    deposit(user, token, amount, fee).await
+}
+
+// Moves the amount from user's subaccount to BEACON and credits the amount that arrived to the
+// user's pool balance. Tokens delivering less than the sent amount are rejected.
+async fn deposit(user: Principal, token: TokenId, amount: u128, fee: u128) -> Result<(), String> {
+    let icrc1::Deposit {
+        sent,
+        received,
+        fee: paid_fee,
+    } = icrc1::deposit(token, icrc1::user_account(user).subaccount, amount, fee)
         .await
         .map_err(|err| {
             let error = format!("deposit transfer failed: {}", err);
             mutate(|state| state.log(error.clone()));
             error
         })?;
-        mutate_with_invarant_check(
-            |state| state.add_liquidity(user, token, wallet_balance),
-            Some((token, wallet_balance as i128)),
+    if paid_fee != fee {
+        update_token_fee(token, paid_fee);
+    }
+    let amount = received;
+    mutate_with_invarant_check(
+        |state| {
+            state.add_liquidity(user, token, amount);
+            state.emit(Event::LiquidityDeposited {
+                user,
+                token,
+                amount,
+            })
+        },
+        Some((token, amount as i128)),
+    );
+    if received < sent {
+        let error = format!(
+            "the ledger delivered only {} of {} transferred particles; tokens charging a fee on transfers are not supported",
+            received, sent
         );
+        mutate(|state| {
+            state.log(format!(
+                "WARNING: deposit of {} for {}: {}",
+                token, user, error
+            ))
+        });
+        return Err(error);
     }
     Ok(())
 }
 
+// Adds the orders to the books without matching them and returns the result for each order
+// including the maximal maker fee. Orders which would cross the book are rejected.
+#[update]
+fn create_orders(orders: Vec<OrderRequest>) -> Vec<Result<OrderFees, String>> {
+    mutate(|state| state.create_orders(caller(), orders, time()))
+}
+
+// Errors are returned only if no funds were moved: the rest order of a limit trade is validated
+// before the matching, and a trade failing in the middle of the execution breaks the invariant
+// checked by `mutate` and traps, which rolls back the call.
+//
+// A trade with a 32-byte client order id can be retried safely: a repeated id returns the receipt
+// of the first execution.
 #[update]
+#[allow(clippy::too_many_arguments)]
 async fn trade(
     token: TokenId,
     amount: u128,
     price: Tokens,
     order_type: OrderType,
-) -> OrderExecution {
-    mutate(|state| {
-        state
-            .trade(order_type, caller(), token, amount, price, time())
-            .expect("trade failed")
-    })
+    self_trade_prevention: Option<SelfTradePrevention>,
+    quote: Option<TokenId>,
+    expires_at: Option<Timestamp>,
+    post_only: Option<bool>,
+    keepalive_secs: Option<u64>,
+    client_order_id: Option<Vec<u8>>,
+) -> Result<TradeReceipt, String> {
+    let client_order_id = client_order_id
+        .map(|id| {
+            id.try_into()
+                .map_err(|_| "the client order id must have 32 bytes")
+        })
+        .transpose()?;
+    let result = mutate(|state| {
+        state.trade(
+            order_type,
+            caller(),
+            Pair { base: token, quote },
+            amount,
+            price,
+            time(),
+            self_trade_prevention.unwrap_or_default(),
+            expires_at,
+            post_only.unwrap_or_default(),
+            keepalive_secs,
+            client_order_id,
+        )
+    });
+    notify_fills();
+    result
 }
 
+// Buys the token for at most `quote_amount` payment tokens including fees, up to the price
+// `max_price`. Returns the amount of bought tokens and the spent payment tokens.
 #[update]
-async fn withdraw(token: Principal) -> Result<u128, String> {
+fn trade_quote_amount(
+    token: TokenId,
+    quote_amount: Tokens,
+    max_price: ParticlesPerToken,
+) -> Result<(Tokens, Tokens), String> {
+    let result =
+        mutate(|state| state.trade_quote_amount(caller(), token, quote_amount, max_price, time()));
+    notify_fills();
+    result
+}
+
+// Compares the internal accounting of the token with the balance of the main account on its
+// ledger. A persistent non-zero difference indicates lost or phantom funds.
+#[update]
+async fn reconcile(token: TokenId) -> Result<Reconciliation, String> {
+    let on_ledger = icrc1::balance_of(token, &icrc1::main_account()).await?;
+    Ok(read(|state| state.reconciliation(token, on_ledger)))
+}
+
+// Returns the balances of BEACON's main account on the ledgers of all listed tokens. The ledgers
+// are called concurrently, so that the call stays within the message deadline. Tokens whose
+// ledger couldn't be called are logged and omitted.
+#[update]
+async fn onchain_balances() -> Vec<(TokenId, u128)> {
+    let tokens = read(|state| state.tokens.keys().copied().collect::<Vec<_>>());
+    let account = icrc1::main_account();
+    let balances = futures::future::join_all(
+        tokens
+            .iter()
+            .map(|token| icrc1::balance_of(*token, &account)),
+    )
+    .await;
+    tokens
+        .into_iter()
+        .zip(balances)
+        .filter_map(|(token, balance)| match balance {
+            Ok(balance) => Some((token, balance)),
+            Err(err) => {
+                mutate(|state| {
+                    state.log(format!("couldn't fetch the balance of {}: {}", token, err))
+                });
+                None
+            }
+        })
+        .collect()
+}
+
+// Registers a method of a canister which gets called with every filled order of the caller.
+#[update]
+fn set_fill_notification(canister: Principal, method: String) {
+    mutate(|state| state.set_fill_notification(caller(), canister, method))
+}
+
+// Sends all pending fill notifications. The notifications are best-effort: failures are only
+// logged and never affect the trade.
+fn notify_fills() {
+    for (canister, method, order) in mutate(|state| state.take_fill_notifications()) {
+        spawn(async move {
+            if let Err((code, message)) = ic_cdk::call::<_, ()>(canister, &method, (order,)).await {
+                mutate(|state| {
+                    state.log(format!(
+                        "fill notification {}.{} failed: {:?} {}",
+                        canister, method, code, message
+                    ))
+                });
+            }
+        });
+    }
+}
+
+// Withdraws the whole balance of the caller in the token to the caller's account with the given
+// subaccount, or to the default account without a subaccount.
+#[update]
+async fn withdraw(
+    token: Principal,
+    to_subaccount: Option<icrc1::Subaccount>,
+) -> Result<u128, String> {
+    if to_subaccount
+        .as_ref()
+        .is_some_and(|subaccount| subaccount.len() != 32)
+    {
+        return Err("the subaccount must be 32 bytes long".into());
+    }
+    withdraw_token(caller(), token, to_subaccount).await
+}
+
+// Withdraws all non-zero balances of the caller and returns the result for each token.
+//
+// The transfers run concurrently. Each withdrawal runs in its own task, so that a trap while
+// handling the response of one ledger only rolls back that response and doesn't drop the other
+// withdrawals together with their reservations.
+#[update]
+async fn withdraw_all() -> Vec<(TokenId, Result<u128, String>)> {
     let user = caller();
+    let tokens = read(|state| {
+        state
+            .token_balances(user)
+            .into_iter()
+            .filter_map(|(token, (balance, _))| (balance > 0).then_some(token))
+            .collect::<Vec<_>>()
+    });
+    let receivers = tokens
+        .iter()
+        .map(|token| {
+            let (sender, receiver) = futures::channel::oneshot::channel();
+            let token = *token;
+            spawn(async move {
+                let _ = sender.send(withdraw_token(user, token, None).await);
+            });
+            receiver
+        })
+        .collect::<Vec<_>>();
+    let results = futures::future::join_all(receivers).await;
+    tokens
+        .into_iter()
+        .zip(results)
+        .map(|(token, result)| {
+            (
+                token,
+                result.unwrap_or_else(|_| Err("withdrawal aborted".into())),
+            )
+        })
+        .collect()
+}
+
+// Transfers the given amount of the collected fees to the given account. The ledger fee is
+// deducted from the amount.
+#[update]
+async fn withdraw_revenue(token: TokenId, amount: u128, to: Account) -> Result<u128, String> {
+    if read(|state| state.revenue_account) != Some(caller()) {
+        return Err("not authorized".into());
+    }
+    let fee = read(|state| state.token(token))?.fee;
+    if amount <= fee {
+        return Err("amount smaller than the fee".into());
+    }
+    assert!(amount < i128::MAX as u128, "overflow");
+    withdraw_revenue_with(&icrc1::Icrc1Ledger(token), token, amount, fee, to, time()).await
+}
+
+// The revenue is reserved during the transfer and only deducted once the ledger confirms it.
+async fn withdraw_revenue_with(
+    ledger: &impl icrc1::Ledger,
+    token: TokenId,
+    amount: u128,
+    fee: Tokens,
+    to: Account,
+    now: Timestamp,
+) -> Result<u128, String> {
+    let revenue_account = mutate(|state| state.reserve_revenue_withdrawal(token, amount))?;
+    match transfer_with(ledger, token, None, to, amount - fee, fee, now).await {
+        Ok(transferred) => {
+            mutate_with_invarant_check(
+                |state| state.commit_withdrawal(revenue_account, token, amount),
+                Some((token, -(amount as i128))),
+            )?;
+            Ok(transferred)
+        }
+        Err(err) => {
+            let error = format!("revenue transfer failed: {}", err);
+            mutate(|state| {
+                state.log(error.clone());
+                state.release_withdrawal(revenue_account, token, amount)
+            })?;
+            Err(error)
+        }
+    }
+}
+
+// Transfers the funds of the token sent directly to the main account, i.e. the balance of the
+// main account exceeding the funds under management, to the given account and returns the
+// transferred amount.
+//
+// The balance is only used if no transfer of the token ran while it was fetched, because an
+// unconfirmed deposit or withdrawal would otherwise appear as unaccounted funds.
+#[update]
+async fn sweep_unaccounted(token: TokenId, to: Account) -> Result<u128, String> {
+    if read(|state| state.revenue_account) != Some(caller()) {
+        return Err("not authorized".into());
+    }
+    let fee = read(|state| state.token(token))?.fee;
+    let on_ledger = icrc1::settled_balance(token, &icrc1::main_account()).await?;
+    let amount = read(|state| state.sweepable_amount(token, on_ledger, fee))?;
+    let amount = transfer(token, None, to.clone(), amount, fee)
+        .await
+        .map_err(|err| format!("sweep transfer failed: {}", err))?;
+    mutate(|state| {
+        state.log(format!(
+            "swept {} unaccounted tokens of {} to {}",
+            amount, token, to.owner
+        ))
+    });
+    Ok(amount)
+}
+
+// Transfers the funds of the user stuck in their subaccount of a delisted token back to the user's
+// main account and returns the transferred amount.
+//
+// This is safe only for tokens that are not listed: funds in the subaccounts of unlisted tokens
+// are not accounted in any pool, so moving them doesn't affect any internal balance. Should the
+// token get relisted and the user deposit concurrently, only one of the two transfers can succeed,
+// because the ledger rejects the other one for insufficient funds.
+#[update]
+async fn refund_stranded(token: TokenId, user: Principal) -> Result<u128, String> {
+    if read(|state| state.revenue_account) != Some(caller()) {
+        return Err("not authorized".into());
+    }
+    if read(|state| state.tokens().contains_key(&token)) {
+        return Err("token is listed, the funds can be deposited".into());
+    }
+    let user_account = icrc1::user_account(user);
+    let fee = icrc1::fee(token).await?;
+    let balance = icrc1::balance_of(token, &user_account).await?;
+    if balance <= fee {
+        return Err("amount smaller than the fee".into());
+    }
+    let (amount, _) = icrc1::transfer(
+        token,
+        user_account.subaccount,
+        Account {
+            owner: user,
+            subaccount: None,
+        },
+        balance - fee,
+        fee,
+    )
+    .await
+    .map_err(|err| format!("refund transfer failed: {}", err))?;
+    mutate(|state| {
+        state.log(format!(
+            "refunded {} stranded tokens of the delisted token {} to {}",
+            amount, token, user
+        ))
+    });
+    Ok(amount)
+}
+
+async fn withdraw_token(
+    user: Principal,
+    token: TokenId,
+    to_subaccount: Option<icrc1::Subaccount>,
+) -> Result<u128, String> {
+    withdraw_token_with(
+        &icrc1::Icrc1Ledger(token),
+        user,
+        token,
+        to_subaccount,
+        time(),
+    )
+    .await
+}
+
+async fn withdraw_token_with(
+    ledger: &impl icrc1::Ledger,
+    user: Principal,
+    token: TokenId,
+    to_subaccount: Option<icrc1::Subaccount>,
+    now: Timestamp,
+) -> Result<u128, String> {
     let fee = read(|state| state.token(token))?.fee;
     let existing_balance = read(|state| state.token_pool_balance(token, user));
     assert!(existing_balance < i128::MAX as u128, "overflow");
     if existing_balance <= fee {
         return Err("amount smaller than the fee".into());
     }
-    let balance = mutate_with_invarant_check(
-        |state| state.withdraw_liquidity(user, token),
-        Some((token, -(existing_balance as i128))),
-    )?;
+    // The balance gets reserved, so that it can't be spent during the transfer, and is only
+    // removed once the transfer is confirmed.
+    let balance = mutate(|state| state.reserve_withdrawal(user, token))?;
     let amount = balance.checked_sub(fee).expect("underflow");
-    icrc1::transfer(
+    let result = transfer_with(
+        ledger,
         token,
         None,
         Account {
             owner: user,
-            subaccount: None,
+            subaccount: to_subaccount,
         },
         amount,
         fee,
+        now,
     )
-    .await
-    .map_err(|err| {
-        let error = format!("withdraw transfer failed: {}", err);
-        mutate(|state| state.log(error.clone()));
-        mutate_with_invarant_check(
-            |state| state.add_liquidity(user, token, balance),
-            Some((token, balance as i128)),
-        );
-        error
+    .await;
+    match result {
+        Ok(transferred) => {
+            mutate_with_invarant_check(
+                |state| state.commit_withdrawal(user, token, balance),
+                Some((token, -(balance as i128))),
+            )?;
+            Ok(transferred)
+        }
+        Err(err) => {
+            let error = format!("withdraw transfer failed: {}", err);
+            mutate(|state| {
+                state.log(error.clone());
+                state.release_withdrawal(user, token, balance)
+            })?;
+            Err(error)
+        }
+    }
+}
+
+// Transfers the amount and returns the transferred amount. If the ledger charged a different fee
+// than the cached one, the cached fee gets updated and the transferred amount is adjusted by the
+// fee difference.
+async fn transfer(
+    token: TokenId,
+    from_subaccount: Option<icrc1::Subaccount>,
+    to: Account,
+    amount: Tokens,
+    fee: Tokens,
+) -> Result<Tokens, String> {
+    let ledger = icrc1::Icrc1Ledger(token);
+    transfer_with(&ledger, token, from_subaccount, to, amount, fee, time()).await
+}
+
+async fn transfer_with(
+    ledger: &impl icrc1::Ledger,
+    token: TokenId,
+    from_subaccount: Option<icrc1::Subaccount>,
+    to: Account,
+    amount: Tokens,
+    fee: Tokens,
+    created_at_time: Timestamp,
+) -> Result<Tokens, String> {
+    let (amount, paid_fee) = icrc1::transfer_with_fee_retry(
+        ledger,
+        token,
+        from_subaccount,
+        to,
+        amount,
+        fee,
+        created_at_time,
+    )
+    .await?;
+    if paid_fee != fee {
+        update_token_fee(token, paid_fee);
+    }
+    Ok(amount)
+}
+
+// Updates the cached fee after a ledger charged a different one. This must not fail a transfer
+// that already happened, so a token delisted in the meantime is only logged.
+fn update_token_fee(token: TokenId, fee: Tokens) {
+    mutate(|state| {
+        if let Err(err) = state.set_token_fee(token, fee) {
+            state.log(format!(
+                "WARNING: couldn't update the fee of {} to {}: {}",
+                token, fee, err
+            ));
+        }
     })
-    .map(|_| amount)
 }
 
 #[update]
 async fn list_token(token: TokenId) -> Result<(), String> {
     let user = caller();
 
-    let Metadata { fee, decimals, .. } = read(|state| {
-        state
-            .token(state.payment_token_id())
-            .expect("no payment token")
-    });
-    // we subtract the fee twice, because the user moved the funds to BEACON internal account
-    // first and now we need to move it to the payment pool again
-    let effective_amount = LISTING_PRICE_USD * 10_u128.pow(decimals) - fee - fee;
+    if read(|state| state.low_cycles) {
+        return Err("token listings are suspended due to low cycles".into());
+    }
+
+    let effective_amount = read(|state| state.listing_charge(user))?;
 
-    if read(|state| state.payment_token_pool().get(&user) < Some(&effective_amount)) {
+    if effective_amount > 0
+        && read(|state| state.payment_token_pool().get(&user) < Some(&effective_amount))
+    {
         return Err("not enough funds for listing".into());
     }
 
@@ -201,11 +1018,130 @@ async fn list_token(token: TokenId) -> Result<(), String> {
     register_token(token).await?;
 
     // if the listing worked, charge the user
-    mutate(|state| {
-        state
-            .charge(user, effective_amount)
-            .expect("payment failed")
-    });
+    if effective_amount > 0 {
+        mutate(|state| {
+            state
+                .charge_listing(user, token, effective_amount, time())
+                .expect("payment failed")
+        });
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::icrc1::tests::MockLedger;
+    use crate::icrc1::TransferError;
+    use crate::order_book::tests::{list_payment_token, list_test_token, pr};
+    use std::future::Future;
+    use std::task::Context;
+
+    fn funds(token: TokenId) -> Tokens {
+        read(|state| state.funds_under_management())
+            .into_iter()
+            .find(|(id, _)| id == &token.to_string())
+            .map(|(_, funds)| funds)
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_concurrent_withdrawals() {
+        let tokens = [pr(100), pr(101), pr(102)];
+        unsafe_mutate(|state| {
+            list_payment_token(state);
+            for token in tokens {
+                list_test_token(state, token, 2);
+                state.add_liquidity(pr(0), token, 1000);
+                state.add_liquidity(pr(1), token, 500);
+            }
+        });
+        let funds_before = tokens.map(funds);
+
+        // the transfer of the second token fails
+        let ledgers = [Ok(Ok(1)), Ok(Err(TransferError::TooOld)), Ok(Ok(2))].map(|response| {
+            let mut ledger = MockLedger::new(vec![response]);
+            ledger.suspending = true;
+            ledger
+        });
+        let mut withdrawals =
+            Box::pin(futures::future::join_all(tokens.iter().zip(&ledgers).map(
+                |(token, ledger)| withdraw_token_with(ledger, pr(0), *token, None, 0),
+            )));
+
+        // all balances get reserved before any transfer completes
+        let waker = futures::task::noop_waker();
+        assert!(withdrawals
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+        for (token, funds_before) in tokens.iter().zip(funds_before) {
+            assert_eq!(read(|state| state.token_pool_balance(*token, pr(0))), 0);
+            assert_eq!(funds(*token), funds_before);
+        }
+
+        // the ledger fee of 25 is deducted from the transferred amount
+        assert_eq!(
+            futures::executor::block_on(withdrawals),
+            vec![
+                Ok(975),
+                Err("withdraw transfer failed: TooOld".into()),
+                Ok(975)
+            ]
+        );
+
+        // only the withdrawn balances left the pools, the failed one was released
+        assert_eq!(funds(tokens[0]), funds_before[0] - 1000);
+        assert_eq!(funds(tokens[1]), funds_before[1]);
+        assert_eq!(funds(tokens[2]), funds_before[2] - 1000);
+        read(|state| {
+            assert_eq!(state.token_pool_balance(tokens[0], pr(0)), 0);
+            assert_eq!(state.token_pool_balance(tokens[1], pr(0)), 1000);
+            assert_eq!(state.token_pool_balance(tokens[2], pr(0)), 0);
+            for token in tokens {
+                assert_eq!(state.token_pool_balance(token, pr(1)), 500);
+            }
+        });
+    }
+
+    #[test]
+    fn test_failed_revenue_withdrawal() {
+        let token = pr(100);
+        unsafe_mutate(|state| {
+            list_payment_token(state);
+            list_test_token(state, token, 2);
+            state.add_liquidity(pr(255), token, 1000);
+        });
+        let funds_before = funds(token);
+        let revenue = || read(|state| state.revenue_balances().get(&token).copied());
+        let to = Account {
+            owner: pr(3),
+            subaccount: None,
+        };
+
+        // the revenue is only deducted after the ledger confirmed the transfer
+        let ledger = MockLedger::new(vec![Ok(Err(TransferError::TooOld))]);
+        assert_eq!(
+            futures::executor::block_on(withdraw_revenue_with(
+                &ledger,
+                token,
+                600,
+                25,
+                to.clone(),
+                0
+            )),
+            Err("revenue transfer failed: TooOld".into())
+        );
+        assert_eq!(revenue(), Some(1000));
+        assert_eq!(funds(token), funds_before);
+
+        let ledger = MockLedger::new(vec![Ok(Ok(1))]);
+        assert_eq!(
+            futures::executor::block_on(withdraw_revenue_with(&ledger, token, 600, 25, to, 0)),
+            Ok(575)
+        );
+        assert_eq!(revenue(), Some(400));
+        assert_eq!(funds(token), funds_before - 600);
+    }
+}
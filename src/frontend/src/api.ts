@@ -38,7 +38,7 @@ export type Backend = {
 
     deposit_liquidity: (tokenId: Principal) => Promise<JsonValue>;
 
-    close_all_orders: () => Promise<void>;
+    close_all_orders: () => Promise<JsonValue>;
 
     set_revenue_account: (principal: string) => Promise<void>;
 
@@ -220,8 +220,14 @@ export const ApiGenerator = (
                 "set_payment_token",
                 arg,
             );
-
-            decode(response);
+            const result: any = decode(
+                response,
+                IDL.Variant({
+                    Ok: IDL.Null,
+                    Err: IDL.Text,
+                }),
+            );
+            if ("Err" in result) throw new Error(result.Err);
         },
 
         set_revenue_account: async (principal: string): Promise<void> => {
@@ -238,15 +244,20 @@ export const ApiGenerator = (
             decode(response);
         },
 
-        close_all_orders: async (): Promise<void> => {
+        close_all_orders: async (): Promise<JsonValue> => {
             const arg = IDL.encode([], []);
             const response = await call_raw(
                 canisterId,
                 "close_all_orders",
                 arg,
             );
-
-            decode(response);
+            return decode(
+                response,
+                IDL.Variant({
+                    Ok: IDL.Nat64,
+                    Err: IDL.Text,
+                }),
+            );
         },
 
         close_order: async (
@@ -273,8 +284,14 @@ export const ApiGenerator = (
                 ],
             );
             const response = await call_raw(canisterId, "close_order", arg);
-
-            decode(response);
+            const result: any = decode(
+                response,
+                IDL.Variant({
+                    Ok: IDL.Null,
+                    Err: IDL.Text,
+                }),
+            );
+            if ("Err" in result) throw new Error(result.Err);
         },
 
         list_token: async (tokenId: Principal): Promise<JsonValue> => {
@@ -321,13 +338,24 @@ export const ApiGenerator = (
                 [tokenId, amount, price, { [orderType.toString()]: null }],
             );
             const response = await call_raw(canisterId, "trade", arg);
-            return decode(
+            const result: any = decode(
                 response,
                 IDL.Variant({
-                    Filled: IDL.Nat,
-                    FilledAndOrderCreated: IDL.Nat,
+                    Ok: IDL.Record({
+                        execution: IDL.Variant({
+                            Filled: IDL.Nat,
+                            FilledAndOrderCreated: IDL.Nat,
+                        }),
+                        fees: IDL.Record({
+                            taker_fee: IDL.Nat,
+                            max_maker_fee: IDL.Nat,
+                        }),
+                    }),
+                    Err: IDL.Text,
                 }),
             );
+            if ("Err" in result) throw new Error(result.Err);
+            return result.Ok.execution;
         },
 
         withdraw: async (tokenId: Principal) => {
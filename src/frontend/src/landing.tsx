@@ -37,6 +37,7 @@ export const Landing = ({}) => {
         trades_day,
         volume_day,
         fee,
+        maker_fee,
         cycle_balance,
         heap_size,
         tokens_listed,
@@ -110,8 +111,10 @@ export const Landing = ({}) => {
                     </code>
                 </div>
                 <div className="dbcell">
-                    <span>FEE</span>
-                    <code>{Number(fee) / 100}%</code>
+                    <span>MAKER / TAKER FEE</span>
+                    <code>
+                        {Number(maker_fee) / 100}% / {Number(fee) / 100}%
+                    </code>
                 </div>
                 <div className="dbcell">
                     <span>TOKENS LISTED</span>
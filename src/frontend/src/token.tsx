@@ -116,7 +116,10 @@ export const Token = ({ tokenId }: { tokenId: string }) => {
                                     </td>
                                     <td>
                                         <code>
-                                            {token(order.amount, decimals)}
+                                            {token(
+                                                order.amount,
+                                                order.decimals,
+                                            )}
                                         </code>{" "}
                                         {symbol}
                                     </td>
@@ -162,7 +165,10 @@ const Chart = ({ originalOrders }: { originalOrders: Order[] }) => {
         ctx.clearRect(0, 0, canvas.width, canvas.height);
 
         const prices = orders.map((order) => Number(order.price));
-        const volumes = orders.map((order) => Number(order.amount));
+        // Amounts are normalized with the decimals of each order, as a relisting can change them.
+        const volumes = orders.map(
+            (order) => Number(order.amount) / Math.pow(10, order.decimals),
+        );
         let yMax = Math.max(...prices);
         let yMin = Math.min(...prices);
         const scale = Math.max(...prices) - Math.min(...prices);
@@ -3,6 +3,7 @@ import { Principal } from "@dfinity/principal";
 export type BackendData = {
     listing_price_usd: number;
     fee: bigint;
+    maker_fee: bigint;
     volume_day: bigint;
     trades_day: number;
     payment_token_locked: bigint;
@@ -32,6 +33,20 @@ export type Metadata = {
     decimals: number;
     logo: string;
     timestamp: number;
+    min_notional: bigint;
+    tick_size: bigint;
+    min_volume: bigint;
+    order_expiration_days: number;
+};
+
+export type TokenOverview = {
+    metadata: Metadata;
+    best_bid?: bigint;
+    best_ask?: bigint;
+    last_price?: bigint;
+    volume_24h: bigint;
+    open_buy_orders: number;
+    open_sell_orders: number;
 };
 
 export enum OrderType {
@@ -46,6 +61,9 @@ export type Order = {
     price: bigint;
     decimals: number;
     executed: number;
+    expires_at?: number;
+    keepalive_secs?: number;
+    id: number;
 };
 
 export type OrderExecution =